@@ -0,0 +1,83 @@
+//! Benchmarks of the steps of a conversion on a disk in memory: computing the
+//! image layout, scanning for zeros, and copying with different buffer sizes.
+//!
+//! Run them with `cargo bench`, optionally giving a part of the names of the
+//! benchmarks to run.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::io::Cursor;
+use std::ops::Range;
+
+use streaming_qcow2_writer::qcow2::StreamingQcow2Writer;
+use streaming_qcow2_writer::scan;
+
+const DISK_SIZE: u64 = 256 << 20;
+
+/// Size of each extent of data, and of the holes between them
+const EXTENT_SIZE: u64 = 1 << 20;
+
+/// Runs of each benchmark, each one going over the whole disk
+const SAMPLES: usize = 10;
+
+const BUFFER_SIZES: [usize; 4] = [8 << 10, 64 << 10, 1 << 20, 8 << 20];
+
+/// A disk alternating extents of data and holes, the second half of each data
+/// extent being zeros.
+fn disk() -> (Vec<u8>, Vec<Range<u64>>) {
+    let mut data = vec![0u8; DISK_SIZE as usize];
+    let mut layout = Vec::new();
+    for start in (0..DISK_SIZE).step_by(2 * EXTENT_SIZE as usize) {
+        let half = (start + EXTENT_SIZE / 2) as usize;
+        for (i, byte) in data[start as usize..half].iter_mut().enumerate() {
+            *byte = (i % 251) as u8 + 1;
+        }
+        layout.push(start..start + EXTENT_SIZE);
+    }
+    (data, layout)
+}
+
+fn layout(c: &mut Criterion) {
+    // Mapping a fragmented layout to clusters, extents of 4 KiB every 12 KiB
+    let fragmented: Vec<Range<u64>> = (0..DISK_SIZE).step_by(12 << 10).map(|start| start..start + 4096).collect();
+    let mut group = c.benchmark_group("layout");
+    group.sample_size(SAMPLES);
+    group.throughput(Throughput::Bytes(DISK_SIZE));
+    group.bench_function("fragmented", |b| {
+        b.iter(|| black_box(StreamingQcow2Writer::new(DISK_SIZE, fragmented.iter().cloned()).unwrap()));
+    });
+    group.finish();
+}
+
+fn zero_scan(c: &mut Criterion) {
+    let (data, layout) = disk();
+    let data_bytes: u64 = layout.iter().map(|r| r.end - r.start).sum();
+    let mut group = c.benchmark_group("zero_scan");
+    group.sample_size(SAMPLES);
+    group.throughput(Throughput::Bytes(data_bytes));
+    group.bench_function("extents", |b| {
+        b.iter(|| black_box(scan::nonzero_extents(Cursor::new(&data), &layout).unwrap()));
+    });
+    group.finish();
+}
+
+fn copy(c: &mut Criterion) {
+    let (data, layout) = disk();
+    let writer = StreamingQcow2Writer::new(DISK_SIZE, layout.iter().cloned()).unwrap();
+    let mut group = c.benchmark_group("copy");
+    group.sample_size(SAMPLES);
+    group.throughput(Throughput::Bytes(writer.file_size()));
+    for buffer_size in BUFFER_SIZES {
+        group.bench_with_input(BenchmarkId::new("buffer", format!("{}k", buffer_size >> 10)), &buffer_size, |b, &buffer_size| {
+            b.iter(|| {
+                let mut output = std::io::BufWriter::with_capacity(buffer_size, std::io::sink());
+                writer.write_header(&mut output).unwrap();
+                writer.copy_data(Cursor::new(&data), &mut output, None).unwrap();
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, layout, zero_scan, copy);
+criterion_main!(benches);
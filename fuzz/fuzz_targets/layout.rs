@@ -0,0 +1,51 @@
+//! Layout files in every format, checked against a disk: parsing must not
+//! panic, and the extents found have to describe the disk exactly.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use streaming_qcow2_writer::layout::{self, ExtentKind, LayoutFormat, OutOfRange};
+
+#[derive(Arbitrary, Debug)]
+struct Input<'a> {
+    format: u8,
+    size: u64,
+    /// Alignment is `1 << (alignment_bits % 17)`, up to a cluster
+    alignment_bits: u8,
+    strict: bool,
+    out_of_range: u8,
+    layout: &'a [u8],
+}
+
+fuzz_target!(|input: Input| {
+    let format = [LayoutFormat::Json, LayoutFormat::Csv, LayoutFormat::Ddrescue, LayoutFormat::Partclone]
+        [input.format as usize % 4];
+    let out_of_range = [None, Some(OutOfRange::Clamp), Some(OutOfRange::Error), Some(OutOfRange::Extend)]
+        [input.out_of_range as usize % 4];
+    let alignment = 1 << (input.alignment_bits % 17);
+    let Ok(entries) = layout::read_extents(input.layout, format) else {
+        return;
+    };
+    let Ok(extents) = layout::typed_extents(entries, input.size, alignment, input.strict, out_of_range) else {
+        return;
+    };
+
+    // The extents are sorted, don't overlap, and cover the disk; only data
+    // can go past its end, with --out-of-range extend
+    let mut end = 0;
+    for extent in extents {
+        if end < input.size {
+            assert_eq!(extent.range.start, end, "{:?} doesn't follow the previous extent", extent);
+        } else {
+            assert!(extent.range.start >= end, "{:?} overlaps the previous extent", extent);
+        }
+        assert!(extent.range.start < extent.range.end, "{:?} is empty", extent);
+        if extent.range.end > input.size {
+            assert_eq!(extent.kind, ExtentKind::Data, "{:?} is past the end", extent);
+            assert!(out_of_range == Some(OutOfRange::Extend), "{:?} is past the end", extent);
+        }
+        end = extent.range.end;
+    }
+    assert!(end >= input.size, "extents end at {}, before the end of the disk", end);
+});
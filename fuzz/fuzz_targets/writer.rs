@@ -0,0 +1,70 @@
+//! Images written for arbitrary sizes and layouts: the writer must not panic
+//! or overflow, and the image has to pass `check` with the expected size.
+
+#![no_main]
+
+use std::io::Cursor;
+use std::ops::Range;
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use streaming_qcow2_writer::check;
+use streaming_qcow2_writer::layout;
+use streaming_qcow2_writer::qcow2::{StreamingQcow2Writer, CLUSTER_SIZE};
+
+/// Largest input, so the data clusters stay small enough to write in memory
+const MAX_INPUT_SIZE: u64 = 16 << 20;
+
+/// Largest virtual size, so the metadata stays small enough too
+const MAX_VIRTUAL_SIZE: u64 = 8 << 30;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    input_size: u64,
+    /// Offsets and lengths of the layout entries
+    layout: Vec<(u64, u64)>,
+    zero_ranges: Vec<(u64, u64)>,
+    virtual_size: Option<u64>,
+    size_alignment: Option<u64>,
+    backing_file: Option<String>,
+}
+
+/// Turn offsets and lengths into ranges of the input, as the layout sources
+/// give them.
+fn ranges(entries: &[(u64, u64)], size: u64) -> Vec<Range<u64>> {
+    let ranges = entries.iter().map(|&(offset, length)| offset..offset.saturating_add(length)).collect();
+    layout::intersect(ranges, std::iter::once(0..size).collect())
+}
+
+fuzz_target!(|input: Input| {
+    let input_size = input.input_size % (MAX_INPUT_SIZE + 1);
+    let mut writer = StreamingQcow2Writer::new(input_size, ranges(&input.layout, input_size).into_iter()).unwrap();
+    if let Some(size) = input.virtual_size {
+        writer.set_virtual_size(size % (MAX_VIRTUAL_SIZE + 1));
+    }
+    if let Some(alignment) = input.size_alignment {
+        writer.set_size_alignment(alignment % CLUSTER_SIZE + 1);
+    }
+    let zero_ranges = ranges(&input.zero_ranges, writer.virtual_size());
+    if writer.set_zero_ranges(&zero_ranges).is_err() {
+        return;
+    }
+    if let Some(backing_file) = input.backing_file {
+        // Names too large for the header are rejected
+        if writer.set_backing_file(backing_file).is_err() {
+            return;
+        }
+    }
+
+    let mut image = Vec::new();
+    writer.write_header(&mut image).unwrap();
+    // The input reads as zeros
+    writer.copy_data(Cursor::new(Vec::new()), &mut image, None).unwrap();
+    assert_eq!(image.len() as u64, writer.file_size());
+
+    let report = check::check(Cursor::new(&image)).unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.leaked_clusters, 0);
+    assert_eq!(report.guest_clusters, writer.total_guest_clusters());
+    assert_eq!(report.allocated_clusters, writer.data_clusters().len());
+});
@@ -0,0 +1,52 @@
+/* C interface to streaming-qcow2-writer, implemented in src/ffi.rs.
+ *
+ * Writing an image:
+ *
+ *     sqw_writer *w = sqw_writer_new(disk_size, ranges, count);
+ *     sqw_writer_write_header(w, write_fn, opaque);
+ *     uint64_t offset;
+ *     while(sqw_writer_next_cluster(w, &offset) == 1)
+ *         ... write SQW_CLUSTER_SIZE bytes of the disk at offset ...
+ *     sqw_writer_finish(w);
+ */
+
+#ifndef STREAMING_QCOW2_WRITER_H
+#define STREAMING_QCOW2_WRITER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SQW_CLUSTER_SIZE 65536
+
+typedef struct SqwWriter sqw_writer;
+
+typedef struct {
+    uint64_t offset;
+    uint64_t length;
+} sqw_range;
+
+/* Writes bytes to the output, returning the number of bytes written
+ * (possibly fewer than asked), or a negative number on error. */
+typedef intptr_t (*sqw_write_fn)(void *opaque, const uint8_t *buf, size_t len);
+
+/* Returns NULL if a range goes past the end of the disk. */
+sqw_writer *sqw_writer_new(uint64_t input_size, const sqw_range *ranges, size_t count);
+uint64_t sqw_writer_file_size(const sqw_writer *writer);
+int sqw_writer_write_header(sqw_writer *writer, sqw_write_fn write, void *opaque);
+/* Alternative to sqw_writer_write_header(): reads the next piece of the
+ * header and metadata, returning its length, or 0 once all was read. */
+intptr_t sqw_writer_read_header(sqw_writer *writer, uint8_t *buf, size_t len);
+/* Returns 1 and sets offset, or 0 once all the clusters were listed. */
+int sqw_writer_next_cluster(sqw_writer *writer, uint64_t *offset);
+/* Frees the writer; returns -1 if not all the clusters were listed. */
+int sqw_writer_finish(sqw_writer *writer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,98 @@
+// Streaming qcow2 conversion in the browser or in edge workers, using the
+// WebAssembly build of the library:
+//
+//     cargo build --release --lib --target wasm32-unknown-unknown
+//
+// which produces target/wasm32-unknown-unknown/release/streaming_qcow2_writer.wasm
+
+const CLUSTER_SIZE = 65536;
+
+/**
+ * Instantiate the WebAssembly module, from a Response, an ArrayBuffer or a
+ * compiled WebAssembly.Module.
+ */
+export async function load(source) {
+  let instance;
+  if (source instanceof WebAssembly.Module) {
+    instance = await WebAssembly.instantiate(source, {});
+  } else if (typeof Response !== 'undefined' && source instanceof Response) {
+    ({ instance } = await WebAssembly.instantiateStreaming(source, {}));
+  } else {
+    ({ instance } = await WebAssembly.instantiate(source, {}));
+  }
+  return instance.exports;
+}
+
+/**
+ * Convert a raw disk image to qcow2, as a stream.
+ *
+ * `disk` is a Blob (such as a File from an upload), read as the image is
+ * pulled from the stream. `ranges` lists the parts of the disk holding data,
+ * as {offset, length} objects in bytes; by default the whole disk is copied.
+ * The rest is left unallocated.
+ */
+export function qcow2Stream(exports, disk, ranges = [{ offset: 0, length: disk.size }]) {
+  const {
+    memory, sqw_alloc, sqw_free,
+    sqw_writer_new, sqw_writer_read_header, sqw_writer_next_cluster, sqw_writer_finish,
+  } = exports;
+
+  // Pass the ranges as an array of two 64-bit integers each
+  const rangesLength = Math.max(ranges.length * 16, 1);
+  const rangesPtr = sqw_alloc(rangesLength) >>> 0;
+  const view = new DataView(memory.buffer, rangesPtr, rangesLength);
+  ranges.forEach(({ offset, length }, i) => {
+    view.setBigUint64(i * 16, BigInt(offset), true);
+    view.setBigUint64(i * 16 + 8, BigInt(length), true);
+  });
+  const writer = sqw_writer_new(BigInt(disk.size), rangesPtr, ranges.length) >>> 0;
+  sqw_free(rangesPtr, rangesLength);
+  if (writer === 0) {
+    throw new RangeError('a range goes past the end of the disk');
+  }
+
+  const buffer = sqw_alloc(CLUSTER_SIZE) >>> 0;
+  const offsetPtr = sqw_alloc(8) >>> 0;
+  let headerDone = false;
+  let finished = false;
+  const finish = () => {
+    if (!finished) {
+      finished = true;
+      sqw_free(buffer, CLUSTER_SIZE);
+      sqw_free(offsetPtr, 8);
+      return sqw_writer_finish(writer);
+    }
+  };
+
+  return new ReadableStream({
+    async pull(controller) {
+      if (!headerDone) {
+        const read = sqw_writer_read_header(writer, buffer, CLUSTER_SIZE);
+        if (read < 0) {
+          finish();
+          controller.error(new Error('error writing the qcow2 header'));
+          return;
+        }
+        if (read > 0) {
+          // Copy it out, the memory can be reused and grown
+          controller.enqueue(new Uint8Array(memory.buffer, buffer, read).slice());
+          return;
+        }
+        headerDone = true;
+      }
+      if (sqw_writer_next_cluster(writer, offsetPtr) === 1) {
+        const offset = Number(new DataView(memory.buffer).getBigUint64(offsetPtr, true));
+        // The last cluster is padded with zeros past the end of the disk
+        const cluster = new Uint8Array(CLUSTER_SIZE);
+        cluster.set(new Uint8Array(await disk.slice(offset, offset + CLUSTER_SIZE).arrayBuffer()));
+        controller.enqueue(cluster);
+      } else {
+        finish();
+        controller.close();
+      }
+    },
+    cancel() {
+      finish();
+    },
+  });
+}
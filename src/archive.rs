@@ -0,0 +1,410 @@
+//! Inputs that are a member of a tar archive, such as an OVA appliance, or of
+//! a zip archive, read in place without extracting them.
+//!
+//! Only the headers are read to find the member. A member stored as is can
+//! then be read as a range of the archive, in any input format; a deflated
+//! zip member is decompressed as it is read, in a single pass.
+
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::qcow2::read_full;
+use crate::tar;
+
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// Largest GNU long name or pax extended header we read
+const MAX_TAR_EXTENSION_SIZE: u64 = 1 << 20;
+
+/// Size of the zip end of central directory record, without its comment
+const ZIP_EOCD_SIZE: u64 = 22;
+
+const ZIP_MAX_COMMENT_SIZE: u64 = 65535;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Stored,
+    Deflated,
+}
+
+/// Location of a member's data in the archive.
+#[derive(Debug)]
+pub struct Member {
+    pub offset: u64,
+    /// Size of the data in the archive
+    pub stored_size: u64,
+    /// Size of the member once decompressed
+    pub size: u64,
+    pub method: Method,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, msg)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    if read_full(reader, &mut buffer)? < len {
+        return Err(invalid("archive is truncated"));
+    }
+    Ok(buffer)
+}
+
+/// Compare member names, ignoring a leading `./` as tar adds it.
+fn same_name(member: &str, name: &str) -> bool {
+    member.trim_start_matches("./") == name.trim_start_matches("./")
+}
+
+/// Find a member by name in a tar or zip archive.
+pub fn find_member<R: Read + Seek>(mut archive: R, name: &str) -> std::io::Result<Member> {
+    let archive_size = archive.seek(SeekFrom::End(0))?;
+    archive.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+    let read = read_full(&mut archive, &mut header)?;
+    let member = if header[..read].starts_with(b"PK\x03\x04") || header[..read].starts_with(b"PK\x05\x06") {
+        find_zip_member(&mut archive, archive_size, name)?
+    } else if read == header.len() && is_tar_header(&header) {
+        find_tar_member(&mut archive, name)?
+    } else {
+        return Err(invalid("the input is not a tar or zip archive"));
+    };
+    let Some(member) = member else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no member {} in the archive", name),
+        ));
+    };
+    if member.offset.checked_add(member.stored_size).is_none_or(|end| end > archive_size) {
+        return Err(invalid(&format!("member {} goes past the end of the archive", name)));
+    }
+    Ok(member)
+}
+
+/// Check the checksum of a tar header, computed with its own field set to
+/// spaces.
+fn is_tar_header(header: &[u8; TAR_BLOCK_SIZE as usize]) -> bool {
+    let Some(checksum) = tar::read_number(&header[148..156]) else {
+        return false;
+    };
+    let sum: u64 = header.iter().enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum();
+    sum == checksum
+}
+
+/// Text of a header field, up to its first NUL byte.
+fn field_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn tar_header_name(header: &[u8; TAR_BLOCK_SIZE as usize]) -> String {
+    let name = field_string(&header[0..100]);
+    // POSIX ustar headers can split long names, GNU ones use the space for
+    // other fields
+    let prefix = if &header[257..263] == b"ustar\0" { field_string(&header[345..500]) } else { String::new() };
+    if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+}
+
+/// Parse the records of a pax extended header, `<length> <key>=<value>\n`.
+fn pax_records(mut data: &[u8]) -> std::io::Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() && data[0] != 0 {
+        let malformed = || invalid("malformed pax extended header");
+        let space = data.iter().position(|&b| b == b' ').ok_or_else(malformed)?;
+        let length: usize = std::str::from_utf8(&data[..space]).ok()
+            .and_then(|l| l.parse().ok())
+            .filter(|&l| l > space && l <= data.len())
+            .ok_or_else(malformed)?;
+        let record = String::from_utf8_lossy(&data[space + 1..length]);
+        let (key, value) = record.trim_end_matches('\n').split_once('=').ok_or_else(malformed)?;
+        records.push((key.to_owned(), value.to_owned()));
+        data = &data[length..];
+    }
+    Ok(records)
+}
+
+fn find_tar_member<R: Read + Seek>(archive: &mut R, name: &str) -> std::io::Result<Option<Member>> {
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+    let mut offset = 0;
+    // Name and size of the next member, from a GNU long name or a pax
+    // extended header
+    let mut next_name = None;
+    let mut next_size = None;
+    loop {
+        archive.seek(SeekFrom::Start(offset))?;
+        if read_full(&mut *archive, &mut header)? < header.len() {
+            return Err(invalid("tar archive is truncated"));
+        }
+        if header.iter().all(|&b| b == 0) {
+            // End of archive
+            return Ok(None);
+        }
+        if !is_tar_header(&header) {
+            return Err(invalid(&format!("invalid tar header at offset {}", offset)));
+        }
+        let data = offset + TAR_BLOCK_SIZE;
+        let mut size = tar::read_number(&header[124..136])
+            .ok_or_else(|| invalid(&format!("invalid size in tar header at offset {}", offset)))?;
+        match header[156] {
+            b'L' | b'x' => {
+                if size > MAX_TAR_EXTENSION_SIZE {
+                    return Err(invalid(&format!("tar extended header at offset {} is too large", offset)));
+                }
+                let extension = read_at(archive, data, size as usize)?;
+                if header[156] == b'L' {
+                    next_name = Some(field_string(&extension));
+                }
+                for (key, value) in if header[156] == b'x' { pax_records(&extension)? } else { Vec::new() } {
+                    match key.as_str() {
+                        "path" => next_name = Some(value),
+                        "size" => {
+                            let value = value.parse()
+                                .map_err(|_| invalid(&format!("invalid size in pax header at offset {}", offset)))?;
+                            next_size = Some(value);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // Global pax headers don't describe a member
+            b'g' => {}
+            typeflag => {
+                let member_name = next_name.take().unwrap_or_else(|| tar_header_name(&header));
+                size = next_size.take().unwrap_or(size);
+                if same_name(&member_name, name) {
+                    return match typeflag {
+                        b'0' | b'\0' | b'7' => {
+                            Ok(Some(Member { offset: data, stored_size: size, size, method: Method::Stored }))
+                        }
+                        b'S' => Err(unsupported(&format!("{} is a sparse tar member, which isn't supported", name))),
+                        _ => Err(invalid(&format!("{} is not a regular file", name))),
+                    };
+                }
+            }
+        }
+        offset = size.checked_next_multiple_of(TAR_BLOCK_SIZE)
+            .and_then(|size| data.checked_add(size))
+            .ok_or_else(|| invalid(&format!("invalid size in tar header at offset {}", offset)))?;
+    }
+}
+
+fn find_zip_member<R: Read + Seek>(archive: &mut R, archive_size: u64, name: &str) -> std::io::Result<Option<Member>> {
+    // The end of central directory record is last, followed by a comment
+    let tail_offset = archive_size.saturating_sub(ZIP_EOCD_SIZE + ZIP_MAX_COMMENT_SIZE);
+    let tail = read_at(archive, tail_offset, (archive_size - tail_offset) as usize)?;
+    let eocd = (0..(tail.len() + 1).saturating_sub(ZIP_EOCD_SIZE as usize))
+        .rev()
+        .find(|&i| tail[i..].starts_with(b"PK\x05\x06"))
+        .ok_or_else(|| invalid("zip end of central directory not found"))?;
+    let record = &tail[eocd..];
+    let mut entries = LittleEndian::read_u16(&record[10..12]) as u64;
+    let mut directory_size = LittleEndian::read_u32(&record[12..16]) as u64;
+    let mut directory_offset = LittleEndian::read_u32(&record[16..20]) as u64;
+    if entries == 0xffff || directory_size == 0xffff_ffff || directory_offset == 0xffff_ffff {
+        // Zip64, the locator before the record points to a larger record
+        let locator_offset = (tail_offset + eocd as u64).checked_sub(20)
+            .ok_or_else(|| invalid("zip64 end of central directory locator not found"))?;
+        let locator = read_at(archive, locator_offset, 20)?;
+        if !locator.starts_with(b"PK\x06\x07") {
+            return Err(invalid("zip64 end of central directory locator not found"));
+        }
+        let record = read_at(archive, LittleEndian::read_u64(&locator[8..16]), 56)?;
+        if !record.starts_with(b"PK\x06\x06") {
+            return Err(invalid("zip64 end of central directory not found"));
+        }
+        entries = LittleEndian::read_u64(&record[32..40]);
+        directory_size = LittleEndian::read_u64(&record[40..48]);
+        directory_offset = LittleEndian::read_u64(&record[48..56]);
+    }
+
+    archive.seek(SeekFrom::Start(directory_offset))?;
+    let mut directory = BufReader::new((&mut *archive).take(directory_size));
+    let truncated = |_| invalid("zip central directory is truncated");
+    let mut found = None;
+    for _ in 0..entries {
+        let mut entry = [0u8; 46];
+        directory.read_exact(&mut entry).map_err(truncated)?;
+        if !entry.starts_with(b"PK\x01\x02") {
+            return Err(invalid("invalid zip central directory entry"));
+        }
+        let mut entry_name = vec![0u8; LittleEndian::read_u16(&entry[28..30]) as usize];
+        directory.read_exact(&mut entry_name).map_err(truncated)?;
+        let mut extra = vec![0u8; LittleEndian::read_u16(&entry[30..32]) as usize];
+        directory.read_exact(&mut extra).map_err(truncated)?;
+        let comment_length = LittleEndian::read_u16(&entry[32..34]) as u64;
+        std::io::copy(&mut (&mut directory).take(comment_length), &mut std::io::sink())?;
+        if same_name(&String::from_utf8_lossy(&entry_name), name) {
+            found = Some((entry, extra));
+            break;
+        }
+    }
+    drop(directory);
+    let Some((entry, extra)) = found else {
+        return Ok(None);
+    };
+
+    if LittleEndian::read_u16(&entry[8..10]) & 1 != 0 {
+        return Err(unsupported(&format!("{} is encrypted", name)));
+    }
+    let method = match LittleEndian::read_u16(&entry[10..12]) {
+        0 => Method::Stored,
+        8 => Method::Deflated,
+        m => return Err(unsupported(&format!("{} uses unsupported zip compression method {}", name, m))),
+    };
+    let mut stored_size = LittleEndian::read_u32(&entry[20..24]) as u64;
+    let mut size = LittleEndian::read_u32(&entry[24..28]) as u64;
+    let mut local_offset = LittleEndian::read_u32(&entry[42..46]) as u64;
+
+    // The zip64 extra field holds the values that don't fit, in this order
+    let mut extra = extra.as_slice();
+    while extra.len() >= 4 {
+        let id = LittleEndian::read_u16(&extra[0..2]);
+        let length = (LittleEndian::read_u16(&extra[2..4]) as usize).min(extra.len() - 4);
+        if id == 1 {
+            let mut values = extra[4..4 + length].chunks_exact(8).map(LittleEndian::read_u64);
+            for field in [&mut size, &mut stored_size, &mut local_offset] {
+                if *field == 0xffff_ffff {
+                    *field = values.next().ok_or_else(|| invalid("invalid zip64 extra field"))?;
+                }
+            }
+        }
+        extra = &extra[4 + length..];
+    }
+    if method == Method::Stored && stored_size != size {
+        return Err(invalid(&format!("stored zip member {} has inconsistent sizes", name)));
+    }
+
+    // The data follows the local header, whose variable fields can differ
+    // from the central directory's
+    let local = read_at(archive, local_offset, 30)?;
+    if !local.starts_with(b"PK\x03\x04") {
+        return Err(invalid(&format!("invalid zip local header for {}", name)));
+    }
+    let offset = local_offset
+        + 30
+        + LittleEndian::read_u16(&local[26..28]) as u64
+        + LittleEndian::read_u16(&local[28..30]) as u64;
+    Ok(Some(Member { offset, stored_size, size, method }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::{Cursor, Write};
+
+    /// Append a ustar member to a tar archive.
+    fn tar_member(archive: &mut Vec<u8>, name: &str, typeflag: u8, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(TAR_BLOCK_SIZE as usize), 0);
+    }
+
+    fn tar_archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        tar_member(&mut archive, "appliance.ovf", b'0', b"<Envelope/>");
+        tar_member(&mut archive, "PaxHeader", b'x', b"35 path=disk-with-a-long-name.vmdk\n");
+        tar_member(&mut archive, "ignored", b'0', &[7; 1000]);
+        tar_member(&mut archive, "./disk.raw", b'0', &[1; 600]);
+        archive.extend_from_slice(&[0; 2 * TAR_BLOCK_SIZE as usize]);
+        archive
+    }
+
+    /// A zip archive of members stored as is.
+    fn zip_archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data) in members {
+            let offset = archive.len() as u32;
+            archive.write_all(b"PK\x03\x04\x14\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+            archive.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            archive.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            archive.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+            archive.write_u16::<LittleEndian>(0).unwrap();
+            archive.write_all(name.as_bytes()).unwrap();
+            archive.write_all(data).unwrap();
+
+            directory.write_all(b"PK\x01\x02\x14\x00\x14\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+            directory.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            directory.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            directory.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+            directory.write_all(&[0; 12]).unwrap();
+            directory.write_u32::<LittleEndian>(offset).unwrap();
+            directory.write_all(name.as_bytes()).unwrap();
+        }
+        let directory_offset = archive.len() as u32;
+        archive.write_all(&directory).unwrap();
+        archive.write_all(b"PK\x05\x06\x00\x00\x00\x00").unwrap();
+        archive.write_u16::<LittleEndian>(members.len() as u16).unwrap();
+        archive.write_u16::<LittleEndian>(members.len() as u16).unwrap();
+        archive.write_u32::<LittleEndian>(directory.len() as u32).unwrap();
+        archive.write_u32::<LittleEndian>(directory_offset).unwrap();
+        archive.write_u16::<LittleEndian>(0).unwrap();
+        archive
+    }
+
+    #[test]
+    fn tar_lookup() {
+        let archive = tar_archive();
+        let member = find_member(Cursor::new(&archive), "disk.raw").unwrap();
+        assert_eq!((member.offset, member.size, member.method), (4096, 600, Method::Stored));
+        assert!(archive[4096..4696].iter().all(|&b| b == 1));
+
+        // Named by the pax header before it
+        let member = find_member(Cursor::new(&archive), "disk-with-a-long-name.vmdk").unwrap();
+        assert_eq!((member.offset, member.size), (2560, 1000));
+        assert_eq!(archive[2560], 7);
+
+        let error = find_member(Cursor::new(&archive), "ignored").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        let error = find_member(Cursor::new(&archive), "disk.vmdk").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn zip_lookup() {
+        let archive = zip_archive(&[("appliance.ovf", b"<Envelope/>"), ("disk.raw", &[1; 600])]);
+        let member = find_member(Cursor::new(&archive), "./disk.raw").unwrap();
+        assert_eq!((member.offset, member.stored_size, member.size, member.method), (92, 600, 600, Method::Stored));
+        assert!(archive[92..692].iter().all(|&b| b == 1));
+
+        let error = find_member(Cursor::new(&archive), "disk.vmdk").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn truncated() {
+        // In the header of the second member
+        let archive = tar_archive();
+        let error = find_member(Cursor::new(&archive[..1200]), "disk.raw").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // Member data going past the end
+        let error = find_member(Cursor::new(&archive[..4600]), "disk.raw").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // Zip central directory shorter than its entries
+        let mut archive = zip_archive(&[("disk.raw", &[1; 600])]);
+        let length = archive.len();
+        archive[length - 12..length - 10].copy_from_slice(&2u16.to_le_bytes());
+        let error = find_member(Cursor::new(&archive), "disk.vmdk").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
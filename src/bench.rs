@@ -0,0 +1,130 @@
+//! Benchmark mode (`--bench`): timing the steps of a conversion on the
+//! user's hardware, to help choose between the sparsification and output
+//! options.
+//!
+//! Only the first pass over the data reads the storage, later ones may be
+//! served from the page cache if the input fits in memory.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::qcow2::{read_full, StreamingQcow2Writer};
+use crate::{scan, signals, throttle};
+
+/// Sizes of the output buffer to compare
+const BUFFER_SIZES: [usize; 4] = [8 << 10, 64 << 10, 1 << 20, 8 << 20];
+
+/// Numbers of threads reading the input at the same time
+const THREAD_COUNTS: [usize; 4] = [1, 2, 4, 8];
+
+/// Size of the requests made by the reading threads
+const READ_CHUNK: u64 = 1 << 20;
+
+pub struct Benchmark<'a> {
+    pub input_size: u64,
+    pub layout: &'a [Range<u64>],
+    /// Time it took to compute the layout
+    pub layout_time: Duration,
+    /// Path to the input file, to read it from several threads
+    pub input_path: Option<&'a Path>,
+    /// File to write the images to, instead of discarding them
+    pub output: Option<&'a File>,
+}
+
+impl Benchmark<'_> {
+    pub fn run<R: Read + Seek + Send>(&self, mut input: R) -> std::io::Result<()> {
+        let data_bytes: u64 = self.layout.iter().map(|r| r.end - r.start).sum();
+        println!(
+            "Layout: {} bytes of data in {} extents, computed in {:.2} s",
+            data_bytes, self.layout.len(), self.layout_time.as_secs_f64(),
+        );
+
+        let started = Instant::now();
+        let extents = scan::nonzero_extents(&mut input, self.layout)?;
+        let nonzero_bytes: u64 = extents.iter().map(|r| r.end - r.start).sum();
+        println!(
+            "Zero scanning: {} bytes of non-zero data, {}",
+            nonzero_bytes, rate(data_bytes, started.elapsed()),
+        );
+
+        let writer = StreamingQcow2Writer::new(self.input_size, self.layout.iter().cloned())?;
+        match self.output {
+            Some(mut file) => {
+                for buffer_size in BUFFER_SIZES {
+                    file.seek(SeekFrom::Start(0))?;
+                    let started = Instant::now();
+                    let mut output = std::io::BufWriter::with_capacity(buffer_size, file);
+                    writer.write_header(&mut output)?;
+                    writer.copy_data(&mut input, &mut output, None)?;
+                    output.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+                    println!(
+                        "Copy with a {} KiB buffer: {}",
+                        buffer_size >> 10, rate(writer.file_size(), started.elapsed()),
+                    );
+                }
+            }
+            None => {
+                let started = Instant::now();
+                let mut output = std::io::sink();
+                writer.write_header(&mut output)?;
+                writer.copy_data(&mut input, &mut output, None)?;
+                println!(
+                    "Copy without output: {}",
+                    rate(writer.file_size(), started.elapsed()),
+                );
+            }
+        }
+
+        if let Some(path) = self.input_path {
+            for threads in THREAD_COUNTS {
+                let started = Instant::now();
+                parallel_read(path, self.layout, threads)?;
+                println!(
+                    "Reading with {} thread{}: {}",
+                    threads, if threads == 1 { "" } else { "s" }, rate(data_bytes, started.elapsed()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the extents of the layout from several threads, each one reading
+/// every nth chunk from its own handle on the file.
+fn parallel_read(path: &Path, layout: &[Range<u64>], threads: usize) -> std::io::Result<()> {
+    let chunks: Vec<Range<u64>> = layout.iter()
+        .flat_map(|r| {
+            (r.start..r.end).step_by(READ_CHUNK as usize)
+                .map(move |start| start..(start + READ_CHUNK).min(r.end))
+        })
+        .collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|first| {
+                let chunks = &chunks;
+                scope.spawn(move || -> std::io::Result<()> {
+                    let mut file = File::open(path)?;
+                    let mut buffer = vec![0u8; READ_CHUNK as usize];
+                    for chunk in chunks.iter().skip(first).step_by(threads) {
+                        signals::check_cancelled()?;
+                        throttle::wait_for_read();
+                        file.seek(SeekFrom::Start(chunk.start))?;
+                        read_full(&mut file, &mut buffer[..(chunk.end - chunk.start) as usize])?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles.into_iter().try_for_each(|h| h.join().unwrap())
+    })
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let rate = if seconds > 0.0 { bytes as f64 / seconds } else { 0.0 };
+    format!("{:.2} s, {:.1} MB/s", seconds, rate / 1_000_000.0)
+}
@@ -0,0 +1,73 @@
+//! Buffers the data is copied through (`--buffer-size`), allocated on the
+//! heap and kept in a pool once used, so the copy and the read-ahead threads
+//! reuse the same memory instead of allocating for every read.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::qcow2::CLUSTER_SIZE;
+
+/// Size of the buffers by default
+pub const DEFAULT_SIZE: usize = 1 << 20;
+
+/// Size set with `set_size()`
+static SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_SIZE);
+
+/// Buffers no longer in use
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Use buffers of this size, rounded up to whole clusters.
+pub fn set_size(size: usize) {
+    let size = size.max(1).div_ceil(CLUSTER_SIZE as usize) * CLUSTER_SIZE as usize;
+    SIZE.store(size, Ordering::Relaxed);
+    // Buffers of the previous size are not reused
+    POOL.lock().unwrap().clear();
+}
+
+/// Size of the buffers, a multiple of the cluster size.
+pub fn size() -> usize {
+    SIZE.load(Ordering::Relaxed)
+}
+
+/// A zero-filled buffer from the pool, returned to it on drop.
+pub struct Buffer(Vec<u8>);
+
+impl Buffer {
+    /// Get a buffer of `size()` bytes.
+    pub fn get() -> Buffer {
+        Buffer::with_len(size())
+    }
+
+    /// Get a buffer of this length, at most `size()` bytes.
+    pub fn with_len(len: usize) -> Buffer {
+        let capacity = size();
+        assert!(len <= capacity);
+        let mut data = POOL.lock().unwrap().pop().unwrap_or_else(|| Vec::with_capacity(capacity));
+        data.clear();
+        data.resize(len, 0);
+        Buffer(data)
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.0.capacity() == size() {
+            POOL.lock().unwrap().push(std::mem::take(&mut self.0));
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Checking the consistency of existing qcow2 images (`check` subcommand),
+//! like `qemu-img check`: header fields, L1 and L2 tables, cluster bounds and
+//! refcounts.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::qcow2::reader::{
+    read_table, Cluster, Header, INCOMPAT_CORRUPT, INCOMPAT_DIRTY, OFFSET_MASK,
+};
+
+const L1_RESERVED: u64 = 0x7f00_0000_0000_01ff;
+const L2_RESERVED: u64 = 0x3f00_0000_0000_01fe;
+const L2_COMPRESSED: u64 = 1 << 62;
+const REFCOUNT_TABLE_RESERVED: u64 = 0x1ff;
+
+/// Problems found in an image.
+#[derive(Default)]
+pub struct Report {
+    /// Corruptions, which can cause data loss
+    pub errors: Vec<String>,
+    /// Warnings about things that could not be checked
+    pub warnings: Vec<String>,
+    /// Clusters with a refcount higher than their number of references
+    pub leaked_clusters: u64,
+    /// Number of guest clusters allocated in the image
+    pub allocated_clusters: u64,
+    /// Number of guest clusters of the virtual disk
+    pub guest_clusters: u64,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Reference counts computed from the metadata, for each host cluster.
+struct References {
+    cluster_bits: u32,
+    file_size: u64,
+    counts: Vec<u32>,
+}
+
+impl References {
+    /// Count a reference to the clusters covering a range of the file.
+    fn add(&mut self, report: &mut Report, what: std::fmt::Arguments, offset: u64, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let end = offset.saturating_add(len);
+        if end > self.file_size {
+            report.errors.push(format!(
+                "{} at offset {} ({} bytes) is past the end of the file",
+                what, offset, len,
+            ));
+            return false;
+        }
+        for cluster in (offset >> self.cluster_bits)..=((end - 1) >> self.cluster_bits) {
+            self.counts[cluster as usize] += 1;
+        }
+        true
+    }
+}
+
+/// Check an image.
+pub fn check<R: Read + Seek>(mut reader: R) -> std::io::Result<Report> {
+    let mut report = Report::default();
+    let file_size = reader.seek(SeekFrom::End(0))?;
+
+    let header = Header::read(&mut reader)?;
+    let incompatible = header.incompatible_features;
+    if incompatible & INCOMPAT_DIRTY != 0 {
+        report.warnings.push("the image is marked dirty, refcounts may be stale".to_owned());
+    }
+    if incompatible & INCOMPAT_CORRUPT != 0 {
+        report.errors.push("the image is marked corrupt".to_owned());
+    }
+    if incompatible & !(INCOMPAT_DIRTY | INCOMPAT_CORRUPT) != 0 {
+        return Err(invalid("qcow2 image uses unsupported features"));
+    }
+    let Header {
+        backing_file_offset,
+        backing_file_size,
+        cluster_bits,
+        size,
+        l1_size,
+        l1_table_offset,
+        refcount_table_offset,
+        refcount_table_clusters,
+        nb_snapshots,
+        refcount_order,
+        ..
+    } = header;
+
+    let cluster_size = header.cluster_size();
+    let l2_entries = header.l2_entries();
+    let mut references = References {
+        cluster_bits,
+        file_size,
+        counts: vec![0; file_size.div_ceil(cluster_size) as usize],
+    };
+
+    // Header, and backing file name
+    references.add(&mut report, format_args!("header"), 0, cluster_size.min(file_size));
+    if backing_file_offset != 0 {
+        match backing_file_offset.checked_add(backing_file_size) {
+            None => report.errors.push(format!(
+                "backing file name at offset {} ({} bytes) is past the end of the file",
+                backing_file_offset, backing_file_size,
+            )),
+            Some(end) if end > cluster_size => {
+                references.add(&mut report, format_args!("backing file name"), backing_file_offset, backing_file_size);
+            }
+            Some(_) => {}
+        }
+    }
+
+    if nb_snapshots > 0 {
+        report.warnings.push(format!(
+            "the image has {} snapshots, which are not checked; refcounts were not compared",
+            nb_snapshots,
+        ));
+    }
+
+    // L1 table
+    let num_clusters = header.guest_clusters();
+    if l1_size < header.l1_entries_needed() {
+        report.errors.push(format!(
+            "L1 table has {} entries, too few for the virtual size of {} bytes",
+            l1_size, size,
+        ));
+    }
+    if !l1_table_offset.is_multiple_of(cluster_size) {
+        return Err(invalid("L1 table is not aligned to clusters"));
+    }
+    if !references.add(&mut report, format_args!("L1 table"), l1_table_offset, l1_size * 8) {
+        return Ok(report);
+    }
+    let l1 = read_table(&mut reader, l1_table_offset, l1_size)?;
+
+    // L2 tables and data clusters
+    let mut allocated = 0;
+    for (l1_index, l1_entry) in l1.into_iter().enumerate() {
+        if l1_entry & L1_RESERVED != 0 {
+            report.errors.push(format!("L1 entry {} has reserved bits set", l1_index));
+        }
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            continue;
+        }
+        if !l2_offset.is_multiple_of(cluster_size) {
+            report.errors.push(format!("L2 table {} at offset {} is not aligned to clusters", l1_index, l2_offset));
+            continue;
+        }
+        if !references.add(&mut report, format_args!("L2 table {}", l1_index), l2_offset, cluster_size) {
+            continue;
+        }
+        let l2 = read_table(&mut reader, l2_offset, l2_entries)?;
+        for (l2_index, l2_entry) in l2.into_iter().enumerate() {
+            let guest_cluster = l1_index as u64 * l2_entries + l2_index as u64;
+            if l2_entry & L2_COMPRESSED == 0 && l2_entry & L2_RESERVED != 0 {
+                report.errors.push(format!("L2 entry for guest cluster {} has reserved bits set", guest_cluster));
+            }
+            let offset = match Cluster::from_entry(l2_entry, cluster_bits) {
+                Cluster::Unallocated | Cluster::Zero(None) => continue,
+                Cluster::Compressed { offset, size } => {
+                    // The last sector of compressed data can be past the end
+                    // of the file
+                    let start = offset & !511;
+                    let len = (size + (offset & 511)).min(file_size.saturating_sub(start));
+                    references.add(&mut report, format_args!("guest cluster {}", guest_cluster), start, len);
+                    allocated += 1;
+                    continue;
+                }
+                Cluster::Zero(Some(offset)) | Cluster::Data(offset) => offset,
+            };
+            if guest_cluster >= num_clusters {
+                report.errors.push(format!("guest cluster {} is past the virtual size", guest_cluster));
+            }
+            if !offset.is_multiple_of(cluster_size) {
+                report.errors.push(format!("guest cluster {} at offset {} is not aligned to clusters", guest_cluster, offset));
+                continue;
+            }
+            references.add(&mut report, format_args!("guest cluster {}", guest_cluster), offset, cluster_size);
+            allocated += 1;
+        }
+    }
+
+    // Refcount table and blocks
+    if !refcount_table_offset.is_multiple_of(cluster_size) {
+        return Err(invalid("refcount table is not aligned to clusters"));
+    }
+    let table_size = refcount_table_clusters * cluster_size;
+    if !references.add(&mut report, format_args!("refcount table"), refcount_table_offset, table_size) {
+        return Ok(report);
+    }
+    let table = read_at(&mut reader, refcount_table_offset, table_size as usize)?;
+    let refcount_bits = 1u64 << refcount_order;
+    let block_entries = cluster_size * 8 / refcount_bits;
+    let mut stored = vec![0u64; references.counts.len()];
+    for (block_index, table_entry) in table.chunks(8).map(BigEndian::read_u64).enumerate() {
+        if table_entry & REFCOUNT_TABLE_RESERVED != 0 {
+            report.errors.push(format!("refcount table entry {} has reserved bits set", block_index));
+        }
+        let block_offset = table_entry & !REFCOUNT_TABLE_RESERVED;
+        if block_offset == 0 {
+            continue;
+        }
+        if !block_offset.is_multiple_of(cluster_size) {
+            report.errors.push(format!("refcount block {} at offset {} is not aligned to clusters", block_index, block_offset));
+            continue;
+        }
+        if !references.add(&mut report, format_args!("refcount block {}", block_index), block_offset, cluster_size) {
+            continue;
+        }
+        let block = read_at(&mut reader, block_offset, cluster_size as usize)?;
+        let first_cluster = block_index as u64 * block_entries;
+        for i in 0..block_entries {
+            let cluster = first_cluster + i;
+            let bit = i * refcount_bits;
+            let refcount = match refcount_bits {
+                64 => BigEndian::read_u64(&block[(bit / 8) as usize..]),
+                32 => BigEndian::read_u32(&block[(bit / 8) as usize..]) as u64,
+                16 => BigEndian::read_u16(&block[(bit / 8) as usize..]) as u64,
+                8 => block[(bit / 8) as usize] as u64,
+                // Sub-byte refcounts are stored from the least significant bit
+                _ => {
+                    let byte = block[(bit / 8) as usize] as u64;
+                    (byte >> (bit % 8)) & ((1 << refcount_bits) - 1)
+                }
+            };
+            match stored.get_mut(cluster as usize) {
+                Some(s) => *s = refcount,
+                None if refcount != 0 => {
+                    report.errors.push(format!("cluster {} has a refcount but is past the end of the file", cluster));
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Compare the refcounts
+    if nb_snapshots == 0 {
+        for (cluster, (&computed, &refcount)) in references.counts.iter().zip(&stored).enumerate() {
+            let computed = computed as u64;
+            if refcount < computed {
+                report.errors.push(format!(
+                    "cluster {} has refcount {} but {} references",
+                    cluster, refcount, computed,
+                ));
+            } else if refcount > computed {
+                report.leaked_clusters += 1;
+            }
+        }
+    }
+
+    report.allocated_clusters = allocated;
+    report.guest_clusters = num_clusters;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const CLUSTER_SIZE: u64 = 1 << 16;
+
+    /// A version 2 image of a 1 MiB disk with nothing allocated: the header,
+    /// the refcount table in cluster 1, its block in cluster 2 and the L1
+    /// table in cluster 3, each with a refcount of 1.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 4 * CLUSTER_SIZE as usize];
+        image[0..4].copy_from_slice(b"QFI\xfb");
+        (&mut image[4..8]).write_u32::<BigEndian>(2).unwrap();
+        (&mut image[20..24]).write_u32::<BigEndian>(16).unwrap();
+        (&mut image[24..32]).write_u64::<BigEndian>(1 << 20).unwrap();
+        (&mut image[36..40]).write_u32::<BigEndian>(1).unwrap();
+        (&mut image[40..48]).write_u64::<BigEndian>(3 * CLUSTER_SIZE).unwrap();
+        (&mut image[48..56]).write_u64::<BigEndian>(CLUSTER_SIZE).unwrap();
+        (&mut image[56..60]).write_u32::<BigEndian>(1).unwrap();
+        (&mut image[CLUSTER_SIZE as usize..][..8]).write_u64::<BigEndian>(2 * CLUSTER_SIZE).unwrap();
+        for cluster in 0..4 {
+            set_refcount(&mut image, cluster, 1);
+        }
+        image
+    }
+
+    fn set_refcount(image: &mut [u8], cluster: usize, refcount: u16) {
+        (&mut image[2 * CLUSTER_SIZE as usize + cluster * 2..][..2]).write_u16::<BigEndian>(refcount).unwrap();
+    }
+
+    fn has_error(report: &Report, text: &str) -> bool {
+        report.errors.iter().any(|e| e.contains(text))
+    }
+
+    #[test]
+    fn clean_image() {
+        let report = check(Cursor::new(image())).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.leaked_clusters, 0);
+        assert_eq!(report.guest_clusters, 16);
+        assert_eq!(report.allocated_clusters, 0);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut image = image();
+        image[3] = 0;
+        assert!(check(Cursor::new(image)).is_err());
+    }
+
+    #[test]
+    fn backing_file_offset_out_of_range() {
+        let mut image = image();
+        (&mut image[8..16]).write_u64::<BigEndian>(u64::MAX - 10).unwrap();
+        (&mut image[16..20]).write_u32::<BigEndian>(100).unwrap();
+        let report = check(Cursor::new(image)).unwrap();
+        assert!(has_error(&report, "backing file name"), "{:?}", report.errors);
+
+        let mut image = self::image();
+        (&mut image[8..16]).write_u64::<BigEndian>(8 * CLUSTER_SIZE).unwrap();
+        (&mut image[16..20]).write_u32::<BigEndian>(100).unwrap();
+        let report = check(Cursor::new(image)).unwrap();
+        assert!(has_error(&report, "backing file name at offset"), "{:?}", report.errors);
+    }
+
+    #[test]
+    fn overlapping_tables() {
+        // The L1 table is put over the refcount table
+        let mut image = image();
+        (&mut image[40..48]).write_u64::<BigEndian>(CLUSTER_SIZE).unwrap();
+        let report = check(Cursor::new(image)).unwrap();
+        assert!(has_error(&report, "cluster 1 has refcount 1 but 2 references"), "{:?}", report.errors);
+    }
+
+    #[test]
+    fn refcount_mismatch() {
+        let mut image = image();
+        set_refcount(&mut image, 3, 0);
+        let report = check(Cursor::new(image)).unwrap();
+        assert_eq!(report.errors, vec!["cluster 3 has refcount 0 but 1 references".to_owned()]);
+
+        let mut image = self::image();
+        set_refcount(&mut image, 3, 2);
+        let report = check(Cursor::new(image)).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.leaked_clusters, 1);
+    }
+}
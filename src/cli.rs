@@ -0,0 +1,1279 @@
+use std::ffi::{OsStr, OsString};
+use std::time::Duration;
+
+use streaming_qcow2_writer::fixture::{self, Fill, Fixture};
+use streaming_qcow2_writer::http::Method;
+use streaming_qcow2_writer::input::InputFormat;
+use streaming_qcow2_writer::layout::{LayoutFormat, Operation, OutOfRange};
+use streaming_qcow2_writer::nbd;
+use streaming_qcow2_writer::partition::PartitionType;
+use streaming_qcow2_writer::priority::IoPriority;
+use streaming_qcow2_writer::progress::Interval;
+use streaming_qcow2_writer::qcow2::Preallocation;
+use streaming_qcow2_writer::secret;
+
+pub const USAGE: &str = "\
+Usage: streaming-qcow2-writer [options] input.img [layout.json...] > output.qcow2
+       streaming-qcow2-writer [options] -o output.qcow2 input.img [layout.json...]
+       streaming-qcow2-writer [options] --empty SIZE > output.qcow2
+       streaming-qcow2-writer gen-fixture [options] output.img
+       streaming-qcow2-writer layout [options] OPERATION layout.json...
+       streaming-qcow2-writer check image.qcow2
+
+The input can be a file, a block device, a Ceph RBD image given as
+rbd:pool/image[@snapshot], or an NBD export given as nbd://host[:port]/export
+or nbd+unix:///export?socket=PATH. RBD images are mapped read-only with the
+rbd tool. For RBD images and NBD exports, their allocated extents are used as
+the layout if none is given. Inputs compressed with gzip, xz or zstd are
+decompressed, and read like a pipe. The layout is read from stdin if given as
+-; several layouts are merged (--layout-merge).
+Entries of a JSON layout can have a type: data (the default) to copy, zero to
+record as zero clusters without reading the input, or discard to leave
+unallocated.
+
+Options:
+  -o, --output PATH         Write the image to this file instead of stdout; it
+                            can be a block device, if large enough. Files are
+                            written as PATH.XXXX.tmp, renamed once complete
+  --force                   Replace the output file if it already exists
+  --fsync                   Flush the output to disk, and its name once
+                            renamed, before exiting successfully
+  --verify-after-write      Once the image is written, read it back from the
+                            disk, check its metadata and compare each data
+                            cluster with the hash of the data written;
+                            failing removes the output (unless
+                            --keep-partial) and exits with status 8
+  --direct-output           Write the output with O_DIRECT, bypassing the page
+                            cache (Linux only)
+  --tape-block-size SIZE    Write the output in records of exactly SIZE bytes
+                            (e.g. 256K), padding the last one with zeros, to
+                            write to a tape drive such as /dev/nst0
+  --write-timeout SECONDS   Fail with exit status 7 if the output or upload
+                            accepts no data for this long, e.g. when the
+                            reader of the pipe hangs
+  --upload URL              Upload the image instead of writing it, to:
+                            glance://NAME, an OpenStack Glance image created
+                            with this name (requires the openstack and glance
+                            tools), with the SHA-512 of the image as its
+                            sha512 property; the checksum is checked after
+                            upload
+                            pve://STORAGE/VMID[?attach=DRIVE], a new disk of
+                            the VM on a Proxmox VE storage, optionally
+                            attached as DRIVE such as scsi1 (requires pvesm
+                            and qm, run on the Proxmox node)
+                            libvirt://POOL/NAME[?connect=URI], a new volume
+                            in a libvirt storage pool, possibly on a remote
+                            host (requires virsh)
+                            tus+https://HOST/PATH, a new upload on a tus
+                            server, resumed after network errors (requires
+                            curl); an interrupted upload is left on the
+                            server and its URL printed
+                            nbd://HOST[:PORT]/EXPORT or
+                            nbd+unix:///EXPORT?socket=PATH, written from the
+                            start of an NBD export at least as large as the
+                            image, e.g. served by qemu-nbd
+  --tus-resume              The tus+ URL given to --upload is an upload left
+                            by an interrupted run, to resume from the offset
+                            the server has; the image has to be produced
+                            identically (see --reproducible)
+  --upload-url URL          Upload the image instead of writing it, as the
+                            body of a request to this http:// or https://
+                            URL, sent with chunked transfer encoding
+                            (requires curl); the Location of the response,
+                            or else the URL, is printed
+  --upload-method METHOD    Method of the --upload-url request: PUT (default)
+                            or POST
+  --upload-header HEADER    Header to send with the --upload-url request, as
+                            \"Name: value\", or @FILE to read headers from a
+                            file (can be repeated)
+  --upload-user USER[:PASSWORD]
+                            Credentials for the --upload-url request, as with
+                            curl --user; they can also be read from ~/.netrc
+  --format FORMAT           Output format: qcow2 (default), or tar-sparse for
+                            a GNU tar archive with the raw disk as a sparse
+                            member named disk.raw
+  --discard                 With a block device --output, discard its whole
+                            contents first (Linux only)
+  --keep-partial            Don't remove the output file (PATH.XXXX.tmp) if the
+                            conversion fails or is cancelled
+  --spool                   If the input is a pipe, copy it to a temporary
+                            file first, so it can be written to stdout and
+                            used with the options that analyze the disk
+  --spool-dir DIR           Directory for the --spool file (default: the
+                            system's temporary directory)
+  --spool-max SIZE          Fail if the --spool file would be larger than
+                            SIZE bytes (suffixes K, M, G, T are accepted)
+  --layout-cmd COMMAND      Run this shell command to get the layout, read
+                            from its output like a layout file; it gets the
+                            path and size of the input as the environment
+                            variables STREAMING_QCOW2_INPUT and
+                            STREAMING_QCOW2_INPUT_SIZE; it can be combined
+                            with layout files (see --layout-merge)
+  --strict-layout           Fail on layout file entries that are empty, past
+                            the end of the input, not aligned to 512-byte
+                            sectors, overlapping or out of order, instead of
+                            fixing them with a warning
+  --out-of-range POLICY     What to do with layout file entries past the end
+                            of the input: clamp (truncate them), error, or
+                            extend (make the virtual disk large enough to
+                            hold them); by default they are truncated with a
+                            warning, or rejected with --strict-layout
+  --layout-format FORMAT    Format of the layout file: json (default), csv
+                            (lines of offset,length in bytes), ddrescue for
+                            a GNU ddrescue mapfile, copying only the blocks
+                            that were rescued, or partclone for the used-block
+                            bitmap of a partclone image (which can be
+                            gzipped; only its start is read)
+  --layout-merge MODE       How to combine several layouts (files and
+                            --layout-cmd): union (default, copy what any of
+                            them has as data), or intersect (only what all of
+                            them have as data)
+  --input-format FORMAT     Format of the input file: raw, vmdk
+                            (monolithicSparse or streamOptimized), vhd, vhdx,
+                            qcow2 (flattening its backing chain, if any,
+                            raw unless the images record their format),
+                            ntfsclone (an ntfsclone --save-image image); by
+                            default, detected from the magic bytes of
+                            regular files and archive members, with a
+                            warning (a detected qcow2 image with a backing
+                            file is refused), other inputs and those given
+                            --input-size being read as raw
+  --input-member NAME       Read the input from this member of the tar (such
+                            as an OVA appliance) or zip archive given as
+                            input, without extracting it; deflated zip
+                            members can only be read as raw images, in one
+                            pass
+  --input-size SIZE         Size of the input in bytes (suffixes K, M, G, T
+                            are accepted), for inputs whose size can't be
+                            determined such as character devices, or to
+                            override it; a pipe is then read in one pass, so
+                            the options that read it out of order fail
+  --empty SIZE              Create an empty image with a virtual disk of SIZE
+                            bytes (suffixes K, M, G, T are accepted) instead
+                            of converting an input, which is not given, like
+                            a streamed qemu-img create
+  --virtual-size SIZE       Make the virtual disk this large, to grow it
+                            during the conversion; the space past the input
+                            is left unallocated (unless --preallocation full)
+  --round-size SIZE         Round the size of the virtual disk up to a
+                            multiple of SIZE (e.g. 1M or 1G), for importers
+                            that reject other sizes; the extra space is left
+                            unallocated (unless --preallocation full)
+  --ebs-snapshot            Upload the disk as an EBS snapshot through the EBS
+                            direct APIs instead of writing a qcow2 image
+                            (requires the AWS CLI)
+  --ebs-description TEXT    Description to set on the EBS snapshot
+  --info                    After writing the image, print a description of
+                            it as JSON, like qemu-img info --output=json
+                            (requires --output)
+  --manifest PATH           Also write a JSON manifest listing the SHA-256
+                            hash of every data cluster of the image
+  --age-recipient RECIPIENT
+                            Encrypt the image with age to this recipient, by
+                            piping it through the age tool (can be repeated)
+  --age-recipients-file PATH
+                            Encrypt the image with age to the recipients
+                            listed in this file (can be repeated)
+  --sign-key KEY            Write a detached OpenPGP signature of the image,
+                            made with this GnuPG key as the image is written
+                            (of the unencrypted image, with --age-recipient)
+  --sign-passphrase SOURCE  Passphrase of the --sign-key key, handed to gpg
+                            through a pipe (Unix only), read from:
+                            file:PATH, the first line of a file
+                            env:VARIABLE, an environment variable
+                            prompt, typed on the terminal
+                            keyring:SERVICE, the Secret Service (with
+                            secret-tool) or the macOS keychain
+  --signature PATH          Where to write the signature (default: the output
+                            path with .sig appended; required with stdout)
+  --torrent PATH            Also write BitTorrent v2 metadata for the image to
+                            this .torrent file, hashed as it is written
+  --torrent-tracker URL     Tracker to announce the torrent to (can be
+                            repeated)
+  --reproducible            Make the output byte-identical for identical
+                            inputs and options, by using SOURCE_DATE_EPOCH
+                            (default: 0) instead of the current time
+  --provenance              Record the tool version, source and creation time
+                            in a header extension of the image
+  --source-id TEXT          Source identifier to record with --provenance
+                            (default: the input path)
+  --seek-hole               Leave out the holes of the input, as reported by
+                            SEEK_HOLE (sparse files, ZFS volumes) or by
+                            FSCTL_QUERY_ALLOCATED_RANGES on Windows
+  --sparsify-mode MODES     Comma-separated strategies to find the parts of
+                            the input to leave out: seek-hole (same as
+                            --seek-hole), fiemap (the extent map of the file,
+                            leaving out preallocated extents; Linux only),
+                            fs-aware (same as --fs-aware), scan (read the
+                            data and leave out clusters of zeros). The
+                            others are applied first, so scan only reads the
+                            data they found
+  --exclude-ranges PATH     Zero these ranges of the disk in the image,
+                            whatever the input has there (e.g. to scrub
+                            keys or logs); a JSON file in the format of the
+                            layout
+  --mask-outside-layout     Zero the parts of the copied clusters that are
+                            outside of the layout, instead of copying whole
+                            clusters, so no data next to the copied ranges
+                            ends up in the image
+  --detect-zeroes           Leave out clusters that turn out to be all zeros
+                            as they are copied; the metadata is written after
+                            the data, so this requires --output
+  --partition-table         Only copy the partitions and the partition table
+                            (MBR or GPT), leaving unpartitioned space out
+  --exclude-partition-type TYPE
+                            With --partition-table, also leave out partitions
+                            of this type, given as an MBR type byte in hex
+                            (e.g. 82) or a GPT type GUID (can be repeated)
+  --partitions LIST         Only copy these partitions, given by number as
+                            Linux numbers them (e.g. 1,3), and the partition
+                            table, leaving the others unallocated
+  --fs-aware                Read the filesystems on the disk (NTFS, XFS,
+                            btrfs) and leave out the space they have marked
+                            as free
+  --guestfs                 Leave out the space the filesystems don't use, as
+                            libguestfs finds it for any filesystem it can
+                            mount (requires virt-sparsify and qemu-img; the
+                            input is not modified)
+  --swap MODE               What to do with the contents of Linux swap areas,
+                            detected by partition type or signature: keep
+                            (default), header (only keep the swap header),
+                            drop (leave the whole area out)
+  --rbd-nbd                 Map RBD images with rbd-nbd instead of the kernel
+                            driver
+  --snapshot-lv             The input is an LVM logical volume; create a
+                            temporary snapshot of it, convert from the
+                            snapshot, and remove it at the end
+  --snapshot-size SIZE      Size of the snapshot's copy-on-write area, as
+                            given to lvcreate --size (default: 10% of the
+                            volume, or a thin snapshot for thin volumes)
+  --snapshot-vss            The input is a Windows volume (e.g. C:); create a
+                            temporary VSS shadow copy of it, convert from the
+                            shadow copy, and delete it at the end (Windows
+                            only; requires administrator rights)
+  --fsfreeze MOUNTPOINT     Freeze the filesystem mounted there while the
+                            layout is computed, for a consistent view of
+                            mounted volumes (can be repeated; Linux only)
+  --fsfreeze-copy           With --fsfreeze, keep the filesystems frozen
+                            until the whole copy is done
+  --qmp SOCKET              The input is the node name of a disk of a running
+                            QEMU, reached through this QMP socket; only copy
+                            the blocks marked in --bitmap, through an NBD
+                            export, then reset the bitmap
+  --bitmap NAME             Dirty bitmap to use with --qmp
+  --backing-file NAME       Record NAME as the backing file of the image, so
+                            clusters that are not copied are read from it
+  --backing-format FORMAT   Format of the --backing-file to record: raw or
+                            qcow2 (default: detected from the file, found
+                            next to the output)
+  --unsafe-backing-paths    Let a qcow2 input (--input-format qcow2) have
+                            backing files that are not in its directory:
+                            absolute paths, or going up with ..
+  --preallocation MODE      Which clusters to allocate in the image: off
+                            (default, only those with data), full (every
+                            cluster, zero-filled where there is no data)
+  --status-fd N             Write status records to file descriptor N, as
+                            JSON objects on separate lines: the phases, the
+                            progress of copies, and the final result (Unix
+                            only); it can't be stdin, or stdout if the image
+                            is written there
+  --no-progress             Don't show the progress of copies on stderr (by
+                            default, a progress bar if stderr is a terminal,
+                            else a line at each --progress-interval)
+  --progress-interval N     How often to print a line with the progress of
+                            copies when stderr is not a terminal, as an
+                            amount of data (e.g. 100M) or a number of seconds
+                            (e.g. 10s) (default: 500 MB)
+  --force-tty               Write the image to stdout even if it is a
+                            terminal
+  --tui                     Show a live dashboard on the terminal instead of
+                            messages: a map of the disk showing what has been
+                            copied, the throughput, and the time of each
+                            phase
+  --log-file PATH           Also append the messages, phases and result to
+                            this file, with timestamps
+  --report PATH             At the end, write a JSON report to this file: the
+                            arguments, input, layout, image written and its
+                            SHA-256 (of the unencrypted image, with
+                            --age-recipient), warnings, time of each phase,
+                            and result
+  --errors-json             On failure, write a last line to stderr with the
+                            cause, exit status and message, as a JSON object
+  --iops-limit N            Read at most N requests per second from the
+                            input, for storage that limits the number of
+                            requests rather than the bandwidth
+  --threads N               Read local raw inputs (files and block devices)
+                            ahead of the copy and of --sparsify-mode scan
+                            with N threads (default: the number of CPUs, at
+                            most 8; 1 to read from a single thread). Other
+                            inputs are read from a single thread; with
+                            --iops-limit, the limit is shared by all threads
+  --buffer-size SIZE        Copy the data through buffers of SIZE bytes,
+                            rounded up to whole clusters, also the size of
+                            the requests made by the --threads (default: 1M)
+  --max-memory SIZE         Keep the memory used by buffers and the lists of
+                            clusters under SIZE (suffixes K, M, G, T are
+                            accepted), reading ahead with fewer --threads if
+                            needed; fails if the lists alone are larger
+  --bench                   Instead of converting, time the steps of a
+                            conversion with the given layout options: the
+                            layout, scanning for zeros, copying with
+                            different buffer sizes (to --output, which is
+                            removed after), reading with several threads
+  --ionice CLASS            Set the I/O scheduling class of the process: idle,
+                            or best-effort[:LEVEL] with LEVEL from 0 to 7
+                            (Linux only)
+  --nice N                  Set the CPU niceness of the process, from -20 to
+                            19
+  -h, --help                Show this message
+
+The gen-fixture subcommand creates a sparse raw image with data at known
+places, to test the tool or a conversion pipeline. Its options are:
+  --size SIZE               Size of the image (default: 64M)
+  --extent OFFSET:LENGTH    Put data at this place (can be repeated)
+  --random-extents N        Put data at N pseudo-random places (default: 16,
+                            if no --extent is given)
+  --seed N                  Seed for --random-extents and --fill random
+                            (default: 0)
+  --fill MODE               Contents of the data: markers (default, each
+                            sector starts with its offset), random
+  --layout PATH             Also write the layout of the data, as JSON
+
+The layout subcommand combines layout files with a set operation and writes
+the result as JSON, sorted and merged: union (the ranges in any of the files,
+also used to normalize a single file), intersect (the ranges in all of them),
+or subtract (the ranges of the first file that are in none of the others, e.g.
+to leave an exclude list out of a filesystem map). Entries other than data are
+ignored. Its options are:
+  -o, --output PATH         Write the layout to this file instead of stdout
+  --layout-format FORMAT    Format of the layout files: json (default), csv,
+                            ddrescue, partclone
+
+The exit status tells the cause of a failed conversion: 1 for other errors, 2
+for invalid options, 3 if the input can't be opened, 4 if the layout is
+invalid, 5 for errors reading the input, 6 for errors writing the output
+(including a closed pipe or a full disk), 7 if the output stalled for
+--write-timeout, 8 if the image read back for --verify-after-write doesn't
+match, and 128+N if cancelled by signal N.
+
+The check subcommand checks the consistency of a qcow2 image: header, L1 and
+L2 tables and refcounts. It exits with status 2 if the image is corrupted,
+and 3 if it only has leaked clusters.";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SwapMode {
+    Keep,
+    Header,
+    Drop,
+}
+
+/// Where to upload the image, with `--upload`.
+pub enum UploadTarget {
+    /// OpenStack Glance image to create, with this name
+    Glance(String),
+    /// Disk to allocate on a Proxmox VE storage for a VM
+    Proxmox {
+        storage: String,
+        vmid: u32,
+        /// Drive to attach the disk to the VM as, such as `scsi1`
+        attach: Option<String>,
+    },
+    /// Volume to create in a libvirt storage pool
+    Libvirt {
+        /// URI of the libvirt connection, if not the default one
+        connect: Option<String>,
+        pool: String,
+        name: String,
+    },
+    /// Endpoint of a tus server to create the upload on, or the URL of an
+    /// existing upload to resume
+    Tus { url: String, resume: bool },
+    /// NBD export to write the image to, and its URI
+    Nbd(nbd::Address, String),
+    /// URL to send the image to in the body of a request (`--upload-url`)
+    Http {
+        url: String,
+        method: Method,
+        /// Headers, as given to curl
+        headers: Vec<String>,
+        /// Credentials, as `USER[:PASSWORD]`
+        user: Option<String>,
+    },
+}
+
+impl UploadTarget {
+    fn parse(s: &str) -> Option<UploadTarget> {
+        if let Some(name) = s.strip_prefix("glance://") {
+            return (!name.is_empty()).then(|| UploadTarget::Glance(name.to_owned()));
+        }
+        if let Some(rest) = s.strip_prefix("pve://") {
+            let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+            let (storage, vmid) = path.split_once('/')?;
+            if storage.is_empty() {
+                return None;
+            }
+            let attach = match query {
+                "" => None,
+                query => Some(query.strip_prefix("attach=").filter(|d| !d.is_empty())?.to_owned()),
+            };
+            return Some(UploadTarget::Proxmox {
+                storage: storage.to_owned(),
+                vmid: vmid.parse().ok()?,
+                attach,
+            });
+        }
+        if let Some(endpoint) = s.strip_prefix("tus+") {
+            let valid = endpoint.strip_prefix("http://").or_else(|| endpoint.strip_prefix("https://"))
+                .is_some_and(|rest| !rest.is_empty());
+            return valid.then(|| UploadTarget::Tus { url: endpoint.to_owned(), resume: false });
+        }
+        if let Some(address) = nbd::Address::parse(s) {
+            return address.ok().map(|a| UploadTarget::Nbd(a, s.to_owned()));
+        }
+        if let Some(rest) = s.strip_prefix("libvirt://") {
+            let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+            let (pool, name) = path.split_once('/')?;
+            if pool.is_empty() || name.is_empty() || name.contains('/') {
+                return None;
+            }
+            let connect = match query {
+                "" => None,
+                query => Some(query.strip_prefix("connect=").filter(|u| !u.is_empty())?.to_owned()),
+            };
+            return Some(UploadTarget::Libvirt {
+                connect,
+                pool: pool.to_owned(),
+                name: name.to_owned(),
+            });
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Qcow2,
+    TarSparse,
+}
+
+pub struct Options {
+    pub input: OsString,
+    pub layouts: Vec<OsString>,
+    pub output: Option<OsString>,
+    pub upload: Option<UploadTarget>,
+    pub format: OutputFormat,
+    pub force: bool,
+    pub fsync: bool,
+    pub verify_after_write: bool,
+    pub write_timeout: Option<Duration>,
+    pub direct_output: bool,
+    pub tape_block_size: Option<u64>,
+    pub keep_partial: bool,
+    pub discard: bool,
+    pub spool: bool,
+    pub spool_dir: Option<OsString>,
+    pub spool_max: Option<u64>,
+    pub layout_cmd: Option<String>,
+    pub strict_layout: bool,
+    pub out_of_range: Option<OutOfRange>,
+    pub layout_format: LayoutFormat,
+    pub layout_merge: Option<Operation>,
+    pub input_format: Option<InputFormat>,
+    pub input_member: Option<String>,
+    pub input_size: Option<u64>,
+    pub empty: Option<u64>,
+    pub virtual_size: Option<u64>,
+    pub round_size: Option<u64>,
+    pub ebs_snapshot: bool,
+    pub ebs_description: Option<String>,
+    pub info: bool,
+    pub manifest: Option<OsString>,
+    pub age_recipients: Vec<String>,
+    pub age_recipients_files: Vec<OsString>,
+    pub sign_key: Option<String>,
+    pub sign_passphrase: Option<secret::Source>,
+    pub signature: Option<OsString>,
+    pub torrent: Option<OsString>,
+    pub torrent_trackers: Vec<String>,
+    pub reproducible: bool,
+    pub provenance: bool,
+    pub source_id: Option<String>,
+    pub seek_hole: bool,
+    pub fiemap: bool,
+    pub scan_zeroes: bool,
+    pub detect_zeroes: bool,
+    pub mask_outside_layout: bool,
+    pub exclude_ranges: Option<OsString>,
+    pub partition_table: bool,
+    pub exclude_partition_types: Vec<PartitionType>,
+    pub partitions: Option<Vec<u32>>,
+    pub fs_aware: bool,
+    pub guestfs: bool,
+    pub swap: SwapMode,
+    pub rbd_nbd: bool,
+    pub snapshot_lv: bool,
+    pub snapshot_size: Option<String>,
+    pub snapshot_vss: bool,
+    pub fsfreeze: Vec<OsString>,
+    pub fsfreeze_copy: bool,
+    pub qmp: Option<OsString>,
+    pub bitmap: Option<String>,
+    pub backing_file: Option<String>,
+    /// raw or qcow2
+    pub backing_format: Option<String>,
+    pub unsafe_backing_paths: bool,
+    pub preallocation: Preallocation,
+    pub status_fd: Option<i32>,
+    pub no_progress: bool,
+    pub progress_interval: Option<Interval>,
+    pub force_tty: bool,
+    pub tui: bool,
+    pub log_file: Option<OsString>,
+    pub report: Option<OsString>,
+    pub errors_json: bool,
+    pub iops_limit: Option<u32>,
+    pub threads: Option<usize>,
+    pub buffer_size: Option<usize>,
+    pub max_memory: Option<u64>,
+    pub bench: bool,
+    pub ionice: Option<IoPriority>,
+    pub nice: Option<i32>,
+}
+
+pub struct GenFixtureOptions {
+    pub output: OsString,
+    pub layout: Option<OsString>,
+    pub fixture: Fixture,
+}
+
+pub struct LayoutOptions {
+    pub operation: Operation,
+    pub layouts: Vec<OsString>,
+    pub format: LayoutFormat,
+    pub output: Option<OsString>,
+}
+
+pub enum ParseResult {
+    Run(Box<Options>),
+    GenFixture(GenFixtureOptions),
+    Layout(LayoutOptions),
+    Check(OsString),
+    Help,
+}
+
+/// Parse the command line, not including the program name.
+pub fn parse_args<I: Iterator<Item=OsString>>(args: I) -> Result<ParseResult, String> {
+    let mut args = args.peekable();
+    if args.peek().is_some_and(|a| a == "gen-fixture") {
+        args.next();
+        return parse_gen_fixture_args(args);
+    }
+    if args.peek().is_some_and(|a| a == "layout") {
+        args.next();
+        return parse_layout_args(args);
+    }
+    if args.peek().is_some_and(|a| a == "check") {
+        args.next();
+        return match (args.next(), args.next()) {
+            (Some(a), None) if a == "-h" || a == "--help" => Ok(ParseResult::Help),
+            (Some(image), None) => Ok(ParseResult::Check(image)),
+            (None, _) => Err("Not enough arguments".to_owned()),
+            (Some(_), Some(_)) => Err("Too many arguments".to_owned()),
+        };
+    }
+
+    let mut positional = Vec::new();
+    let mut output = None;
+    let mut upload = None;
+    let mut upload_url = None;
+    let mut upload_method = None;
+    let mut upload_headers = Vec::new();
+    let mut upload_user = None;
+    let mut format = OutputFormat::Qcow2;
+    let mut force = false;
+    let mut fsync = false;
+    let mut verify_after_write = false;
+    let mut write_timeout = None;
+    let mut direct_output = false;
+    let mut tape_block_size = None;
+    let mut keep_partial = false;
+    let mut discard = false;
+    let mut spool = false;
+    let mut spool_dir = None;
+    let mut spool_max = None;
+    let mut layout_cmd = None;
+    let mut strict_layout = false;
+    let mut out_of_range = None;
+    let mut layout_format = LayoutFormat::Json;
+    let mut layout_merge = None;
+    let mut input_format = None;
+    let mut input_member = None;
+    let mut input_size = None;
+    let mut empty = None;
+    let mut virtual_size = None;
+    let mut round_size = None;
+    let mut ebs_snapshot = false;
+    let mut ebs_description = None;
+    let mut info = false;
+    let mut manifest = None;
+    let mut age_recipients = Vec::new();
+    let mut age_recipients_files = Vec::new();
+    let mut sign_key = None;
+    let mut sign_passphrase = None;
+    let mut signature = None;
+    let mut torrent = None;
+    let mut torrent_trackers = Vec::new();
+    let mut reproducible = false;
+    let mut provenance = false;
+    let mut source_id = None;
+    let mut seek_hole = false;
+    let mut fiemap = false;
+    let mut scan_zeroes = false;
+    let mut detect_zeroes = false;
+    let mut mask_outside_layout = false;
+    let mut exclude_ranges = None;
+    let mut partition_table = false;
+    let mut exclude_partition_types = Vec::new();
+    let mut partitions = None;
+    let mut fs_aware = false;
+    let mut guestfs = false;
+    let mut swap = SwapMode::Keep;
+    let mut rbd_nbd = false;
+    let mut snapshot_lv = false;
+    let mut snapshot_size = None;
+    let mut snapshot_vss = false;
+    let mut fsfreeze = Vec::new();
+    let mut fsfreeze_copy = false;
+    let mut qmp = None;
+    let mut bitmap = None;
+    let mut backing_file = None;
+    let mut backing_format = None;
+    let mut unsafe_backing_paths = false;
+    let mut tus_resume = false;
+    let mut preallocation = Preallocation::Off;
+    let mut status_fd = None;
+    let mut no_progress = false;
+    let mut progress_interval = None;
+    let mut force_tty = false;
+    let mut tui = false;
+    let mut log_file = None;
+    let mut report = None;
+    let mut errors_json = false;
+    let mut iops_limit = None;
+    let mut threads = None;
+    let mut buffer_size = None;
+    let mut max_memory = None;
+    let mut bench = false;
+    let mut ionice = None;
+    let mut nice = None;
+
+    while let Some(arg) = args.next() {
+        let Some(arg_str) = arg.to_str().filter(|a| a.starts_with('-') && *a != "-") else {
+            positional.push(arg);
+            continue;
+        };
+        if arg_str == "--" {
+            positional.extend(args.by_ref());
+            break;
+        }
+
+        // Accept both "--name value" and "--name=value"
+        let (name, inline_value) = match arg_str.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n, Some(OsString::from(v))),
+            _ => (arg_str, None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(v) => Ok(v),
+            None => Err(format!("Missing value for {}", name)),
+        };
+
+        match name {
+            "-h" | "--help" => return Ok(ParseResult::Help),
+            "-o" | "--output" => output = Some(value()?),
+            "--upload" => {
+                let value = utf8(name, value()?)?;
+                match UploadTarget::parse(&value) {
+                    Some(t) => upload = Some(t),
+                    None => return Err(format!("Invalid value for --upload: {}", value)),
+                }
+            }
+            "--tus-resume" => tus_resume = true,
+            "--upload-url" => {
+                let value = utf8(name, value()?)?;
+                let valid = value.strip_prefix("http://").or_else(|| value.strip_prefix("https://"))
+                    .is_some_and(|rest| !rest.is_empty());
+                if !valid {
+                    return Err(format!("Invalid value for --upload-url: {}", value));
+                }
+                upload_url = Some(value);
+            }
+            "--upload-method" => {
+                let value = utf8(name, value()?)?;
+                match Method::parse(&value) {
+                    Some(m) => upload_method = Some(m),
+                    None => return Err(format!("Invalid value for --upload-method: {}", value)),
+                }
+            }
+            "--upload-header" => {
+                let value = utf8(name, value()?)?;
+                let valid = value.starts_with('@') || value.split_once(':').is_some_and(|(n, _)| !n.trim().is_empty());
+                if !valid {
+                    return Err(format!("Invalid value for --upload-header: {}", value));
+                }
+                upload_headers.push(value);
+            }
+            "--upload-user" => upload_user = Some(utf8(name, value()?)?),
+            "--format" => {
+                format = match utf8(name, value()?)?.as_str() {
+                    "qcow2" => OutputFormat::Qcow2,
+                    "tar-sparse" => OutputFormat::TarSparse,
+                    v => return Err(format!("Invalid value for --format: {}", v)),
+                };
+            }
+            "--force" => force = true,
+            "--fsync" => fsync = true,
+            "--verify-after-write" => verify_after_write = true,
+            "--write-timeout" => {
+                let value = utf8(name, value()?)?;
+                match value.parse().ok().and_then(|s| Duration::try_from_secs_f64(s).ok()) {
+                    Some(d) if !d.is_zero() => write_timeout = Some(d),
+                    _ => return Err(format!("Invalid value for --write-timeout: {}", value)),
+                }
+            }
+            "--direct-output" => direct_output = true,
+            "--tape-block-size" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) if s > 0 && s <= u32::MAX as u64 => tape_block_size = Some(s),
+                    _ => return Err(format!("Invalid value for --tape-block-size: {}", value)),
+                }
+            }
+            "--keep-partial" => keep_partial = true,
+            "--discard" => discard = true,
+            "--spool" => spool = true,
+            "--spool-dir" => spool_dir = Some(value()?),
+            "--spool-max" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) => spool_max = Some(s),
+                    None => return Err(format!("Invalid value for --spool-max: {}", value)),
+                }
+            }
+            "--layout-cmd" => layout_cmd = Some(utf8(name, value()?)?),
+            "--strict-layout" => strict_layout = true,
+            "--out-of-range" => {
+                let value = utf8(name, value()?)?;
+                match OutOfRange::parse(&value) {
+                    Some(p) => out_of_range = Some(p),
+                    None => return Err(format!("Invalid value for --out-of-range: {}", value)),
+                }
+            }
+            "--layout-format" => {
+                let value = utf8(name, value()?)?;
+                match LayoutFormat::parse(&value) {
+                    Some(f) => layout_format = f,
+                    None => return Err(format!("Unknown layout format {}", value)),
+                }
+            }
+            "--layout-merge" => {
+                let value = utf8(name, value()?)?;
+                match Operation::parse(&value) {
+                    Some(o @ (Operation::Union | Operation::Intersect)) => layout_merge = Some(o),
+                    _ => return Err(format!("Invalid value for --layout-merge: {}", value)),
+                }
+            }
+            "--input-format" => {
+                let value = utf8(name, value()?)?;
+                match InputFormat::parse(&value) {
+                    Some(f) => input_format = Some(f),
+                    None => return Err(format!("Unknown input format {}", value)),
+                }
+            }
+            "--input-member" => input_member = Some(utf8(name, value()?)?),
+            "--input-size" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) => input_size = Some(s),
+                    None => return Err(format!("Invalid value for --input-size: {}", value)),
+                }
+            }
+            "--empty" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) => empty = Some(s),
+                    None => return Err(format!("Invalid value for --empty: {}", value)),
+                }
+            }
+            "--virtual-size" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) => virtual_size = Some(s),
+                    None => return Err(format!("Invalid value for --virtual-size: {}", value)),
+                }
+            }
+            "--round-size" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) if s > 0 => round_size = Some(s),
+                    _ => return Err(format!("Invalid value for --round-size: {}", value)),
+                }
+            }
+            "--ebs-snapshot" => ebs_snapshot = true,
+            "--ebs-description" => ebs_description = Some(utf8(name, value()?)?),
+            "--info" => info = true,
+            "--manifest" => manifest = Some(value()?),
+            "--age-recipient" => age_recipients.push(utf8(name, value()?)?),
+            "--age-recipients-file" => age_recipients_files.push(value()?),
+            "--sign-key" => sign_key = Some(utf8(name, value()?)?),
+            "--sign-passphrase" => {
+                let value = utf8(name, value()?)?;
+                match secret::Source::parse(&value) {
+                    Some(s) => sign_passphrase = Some(s),
+                    None => return Err(format!("Invalid value for --sign-passphrase: {}", value)),
+                }
+            }
+            "--signature" => signature = Some(value()?),
+            "--torrent" => torrent = Some(value()?),
+            "--torrent-tracker" => torrent_trackers.push(utf8(name, value()?)?),
+            "--reproducible" => reproducible = true,
+            "--provenance" => provenance = true,
+            "--source-id" => source_id = Some(utf8(name, value()?)?),
+            "--seek-hole" => seek_hole = true,
+            "--sparsify-mode" => {
+                for mode in utf8(name, value()?)?.split(',') {
+                    match mode {
+                        "scan" => scan_zeroes = true,
+                        "seek-hole" => seek_hole = true,
+                        "fiemap" => fiemap = true,
+                        "fs-aware" => fs_aware = true,
+                        v => return Err(format!("Invalid value for --sparsify-mode: {}", v)),
+                    }
+                }
+            }
+            "--detect-zeroes" => detect_zeroes = true,
+            "--mask-outside-layout" => mask_outside_layout = true,
+            "--exclude-ranges" => exclude_ranges = Some(value()?),
+            "--partition-table" => partition_table = true,
+            "--exclude-partition-type" => {
+                let value = utf8(name, value()?)?;
+                match PartitionType::parse(&value) {
+                    Some(t) => exclude_partition_types.push(t),
+                    None => return Err(format!("Invalid partition type {}", value)),
+                }
+            }
+            "--partitions" => {
+                let value = utf8(name, value()?)?;
+                let numbers: Option<Vec<u32>> = value.split(',')
+                    .map(|n| n.trim().parse().ok().filter(|&n| n > 0))
+                    .collect();
+                match numbers {
+                    Some(n) => partitions = Some(n),
+                    None => return Err(format!("Invalid value for --partitions: {}", value)),
+                }
+            }
+            "--fs-aware" => fs_aware = true,
+            "--guestfs" => guestfs = true,
+            "--swap" => {
+                swap = match utf8(name, value()?)?.as_str() {
+                    "keep" => SwapMode::Keep,
+                    "header" => SwapMode::Header,
+                    "drop" => SwapMode::Drop,
+                    v => return Err(format!("Invalid value for --swap: {}", v)),
+                };
+            }
+            "--rbd-nbd" => rbd_nbd = true,
+            "--snapshot-lv" => snapshot_lv = true,
+            "--snapshot-size" => snapshot_size = Some(utf8(name, value()?)?),
+            "--snapshot-vss" => snapshot_vss = true,
+            "--fsfreeze" => fsfreeze.push(value()?),
+            "--fsfreeze-copy" => fsfreeze_copy = true,
+            "--qmp" => qmp = Some(value()?),
+            "--bitmap" => bitmap = Some(utf8(name, value()?)?),
+            "--backing-file" => backing_file = Some(utf8(name, value()?)?),
+            "--backing-format" => {
+                backing_format = match utf8(name, value()?)?.as_str() {
+                    v @ ("raw" | "qcow2") => Some(v.to_owned()),
+                    v => return Err(format!("Invalid value for --backing-format: {}", v)),
+                };
+            }
+            "--unsafe-backing-paths" => unsafe_backing_paths = true,
+            "--preallocation" => {
+                preallocation = match utf8(name, value()?)?.as_str() {
+                    "off" => Preallocation::Off,
+                    "full" => Preallocation::Full,
+                    v => return Err(format!("Invalid value for --preallocation: {}", v)),
+                };
+            }
+            "--status-fd" => {
+                let value = utf8(name, value()?)?;
+                match value.parse() {
+                    Ok(fd) if fd >= 0 => status_fd = Some(fd),
+                    _ => return Err(format!("Invalid value for --status-fd: {}", value)),
+                }
+            }
+            "--no-progress" => no_progress = true,
+            "--progress-interval" => {
+                let value = utf8(name, value()?)?;
+                match parse_interval(&value) {
+                    Some(i) => progress_interval = Some(i),
+                    None => return Err(format!("Invalid value for --progress-interval: {}", value)),
+                }
+            }
+            "--force-tty" => force_tty = true,
+            "--tui" => tui = true,
+            "--log-file" => log_file = Some(value()?),
+            "--report" => report = Some(value()?),
+            "--errors-json" => errors_json = true,
+            "--iops-limit" => {
+                let value = utf8(name, value()?)?;
+                match value.parse() {
+                    Ok(n) if n > 0 => iops_limit = Some(n),
+                    _ => return Err(format!("Invalid value for --iops-limit: {}", value)),
+                }
+            }
+            "--threads" => {
+                let value = utf8(name, value()?)?;
+                match value.parse() {
+                    Ok(n) if n > 0 => threads = Some(n),
+                    _ => return Err(format!("Invalid value for --threads: {}", value)),
+                }
+            }
+            "--buffer-size" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) if s > 0 && s <= 1 << 30 => buffer_size = Some(s as usize),
+                    _ => return Err(format!("Invalid value for --buffer-size: {}", value)),
+                }
+            }
+            "--max-memory" => {
+                let value = utf8(name, value()?)?;
+                match parse_size(&value) {
+                    Some(s) => max_memory = Some(s),
+                    None => return Err(format!("Invalid value for --max-memory: {}", value)),
+                }
+            }
+            "--bench" => bench = true,
+            "--ionice" => {
+                let value = utf8(name, value()?)?;
+                match IoPriority::parse(&value) {
+                    Some(p) => ionice = Some(p),
+                    None => return Err(format!("Invalid value for --ionice: {}", value)),
+                }
+            }
+            "--nice" => {
+                let value = utf8(name, value()?)?;
+                match value.parse() {
+                    Ok(n) if (-20..=19).contains(&n) => nice = Some(n),
+                    _ => return Err(format!("Invalid value for --nice: {}", value)),
+                }
+            }
+            _ => return Err(format!("Unknown option {}", name)),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let input = match (positional.next(), empty) {
+        (Some(input), None) => input,
+        (None, Some(_)) => OsString::new(),
+        (Some(_), Some(_)) => return Err("No input can be given with --empty".to_owned()),
+        (None, None) => return Err("Not enough arguments".to_owned()),
+    };
+    let layouts = positional.collect();
+    let upload = match (upload, upload_url) {
+        (upload, None) => {
+            let url_options = [
+                ("--upload-method", upload_method.is_some()),
+                ("--upload-header", !upload_headers.is_empty()),
+                ("--upload-user", upload_user.is_some()),
+            ];
+            if let Some((name, _)) = url_options.iter().find(|(_, set)| *set) {
+                return Err(format!("{} requires --upload-url", name));
+            }
+            upload
+        }
+        (None, Some(url)) => Some(UploadTarget::Http {
+            url,
+            method: upload_method.unwrap_or(Method::Put),
+            headers: upload_headers,
+            user: upload_user,
+        }),
+        (Some(_), Some(_)) => return Err("--upload-url can't be used with --upload".to_owned()),
+    };
+    let upload = match upload {
+        Some(UploadTarget::Tus { url, .. }) if tus_resume => Some(UploadTarget::Tus { url, resume: true }),
+        _ if tus_resume => return Err("--tus-resume requires --upload tus+URL".to_owned()),
+        upload => upload,
+    };
+    let writes_stdout = output.is_none() && upload.is_none() && !ebs_snapshot && !bench;
+    match status_fd {
+        Some(0) => return Err("--status-fd can't be 0, which is standard input".to_owned()),
+        Some(1) if writes_stdout => {
+            return Err("--status-fd can't be 1, the image is written to stdout".to_owned());
+        }
+        _ => {}
+    }
+
+    Ok(ParseResult::Run(Box::new(Options {
+        input,
+        layouts,
+        output,
+        upload,
+        format,
+        force,
+        fsync,
+        verify_after_write,
+        write_timeout,
+        direct_output,
+        tape_block_size,
+        keep_partial,
+        discard,
+        spool,
+        spool_dir,
+        spool_max,
+        layout_cmd,
+        strict_layout,
+        out_of_range,
+        layout_format,
+        layout_merge,
+        input_format,
+        input_member,
+        input_size,
+        empty,
+        virtual_size,
+        round_size,
+        ebs_snapshot,
+        ebs_description,
+        info,
+        manifest,
+        age_recipients,
+        age_recipients_files,
+        sign_key,
+        sign_passphrase,
+        signature,
+        torrent,
+        torrent_trackers,
+        reproducible,
+        provenance,
+        source_id,
+        seek_hole,
+        fiemap,
+        scan_zeroes,
+        detect_zeroes,
+        mask_outside_layout,
+        exclude_ranges,
+        partition_table,
+        exclude_partition_types,
+        partitions,
+        fs_aware,
+        guestfs,
+        swap,
+        rbd_nbd,
+        snapshot_lv,
+        snapshot_size,
+        snapshot_vss,
+        fsfreeze,
+        fsfreeze_copy,
+        qmp,
+        bitmap,
+        backing_file,
+        backing_format,
+        unsafe_backing_paths,
+        preallocation,
+        status_fd,
+        no_progress,
+        progress_interval,
+        force_tty,
+        tui,
+        log_file,
+        report,
+        errors_json,
+        iops_limit,
+        threads,
+        buffer_size,
+        max_memory,
+        bench,
+        ionice,
+        nice,
+    })))
+}
+
+fn parse_gen_fixture_args<I: Iterator<Item=OsString>>(mut args: I) -> Result<ParseResult, String> {
+    let mut output = None;
+    let mut layout = None;
+    let mut size = 64 << 20;
+    let mut extents = Vec::new();
+    let mut random_extents = None;
+    let mut seed = 0;
+    let mut fill = Fill::Markers;
+
+    while let Some(arg) = args.next() {
+        let Some(arg_str) = arg.to_str().filter(|a| a.starts_with('-')) else {
+            if output.replace(arg).is_some() {
+                return Err("Too many arguments".to_owned());
+            }
+            continue;
+        };
+
+        let (name, inline_value) = match arg_str.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n, Some(OsString::from(v))),
+            _ => (arg_str, None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(v) => utf8(name, v),
+            None => Err(format!("Missing value for {}", name)),
+        };
+        let invalid = |value: &str| format!("Invalid value for {}: {}", name, value);
+
+        match name {
+            "-h" | "--help" => return Ok(ParseResult::Help),
+            "--size" => {
+                let value = value()?;
+                size = parse_size(&value).ok_or_else(|| invalid(&value))?;
+            }
+            "--extent" => {
+                let value = value()?;
+                let extent = value.split_once(':').and_then(|(offset, length)| {
+                    let offset = parse_size(offset)?;
+                    Some(offset..offset.checked_add(parse_size(length)?)?)
+                });
+                extents.push(extent.ok_or_else(|| invalid(&value))?);
+            }
+            "--random-extents" => {
+                let value = value()?;
+                random_extents = Some(value.parse().map_err(|_| invalid(&value))?);
+            }
+            "--seed" => {
+                let value = value()?;
+                seed = value.parse().map_err(|_| invalid(&value))?;
+            }
+            "--fill" => {
+                fill = match value()?.as_str() {
+                    "markers" => Fill::Markers,
+                    "random" => Fill::Random,
+                    v => return Err(invalid(v)),
+                };
+            }
+            "--layout" => layout = Some(OsString::from(value()?)),
+            _ => return Err(format!("Unknown option {}", name)),
+        }
+    }
+
+    let Some(output) = output else {
+        return Err("Not enough arguments".to_owned());
+    };
+    if extents.is_empty() && random_extents.is_none() {
+        random_extents = Some(16);
+    }
+    if let Some(count) = random_extents {
+        extents.extend(fixture::random_extents(size, count, seed));
+    }
+    Ok(ParseResult::GenFixture(GenFixtureOptions {
+        output,
+        layout,
+        fixture: Fixture { size, extents, fill, seed },
+    }))
+}
+
+fn parse_layout_args<I: Iterator<Item=OsString>>(mut args: I) -> Result<ParseResult, String> {
+    let mut positional = Vec::new();
+    let mut format = LayoutFormat::Json;
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        let Some(arg_str) = arg.to_str().filter(|a| a.starts_with('-') && *a != "-") else {
+            positional.push(arg);
+            continue;
+        };
+
+        let (name, inline_value) = match arg_str.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n, Some(OsString::from(v))),
+            _ => (arg_str, None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(v) => Ok(v),
+            None => Err(format!("Missing value for {}", name)),
+        };
+
+        match name {
+            "-h" | "--help" => return Ok(ParseResult::Help),
+            "-o" | "--output" => output = Some(value()?),
+            "--layout-format" => {
+                let value = utf8(name, value()?)?;
+                match LayoutFormat::parse(&value) {
+                    Some(f) => format = f,
+                    None => return Err(format!("Unknown layout format {}", value)),
+                }
+            }
+            _ => return Err(format!("Unknown option {}", name)),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let operation = match positional.next() {
+        Some(operation) => {
+            let operation = utf8("layout", operation)?;
+            Operation::parse(&operation).ok_or_else(|| format!("Unknown layout operation {}", operation))?
+        }
+        None => return Err("Not enough arguments".to_owned()),
+    };
+    let layouts: Vec<OsString> = positional.collect();
+    if layouts.is_empty() {
+        return Err("Not enough arguments".to_owned());
+    }
+    if layouts.iter().filter(|l| *l == "-").count() > 1 {
+        return Err("Only one layout can be read from stdin".to_owned());
+    }
+    Ok(ParseResult::Layout(LayoutOptions { operation, layouts, format, output }))
+}
+
+/// Parse a size in bytes, with an optional binary suffix (K, M, G, T).
+fn parse_size(s: &str) -> Option<u64> {
+    let (number, shift) = match s.char_indices().last()? {
+        (i, 'K' | 'k') => (&s[..i], 10),
+        (i, 'M' | 'm') => (&s[..i], 20),
+        (i, 'G' | 'g') => (&s[..i], 30),
+        (i, 'T' | 't') => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parse an interval, as a size (see `parse_size()`) or a number of seconds
+/// with an `s` suffix.
+fn parse_interval(s: &str) -> Option<Interval> {
+    let interval = match s.strip_suffix('s') {
+        Some(seconds) => Interval::Time(Duration::try_from_secs_f64(seconds.parse().ok()?).ok()?),
+        None => Interval::Bytes(parse_size(s)?),
+    };
+    match interval {
+        Interval::Bytes(0) => None,
+        Interval::Time(d) if d.is_zero() => None,
+        i => Some(i),
+    }
+}
+
+fn utf8(name: &str, value: OsString) -> Result<String, String> {
+    value.into_string().map_err(|v| {
+        format!("Invalid value for {}: {:?}", name, OsStr::new(&v))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, ParseResult};
+
+    fn status_fd(args: &[&str]) -> Result<Option<i32>, String> {
+        match parse_args(args.iter().map(Into::into))? {
+            ParseResult::Run(options) => Ok(options.status_fd),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn status_fd_stdio() {
+        assert!(status_fd(&["--status-fd", "0", "-o", "out.qcow2", "disk.raw"]).is_err());
+        assert!(status_fd(&["--status-fd", "1", "disk.raw"]).is_err());
+        assert_eq!(status_fd(&["--status-fd", "1", "-o", "out.qcow2", "disk.raw"]), Ok(Some(1)));
+        assert_eq!(status_fd(&["--status-fd", "3", "disk.raw"]), Ok(Some(3)));
+    }
+}
@@ -0,0 +1,238 @@
+//! Live dashboard on the terminal (`--tui`), for operators watching large
+//! conversions: a map of the disk showing what has been copied, the
+//! throughput, and the time spent in each phase.
+//!
+//! It is redrawn in place on stderr with ANSI escape codes, from a separate
+//! thread. Messages are shown in it rather than printed while it is active.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::progress;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+const MAP_COLUMNS: usize = 64;
+const MAP_ROWS: usize = 8;
+
+/// Number of messages shown under the dashboard
+const MESSAGE_LINES: usize = 5;
+
+/// Frames never get wider than this, so they don't wrap on usual terminals
+const MAX_WIDTH: usize = 79;
+
+const SPARKLINE: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+struct State {
+    /// Extents of the disk being copied
+    layout: Vec<Range<u64>>,
+    size: u64,
+    messages: VecDeque<String>,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<State> = Mutex::new(State {
+    layout: Vec::new(),
+    size: 0,
+    messages: VecDeque::new(),
+});
+static THREAD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Start drawing the dashboard.
+pub fn start() {
+    ACTIVE.store(true, Ordering::Relaxed);
+    let thread = std::thread::spawn(|| {
+        let mut renderer = Renderer::default();
+        loop {
+            // Draw a last frame once stopped
+            let active = ACTIVE.load(Ordering::Relaxed);
+            renderer.draw();
+            if !active {
+                break;
+            }
+            std::thread::park_timeout(REFRESH_INTERVAL);
+        }
+    });
+    *THREAD.lock().unwrap() = Some(thread);
+}
+
+/// Draw the final state and stop, so messages are printed normally again.
+pub fn stop() {
+    let Some(thread) = THREAD.lock().unwrap().take() else {
+        return;
+    };
+    ACTIVE.store(false, Ordering::Relaxed);
+    thread.thread().unpark();
+    let _ = thread.join();
+}
+
+/// Set the extents of the disk that will be copied, shown on the map.
+pub fn set_layout(layout: &[Range<u64>], size: u64) {
+    let mut state = STATE.lock().unwrap();
+    state.layout = layout.to_vec();
+    state.size = size;
+}
+
+/// Show a message in the dashboard, if it is active.
+///
+/// Returns false if it is not, in which case the message should be printed.
+pub fn show_message(args: std::fmt::Arguments) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut state = STATE.lock().unwrap();
+    if state.messages.len() == MESSAGE_LINES {
+        state.messages.pop_front();
+    }
+    state.messages.push_back(args.to_string());
+    true
+}
+
+#[derive(Default)]
+struct Renderer {
+    /// Number of lines of the last frame, to draw over it
+    lines: usize,
+    /// Bytes copied during each refresh interval
+    samples: VecDeque<u64>,
+    last_copied: u64,
+    last_frame: Option<Instant>,
+}
+
+impl Renderer {
+    fn draw(&mut self) {
+        let frame = self.frame();
+        let mut stderr = std::io::stderr().lock();
+        if self.lines > 0 {
+            // Go back to the start of the last frame and clear it
+            let _ = write!(stderr, "\x1b[{}F\x1b[J", self.lines);
+        }
+        let _ = stderr.write_all(frame.as_bytes());
+        let _ = stderr.flush();
+        self.lines = frame.lines().count();
+    }
+
+    fn frame(&mut self) -> String {
+        let mut lines = Vec::new();
+        let (name, elapsed, copied, total) = progress::current().unwrap_or(("Starting", Duration::ZERO, 0, 0));
+
+        // Current phase
+        if total > 0 {
+            lines.push(format!(
+                "{} ({:.1} s): {} / {} ({:.1}%)",
+                name,
+                elapsed.as_secs_f64(),
+                format_size(copied),
+                format_size(total),
+                copied as f64 * 100.0 / total as f64,
+            ));
+        } else {
+            lines.push(format!("{} ({:.1} s)", name, elapsed.as_secs_f64()));
+        }
+
+        // Map of the disk
+        let state = STATE.lock().unwrap();
+        if state.size > 0 {
+            let position = copy_position(&state.layout, state.size, copied);
+            let cells = MAP_COLUMNS * MAP_ROWS;
+            let map: Vec<char> = (0..cells as u64)
+                .map(|i| {
+                    let start = state.size * i / cells as u64;
+                    let end = state.size * (i + 1) / cells as u64;
+                    cell(&state.layout, start..end, position)
+                })
+                .collect();
+            for row in map.chunks(MAP_COLUMNS) {
+                lines.push(row.iter().collect());
+            }
+            lines.push("█ copied  ▒ in progress  ░ pending  · not copied".to_owned());
+        }
+
+        // Throughput, reset when a new copy starts
+        let delta = copied.checked_sub(self.last_copied).unwrap_or(copied);
+        self.last_copied = copied;
+        if self.samples.len() == MAP_COLUMNS {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(delta);
+        let max = self.samples.iter().copied().max().unwrap_or(0).max(1);
+        let sparkline: String = self.samples.iter()
+            .map(|&s| SPARKLINE[(s * (SPARKLINE.len() as u64 - 1)).div_ceil(max) as usize])
+            .collect();
+        let now = Instant::now();
+        let interval = self.last_frame.map_or(REFRESH_INTERVAL, |t| now - t);
+        self.last_frame = Some(now);
+        let rate = delta as f64 / interval.as_secs_f64().max(0.001);
+        lines.push(format!("{:>10}/s {}", format_size(rate as u64), sparkline));
+
+        // Time spent in each phase
+        let mut phases = String::from("Phases:");
+        for (name, duration) in progress::history() {
+            phases.push_str(&format!(" {} {:.1} s,", name, duration.as_secs_f64()));
+        }
+        phases.push_str(&format!(" {} {:.1} s", name, elapsed.as_secs_f64()));
+        lines.push(phases);
+
+        for message in &state.messages {
+            lines.push(message.clone());
+        }
+
+        let mut frame = String::new();
+        for line in lines {
+            frame.extend(line.chars().take(MAX_WIDTH));
+            frame.push('\n');
+        }
+        frame
+    }
+}
+
+/// Find the offset on the disk up to which the layout has been copied, the
+/// extents being copied in order.
+fn copy_position(layout: &[Range<u64>], size: u64, mut copied: u64) -> u64 {
+    for range in layout {
+        let length = range.end - range.start;
+        if copied < length {
+            return range.start + copied;
+        }
+        copied -= length;
+    }
+    size
+}
+
+/// Symbol for a part of the disk, from the extents it holds and the current
+/// position of the copy.
+fn cell(layout: &[Range<u64>], cell: Range<u64>, position: u64) -> char {
+    let first = layout.partition_point(|r| r.end <= cell.start);
+    let mut ranges = layout[first..].iter().take_while(|r| r.start < cell.end);
+    let Some(first_range) = ranges.next() else {
+        return '·';
+    };
+    let data_start = first_range.start.max(cell.start);
+    let data_end = ranges.last().unwrap_or(first_range).end.min(cell.end);
+    if data_end <= position {
+        '█'
+    } else if data_start >= position {
+        '░'
+    } else {
+        '▒'
+    }
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,193 @@
+//! Inputs compressed with gzip, xz or zstd, as disk dumps are often stored.
+//!
+//! They are recognized from their first bytes and decompressed as they are
+//! read, which makes them inputs of unknown size, to spool or read
+//! sequentially. Like their command-line tools, concatenated streams are
+//! decompressed one after the other.
+
+use ruzstd::decoding::{BlockDecodingStrategy, FrameDecoder};
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Cursor, Read};
+
+use crate::log::message;
+use crate::qcow2::read_full;
+
+/// Longest magic number
+const MAGIC_SIZE: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Recognize the compression from the first bytes of the data.
+    pub fn detect(magic: &[u8]) -> Option<Compression> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compression of a file, if it is a compressed regular file.
+///
+/// Devices and pipes are never considered compressed, as reading them to
+/// check would consume or disturb them.
+pub fn file_compression(path: &OsStr) -> std::io::Result<Option<Compression>> {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        // Not a local file
+        return Ok(None);
+    };
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let mut magic = [0u8; MAGIC_SIZE];
+    let read = read_full(std::fs::File::open(path)?, &mut magic)?;
+    Ok(Compression::detect(&magic[..read]))
+}
+
+/// Decompress the data if it starts like compressed data, or read it as is.
+pub fn open<R: Read + Send + 'static>(mut reader: R) -> std::io::Result<Box<dyn Read + Send>> {
+    let mut magic = [0u8; MAGIC_SIZE];
+    let read = read_full(&mut reader, &mut magic)?;
+    let reader = Cursor::new(magic[..read].to_vec()).chain(reader);
+    let Some(compression) = Compression::detect(&magic[..read]) else {
+        return Ok(Box::new(reader));
+    };
+    message!("Decompressing {} input", compression.name());
+    match compression {
+        Compression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(reader))),
+        Compression::Xz => Ok(Box::new(lzma_rust2::XzReader::new(reader, true))),
+        Compression::Zstd => Ok(Box::new(ZstdDecoder::new(reader))),
+    }
+}
+
+fn invalid_zstd<E: std::fmt::Display>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid zstd data: {}", error))
+}
+
+/// Decompression of zstd frames, one after the other until the end of the
+/// data.
+struct ZstdDecoder<R: Read> {
+    source: BufReader<R>,
+    decoder: FrameDecoder,
+    /// Whether a frame is being decompressed
+    in_frame: bool,
+}
+
+impl<R: Read> ZstdDecoder<R> {
+    fn new(source: R) -> ZstdDecoder<R> {
+        ZstdDecoder { source: BufReader::new(source), decoder: FrameDecoder::new(), in_frame: false }
+    }
+}
+
+impl<R: Read> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if !self.in_frame {
+                if self.source.fill_buf()?.is_empty() {
+                    return Ok(0);
+                }
+                self.decoder.reset(&mut self.source).map_err(invalid_zstd)?;
+                self.in_frame = true;
+            }
+            // Decoding can fill the internal buffer without making enough
+            // bytes collectable, so decode until they are
+            while self.decoder.can_collect() < buf.len() && !self.decoder.is_finished() {
+                let needed = buf.len() - self.decoder.can_collect();
+                self.decoder.decode_blocks(&mut self.source, BlockDecodingStrategy::UptoBytes(needed))
+                    .map_err(invalid_zstd)?;
+            }
+            let read = self.decoder.read(buf)?;
+            if read > 0 {
+                return Ok(read);
+            }
+            // End of the frame, another one may follow
+            self.in_frame = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn data() -> Vec<u8> {
+        // Compressible, with runs of zeros like a disk
+        let mut data: Vec<u8> = (0..300_000).map(|i| (i / 1000 % 7) as u8).collect();
+        data[100_000..200_000].fill(0);
+        data
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut writer = lzma_rust2::XzWriter::new(Vec::new(), lzma_rust2::XzOptions::with_preset(1)).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+    }
+
+    fn decompress(compressed: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        open(Cursor::new(compressed))?.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = data();
+        for (compression, compress) in [
+            (Compression::Gzip, gzip as fn(&[u8]) -> Vec<u8>),
+            (Compression::Xz, xz),
+            (Compression::Zstd, zstd),
+        ] {
+            let compressed = compress(&data);
+            assert_eq!(Compression::detect(&compressed), Some(compression));
+            assert!(decompress(compressed.clone()).unwrap() == data, "{}", compression.name());
+
+            // Concatenated streams
+            let (first, second) = data.split_at(123_456);
+            let concatenated = [compress(first), compress(second)].concat();
+            assert!(decompress(concatenated).unwrap() == data, "{} concatenated", compression.name());
+
+            // Truncated streams are errors, not short data
+            let truncated = compressed[..compressed.len() / 2].to_vec();
+            assert!(decompress(truncated).is_err(), "{} truncated", compression.name());
+        }
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert!(decompress(data()).unwrap() == data());
+        assert!(decompress(b"abc".to_vec()).unwrap() == b"abc");
+        assert!(decompress(Vec::new()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+//! Upload a raw disk as an EBS snapshot through the EBS direct APIs.
+//!
+//! The requests are made through the AWS CLI (`aws ebs ...`), so credentials,
+//! profiles and regions are picked up the same way as for other AWS tooling.
+//! Blocks are uploaded by several commands at the same time, since starting
+//! the CLI takes much longer than sending a block.
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+use crate::log::message;
+use crate::{progress, signals};
+use crate::qcow2::read_full;
+
+/// Block size used by the EBS direct APIs.
+pub const EBS_BLOCK_SIZE: u64 = 512 * 1024;
+
+const GIB: u64 = 1 << 30;
+
+/// Number of blocks uploaded at the same time
+const PARALLEL_UPLOADS: usize = 16;
+
+/// Compute the sorted list of EBS blocks containing data from the layout.
+pub fn blocks_for_layout<I: Iterator<Item=Range<u64>>>(ranges: I) -> std::io::Result<Vec<u64>> {
+    let mut blocks: Vec<u64> = Vec::new();
+    for range in ranges {
+        if range.start >= range.end {
+            continue;
+        }
+        let mut from_block = range.start / EBS_BLOCK_SIZE;
+        let to_block = range.end.div_ceil(EBS_BLOCK_SIZE);
+        if let Some(&last_block) = blocks.last() {
+            if from_block < last_block {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "data ranges are not sorted",
+                ));
+            } else if from_block == last_block {
+                from_block += 1;
+            }
+        }
+        blocks.extend(from_block..to_block);
+    }
+    Ok(blocks)
+}
+
+/// Create a snapshot, upload the given blocks from the input, and complete it.
+///
+/// Returns the snapshot ID. If the upload fails, the pending snapshot is
+/// deleted, or its ID is printed if that fails too.
+pub fn upload_snapshot<R: Read + Seek>(
+    reader: R,
+    input_size: u64,
+    blocks: &[u64],
+    description: Option<&str>,
+) -> std::io::Result<String> {
+    let volume_size = input_size.div_ceil(GIB).max(1);
+    let snapshot_id = start_snapshot(volume_size, description)?;
+    message!("Started snapshot {}", snapshot_id);
+
+    match upload_blocks(reader, &snapshot_id, blocks) {
+        Ok(()) => Ok(snapshot_id),
+        Err(e) => {
+            if delete_snapshot(&snapshot_id).is_ok() {
+                message!("Deleted incomplete snapshot {}", snapshot_id);
+            } else {
+                message!("Snapshot {} is incomplete and could not be deleted", snapshot_id);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn upload_blocks<R: Read + Seek>(mut reader: R, snapshot_id: &str, blocks: &[u64]) -> std::io::Result<()> {
+    let mut uploads = Uploads {
+        block_files: Vec::new(),
+        running: VecDeque::new(),
+        uploaded: 0,
+        total: blocks.len(),
+    };
+    let mut buffer = vec![0u8; EBS_BLOCK_SIZE as usize];
+    progress::start_copy("Uploading blocks", blocks.len() as u64 * EBS_BLOCK_SIZE);
+    for &block in blocks {
+        signals::check_cancelled()?;
+        // Use a new file until there are enough, then wait for the oldest
+        // upload and reuse its file
+        let slot = if uploads.block_files.len() < PARALLEL_UPLOADS {
+            uploads.block_files.push(BlockFile::create()?);
+            uploads.block_files.len() - 1
+        } else {
+            uploads.wait_oldest()?
+        };
+        reader.seek(SeekFrom::Start(block * EBS_BLOCK_SIZE))?;
+        read_full(&mut reader, &mut buffer)?;
+        let child = put_snapshot_block(snapshot_id, block, &buffer, &mut uploads.block_files[slot])?;
+        uploads.running.push_back((child, slot));
+    }
+    while !uploads.running.is_empty() {
+        signals::check_cancelled()?;
+        uploads.wait_oldest()?;
+    }
+
+    progress::set_phase("Completing snapshot");
+    complete_snapshot(snapshot_id, blocks.len())
+}
+
+/// Blocks being uploaded, each by a command reading it from its own file.
+struct Uploads {
+    block_files: Vec<BlockFile>,
+    /// Commands running, oldest first, with the index of their file
+    running: VecDeque<(Child, usize)>,
+    uploaded: usize,
+    total: usize,
+}
+
+impl Uploads {
+    /// Wait for the oldest upload to succeed, returning the index of its file.
+    fn wait_oldest(&mut self) -> std::io::Result<usize> {
+        let (mut child, slot) = self.running.pop_front().expect("no upload running");
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("AWS CLI command failed ({})", status)));
+        }
+        progress::add_copied(EBS_BLOCK_SIZE);
+        self.uploaded += 1;
+        let uploaded = self.uploaded as u64;
+        if progress::line_due((uploaded - 1) * EBS_BLOCK_SIZE, uploaded * EBS_BLOCK_SIZE) {
+            message!("{}/{} blocks uploaded", uploaded, self.total);
+        }
+        Ok(slot)
+    }
+}
+
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        // Stop the uploads left after an error, before removing their files
+        for (child, _) in &mut self.running {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn start_snapshot(volume_size: u64, description: Option<&str>) -> std::io::Result<String> {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct StartSnapshotResponse {
+        snapshot_id: String,
+    }
+
+    let mut command = aws_ebs("start-snapshot");
+    command.arg("--volume-size").arg(volume_size.to_string());
+    if let Some(description) = description {
+        command.arg("--description").arg(description);
+    }
+    let output = run(command)?;
+    let response: StartSnapshotResponse = serde_json::from_slice(&output)?;
+    Ok(response.snapshot_id)
+}
+
+/// Start uploading a block, returning the command running.
+fn put_snapshot_block(
+    snapshot_id: &str,
+    block: u64,
+    data: &[u8],
+    block_file: &mut BlockFile,
+) -> std::io::Result<Child> {
+    let checksum = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(data));
+    block_file.write(data)?;
+
+    let mut block_data = std::ffi::OsString::from("fileb://");
+    block_data.push(&block_file.path);
+
+    let mut command = aws_ebs("put-snapshot-block");
+    command
+        .arg("--snapshot-id").arg(snapshot_id)
+        .arg("--block-index").arg(block.to_string())
+        .arg("--block-data").arg(block_data)
+        .arg("--data-length").arg(data.len().to_string())
+        .arg("--checksum").arg(checksum)
+        .arg("--checksum-algorithm").arg("SHA256");
+    command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::inherit()).spawn()
+}
+
+fn complete_snapshot(snapshot_id: &str, changed_blocks: usize) -> std::io::Result<()> {
+    let mut command = aws_ebs("complete-snapshot");
+    command
+        .arg("--snapshot-id").arg(snapshot_id)
+        .arg("--changed-blocks-count").arg(changed_blocks.to_string());
+    run(command)?;
+    Ok(())
+}
+
+fn delete_snapshot(snapshot_id: &str) -> std::io::Result<()> {
+    let mut command = Command::new("aws");
+    command
+        .arg("ec2").arg("delete-snapshot")
+        .arg("--snapshot-id").arg(snapshot_id);
+    run(command)?;
+    Ok(())
+}
+
+fn aws_ebs(operation: &str) -> Command {
+    let mut command = Command::new("aws");
+    command.arg("ebs").arg(operation).arg("--output").arg("json");
+    command
+}
+
+fn run(mut command: Command) -> std::io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "AWS CLI command failed ({})",
+            output.status,
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Temporary file used to hand block contents to the AWS CLI.
+///
+/// It is created exclusively and only readable by the user, since the
+/// temporary directory is usually shared.
+struct BlockFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl BlockFile {
+    fn create() -> std::io::Result<BlockFile> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut attempt = 0;
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "streaming-qcow2-writer-{}-{}.block",
+                std::process::id(),
+                attempt,
+            ));
+            match options.open(&path) {
+                Ok(file) => return Ok(BlockFile { path, file }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Replace the contents of the file with this block.
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(data)?;
+        self.file.flush()
+    }
+}
+
+impl Drop for BlockFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let b = EBS_BLOCK_SIZE;
+        let ranges = [0..10, 100..b + 1, b + 5..b + 6, 3 * b..4 * b, 5 * b..5 * b, 6 * b - 1..6 * b];
+        assert_eq!(blocks_for_layout(ranges.into_iter()).unwrap(), [0, 1, 3, 5]);
+
+        let error = blocks_for_layout([2 * b..3 * b, 0..b].into_iter()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,58 @@
+//! Encrypting the image with age as it is written, by piping it through the
+//! `age` tool.
+
+use std::ffi::OsString;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// A running `age --encrypt`, writing to the output.
+pub struct Encryptor {
+    child: Child,
+    finished: bool,
+}
+
+impl Encryptor {
+    /// Start encrypting to the given recipients and recipient files.
+    ///
+    /// The image should be written to the returned stdin, which must be
+    /// closed before calling `finish()`.
+    pub fn start(
+        recipients: &[String],
+        recipients_files: &[OsString],
+        output: Stdio,
+    ) -> std::io::Result<(Encryptor, ChildStdin)> {
+        let mut command = Command::new("age");
+        command.arg("--encrypt");
+        for recipient in recipients {
+            command.arg("--recipient").arg(recipient);
+        }
+        for file in recipients_files {
+            command.arg("--recipients-file").arg(file);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(output)
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        Ok((Encryptor { child, finished: false }, stdin))
+    }
+
+    /// Wait for the encrypted image to be written.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.finished = true;
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("age command failed ({})", status)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Encryptor {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! C ABI for the streaming writer, declared in
+//! `include/streaming_qcow2_writer.h`, so C and C++ programs can embed it.
+//!
+//! The caller provides the layout, then writes the header and metadata
+//! through a callback (or reads them in pieces), and the data of each
+//! cluster that the writer lists, in order. Every call returns 0 on success
+//! and -1 on error.
+//!
+//! The same functions are exported by the WebAssembly build, which
+//! `js/streaming-qcow2-writer.mjs` wraps as a stream.
+
+use std::ffi::{c_int, c_void};
+use std::io::Write;
+use std::ops::Range;
+
+use crate::layout;
+use crate::qcow2::{StreamingQcow2Writer, CLUSTER_SIZE};
+
+/// A range of the disk, in bytes.
+#[repr(C)]
+pub struct SqwRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Callback writing bytes to the output, returning the number of bytes
+/// written (possibly fewer than asked), or a negative number on error.
+pub type SqwWriteFn = unsafe extern "C" fn(opaque: *mut c_void, buf: *const u8, len: usize) -> isize;
+
+/// Writer handle given to C, with the position in the list of clusters.
+pub struct SqwWriter {
+    writer: StreamingQcow2Writer,
+    /// Last cluster listed, and how many were
+    last_cluster: Option<u64>,
+    listed: u64,
+    /// Header and metadata being read with `sqw_writer_read_header()`, and
+    /// how much of it was read
+    header: Option<(Vec<u8>, usize)>,
+}
+
+struct CallbackWriter {
+    write: SqwWriteFn,
+    opaque: *mut c_void,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = unsafe { (self.write)(self.opaque, buf.as_ptr(), buf.len()) };
+        usize::try_from(written).map_err(|_| std::io::Error::other("write callback failed"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Create a writer for a disk of `input_size` bytes, with data in `ranges`.
+///
+/// The ranges can be in any order and overlap. Returns NULL if a range goes
+/// past the end of the disk.
+///
+/// # Safety
+///
+/// `ranges` must point to `count` ranges, or be NULL if `count` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn sqw_writer_new(input_size: u64, ranges: *const SqwRange, count: usize) -> *mut SqwWriter {
+    let ranges = if count == 0 { &[] } else { std::slice::from_raw_parts(ranges, count) };
+    let mut layout: Vec<Range<u64>> = Vec::with_capacity(count);
+    for range in ranges {
+        match range.offset.checked_add(range.length) {
+            Some(end) if end <= input_size => layout.push(range.offset..end),
+            _ => return std::ptr::null_mut(),
+        }
+    }
+    let Ok(writer) = StreamingQcow2Writer::new(input_size, layout::normalize(layout).into_iter()) else {
+        return std::ptr::null_mut();
+    };
+    Box::into_raw(Box::new(SqwWriter { writer, last_cluster: None, listed: 0, header: None }))
+}
+
+/// Size of the image that will be written, in bytes.
+///
+/// # Safety
+///
+/// `writer` must have been returned by `sqw_writer_new()` and not finished.
+#[no_mangle]
+pub unsafe extern "C" fn sqw_writer_file_size(writer: *const SqwWriter) -> u64 {
+    (*writer).writer.file_size()
+}
+
+/// Write the header and metadata, which go before the data.
+///
+/// # Safety
+///
+/// `writer` must have been returned by `sqw_writer_new()` and not finished.
+/// `write` is called with `opaque` and must be safe to call with it.
+#[no_mangle]
+pub unsafe extern "C" fn sqw_writer_write_header(writer: *mut SqwWriter, write: SqwWriteFn, opaque: *mut c_void) -> c_int {
+    let mut output = CallbackWriter { write, opaque };
+    match (*writer).writer.write_header(&mut output) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Read the next piece of the header and metadata into `buf`, as an
+/// alternative to `sqw_writer_write_header()` for callers that can't provide
+/// a callback.
+///
+/// Returns the number of bytes read, 0 once all were read. The metadata is
+/// held in memory until then.
+///
+/// # Safety
+///
+/// `writer` must have been returned by `sqw_writer_new()` and not finished.
+/// `buf` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sqw_writer_read_header(writer: *mut SqwWriter, buf: *mut u8, len: usize) -> isize {
+    let writer = &mut *writer;
+    if writer.header.is_none() {
+        let mut header = Vec::with_capacity((writer.writer.file_size() - writer.writer.data_clusters().len() * CLUSTER_SIZE) as usize);
+        if writer.writer.write_header(&mut header).is_err() {
+            return -1;
+        }
+        writer.header = Some((header, 0));
+    }
+    let (header, position) = writer.header.as_mut().unwrap();
+    let length = len.min(header.len() - *position);
+    std::ptr::copy_nonoverlapping(header[*position..].as_ptr(), buf, length);
+    *position += length;
+    length as isize
+}
+
+/// Get the offset on the disk of the next cluster to write, whose
+/// `SQW_CLUSTER_SIZE` bytes of data the caller writes next (zeros past the
+/// end of the disk).
+///
+/// Returns 1 and sets `offset` if there is one, 0 once all were listed.
+///
+/// # Safety
+///
+/// `writer` must have been returned by `sqw_writer_new()` and not finished.
+/// `offset` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sqw_writer_next_cluster(writer: *mut SqwWriter, offset: *mut u64) -> c_int {
+    let writer = &mut *writer;
+    match writer.writer.data_clusters().next_after(writer.last_cluster) {
+        Some(cluster) => {
+            *offset = cluster * CLUSTER_SIZE;
+            writer.last_cluster = Some(cluster);
+            writer.listed += 1;
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Free the writer. Returns -1 if not every cluster was listed, in which
+/// case the image is incomplete.
+///
+/// # Safety
+///
+/// `writer` must have been returned by `sqw_writer_new()` and not finished.
+#[no_mangle]
+pub unsafe extern "C" fn sqw_writer_finish(writer: *mut SqwWriter) -> c_int {
+    let writer = Box::from_raw(writer);
+    if writer.listed == writer.writer.data_clusters().len() {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Allocate a buffer, for WebAssembly callers to pass data in memory.
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub extern "C" fn sqw_alloc(len: usize) -> *mut u8 {
+    let mut buffer = std::mem::ManuallyDrop::new(Vec::<u8>::with_capacity(len));
+    buffer.as_mut_ptr()
+}
+
+/// Free a buffer allocated with `sqw_alloc()`.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by `sqw_alloc(len)`.
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub unsafe extern "C" fn sqw_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
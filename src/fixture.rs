@@ -0,0 +1,106 @@
+//! Generating raw test images with data at known places, to test the tool or
+//! validate a conversion pipeline end to end (`gen-fixture` subcommand).
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::layout;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Contents of the data extents.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// Each sector starts with `SQCW-FIXTURE` and its offset in hex, so
+    /// misplaced data can be spotted
+    Markers,
+    /// Pseudo-random bytes, from the seed
+    Random,
+}
+
+pub struct Fixture {
+    pub size: u64,
+    pub extents: Vec<Range<u64>>,
+    pub fill: Fill,
+    pub seed: u64,
+}
+
+/// SplitMix64, a small deterministic generator, so fixtures can be recreated
+/// from their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.next() % n }
+    }
+}
+
+/// Pick `count` sector-aligned extents in a disk of the given size.
+pub fn random_extents(size: u64, count: u64, seed: u64) -> Vec<Range<u64>> {
+    let mut rng = Rng(seed);
+    let sectors = size / SECTOR_SIZE;
+    let max_length = (sectors / (4 * count.max(1))).max(1);
+    let mut extents = Vec::new();
+    for _ in 0..count {
+        let start = rng.below(sectors);
+        let length = 1 + rng.below(max_length);
+        let end = (start + length).min(sectors);
+        extents.push(start * SECTOR_SIZE..end * SECTOR_SIZE);
+    }
+    layout::normalize(extents)
+}
+
+impl Fixture {
+    /// Extents with data, clipped to the disk and merged.
+    pub fn layout(&self) -> Vec<Range<u64>> {
+        let extents = self.extents.iter().map(|e| e.start.min(self.size)..e.end.min(self.size)).collect();
+        layout::normalize(extents)
+    }
+
+    /// Write the image, leaving holes outside of the extents.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let file = File::create(path)?;
+        file.set_len(self.size)?;
+        let mut writer = BufWriter::new(file);
+        let mut rng = Rng(self.seed ^ 0x5351_4357);
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        for extent in self.layout() {
+            writer.seek(SeekFrom::Start(extent.start))?;
+            let mut offset = extent.start;
+            while offset < extent.end {
+                match self.fill {
+                    Fill::Markers => {
+                        // Never all zeros, so the data isn't mistaken for a hole
+                        sector.fill((offset / SECTOR_SIZE % 251) as u8 + 1);
+                        let marker = format!("SQCW-FIXTURE {:016x}\n", offset);
+                        sector[..marker.len()].copy_from_slice(marker.as_bytes());
+                    }
+                    Fill::Random => {
+                        for chunk in sector.chunks_mut(8) {
+                            chunk.copy_from_slice(&rng.next().to_le_bytes());
+                        }
+                    }
+                }
+                let len = (extent.end - offset).min(SECTOR_SIZE) as usize;
+                writer.write_all(&sector[..len])?;
+                offset += len as u64;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Write the layout in JSON format, as read by the conversion.
+    pub fn write_layout(&self, path: &Path) -> std::io::Result<()> {
+        layout::Layout::new(self.layout()).write_json(File::create(path)?)
+    }
+}
@@ -0,0 +1,440 @@
+//! Btrfs support: walks the extent tree, which has an item for every extent
+//! of data and metadata in use, and maps them to the disk with the chunk
+//! tree.
+//!
+//! Only filesystems on a single device are supported, with the single and
+//! DUP profiles, as the other profiles spread chunks over several devices.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+use super::{invalid, read_at};
+use crate::layout;
+
+/// Offsets of the superblock and its mirrors
+const SUPERBLOCK_OFFSETS: [u64; 3] = [0x1_0000, 0x400_0000, 0x40_0000_0000];
+
+const SUPERBLOCK_SIZE: usize = 4096;
+
+/// Start of the device, which btrfs never allocates and may hold a boot
+/// loader
+const RESERVED_SIZE: u64 = 1 << 20;
+
+const MAGIC: &[u8] = b"_BHRfS_M";
+
+/// Extent tree v2 changes how extents are tracked
+const INCOMPAT_EXTENT_TREE_V2: u64 = 1 << 13;
+
+/// Block group profiles other than single and DUP: RAID0, RAID1, RAID10,
+/// RAID5, RAID6, RAID1C3 and RAID1C4
+const MULTI_DEVICE_PROFILES: u64 = 0x8 | 0x10 | 0x40 | 0x80 | 0x100 | 0x200 | 0x400;
+
+const HEADER_SIZE: usize = 101;
+/// A key and the offset and size of its data
+const ITEM_SIZE: usize = 25;
+/// A key, the address of the child node and its generation
+const KEY_POINTER_SIZE: usize = 33;
+const CHUNK_ITEM_SIZE: usize = 48;
+const STRIPE_SIZE: usize = 32;
+const MAX_LEVEL: u8 = 7;
+
+const EXTENT_TREE_OBJECTID: u64 = 2;
+const ROOT_ITEM_KEY: u8 = 132;
+const EXTENT_ITEM_KEY: u8 = 168;
+const METADATA_ITEM_KEY: u8 = 169;
+const CHUNK_ITEM_KEY: u8 = 228;
+
+struct Key {
+    objectid: u64,
+    item_type: u8,
+    offset: u64,
+}
+
+impl Key {
+    fn read(data: &[u8]) -> Key {
+        Key {
+            objectid: LittleEndian::read_u64(&data[0..8]),
+            item_type: data[8],
+            offset: LittleEndian::read_u64(&data[9..17]),
+        }
+    }
+}
+
+/// A range of the logical address space, and where its copies are on the
+/// device.
+struct Chunk {
+    logical: Range<u64>,
+    stripes: Vec<u64>,
+}
+
+struct Superblock {
+    root: u64,
+    chunk_root: u64,
+    log_root: u64,
+    num_devices: u64,
+    node_size: u64,
+    incompat_flags: u64,
+    device_id: u64,
+    device_size: u64,
+    sys_chunk_array: Vec<u8>,
+}
+
+fn read_superblock(sb: &[u8]) -> Option<Superblock> {
+    if &sb[64..72] != MAGIC || LittleEndian::read_u64(&sb[48..56]) != SUPERBLOCK_OFFSETS[0] {
+        return None;
+    }
+    let node_size = LittleEndian::read_u32(&sb[148..152]) as u64;
+    let sys_chunk_array_size = LittleEndian::read_u32(&sb[160..164]) as usize;
+    if !node_size.is_power_of_two() || !(4096..=65536).contains(&node_size) || sys_chunk_array_size > 2048 {
+        return None;
+    }
+    Some(Superblock {
+        root: LittleEndian::read_u64(&sb[80..88]),
+        chunk_root: LittleEndian::read_u64(&sb[88..96]),
+        log_root: LittleEndian::read_u64(&sb[96..104]),
+        num_devices: LittleEndian::read_u64(&sb[136..144]),
+        node_size,
+        incompat_flags: LittleEndian::read_u64(&sb[188..196]),
+        device_id: LittleEndian::read_u64(&sb[201..209]),
+        device_size: LittleEndian::read_u64(&sb[209..217]),
+        sys_chunk_array: sb[811..811 + sys_chunk_array_size].to_vec(),
+    })
+}
+
+/// Read a chunk item, returning it and its size.
+fn read_chunk(logical: u64, data: &[u8], device_id: u64, device_size: u64) -> std::io::Result<(Chunk, usize)> {
+    if data.len() < CHUNK_ITEM_SIZE {
+        return Err(invalid("invalid btrfs chunk item"));
+    }
+    let length = LittleEndian::read_u64(&data[0..8]);
+    let profile = LittleEndian::read_u64(&data[24..32]);
+    let num_stripes = LittleEndian::read_u16(&data[44..46]) as usize;
+    let size = CHUNK_ITEM_SIZE + num_stripes * STRIPE_SIZE;
+    if length == 0 || num_stripes == 0 || data.len() < size {
+        return Err(invalid("invalid btrfs chunk item"));
+    }
+    if profile & MULTI_DEVICE_PROFILES != 0 {
+        return Err(invalid("btrfs RAID profiles are not supported"));
+    }
+    let mut stripes = Vec::with_capacity(num_stripes);
+    for stripe in data[CHUNK_ITEM_SIZE..size].chunks(STRIPE_SIZE) {
+        let offset = LittleEndian::read_u64(&stripe[8..16]);
+        if LittleEndian::read_u64(&stripe[0..8]) != device_id {
+            return Err(invalid("btrfs chunk is on another device"));
+        }
+        if offset.checked_add(length).is_none_or(|end| end > device_size) {
+            return Err(invalid("btrfs chunk past the end of the device"));
+        }
+        stripes.push(offset);
+    }
+    let logical = logical..logical.checked_add(length).ok_or_else(|| invalid("invalid btrfs chunk item"))?;
+    Ok((Chunk { logical, stripes }, size))
+}
+
+struct Filesystem<'a, R> {
+    reader: &'a mut R,
+    region_start: u64,
+    node_size: u64,
+    device_id: u64,
+    device_size: u64,
+    /// Chunks sorted by logical address
+    chunks: Vec<Chunk>,
+}
+
+impl<R: Read + Seek> Filesystem<'_, R> {
+    /// Read a tree node from its logical address.
+    fn read_node(&mut self, logical: u64) -> std::io::Result<Vec<u8>> {
+        let index = self.chunks.partition_point(|c| c.logical.end <= logical);
+        let chunk = self.chunks.get(index)
+            .filter(|c| c.logical.start <= logical && logical.saturating_add(self.node_size) <= c.logical.end)
+            .ok_or_else(|| invalid("btrfs tree node isn't in a chunk"))?;
+        let mut node = vec![0u8; self.node_size as usize];
+        read_at(self.reader, self.region_start + chunk.stripes[0] + (logical - chunk.logical.start), &mut node)?;
+        if LittleEndian::read_u64(&node[48..56]) != logical {
+            return Err(invalid("invalid btrfs tree node"));
+        }
+        Ok(node)
+    }
+
+    /// Call `visit` with the key and data of every item of a tree, in key
+    /// order.
+    fn walk<F: FnMut(Key, &[u8]) -> std::io::Result<()>>(&mut self, root: u64, mut visit: F) -> std::io::Result<()> {
+        let mut stack = vec![(root, None)];
+        while let Some((logical, expected_level)) = stack.pop() {
+            let node = self.read_node(logical)?;
+            let count = LittleEndian::read_u32(&node[96..100]) as usize;
+            let level = node[100];
+            // Levels go down by one, so a corrupted tree can't loop
+            if level > MAX_LEVEL || expected_level.is_some_and(|l| l != level) {
+                return Err(invalid("invalid btrfs tree node"));
+            }
+            if level == 0 {
+                if count > (node.len() - HEADER_SIZE) / ITEM_SIZE {
+                    return Err(invalid("invalid btrfs tree leaf"));
+                }
+                for item in node[HEADER_SIZE..HEADER_SIZE + count * ITEM_SIZE].chunks(ITEM_SIZE) {
+                    let offset = HEADER_SIZE.saturating_add(LittleEndian::read_u32(&item[17..21]) as usize);
+                    let size = LittleEndian::read_u32(&item[21..25]) as usize;
+                    if offset > node.len() || size > node.len() - offset {
+                        return Err(invalid("invalid btrfs tree leaf"));
+                    }
+                    visit(Key::read(item), &node[offset..offset + size])?;
+                }
+            } else {
+                if count > (node.len() - HEADER_SIZE) / KEY_POINTER_SIZE {
+                    return Err(invalid("invalid btrfs tree node"));
+                }
+                // Pushed in reverse, so the children are visited in order
+                for pointer in node[HEADER_SIZE..HEADER_SIZE + count * KEY_POINTER_SIZE].chunks(KEY_POINTER_SIZE).rev() {
+                    stack.push((LittleEndian::read_u64(&pointer[17..25]), Some(level - 1)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the chunks of the chunk tree, replacing the system chunks that
+    /// were used to read it.
+    fn read_chunk_tree(&mut self, root: u64) -> std::io::Result<()> {
+        let (device_id, device_size) = (self.device_id, self.device_size);
+        let mut chunks = Vec::new();
+        self.walk(root, |key, data| {
+            if key.item_type == CHUNK_ITEM_KEY {
+                chunks.push(read_chunk(key.offset, data, device_id, device_size)?.0);
+            }
+            Ok(())
+        })?;
+        chunks.sort_by_key(|c| c.logical.start);
+        self.chunks = chunks;
+        Ok(())
+    }
+}
+
+/// Find the space of a btrfs filesystem that isn't allocated to any extent.
+///
+/// Returns `None` if the region doesn't hold a btrfs filesystem.
+pub fn free_ranges<R: Read + Seek>(
+    reader: &mut R,
+    region: Range<u64>,
+) -> std::io::Result<Option<Vec<Range<u64>>>> {
+    if region.end - region.start < SUPERBLOCK_OFFSETS[0] + SUPERBLOCK_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut block = vec![0u8; SUPERBLOCK_SIZE];
+    read_at(reader, region.start + SUPERBLOCK_OFFSETS[0], &mut block)?;
+    let Some(sb) = read_superblock(&block) else {
+        return Ok(None);
+    };
+    if sb.num_devices != 1 {
+        return Err(invalid("btrfs filesystems on several devices are not supported"));
+    }
+    if sb.incompat_flags & INCOMPAT_EXTENT_TREE_V2 != 0 {
+        return Err(invalid("btrfs extent tree v2 is not supported"));
+    }
+    // Extents written since the last commit are only in the log tree
+    if sb.log_root != 0 {
+        return Err(invalid("btrfs filesystem has a log tree to replay, mount it first"));
+    }
+    let device_size = sb.device_size.min(region.end - region.start);
+
+    // The superblock has the system chunks, which hold the chunk tree
+    let mut chunks = Vec::new();
+    let mut array = &sb.sys_chunk_array[..];
+    while !array.is_empty() {
+        if array.len() < 17 || array[8] != CHUNK_ITEM_KEY {
+            return Err(invalid("invalid btrfs system chunk array"));
+        }
+        let (chunk, size) = read_chunk(Key::read(array).offset, &array[17..], sb.device_id, device_size)?;
+        chunks.push(chunk);
+        array = &array[17 + size..];
+    }
+    chunks.sort_by_key(|c| c.logical.start);
+    let mut fs = Filesystem {
+        reader,
+        region_start: region.start,
+        node_size: sb.node_size,
+        device_id: sb.device_id,
+        device_size,
+        chunks,
+    };
+    fs.read_chunk_tree(sb.chunk_root)?;
+
+    // Find the extent tree in the root tree
+    let mut extent_root = None;
+    fs.walk(sb.root, |key, data| {
+        if key.objectid == EXTENT_TREE_OBJECTID && key.item_type == ROOT_ITEM_KEY {
+            if data.len() < 184 {
+                return Err(invalid("invalid btrfs root item"));
+            }
+            extent_root = Some(LittleEndian::read_u64(&data[176..184]));
+        }
+        Ok(())
+    })?;
+    let extent_root = extent_root.ok_or_else(|| invalid("btrfs filesystem has no extent tree"))?;
+
+    // Every extent in use has an item, with its length, or the level of the
+    // tree node for metadata items
+    let node_size = sb.node_size;
+    let mut used_logical = Vec::new();
+    fs.walk(extent_root, |key, _| {
+        match key.item_type {
+            EXTENT_ITEM_KEY => used_logical.push(key.objectid..key.objectid.saturating_add(key.offset)),
+            METADATA_ITEM_KEY => used_logical.push(key.objectid..key.objectid.saturating_add(node_size)),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    let used_logical = layout::normalize(used_logical);
+
+    // Map the extents to every copy of their chunk
+    let mut used: Vec<Range<u64>> = std::iter::once(0..RESERVED_SIZE).collect();
+    for offset in SUPERBLOCK_OFFSETS {
+        used.push(offset..offset + SUPERBLOCK_SIZE as u64);
+    }
+    for chunk in &fs.chunks {
+        let first = used_logical.partition_point(|r| r.end <= chunk.logical.start);
+        for range in used_logical[first..].iter().take_while(|r| r.start < chunk.logical.end) {
+            let start = range.start.max(chunk.logical.start) - chunk.logical.start;
+            let end = range.end.min(chunk.logical.end) - chunk.logical.start;
+            for &stripe in &chunk.stripes {
+                used.push(stripe + start..stripe + end);
+            }
+        }
+    }
+
+    let free = layout::subtract(std::iter::once(0..device_size).collect(), used);
+    Ok(Some(free.into_iter().map(|r| region.start + r.start..region.start + r.end).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1 << 20;
+    const NODE_SIZE: u64 = 4096;
+    const REGION_START: u64 = MIB;
+
+    /// Chunk item of `length` bytes on device 1, its first stripe at 1 MiB.
+    fn chunk_item(length: u64, num_stripes: u16) -> Vec<u8> {
+        let mut item = vec![0u8; CHUNK_ITEM_SIZE + STRIPE_SIZE];
+        (&mut item[0..8]).write_u64::<LittleEndian>(length).unwrap();
+        (&mut item[24..32]).write_u64::<LittleEndian>(2).unwrap();
+        (&mut item[44..46]).write_u16::<LittleEndian>(num_stripes).unwrap();
+        (&mut item[48..56]).write_u64::<LittleEndian>(1).unwrap();
+        (&mut item[56..64]).write_u64::<LittleEndian>(MIB).unwrap();
+        item
+    }
+
+    fn key(objectid: u64, item_type: u8, offset: u64) -> Vec<u8> {
+        let mut key = Vec::new();
+        key.write_u64::<LittleEndian>(objectid).unwrap();
+        key.push(item_type);
+        key.write_u64::<LittleEndian>(offset).unwrap();
+        key
+    }
+
+    /// Write a leaf with these items at a logical address, which is also its
+    /// offset on the device.
+    fn leaf(device: &mut [u8], logical: u64, items: &[(Vec<u8>, Vec<u8>)]) {
+        let node = &mut device[logical as usize..][..NODE_SIZE as usize];
+        (&mut node[48..56]).write_u64::<LittleEndian>(logical).unwrap();
+        (&mut node[96..100]).write_u32::<LittleEndian>(items.len() as u32).unwrap();
+        let mut data_offset = NODE_SIZE as usize - HEADER_SIZE;
+        for (i, (key, data)) in items.iter().enumerate() {
+            data_offset -= data.len();
+            let item = &mut node[HEADER_SIZE + i * ITEM_SIZE..][..ITEM_SIZE];
+            item[..17].copy_from_slice(key);
+            (&mut item[17..21]).write_u32::<LittleEndian>(data_offset as u32).unwrap();
+            (&mut item[21..25]).write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            node[HEADER_SIZE + data_offset..][..data.len()].copy_from_slice(data);
+        }
+    }
+
+    /// A disk with a btrfs filesystem at `REGION_START`, whose device is
+    /// `device_size` bytes and has one chunk from 1 MiB to 4 MiB, holding
+    /// its three tree nodes at 1 MiB and a data extent of 64 KiB at 2 MiB.
+    fn disk(device_size: u64) -> Vec<u8> {
+        let mut disk = vec![0xAAu8; (REGION_START + 5 * MIB) as usize];
+        let device = &mut disk[REGION_START as usize..];
+        device[..4 * MIB as usize].fill(0);
+
+        let sb = &mut device[SUPERBLOCK_OFFSETS[0] as usize..][..SUPERBLOCK_SIZE];
+        (&mut sb[48..56]).write_u64::<LittleEndian>(SUPERBLOCK_OFFSETS[0]).unwrap();
+        sb[64..72].copy_from_slice(MAGIC);
+        (&mut sb[80..88]).write_u64::<LittleEndian>(MIB + NODE_SIZE).unwrap();
+        (&mut sb[88..96]).write_u64::<LittleEndian>(MIB).unwrap();
+        (&mut sb[136..144]).write_u64::<LittleEndian>(1).unwrap();
+        (&mut sb[148..152]).write_u32::<LittleEndian>(NODE_SIZE as u32).unwrap();
+        (&mut sb[201..209]).write_u64::<LittleEndian>(1).unwrap();
+        (&mut sb[209..217]).write_u64::<LittleEndian>(device_size).unwrap();
+        let mut sys_chunk_array = key(256, CHUNK_ITEM_KEY, MIB);
+        sys_chunk_array.extend(chunk_item(3 * MIB, 1));
+        (&mut sb[160..164]).write_u32::<LittleEndian>(sys_chunk_array.len() as u32).unwrap();
+        sb[811..811 + sys_chunk_array.len()].copy_from_slice(&sys_chunk_array);
+
+        leaf(device, MIB, &[(key(256, CHUNK_ITEM_KEY, MIB), chunk_item(3 * MIB, 1))]);
+        let mut root_item = vec![0u8; 239];
+        (&mut root_item[176..184]).write_u64::<LittleEndian>(MIB + 2 * NODE_SIZE).unwrap();
+        leaf(device, MIB + NODE_SIZE, &[(key(EXTENT_TREE_OBJECTID, ROOT_ITEM_KEY, 0), root_item)]);
+        leaf(device, MIB + 2 * NODE_SIZE, &[
+            (key(MIB, METADATA_ITEM_KEY, 0), vec![0; 24]),
+            (key(MIB + NODE_SIZE, METADATA_ITEM_KEY, 0), vec![0; 24]),
+            (key(MIB + 2 * NODE_SIZE, METADATA_ITEM_KEY, 0), vec![0; 24]),
+            (key(2 * MIB, EXTENT_ITEM_KEY, 1 << 16), vec![0; 24]),
+        ]);
+        disk
+    }
+
+    #[test]
+    fn free_at_end_of_region() {
+        let mut reader = Cursor::new(disk(4 * MIB));
+        let region = REGION_START..REGION_START + 4 * MIB;
+        let free = free_ranges(&mut reader, region).unwrap().unwrap();
+        assert_eq!(free, vec![
+            REGION_START + MIB + 3 * NODE_SIZE..REGION_START + 2 * MIB,
+            REGION_START + 2 * MIB + (1 << 16)..REGION_START + 4 * MIB,
+        ]);
+    }
+
+    #[test]
+    fn truncated_region() {
+        // A device larger than the region is clipped to it
+        let mut reader = Cursor::new(disk(8 * MIB));
+        let free = free_ranges(&mut reader, REGION_START..REGION_START + 4 * MIB).unwrap().unwrap();
+        assert_eq!(free.last().unwrap().end, REGION_START + 4 * MIB);
+
+        // A chunk past the end of the region is an error
+        let mut reader = Cursor::new(disk(4 * MIB));
+        assert!(free_ranges(&mut reader, REGION_START..REGION_START + 3 * MIB).is_err());
+    }
+
+    #[test]
+    fn corrupt_counts() {
+        // Items in a leaf
+        let mut image = disk(4 * MIB);
+        let count = (REGION_START + MIB + NODE_SIZE + 96) as usize;
+        (&mut image[count..count + 4]).write_u32::<LittleEndian>(u32::MAX).unwrap();
+        assert!(free_ranges(&mut Cursor::new(image), REGION_START..REGION_START + 4 * MIB).is_err());
+
+        // Stripes of a chunk
+        let mut image = disk(4 * MIB);
+        let stripes = (REGION_START + SUPERBLOCK_OFFSETS[0]) as usize + 811 + 17 + 44;
+        (&mut image[stripes..stripes + 2]).write_u16::<LittleEndian>(u16::MAX).unwrap();
+        assert!(free_ranges(&mut Cursor::new(image), REGION_START..REGION_START + 4 * MIB).is_err());
+
+        // Item data past the end of the leaf
+        let mut image = disk(4 * MIB);
+        let size = (REGION_START + MIB + NODE_SIZE) as usize + HEADER_SIZE + 21;
+        (&mut image[size..size + 4]).write_u32::<LittleEndian>(u32::MAX).unwrap();
+        assert!(free_ranges(&mut Cursor::new(image), REGION_START..REGION_START + 4 * MIB).is_err());
+    }
+
+    #[test]
+    fn not_btrfs() {
+        let mut reader = Cursor::new(vec![0u8; 4 * MIB as usize]);
+        assert!(free_ranges(&mut reader, 0..4 * MIB).unwrap().is_none());
+    }
+}
@@ -0,0 +1,101 @@
+//! Filesystem-aware sparsification: finding the space a filesystem doesn't
+//! use, so it can be left out of the image.
+
+pub mod btrfs;
+pub mod ntfs;
+pub mod swap;
+pub mod xfs;
+
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::input::Input;
+use crate::layout::{self, Extent, ExtentSource};
+use crate::log::message;
+
+/// The space marked free by the filesystems in these regions of the disk, as
+/// an extent source.
+pub struct Filesystems {
+    pub regions: Vec<Range<u64>>,
+}
+
+impl ExtentSource for Filesystems {
+    fn name(&self) -> &'static str {
+        "filesystem"
+    }
+
+    fn phase(&self) -> Option<&'static str> {
+        Some("Reading filesystems")
+    }
+
+    fn extents(&mut self, input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let mut free = Vec::new();
+        for region in &self.regions {
+            if let Some(space) = free_ranges(&mut *input, region.clone())? {
+                let free_bytes: u64 = space.ranges.iter().map(|r| r.end - r.start).sum();
+                message!(
+                    "Found {} filesystem at offset {}, {} bytes free",
+                    space.fs_type, region.start, free_bytes,
+                );
+                free.extend(space.ranges);
+            }
+        }
+        Ok(layout::free_extents(free))
+    }
+}
+
+pub struct FreeSpace {
+    /// Name of the filesystem that was found
+    pub fs_type: &'static str,
+    /// Free byte ranges, as absolute offsets on the disk
+    pub ranges: Vec<Range<u64>>,
+}
+
+/// Find the free space of the filesystem in a region of the disk.
+///
+/// Returns `None` if no supported filesystem was recognized.
+pub fn free_ranges<R: Read + Seek>(
+    mut reader: R,
+    region: Range<u64>,
+) -> std::io::Result<Option<FreeSpace>> {
+    if let Some(ranges) = ntfs::free_ranges(&mut reader, region.clone())? {
+        return Ok(Some(FreeSpace { fs_type: "NTFS", ranges }));
+    }
+    if let Some(ranges) = xfs::free_ranges(&mut reader, region.clone())? {
+        return Ok(Some(FreeSpace { fs_type: "XFS", ranges }));
+    }
+    if let Some(ranges) = btrfs::free_ranges(&mut reader, region.clone())? {
+        return Ok(Some(FreeSpace { fs_type: "btrfs", ranges }));
+    }
+    Ok(None)
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buffer)
+}
+
+/// Collects runs of free blocks into byte ranges, merging adjacent ones.
+struct FreeRanges {
+    ranges: Vec<Range<u64>>,
+}
+
+impl FreeRanges {
+    fn new() -> FreeRanges {
+        FreeRanges { ranges: Vec::new() }
+    }
+
+    fn add(&mut self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+        match self.ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.ranges.push(range),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! NTFS support: reads the `$Bitmap` metadata file, which has one bit per
+//! cluster of the volume set if the cluster is in use.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+use super::{invalid, read_at, FreeRanges};
+use crate::layout;
+
+/// MFT record number of the `$Bitmap` file
+const BITMAP_RECORD: u64 = 6;
+
+const ATTRIBUTE_DATA: u32 = 0x80;
+const ATTRIBUTE_END: u32 = 0xFFFF_FFFF;
+
+/// How much of the bitmap to read at once
+const BITMAP_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Extents of a non-resident attribute: starting cluster (`None` for sparse
+/// runs) and length in clusters
+type DataRuns = Vec<(Option<u64>, u64)>;
+
+struct BootSector {
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    total_sectors: u64,
+    mft_cluster: u64,
+    mft_record_size: u64,
+}
+
+fn read_boot_sector(sector: &[u8]) -> Option<BootSector> {
+    if &sector[3..11] != b"NTFS    " || sector[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    let bytes_per_sector = LittleEndian::read_u16(&sector[11..13]) as u64;
+    let sectors_per_cluster = match sector[13] {
+        n if n <= 0x80 => n as u64,
+        // Values above 0x80 encode a power of two: 2^(256 - n)
+        n => 1u64 << (256 - n as u32),
+    };
+    let total_sectors = LittleEndian::read_u64(&sector[40..48]);
+    let mft_cluster = LittleEndian::read_u64(&sector[48..56]);
+    let cluster_size = bytes_per_sector * sectors_per_cluster;
+    let mft_record_size = match sector[64] as i8 {
+        n if n > 0 => n as u64 * cluster_size,
+        // Negative values encode a size in bytes of 2^-n
+        n => 1u64 << (-(n as i32)),
+    };
+    if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 256
+        || cluster_size == 0 || !(256..=65536).contains(&mft_record_size)
+    {
+        return None;
+    }
+    Some(BootSector {
+        bytes_per_sector,
+        cluster_size,
+        total_sectors,
+        mft_cluster,
+        mft_record_size,
+    })
+}
+
+/// Apply the update sequence array of an MFT record, restoring the last two
+/// bytes of each sector.
+fn apply_fixups(record: &mut [u8], bytes_per_sector: usize) -> std::io::Result<()> {
+    if &record[0..4] != b"FILE" {
+        return Err(invalid("invalid NTFS MFT record"));
+    }
+    let usa_offset = LittleEndian::read_u16(&record[4..6]) as usize;
+    let usa_count = LittleEndian::read_u16(&record[6..8]) as usize;
+    if usa_count == 0 || usa_offset + usa_count * 2 > record.len()
+        || (usa_count - 1) * bytes_per_sector > record.len()
+    {
+        return Err(invalid("invalid NTFS update sequence"));
+    }
+    let usn = [record[usa_offset], record[usa_offset + 1]];
+    for i in 1..usa_count {
+        let end = i * bytes_per_sector;
+        if record[end - 2..end] != usn {
+            return Err(invalid("NTFS MFT record is torn"));
+        }
+        record[end - 2] = record[usa_offset + i * 2];
+        record[end - 1] = record[usa_offset + i * 2 + 1];
+    }
+    Ok(())
+}
+
+/// Decode a list of data runs into (cluster, length) extents.
+fn decode_runs(mut runs: &[u8]) -> std::io::Result<DataRuns> {
+    let mut extents = Vec::new();
+    let mut lcn: i64 = 0;
+    while let Some(&header) = runs.first() {
+        if header == 0 {
+            break;
+        }
+        let length_size = (header & 0x0F) as usize;
+        let offset_size = (header >> 4) as usize;
+        if length_size == 0 || length_size > 8 || offset_size > 8
+            || runs.len() < 1 + length_size + offset_size
+        {
+            return Err(invalid("invalid NTFS data run"));
+        }
+        let length = LittleEndian::read_uint(&runs[1..1 + length_size], length_size);
+        if offset_size == 0 {
+            // Sparse run
+            extents.push((None, length));
+        } else {
+            let offset = LittleEndian::read_int(
+                &runs[1 + length_size..1 + length_size + offset_size],
+                offset_size,
+            );
+            lcn += offset;
+            if lcn < 0 {
+                return Err(invalid("invalid NTFS data run"));
+            }
+            extents.push((Some(lcn as u64), length));
+        }
+        runs = &runs[1 + length_size + offset_size..];
+    }
+    Ok(extents)
+}
+
+/// Find the unnamed, non-resident `$DATA` attribute of a record and return
+/// its data runs and real size.
+fn find_data_runs(record: &[u8]) -> std::io::Result<(DataRuns, u64)> {
+    let mut offset = LittleEndian::read_u16(&record[20..22]) as usize;
+    while offset + 16 <= record.len() {
+        let attr_type = LittleEndian::read_u32(&record[offset..offset + 4]);
+        if attr_type == ATTRIBUTE_END {
+            break;
+        }
+        let length = LittleEndian::read_u32(&record[offset + 4..offset + 8]) as usize;
+        if length < 16 || offset + length > record.len() {
+            break;
+        }
+        let attr = &record[offset..offset + length];
+        let non_resident = attr[8] != 0;
+        let name_length = attr[9];
+        if attr_type == ATTRIBUTE_DATA && name_length == 0 {
+            if !non_resident || attr.len() < 64 {
+                return Err(invalid("unexpected resident NTFS $Bitmap"));
+            }
+            let runs_offset = LittleEndian::read_u16(&attr[32..34]) as usize;
+            let real_size = LittleEndian::read_u64(&attr[48..56]);
+            if runs_offset >= attr.len() {
+                return Err(invalid("invalid NTFS attribute"));
+            }
+            return Ok((decode_runs(&attr[runs_offset..])?, real_size));
+        }
+        offset += length;
+    }
+    Err(invalid("NTFS $Bitmap has no data attribute"))
+}
+
+/// Find the clusters of an NTFS volume that are marked free in its bitmap.
+///
+/// Returns `None` if the region doesn't hold an NTFS filesystem.
+pub fn free_ranges<R: Read + Seek>(
+    reader: &mut R,
+    region: Range<u64>,
+) -> std::io::Result<Option<Vec<Range<u64>>>> {
+    if region.end - region.start < 512 {
+        return Ok(None);
+    }
+    let mut sector = [0u8; 512];
+    read_at(reader, region.start, &mut sector)?;
+    let Some(boot) = read_boot_sector(&sector) else {
+        return Ok(None);
+    };
+
+    // A volume larger than its partition would have us drop the data after it
+    let volume_size = boot.total_sectors.checked_mul(boot.bytes_per_sector)
+        .ok_or_else(|| invalid("invalid NTFS volume size"))?;
+    if volume_size > region.end - region.start {
+        return Err(invalid("NTFS volume is larger than its partition"));
+    }
+    let total_clusters = volume_size / boot.cluster_size;
+
+    // Read the MFT record of $Bitmap; the first records of the MFT are
+    // always contiguous
+    let mut record = vec![0u8; boot.mft_record_size as usize];
+    let record_offset = boot.mft_cluster.checked_mul(boot.cluster_size)
+        .and_then(|o| o.checked_add(BITMAP_RECORD * boot.mft_record_size))
+        .and_then(|o| o.checked_add(region.start))
+        .ok_or_else(|| invalid("invalid NTFS MFT location"))?;
+    read_at(reader, record_offset, &mut record)?;
+    apply_fixups(&mut record, boot.bytes_per_sector as usize)?;
+    let (runs, bitmap_size) = find_data_runs(&record)?;
+
+    if bitmap_size * 8 < total_clusters {
+        return Err(invalid("NTFS $Bitmap is too small for the volume"));
+    }
+
+    // Go over the bitmap, collecting runs of clear bits
+    let mut free = FreeRanges::new();
+    let mut cluster = 0;
+    let mut buffer = vec![0u8; BITMAP_CHUNK_SIZE as usize];
+    'runs: for (lcn, length) in runs {
+        let mut run_offset = 0;
+        let run_bytes = length.checked_mul(boot.cluster_size)
+            .ok_or_else(|| invalid("invalid NTFS data run"))?;
+        let run_start = match lcn {
+            Some(lcn) => {
+                let start = lcn.checked_mul(boot.cluster_size)
+                    .filter(|&o| o.checked_add(run_bytes).is_some_and(|end| end <= volume_size))
+                    .ok_or_else(|| invalid("NTFS $Bitmap data run is outside of the volume"))?;
+                Some(region.start + start)
+            }
+            None => None,
+        };
+        while run_offset < run_bytes {
+            if cluster >= total_clusters {
+                break 'runs;
+            }
+            let chunk = (run_bytes - run_offset).min(BITMAP_CHUNK_SIZE) as usize;
+            match run_start {
+                Some(start) => read_at(reader, start + run_offset, &mut buffer[..chunk])?,
+                None => buffer[..chunk].fill(0),
+            }
+            for &byte in &buffer[..chunk] {
+                for bit in 0..8 {
+                    if cluster >= total_clusters {
+                        break;
+                    }
+                    if byte & (1 << bit) == 0 {
+                        let start = region.start + cluster * boot.cluster_size;
+                        free.add(start..start + boot.cluster_size);
+                    }
+                    cluster += 1;
+                }
+            }
+            run_offset += chunk as u64;
+        }
+    }
+
+    // Space after the last cluster, such as the backup boot sector, is kept
+    Ok(Some(layout::intersect(free.ranges, vec![region])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const REGION_START: u64 = 1 << 16;
+    const CLUSTER_SIZE: u64 = 4096;
+
+    /// A disk with an NTFS volume of 16 clusters at `REGION_START`, whose
+    /// $Bitmap is in cluster `bitmap_lcn` and marks clusters 12 to 15 free,
+    /// followed by another partition.
+    fn disk(total_sectors: u64, bitmap_lcn: u8) -> Vec<u8> {
+        let mut disk = vec![0xAAu8; 3 << 16];
+        let volume = &mut disk[REGION_START as usize..];
+
+        // Boot sector: 512-byte sectors, 8 per cluster, MFT in cluster 1,
+        // 1024-byte records
+        volume[..512].fill(0);
+        volume[3..11].copy_from_slice(b"NTFS    ");
+        (&mut volume[11..13]).write_u16::<LittleEndian>(512).unwrap();
+        volume[13] = 8;
+        (&mut volume[40..48]).write_u64::<LittleEndian>(total_sectors).unwrap();
+        (&mut volume[48..56]).write_u64::<LittleEndian>(1).unwrap();
+        volume[64] = 0xF6;
+        volume[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        // MFT record of $Bitmap, with an update sequence of 2 sectors
+        let record = &mut volume[(CLUSTER_SIZE + BITMAP_RECORD * 1024) as usize..][..1024];
+        record.fill(0);
+        record[0..4].copy_from_slice(b"FILE");
+        (&mut record[4..6]).write_u16::<LittleEndian>(48).unwrap();
+        (&mut record[6..8]).write_u16::<LittleEndian>(3).unwrap();
+        (&mut record[20..22]).write_u16::<LittleEndian>(56).unwrap();
+        record[48..50].copy_from_slice(&[1, 0]);
+        record[510..512].copy_from_slice(&[1, 0]);
+        record[1022..1024].copy_from_slice(&[1, 0]);
+        let attribute = &mut record[56..128];
+        (&mut attribute[0..4]).write_u32::<LittleEndian>(ATTRIBUTE_DATA).unwrap();
+        (&mut attribute[4..8]).write_u32::<LittleEndian>(72).unwrap();
+        attribute[8] = 1;
+        (&mut attribute[32..34]).write_u16::<LittleEndian>(64).unwrap();
+        (&mut attribute[48..56]).write_u64::<LittleEndian>(2).unwrap();
+        attribute[64..67].copy_from_slice(&[0x11, 1, bitmap_lcn]);
+        (&mut record[128..132]).write_u32::<LittleEndian>(ATTRIBUTE_END).unwrap();
+
+        // The bitmap
+        let bitmap = &mut volume[(4 * CLUSTER_SIZE) as usize..][..CLUSTER_SIZE as usize];
+        bitmap.fill(0);
+        bitmap[0..2].copy_from_slice(&[0xFF, 0x0F]);
+        disk
+    }
+
+    #[test]
+    fn free_at_end_of_region() {
+        let mut reader = Cursor::new(disk(128, 4));
+        let region = REGION_START..REGION_START + 16 * CLUSTER_SIZE;
+        let free = free_ranges(&mut reader, region.clone()).unwrap().unwrap();
+        assert_eq!(free, vec![REGION_START + 12 * CLUSTER_SIZE..region.end]);
+    }
+
+    #[test]
+    fn volume_larger_than_region() {
+        let mut reader = Cursor::new(disk(128, 4));
+        let region = REGION_START..REGION_START + 8 * CLUSTER_SIZE;
+        assert!(free_ranges(&mut reader, region).is_err());
+    }
+
+    #[test]
+    fn corrupt_sizes() {
+        let region = REGION_START..REGION_START + 16 * CLUSTER_SIZE;
+        // Overflowing volume size
+        let mut reader = Cursor::new(disk(u64::MAX / 256, 4));
+        assert!(free_ranges(&mut reader, region.clone()).is_err());
+        // Bitmap outside of the volume
+        let mut reader = Cursor::new(disk(128, 200));
+        assert!(free_ranges(&mut reader, region).is_err());
+    }
+
+    #[test]
+    fn not_ntfs() {
+        let mut reader = Cursor::new(vec![0u8; 1 << 16]);
+        assert!(free_ranges(&mut reader, 0..1 << 16).unwrap().is_none());
+    }
+}
@@ -0,0 +1,145 @@
+//! Linux swap support: swap areas only need their header to be usable, the
+//! rest is stale memory contents.
+
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+use super::read_at;
+use crate::input::Input;
+use crate::layout::{self, Extent, ExtentSource};
+use crate::log::message;
+use crate::partition::PartitionType;
+
+/// GPT partition type for Linux swap
+const GPT_SWAP: &str = "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f";
+
+/// MBR partition type for Linux swap
+const MBR_SWAP: u8 = 0x82;
+
+/// Page sizes the swap signature is looked for with, it is written at the
+/// end of the first page
+const PAGE_SIZES: [u64; 4] = [4096, 8192, 16384, 65536];
+
+/// The contents of the swap areas in these regions of the disk, as an extent
+/// source; a region is a swap area if it has a swap signature.
+///
+/// A swap partition type alone is not enough: the partition could have been
+/// reused without changing its type, and its contents would be lost.
+pub struct SwapAreas<'a> {
+    pub regions: Vec<(Range<u64>, Option<&'a PartitionType>)>,
+    /// Keep the swap header, so the area stays usable
+    pub keep_header: bool,
+}
+
+impl ExtentSource for SwapAreas<'_> {
+    fn name(&self) -> &'static str {
+        "swap area"
+    }
+
+    fn phase(&self) -> Option<&'static str> {
+        Some("Reading swap areas")
+    }
+
+    fn extents(&mut self, input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let mut skipped = Vec::new();
+        for (region, partition_type) in &self.regions {
+            let Some(header_size) = swap_header_size(input, region.clone())? else {
+                if partition_type.is_some_and(is_swap_partition_type) {
+                    message!(
+                        "Partition at offset {} has a swap type but no swap signature, keeping its contents",
+                        region.start,
+                    );
+                }
+                continue;
+            };
+            message!(
+                "Found swap area at offset {}, {} bytes",
+                region.start, region.end - region.start,
+            );
+            if self.keep_header {
+                skipped.push((region.start + header_size).min(region.end)..region.end);
+            } else {
+                skipped.push(region.clone());
+            }
+        }
+        Ok(layout::free_extents(skipped))
+    }
+}
+
+pub fn is_swap_partition_type(partition_type: &PartitionType) -> bool {
+    match partition_type {
+        PartitionType::Mbr(t) => *t == MBR_SWAP,
+        PartitionType::Gpt(guid) => guid == GPT_SWAP,
+    }
+}
+
+/// Look for a swap signature, returning the size of the swap header.
+pub fn swap_header_size<R: Read + Seek>(
+    reader: &mut R,
+    region: Range<u64>,
+) -> std::io::Result<Option<u64>> {
+    let mut signature = [0u8; 10];
+    for page_size in PAGE_SIZES {
+        if region.end - region.start < page_size {
+            break;
+        }
+        read_at(reader, region.start + page_size - 10, &mut signature)?;
+        if &signature == b"SWAPSPACE2" || &signature == b"SWAP-SPACE" {
+            return Ok(Some(page_size));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::ForwardReader;
+    use std::io::Cursor;
+
+    const REGION_SIZE: u64 = 1 << 17;
+
+    /// A disk of three regions: a swap area with 8 KiB pages, a region
+    /// without a signature, and a region with a 4 KiB signature.
+    fn disk() -> Input {
+        let mut disk = vec![0xAAu8; 3 * REGION_SIZE as usize];
+        disk[8192 - 10..8192].copy_from_slice(b"SWAPSPACE2");
+        disk[(2 * REGION_SIZE + 4096 - 10) as usize..][..10].copy_from_slice(b"SWAP-SPACE");
+        Input::Stream(ForwardReader::new(Box::new(Cursor::new(disk)), 3 * REGION_SIZE))
+    }
+
+    fn free(regions: Vec<(Range<u64>, Option<&PartitionType>)>, keep_header: bool) -> Vec<Range<u64>> {
+        let mut areas = SwapAreas { regions, keep_header };
+        areas.extents(&mut disk()).unwrap().map(|extent| extent.range).collect()
+    }
+
+    #[test]
+    fn signature_required() {
+        let swap_type = PartitionType::Mbr(MBR_SWAP);
+        let regions = vec![
+            (0..REGION_SIZE, None),
+            (REGION_SIZE..2 * REGION_SIZE, Some(&swap_type)),
+            (2 * REGION_SIZE..3 * REGION_SIZE, None),
+        ];
+        assert_eq!(free(regions.clone(), false), vec![0..REGION_SIZE, 2 * REGION_SIZE..3 * REGION_SIZE]);
+        assert_eq!(
+            free(regions, true),
+            vec![8192..REGION_SIZE, 2 * REGION_SIZE + 4096..3 * REGION_SIZE],
+        );
+    }
+
+    #[test]
+    fn truncated_region() {
+        // The signature for 8 KiB pages is past the end of a 6 KiB region
+        assert_eq!(free(vec![(0..6144, None)], true), Vec::<Range<u64>>::new());
+        // A region shorter than 16 KiB still has the signature of 8 KiB pages
+        assert_eq!(free(vec![(0..12288, None)], true), vec![8192..12288]);
+    }
+
+    #[test]
+    fn partition_types() {
+        assert!(is_swap_partition_type(&PartitionType::Mbr(0x82)));
+        assert!(!is_swap_partition_type(&PartitionType::Mbr(0x83)));
+        assert!(is_swap_partition_type(&PartitionType::Gpt(GPT_SWAP.to_owned())));
+    }
+}
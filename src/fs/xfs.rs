@@ -0,0 +1,264 @@
+//! XFS support: walks the free-space B+tree indexed by block number (and the
+//! free list) of each allocation group.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+use super::{invalid, read_at, FreeRanges};
+use crate::layout;
+
+const NULL_BLOCK: u32 = 0xFFFF_FFFF;
+
+struct Superblock {
+    block_size: u64,
+    /// Size of the filesystem in blocks
+    data_blocks: u64,
+    sector_size: u64,
+    ag_blocks: u64,
+    ag_count: u32,
+    /// Version 5 filesystems have self-describing (CRC) metadata with
+    /// longer headers
+    v5: bool,
+}
+
+fn read_superblock(sb: &[u8]) -> Option<Superblock> {
+    if &sb[0..4] != b"XFSB" {
+        return None;
+    }
+    let block_size = BigEndian::read_u32(&sb[4..8]) as u64;
+    let data_blocks = BigEndian::read_u64(&sb[8..16]);
+    let ag_blocks = BigEndian::read_u32(&sb[84..88]) as u64;
+    let ag_count = BigEndian::read_u32(&sb[88..92]);
+    let version = BigEndian::read_u16(&sb[100..102]) & 0x000F;
+    let sector_size = BigEndian::read_u16(&sb[102..104]) as u64;
+    if !block_size.is_power_of_two() || !(512..=65536).contains(&block_size)
+        || !sector_size.is_power_of_two() || sector_size < 512
+        || ag_blocks == 0 || ag_count == 0
+    {
+        return None;
+    }
+    Some(Superblock {
+        block_size,
+        data_blocks,
+        sector_size,
+        ag_blocks,
+        ag_count,
+        v5: version == 5,
+    })
+}
+
+/// Find the blocks of an XFS filesystem that are free.
+///
+/// Returns `None` if the region doesn't hold an XFS filesystem.
+pub fn free_ranges<R: Read + Seek>(
+    reader: &mut R,
+    region: Range<u64>,
+) -> std::io::Result<Option<Vec<Range<u64>>>> {
+    if region.end - region.start < 512 {
+        return Ok(None);
+    }
+    let mut sector = [0u8; 512];
+    read_at(reader, region.start, &mut sector)?;
+    let Some(sb) = read_superblock(&sector) else {
+        return Ok(None);
+    };
+
+    // A filesystem larger than its partition would have us drop the data
+    // after it
+    let fs_size = sb.data_blocks.checked_mul(sb.block_size)
+        .ok_or_else(|| invalid("invalid XFS filesystem size"))?;
+    let ag_size = sb.ag_blocks * sb.block_size;
+    let last_ag_start = (sb.ag_count as u64 - 1).checked_mul(ag_size)
+        .ok_or_else(|| invalid("invalid XFS allocation group count"))?;
+    if fs_size > region.end - region.start || last_ag_start >= fs_size {
+        return Err(invalid("XFS filesystem is larger than its partition"));
+    }
+
+    let mut free = Vec::new();
+    for ag in 0..sb.ag_count {
+        let ag_start = region.start + ag as u64 * ag_size;
+        free.extend(ag_free_ranges(reader, &sb, ag_start, region.end)?);
+    }
+    Ok(Some(layout::intersect(free, vec![region])))
+}
+
+fn ag_free_ranges<R: Read + Seek>(
+    reader: &mut R,
+    sb: &Superblock,
+    ag_start: u64,
+    region_end: u64,
+) -> std::io::Result<Vec<Range<u64>>> {
+    // The AGF is in the second sector of the allocation group
+    let mut agf = vec![0u8; sb.sector_size as usize];
+    read_at(reader, ag_start + sb.sector_size, &mut agf)?;
+    if &agf[0..4] != b"XAGF" {
+        return Err(invalid("invalid XFS AGF"));
+    }
+    let ag_length = BigEndian::read_u32(&agf[12..16]) as u64;
+    let bno_root = BigEndian::read_u32(&agf[16..20]);
+    let bno_level = BigEndian::read_u32(&agf[28..32]);
+    let fl_first = BigEndian::read_u32(&agf[40..44]) as usize;
+    let fl_count = BigEndian::read_u32(&agf[48..52]) as usize;
+    if ag_length > sb.ag_blocks || ag_start + ag_length * sb.block_size > region_end {
+        return Err(invalid("XFS allocation group is past the end of the partition"));
+    }
+
+    let block_range = |agbno: u64, count: u64| {
+        let start = ag_start + agbno * sb.block_size;
+        start..start + count * sb.block_size
+    };
+
+    let mut free = FreeRanges::new();
+    let (header_size, magic): (usize, &[u8]) = if sb.v5 {
+        (56, b"AB3B")
+    } else {
+        (16, b"ABTB")
+    };
+    let mut block = vec![0u8; sb.block_size as usize];
+
+    // Descend to the leftmost leaf
+    let mut agbno = bno_root;
+    for _ in 0..bno_level.saturating_sub(1) {
+        read_at(reader, block_range(agbno as u64, 1).start, &mut block)?;
+        if &block[0..4] != magic || BigEndian::read_u16(&block[4..6]) == 0 {
+            return Err(invalid("invalid XFS free space B+tree node"));
+        }
+        // Keys and pointers are both arrays of the maximum number of
+        // records; a key is 8 bytes and a pointer 4
+        let max_records = (block.len() - header_size) / 12;
+        let ptr_offset = header_size + max_records * 8;
+        agbno = BigEndian::read_u32(&block[ptr_offset..ptr_offset + 4]);
+    }
+
+    // Follow the chain of leaves, which are in block number order
+    let mut visited = 0;
+    while agbno != NULL_BLOCK {
+        visited += 1;
+        if agbno as u64 >= ag_length || visited > ag_length {
+            return Err(invalid("invalid XFS free space B+tree"));
+        }
+        read_at(reader, block_range(agbno as u64, 1).start, &mut block)?;
+        if &block[0..4] != magic || BigEndian::read_u16(&block[4..6]) != 0 {
+            return Err(invalid("invalid XFS free space B+tree leaf"));
+        }
+        let num_records = BigEndian::read_u16(&block[6..8]) as usize;
+        if header_size + num_records * 8 > block.len() {
+            return Err(invalid("invalid XFS free space B+tree leaf"));
+        }
+        for i in 0..num_records {
+            let record = &block[header_size + i * 8..header_size + (i + 1) * 8];
+            let start = BigEndian::read_u32(&record[0..4]) as u64;
+            let count = BigEndian::read_u32(&record[4..8]) as u64;
+            if start + count > ag_length {
+                return Err(invalid("XFS free extent past the end of the AG"));
+            }
+            free.add(block_range(start, count));
+        }
+        agbno = BigEndian::read_u32(&block[12..16]);
+    }
+
+    // Blocks on the free list are reserved for B+tree growth and hold no data
+    let mut ranges = free.ranges;
+    if fl_count > 0 {
+        let mut agfl = vec![0u8; sb.sector_size as usize];
+        read_at(reader, ag_start + 3 * sb.sector_size, &mut agfl)?;
+        let entries = if sb.v5 {
+            if &agfl[0..4] != b"XAFL" {
+                return Err(invalid("invalid XFS AGFL"));
+            }
+            &agfl[36..]
+        } else {
+            &agfl[..]
+        };
+        let size = entries.len() / 4;
+        for i in 0..fl_count.min(size) {
+            let index = (fl_first + i) % size;
+            let agbno = BigEndian::read_u32(&entries[index * 4..index * 4 + 4]) as u64;
+            if agbno < ag_length {
+                ranges.push(block_range(agbno, 1));
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const REGION_START: u64 = 1 << 16;
+    const BLOCK_SIZE: u64 = 4096;
+
+    /// A disk with a version 4 XFS filesystem at `REGION_START`, of 2
+    /// allocation groups of 16 blocks, the first with blocks 12 to 15 free
+    /// and the second with blocks 8 to 15 free, followed by another
+    /// partition.
+    fn disk(data_blocks: u64, ag_count: u32, ag_length: u32) -> Vec<u8> {
+        let mut disk = vec![0xAAu8; 4 << 16];
+        let fs = &mut disk[REGION_START as usize..];
+        fs[..512].fill(0);
+        fs[0..4].copy_from_slice(b"XFSB");
+        (&mut fs[4..8]).write_u32::<BigEndian>(BLOCK_SIZE as u32).unwrap();
+        (&mut fs[8..16]).write_u64::<BigEndian>(data_blocks).unwrap();
+        (&mut fs[84..88]).write_u32::<BigEndian>(16).unwrap();
+        (&mut fs[88..92]).write_u32::<BigEndian>(ag_count).unwrap();
+        (&mut fs[100..102]).write_u16::<BigEndian>(4).unwrap();
+        (&mut fs[102..104]).write_u16::<BigEndian>(512).unwrap();
+
+        for (ag, first_free) in [(0, 12u32), (1, 8)] {
+            let ag = &mut fs[ag * 16 * BLOCK_SIZE as usize..];
+            // AGF, with the B+tree root in block 1, and an empty free list
+            let agf = &mut ag[512..1024];
+            agf.fill(0);
+            agf[0..4].copy_from_slice(b"XAGF");
+            (&mut agf[12..16]).write_u32::<BigEndian>(ag_length).unwrap();
+            (&mut agf[16..20]).write_u32::<BigEndian>(1).unwrap();
+            (&mut agf[28..32]).write_u32::<BigEndian>(1).unwrap();
+            // Single leaf
+            let leaf = &mut ag[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize];
+            leaf.fill(0);
+            leaf[0..4].copy_from_slice(b"ABTB");
+            (&mut leaf[6..8]).write_u16::<BigEndian>(1).unwrap();
+            (&mut leaf[8..12]).write_u32::<BigEndian>(NULL_BLOCK).unwrap();
+            (&mut leaf[12..16]).write_u32::<BigEndian>(NULL_BLOCK).unwrap();
+            (&mut leaf[16..20]).write_u32::<BigEndian>(first_free).unwrap();
+            (&mut leaf[20..24]).write_u32::<BigEndian>(16 - first_free).unwrap();
+        }
+        disk
+    }
+
+    #[test]
+    fn free_at_end_of_region() {
+        let mut reader = Cursor::new(disk(32, 2, 16));
+        let region = REGION_START..REGION_START + 32 * BLOCK_SIZE;
+        let free = free_ranges(&mut reader, region.clone()).unwrap().unwrap();
+        assert_eq!(free, vec![
+            REGION_START + 12 * BLOCK_SIZE..REGION_START + 16 * BLOCK_SIZE,
+            REGION_START + 24 * BLOCK_SIZE..region.end,
+        ]);
+    }
+
+    #[test]
+    fn filesystem_larger_than_region() {
+        let mut reader = Cursor::new(disk(32, 2, 16));
+        let region = REGION_START..REGION_START + 20 * BLOCK_SIZE;
+        assert!(free_ranges(&mut reader, region).is_err());
+    }
+
+    #[test]
+    fn corrupt_counts() {
+        let region = REGION_START..REGION_START + 32 * BLOCK_SIZE;
+        // More allocation groups than blocks
+        let mut reader = Cursor::new(disk(32, 1000, 16));
+        assert!(free_ranges(&mut reader, region.clone()).is_err());
+        // Overflowing filesystem size
+        let mut reader = Cursor::new(disk(u64::MAX / 2, 2, 16));
+        assert!(free_ranges(&mut reader, region.clone()).is_err());
+        // Allocation group longer than in the superblock
+        let mut reader = Cursor::new(disk(32, 2, 1000));
+        assert!(free_ranges(&mut reader, region).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Freezing mounted filesystems with the FIFREEZE ioctl, so the device
+//! under them is consistent while it is read.
+
+use std::path::{Path, PathBuf};
+
+#[cfg(target_os = "linux")]
+nix::ioctl_readwrite!(ioctl_fifreeze, b'X', 119, nix::libc::c_int); // Defined in linux/fs.h
+#[cfg(target_os = "linux")]
+nix::ioctl_readwrite!(ioctl_fithaw, b'X', 120, nix::libc::c_int);
+
+/// A frozen filesystem, thawed on drop.
+///
+/// Writes to the filesystem block until it is thawed, so the output must
+/// not be written to it.
+pub struct FrozenFilesystem {
+    #[cfg(target_os = "linux")]
+    directory: std::fs::File,
+    mountpoint: PathBuf,
+}
+
+impl FrozenFilesystem {
+    #[cfg(target_os = "linux")]
+    pub fn freeze(mountpoint: &Path) -> std::io::Result<FrozenFilesystem> {
+        use std::os::unix::io::AsRawFd;
+
+        let directory = std::fs::File::open(mountpoint)?;
+        let mut arg = 0;
+        unsafe {
+            ioctl_fifreeze(directory.as_raw_fd(), &mut arg)?;
+        }
+        Ok(FrozenFilesystem {
+            directory,
+            mountpoint: mountpoint.to_owned(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn freeze(_mountpoint: &Path) -> std::io::Result<FrozenFilesystem> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "freezing filesystems is only supported on Linux",
+        ))
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+}
+
+impl Drop for FrozenFilesystem {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            use crate::log::message;
+
+            let mut arg = 0;
+            let result = unsafe { ioctl_fithaw(self.directory.as_raw_fd(), &mut arg) };
+            if result.is_err() {
+                message!("Warning: failed to thaw {}", self.mountpoint.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Uploading the image to OpenStack Glance as it is written (`--upload
+//! glance://NAME`), without staging it on disk.
+//!
+//! The image record is created with the `openstack` CLI, and the data is
+//! streamed to `glance image-upload`, so credentials and endpoints are picked
+//! up from the usual `OS_*` variables or clouds.yaml.
+//!
+//! The image is created with its SHA-512 as the `sha512` property, which
+//! takes hashing it before the upload; if the input can only be read once,
+//! the property is set after the upload instead. Glance computes the
+//! checksums of the data itself; they are checked against the image that was
+//! sent.
+
+use sha2::{Digest, Sha512};
+use std::io::Write;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::log::message;
+
+/// An image being uploaded to Glance.
+pub struct Upload {
+    image_id: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    /// Hash of the data sent, to compare with the one Glance computes
+    hasher: Sha512,
+    /// Hash the image was created with, if it was hashed before the upload
+    sha512: Option<String>,
+    finished: bool,
+}
+
+/// Hash the image written by `write`, as the image is created with its hash.
+pub fn hash<F: FnOnce(&mut dyn Write) -> std::io::Result<()>>(write: F) -> std::io::Result<String> {
+    let mut hasher = Sha512::new();
+    write(&mut hasher)?;
+    Ok(hex(hasher))
+}
+
+fn hex(hasher: Sha512) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Upload {
+    /// Create a qcow2 image record with this name and hash, if known, and
+    /// start uploading its data, which should then be written to the upload.
+    pub fn start(name: &str, sha512: Option<String>) -> std::io::Result<Upload> {
+        use serde::Deserialize;
+
+        #[derive(Deserialize)]
+        struct Image {
+            id: String,
+        }
+
+        let mut command = Command::new("openstack");
+        command
+            .arg("image").arg("create")
+            .arg("--disk-format").arg("qcow2")
+            .arg("--container-format").arg("bare")
+            .arg("--format").arg("json");
+        if let Some(sha512) = &sha512 {
+            command.arg("--property").arg(format!("sha512={}", sha512));
+        }
+        command.arg("--").arg(name);
+        let image: Image = serde_json::from_slice(&run(command)?)?;
+        message!("Created Glance image {}", image.id);
+
+        let child = Command::new("glance")
+            .arg("image-upload")
+            .arg(&image.id)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn();
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => {
+                delete_image(&image.id);
+                return Err(e);
+            }
+        };
+        let stdin = child.stdin.take();
+        Ok(Upload {
+            image_id: image.id,
+            child,
+            stdin,
+            hasher: Sha512::new(),
+            sha512,
+            finished: false,
+        })
+    }
+
+    /// Wait for the upload to complete and check the checksum Glance
+    /// computed, returning the image ID.
+    pub fn finish(mut self) -> std::io::Result<String> {
+        use serde::Deserialize;
+
+        #[derive(Deserialize)]
+        struct Image {
+            status: String,
+            os_hash_algo: Option<String>,
+            os_hash_value: Option<String>,
+        }
+
+        // Close the pipe so glance sees the end of the data
+        self.stdin = None;
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("glance command failed ({})", status)));
+        }
+
+        let mut command = Command::new("openstack");
+        command
+            .arg("image").arg("show")
+            .arg("--format").arg("json")
+            .arg(&self.image_id);
+        let image: Image = serde_json::from_slice(&run(command)?)?;
+        if image.status != "active" {
+            return Err(std::io::Error::other(format!("image is {} after upload", image.status)));
+        }
+        let digest = hex(std::mem::take(&mut self.hasher));
+        match &self.sha512 {
+            Some(sha512) if *sha512 != digest => {
+                return Err(std::io::Error::other(
+                    "the image sent doesn't match the hash it was created with, the input changed during the upload",
+                ));
+            }
+            Some(_) => {}
+            None => {
+                let mut command = Command::new("openstack");
+                command
+                    .arg("image").arg("set")
+                    .arg("--property").arg(format!("sha512={}", digest))
+                    .arg("--").arg(&self.image_id);
+                run(command)?;
+            }
+        }
+        match (image.os_hash_algo.as_deref(), image.os_hash_value) {
+            (Some("sha512"), Some(value)) if value != digest => {
+                return Err(std::io::Error::other("checksum computed by Glance doesn't match the image sent"));
+            }
+            (Some("sha512"), Some(_)) => message!("Glance checksum matches: sha512 {}", digest),
+            _ => message!("Image sha512 is {}", digest),
+        }
+
+        self.finished = true;
+        Ok(std::mem::take(&mut self.image_id))
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(stdin) = &mut self.stdin else {
+            return Err(std::io::Error::other("upload is finished"));
+        };
+        let written = stdin.write(buf).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("writing to glance failed: {}", e))
+        })?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Don't leave an incomplete image behind
+        if !self.finished {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            delete_image(&self.image_id);
+        }
+    }
+}
+
+fn delete_image(image_id: &str) {
+    message!("Deleting Glance image {}", image_id);
+    let mut command = Command::new("openstack");
+    command.arg("image").arg("delete").arg(image_id);
+    if run(command).is_err() {
+        message!("Warning: failed to delete Glance image {}", image_id);
+    }
+}
+
+fn run(mut command: Command) -> std::io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "OpenStack CLI command failed ({})",
+            output.status,
+        )));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,109 @@
+//! Finding the space filesystems don't use with libguestfs (`--guestfs`),
+//! for the filesystems that aren't read natively.
+//!
+//! The input is never written: a temporary qcow2 overlay is created on top
+//! of it, and `virt-sparsify --in-place` mounts each filesystem it finds in
+//! the libguestfs appliance and trims its free space, which leaves zero
+//! clusters in the overlay. `qemu-img map` then lists them as the free
+//! ranges of the disk.
+
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::input::Input;
+use crate::layout::{self, Extent, ExtentSource};
+use crate::log::message;
+
+/// The space libguestfs found unused, as an extent source.
+pub struct FreeSpace<'a> {
+    pub input_path: &'a OsStr,
+    /// Format of the input, as QEMU names it
+    pub format: &'static str,
+}
+
+/// Overlay image, removed on drop.
+struct Overlay(PathBuf);
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn run(command: &mut Command, program: &str) -> std::io::Result<Vec<u8>> {
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("can't run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("{} failed ({})", program, output.status)));
+    }
+    Ok(output.stdout)
+}
+
+/// Ranges that read as zeros from the overlay itself rather than from the
+/// input, as `qemu-img map` reports them.
+fn zero_ranges(overlay: &Path) -> std::io::Result<Vec<Range<u64>>> {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Entry {
+        start: u64,
+        length: u64,
+        depth: u32,
+        zero: bool,
+    }
+
+    let output = run(
+        Command::new("qemu-img").arg("map").arg("--output=json").arg("-f").arg("qcow2").arg(overlay),
+        "qemu-img",
+    )?;
+    let entries: Vec<Entry> = serde_json::from_slice(&output)?;
+    Ok(entries.into_iter()
+        .filter(|e| e.depth == 0 && e.zero)
+        .map(|e| e.start..e.start + e.length)
+        .collect())
+}
+
+impl ExtentSource for FreeSpace<'_> {
+    fn name(&self) -> &'static str {
+        "libguestfs"
+    }
+
+    fn phase(&self) -> Option<&'static str> {
+        Some("Inspecting filesystems with libguestfs")
+    }
+
+    fn extents(&mut self, _input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        // The overlay refers to the input by absolute path
+        let input_path = std::fs::canonicalize(self.input_path)?;
+        let overlay = Overlay(std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.overlay.qcow2", std::process::id())));
+        let mut backing = std::ffi::OsString::from("backing_file=");
+        backing.push(input_path.as_os_str());
+        backing.push(format!(",backing_fmt={}", self.format));
+        run(
+            Command::new("qemu-img")
+                .arg("create").arg("-q")
+                .arg("-f").arg("qcow2")
+                .arg("-o").arg(backing)
+                .arg(&overlay.0),
+            "qemu-img",
+        )?;
+
+        run(
+            Command::new("virt-sparsify")
+                .arg("--in-place").arg("--quiet")
+                .arg("--format").arg("qcow2")
+                .arg(&overlay.0),
+            "virt-sparsify",
+        )?;
+
+        let free = zero_ranges(&overlay.0)?;
+        let free_bytes: u64 = free.iter().map(|r| r.end - r.start).sum();
+        message!("libguestfs found {} bytes unused by filesystems", free_bytes);
+        Ok(layout::free_extents(free))
+    }
+}
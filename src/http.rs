@@ -0,0 +1,238 @@
+//! Uploading the image to an arbitrary HTTP(S) URL as it is written
+//! (`--upload-url`), for image registries and internal services that take a
+//! disk image as the body of a request.
+//!
+//! The image is streamed to `curl` as the body of a PUT or POST request, sent
+//! with chunked transfer encoding since its data is generated as it goes.
+//! Headers and credentials are passed to curl in a config file only readable
+//! by the user rather than on its command line, where other users could see
+//! them; curl also reads `~/.curlrc` and `~/.netrc`.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::log::message;
+
+#[cfg(windows)]
+const NULL_DEVICE: &str = "NUL";
+#[cfg(not(windows))]
+const NULL_DEVICE: &str = "/dev/null";
+
+/// Method of the upload request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Put,
+    Post,
+}
+
+impl Method {
+    pub fn parse(s: &str) -> Option<Method> {
+        match s.to_ascii_uppercase().as_str() {
+            "PUT" => Some(Method::Put),
+            "POST" => Some(Method::Post),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Put => "PUT",
+            Method::Post => "POST",
+        }
+    }
+}
+
+/// Resolve a Location header against the URL of the request.
+pub fn resolve(url: &str, location: &str) -> String {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return location.to_owned();
+    }
+    let authority_end = url.find("://")
+        .and_then(|i| url[i + 3..].find('/').map(|j| i + 3 + j))
+        .unwrap_or(url.len());
+    if location.starts_with('/') {
+        format!("{}{}", &url[..authority_end], location)
+    } else {
+        let directory_end = url.rfind('/').filter(|&i| i >= authority_end).map_or(url.len(), |i| i + 1);
+        let separator = if directory_end == url.len() && !url.ends_with('/') { "/" } else { "" };
+        format!("{}{}{}", &url[..directory_end], separator, location)
+    }
+}
+
+/// An image being uploaded in the body of a request.
+pub struct Upload {
+    url: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    finished: bool,
+    _config: ConfigFile,
+}
+
+impl Upload {
+    /// Start the request, whose body should then be written to the upload.
+    ///
+    /// `headers` are given to curl as is, so they can be `Name: value` or
+    /// `@FILE`, and `user` is `USER[:PASSWORD]`.
+    pub fn start(url: &str, method: Method, headers: &[String], user: Option<&str>) -> std::io::Result<Upload> {
+        let mut command = Command::new("curl");
+        command
+            .arg("--silent").arg("--show-error")
+            .arg("--netrc-optional")
+            .arg("--dump-header").arg("-")
+            .arg("--output").arg(NULL_DEVICE)
+            .arg("--upload-file").arg("-")
+            .arg("--request").arg(method.name());
+        let content_type = headers.iter()
+            .any(|h| h.split_once(':').is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("content-type")));
+        if !content_type {
+            command.arg("--header").arg("Content-Type: application/octet-stream");
+        }
+        let mut options = Vec::new();
+        for header in headers {
+            options.push(("header", header.as_str()));
+        }
+        if let Some(user) = user {
+            options.push(("user", user));
+        }
+        let config = ConfigFile::create(&options)?;
+        let mut child = command
+            .arg("--config").arg(&config.path)
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| std::io::Error::new(e.kind(), format!("can't run curl: {}", e)))?;
+        message!("Uploading to {}", url);
+        let stdin = child.stdin.take();
+        Ok(Upload {
+            url: url.to_owned(),
+            child,
+            stdin,
+            finished: false,
+            _config: config,
+        })
+    }
+
+    /// Wait for the response, returning its Location if it has one, else the
+    /// URL the image was sent to.
+    pub fn finish(mut self) -> std::io::Result<String> {
+        // Close the pipe so curl sends the end of the body
+        self.stdin = None;
+        let mut headers = Vec::new();
+        if let Some(mut stdout) = self.child.stdout.take() {
+            stdout.read_to_end(&mut headers)?;
+        }
+        let status = self.child.wait()?;
+        self.finished = true;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("curl failed ({})", status)));
+        }
+
+        // Keep the last response, after any 100 Continue
+        let text = String::from_utf8_lossy(&headers);
+        let mut response = None;
+        for line in text.lines().map(str::trim_end) {
+            if line.starts_with("HTTP/") {
+                let status: Option<u16> = line.split_whitespace().nth(1).and_then(|s| s.parse().ok());
+                response = status.map(|status| (status, None));
+            } else if let (Some((_, location)), Some((name, value))) = (&mut response, line.split_once(':')) {
+                if name.trim().eq_ignore_ascii_case("location") {
+                    *location = Some(value.trim().to_owned());
+                }
+            }
+        }
+        match response {
+            Some((200..=299, Some(location))) => Ok(resolve(&self.url, &location)),
+            Some((200..=299, None)) => Ok(std::mem::take(&mut self.url)),
+            Some((status, _)) => Err(std::io::Error::other(format!("server returned status {}", status))),
+            None => Err(std::io::Error::other("no HTTP response from server")),
+        }
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(stdin) = &mut self.stdin else {
+            return Err(std::io::Error::other("upload is finished"));
+        };
+        stdin.write(buf).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("writing to curl failed: {}", e))
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Kill curl before closing the pipe, so it doesn't end the body as if
+        // the image was complete
+        if !self.finished {
+            message!("Aborting upload to {}", self.url);
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Config file giving curl the options that can hold secrets, removed on drop.
+struct ConfigFile {
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    fn create(options: &[(&str, &str)]) -> std::io::Result<ConfigFile> {
+        let mut open_options = OpenOptions::new();
+        open_options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+
+        let mut attempt = 0;
+        let (path, mut file) = loop {
+            let path = std::env::temp_dir().join(format!(
+                "streaming-qcow2-writer-{}-{}.curlrc",
+                std::process::id(),
+                attempt,
+            ));
+            match open_options.open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let config = ConfigFile { path };
+
+        let mut contents = String::new();
+        for (name, value) in options {
+            contents.push_str(name);
+            contents.push_str(" = \"");
+            for c in value.chars() {
+                match c {
+                    '\\' => contents.push_str("\\\\"),
+                    '"' => contents.push_str("\\\""),
+                    '\t' => contents.push_str("\\t"),
+                    '\n' => contents.push_str("\\n"),
+                    '\r' => contents.push_str("\\r"),
+                    c => contents.push(c),
+                }
+            }
+            contents.push_str("\"\n");
+        }
+        file.write_all(contents.as_bytes())?;
+        Ok(config)
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
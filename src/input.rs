@@ -0,0 +1,424 @@
+//! Input sources: local files and block devices, NBD exports, and disk
+//! image formats.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::{nbd, ntfsclone, qcow2, readahead, throttle, vhd, vhdx, vmdk};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Raw,
+    Vmdk,
+    Vhd,
+    Vhdx,
+    Qcow2,
+    Ntfsclone,
+}
+
+impl InputFormat {
+    pub fn parse(s: &str) -> Option<InputFormat> {
+        match s {
+            "raw" => Some(InputFormat::Raw),
+            "vmdk" => Some(InputFormat::Vmdk),
+            "vhd" => Some(InputFormat::Vhd),
+            "vhdx" => Some(InputFormat::Vhdx),
+            "qcow2" => Some(InputFormat::Qcow2),
+            "ntfsclone" => Some(InputFormat::Ntfsclone),
+            _ => None,
+        }
+    }
+
+    /// Name of the format in QEMU, if it can read it.
+    pub fn qemu_name(&self) -> Option<&'static str> {
+        match self {
+            InputFormat::Raw => Some("raw"),
+            InputFormat::Vmdk => Some("vmdk"),
+            InputFormat::Vhd => Some("vpc"),
+            InputFormat::Vhdx => Some("vhdx"),
+            InputFormat::Qcow2 => Some("qcow2"),
+            InputFormat::Ntfsclone => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputFormat::Raw => "raw",
+            InputFormat::Vmdk => "vmdk",
+            InputFormat::Vhd => "vhd",
+            InputFormat::Vhdx => "vhdx",
+            InputFormat::Qcow2 => "qcow2",
+            InputFormat::Ntfsclone => "ntfsclone",
+        }
+    }
+}
+
+/// Detect the format of an image from its magic bytes, raw if it has none.
+///
+/// VHD files are recognized by their footer, in the last sector (dynamic
+/// disks also have a copy of it at the start). The reader is left at the
+/// start.
+pub fn detect_format<R: Read + Seek>(mut reader: R) -> std::io::Result<InputFormat> {
+    let mut header = [0u8; 512];
+    reader.seek(SeekFrom::Start(0))?;
+    let read = qcow2::read_full(&mut reader, &mut header)?;
+    let header = &header[..read];
+    let format = if qcow2::reader::is_qcow2(header) {
+        InputFormat::Qcow2
+    } else if vmdk::is_vmdk(header) {
+        InputFormat::Vmdk
+    } else if vhdx::is_vhdx(header) {
+        InputFormat::Vhdx
+    } else if ntfsclone::is_ntfsclone(header) {
+        InputFormat::Ntfsclone
+    } else if vhd::is_vhd_footer(header) {
+        InputFormat::Vhd
+    } else {
+        let mut footer = [0u8; 512];
+        let size = reader.seek(SeekFrom::End(0))?;
+        if size >= footer.len() as u64 {
+            reader.seek(SeekFrom::Start(size - footer.len() as u64))?;
+            qcow2::read_full(&mut reader, &mut footer)?;
+        }
+        if vhd::is_vhd_footer(&footer) { InputFormat::Vhd } else { InputFormat::Raw }
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(format)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const BLKGETSIZE64_CODE: u8 = 0x12; // Defined in linux/fs.h
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const BLKGETSIZE64_SEQ: u8 = 114;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+nix::ioctl_read!(ioctl_blkgetsize64, BLKGETSIZE64_CODE, BLKGETSIZE64_SEQ, u64);
+
+// Defined in sys/disk.h
+#[cfg(target_os = "macos")]
+nix::ioctl_read!(ioctl_dkiocgetblocksize, b'd', 24, u32);
+#[cfg(target_os = "macos")]
+nix::ioctl_read!(ioctl_dkiocgetblockcount, b'd', 25, u64);
+
+/// Get the size of a disk or volume opened as `\\.\PhysicalDriveN` or
+/// `\\.\C:`, or `None` if the file is not one.
+#[cfg(windows)]
+fn get_disk_length(file: &std::fs::File) -> Option<u64> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Ioctl::{GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let mut info = GET_LENGTH_INFORMATION { Length: 0 };
+    let mut returned = 0u32;
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            IOCTL_DISK_GET_LENGTH_INFO,
+            std::ptr::null(),
+            0,
+            &mut info as *mut GET_LENGTH_INFORMATION as *mut _,
+            std::mem::size_of::<GET_LENGTH_INFORMATION>() as u32,
+            &mut returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return None;
+    }
+    Some(info.Length as u64)
+}
+
+pub fn get_file_size(file: &std::fs::File) -> std::io::Result<u64> {
+    #[cfg(windows)]
+    if let Some(size) = get_disk_length(file) {
+        return Ok(size);
+    }
+
+    let metadata = file.metadata()?;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::io::AsRawFd;
+
+        if metadata.file_type().is_block_device() {
+            let fd = file.as_raw_fd();
+            let mut cap = 0u64;
+            let cap_ptr = &mut cap as *mut u64;
+            unsafe {
+                ioctl_blkgetsize64(fd, cap_ptr)?;
+            }
+
+            return Ok(cap);
+        }
+    }
+
+    // Disks are both block devices (/dev/diskN) and character devices
+    // (/dev/rdiskN, which is faster to read)
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::io::AsRawFd;
+
+        let file_type = metadata.file_type();
+        if file_type.is_block_device() || file_type.is_char_device() {
+            let fd = file.as_raw_fd();
+            let mut block_size = 0u32;
+            let mut block_count = 0u64;
+            unsafe {
+                ioctl_dkiocgetblocksize(fd, &mut block_size)?;
+                ioctl_dkiocgetblockcount(fd, &mut block_count)?;
+            }
+
+            return Ok(block_size as u64 * block_count);
+        }
+    }
+
+    if !metadata.file_type().is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "input is not a file",
+        ));
+    }
+
+    Ok(metadata.len())
+}
+
+/// Check whether the input is read as a stream of unknown size: `-` for
+/// stdin, or a pipe.
+pub fn is_stream(path: &std::ffi::OsStr) -> bool {
+    if path == "-" {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo() || m.file_type().is_socket())
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().starts_with(r"\\.\pipe\")
+    }
+}
+
+/// Compute the new position for a seek in a source of a given size.
+pub fn seek_position(position: u64, size: u64, pos: SeekFrom) -> std::io::Result<u64> {
+    let position = match pos {
+        SeekFrom::Start(p) => Some(p),
+        SeekFrom::End(d) => size.checked_add_signed(d),
+        SeekFrom::Current(d) => position.checked_add_signed(d),
+    };
+    position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek"))
+}
+
+/// A stream of known size, such as a pipe given `--input-size`, read in a
+/// single pass: seeking forward skips the data, seeking back fails.
+pub struct ForwardReader {
+    stream: Box<dyn Read + Send>,
+    position: u64,
+    size: u64,
+}
+
+impl ForwardReader {
+    pub fn new(stream: Box<dyn Read + Send>, size: u64) -> ForwardReader {
+        ForwardReader { stream, position: 0, size }
+    }
+}
+
+impl Read for ForwardReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ForwardReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = seek_position(self.position, self.size, pos)?;
+        if position < self.position {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "can't seek back in a stream, the options used need to read the input out of order",
+            ));
+        }
+        // Past the end of the stream, reads return nothing
+        std::io::copy(&mut (&mut self.stream).take(position - self.position), &mut std::io::sink())?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Part of a local file read as a file of its own, such as an archive member
+/// or a whole disk image.
+pub struct FileRange {
+    file: std::fs::File,
+    start: u64,
+    size: u64,
+    position: u64,
+}
+
+impl FileRange {
+    pub fn new(file: std::fs::File, start: u64, size: u64) -> FileRange {
+        FileRange { file, start, size, position: 0 }
+    }
+
+    pub fn whole(file: std::fs::File) -> std::io::Result<FileRange> {
+        let size = get_file_size(&file)?;
+        Ok(FileRange::new(file, 0, size))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for FileRange {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.size.saturating_sub(self.position).min(buf.len() as u64) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+        self.file.seek(SeekFrom::Start(self.start + self.position))?;
+        let read = self.file.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileRange {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Error reading the input, to tell it apart from an error writing the
+/// output when both happen in the same copy.
+#[derive(Debug)]
+pub struct ReadError(std::io::Error);
+
+impl ReadError {
+    pub fn is_read_error(error: &std::io::Error) -> bool {
+        error.get_ref().is_some_and(|e| e.is::<ReadError>())
+    }
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Input whose errors are marked as [`ReadError`].
+pub struct MarkReadErrors<R>(pub R);
+
+fn mark_read_error(error: std::io::Error) -> std::io::Error {
+    std::io::Error::new(error.kind(), ReadError(error))
+}
+
+impl<R: Read> Read for MarkReadErrors<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf).map_err(mark_read_error)
+    }
+}
+
+impl<R: Seek> Seek for MarkReadErrors<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos).map_err(mark_read_error)
+    }
+}
+
+pub enum Input {
+    File(std::fs::File),
+    Stream(ForwardReader),
+    /// Member of an archive
+    FileRange(FileRange),
+    Nbd(nbd::Client),
+    Vmdk(vmdk::Reader<FileRange>),
+    Vhd(vhd::Reader<FileRange>),
+    Vhdx(vhdx::Reader<FileRange>),
+    Qcow2(qcow2::reader::Reader<FileRange>),
+    Ntfsclone(ntfsclone::Reader<FileRange>),
+    /// Local file read ahead from several threads
+    ReadAhead(readahead::ReadAhead),
+}
+
+impl Input {
+    /// Get the underlying raw file, for operations that need a local file.
+    pub fn as_file(&self) -> Option<&std::fs::File> {
+        match self {
+            Input::File(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Format of the image read, raw for the sources that are not images.
+    pub fn format(&self) -> InputFormat {
+        match self {
+            Input::File(_) | Input::FileRange(_) | Input::Stream(_) | Input::Nbd(_) | Input::ReadAhead(_) => InputFormat::Raw,
+            Input::Vmdk(_) => InputFormat::Vmdk,
+            Input::Vhd(_) => InputFormat::Vhd,
+            Input::Vhdx(_) => InputFormat::Vhdx,
+            Input::Qcow2(_) => InputFormat::Qcow2,
+            Input::Ntfsclone(_) => InputFormat::Ntfsclone,
+        }
+    }
+
+    /// Get the ranges holding data, if the source knows them.
+    pub fn data_extents(&mut self) -> std::io::Result<Option<Vec<Range<u64>>>> {
+        match self {
+            Input::File(_) | Input::FileRange(_) | Input::Stream(_) | Input::ReadAhead(_) => Ok(None),
+            Input::Nbd(c) => c.data_extents(),
+            Input::Vmdk(r) => r.data_extents().map(Some),
+            Input::Vhd(r) => Ok(Some(r.data_extents())),
+            Input::Vhdx(r) => Ok(Some(r.data_extents())),
+            Input::Qcow2(r) => r.data_extents().map(Some),
+            Input::Ntfsclone(r) => Ok(Some(r.data_extents())),
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // The read-ahead threads wait for their own requests
+        if !matches!(self, Input::ReadAhead(_)) {
+            throttle::wait_for_read();
+        }
+        match self {
+            Input::File(f) => f.read(buf),
+            Input::FileRange(r) => r.read(buf),
+            Input::Stream(s) => s.read(buf),
+            Input::Nbd(c) => c.read(buf),
+            Input::Vmdk(r) => r.read(buf),
+            Input::Vhd(r) => r.read(buf),
+            Input::Vhdx(r) => r.read(buf),
+            Input::Qcow2(r) => r.read(buf),
+            Input::Ntfsclone(r) => r.read(buf),
+            Input::ReadAhead(r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Input::File(f) => f.seek(pos),
+            Input::FileRange(r) => r.seek(pos),
+            Input::Stream(s) => s.seek(pos),
+            Input::Nbd(c) => c.seek(pos),
+            Input::Vmdk(r) => r.seek(pos),
+            Input::Vhd(r) => r.seek(pos),
+            Input::Vhdx(r) => r.seek(pos),
+            Input::Qcow2(r) => r.seek(pos),
+            Input::Ntfsclone(r) => r.seek(pos),
+            Input::ReadAhead(r) => r.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,1193 @@
+//! Operations on layouts, lists of byte ranges of the input holding data.
+
+use std::ffi::OsString;
+use std::io::{BufRead, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::input::Input;
+use crate::log::message;
+use crate::partclone;
+
+/// Entries aligned to sectors are valid, and rounded out to the alignment
+/// without a warning.
+const SECTOR_SIZE: u64 = 512;
+
+/// Format of a layout file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LayoutFormat {
+    Json,
+    /// Lines of `offset,length`
+    Csv,
+    /// GNU ddrescue mapfile, of which only the finished blocks are used
+    Ddrescue,
+    /// Used-block bitmap in the header of a partclone image
+    Partclone,
+}
+
+impl LayoutFormat {
+    pub fn parse(s: &str) -> Option<LayoutFormat> {
+        match s {
+            "json" => Some(LayoutFormat::Json),
+            "csv" => Some(LayoutFormat::Csv),
+            "ddrescue" => Some(LayoutFormat::Ddrescue),
+            "partclone" => Some(LayoutFormat::Partclone),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with layout entries past the end of the input.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRange {
+    /// Truncate them to the input, without a warning
+    Clamp,
+    /// Fail, reporting the first one
+    Error,
+    /// Make the virtual disk large enough to hold them, the space past the
+    /// input being unallocated
+    Extend,
+}
+
+impl OutOfRange {
+    pub fn parse(s: &str) -> Option<OutOfRange> {
+        match s {
+            "clamp" => Some(OutOfRange::Clamp),
+            "error" => Some(OutOfRange::Error),
+            "extend" => Some(OutOfRange::Extend),
+            _ => None,
+        }
+    }
+}
+
+/// Read a layout in the given format.
+pub fn read<R: BufRead>(reader: R, format: LayoutFormat) -> std::io::Result<Vec<Range<u64>>> {
+    match format {
+        LayoutFormat::Json => read_json(reader),
+        LayoutFormat::Csv => read_csv(reader),
+        LayoutFormat::Ddrescue => read_ddrescue(reader),
+        LayoutFormat::Partclone => partclone::read_layout(reader),
+    }
+}
+
+/// Read the extents of a layout in the given format.
+///
+/// Only JSON layouts can have entries other than data; in other formats,
+/// every entry is data.
+pub fn read_extents<R: BufRead>(reader: R, format: LayoutFormat) -> std::io::Result<Vec<Extent>> {
+    match format {
+        LayoutFormat::Json => read_json_extents(reader),
+        _ => Ok(read(reader, format)?.into_iter().map(|range| Extent { range, kind: ExtentKind::Data }).collect()),
+    }
+}
+
+/// What a range of the disk holds, according to an extent source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtentKind {
+    Data,
+    /// Reads as zeros, recorded as zero clusters without reading the input
+    Zero,
+    /// Unused, to leave out of the image
+    Free,
+}
+
+/// A range of the disk and what it holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub range: Range<u64>,
+    pub kind: ExtentKind,
+}
+
+/// Something that knows what the parts of the disk hold, such as a layout
+/// file, the holes of the input file, or the filesystems on the disk.
+///
+/// Sources are applied to the layout one after the other, each one leaving
+/// out the extents it found to be free, so they can be combined in any order.
+pub trait ExtentSource {
+    /// What is read, for error messages
+    fn name(&self) -> &'static str;
+
+    /// Phase to show while the extents are found, if it can take a while
+    fn phase(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Find the extents, reading the input if needed.
+    fn extents(&mut self, input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>>;
+}
+
+/// Describe a disk of this size from the ranges holding data, the space
+/// between them being free.
+pub fn data_extents(ranges: Vec<Range<u64>>, size: u64) -> Box<dyn Iterator<Item = Extent>> {
+    let data = normalize(ranges);
+    let free = subtract(std::iter::once(0..size).collect(), data.clone());
+    let mut extents: Vec<Extent> = data.into_iter()
+        .map(|range| Extent { range, kind: ExtentKind::Data })
+        .chain(free.into_iter().map(|range| Extent { range, kind: ExtentKind::Free }))
+        .collect();
+    extents.sort_by_key(|e| e.range.start);
+    Box::new(extents.into_iter())
+}
+
+/// Describe the free ranges of a disk, what is around them being unknown.
+pub fn free_extents(ranges: Vec<Range<u64>>) -> Box<dyn Iterator<Item = Extent>> {
+    Box::new(ranges.into_iter().map(|range| Extent { range, kind: ExtentKind::Free }))
+}
+
+/// Describe a disk of this size from a layout of typed entries, the space
+/// between them being free.
+///
+/// The data entries are checked with `validate()`, and can go past the end
+/// of the disk with `OutOfRange::Extend`; the zero entries are clipped to the
+/// disk, and data wins where they overlap.
+pub fn typed_extents(
+    entries: Vec<Extent>,
+    size: u64,
+    alignment: u64,
+    strict: bool,
+    out_of_range: Option<OutOfRange>,
+) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+    let (data, other): (Vec<Extent>, Vec<Extent>) = entries.into_iter().partition(|e| e.kind == ExtentKind::Data);
+    let data = validate(data.into_iter().map(|e| e.range).collect(), size, alignment, strict, out_of_range)?;
+    let zero = other.into_iter().filter(|e| e.kind == ExtentKind::Zero).map(|e| e.range).collect();
+    let zero = subtract(intersect(zero, std::iter::once(0..size).collect()), data.clone());
+    let free = subtract(subtract(std::iter::once(0..size).collect(), data.clone()), zero.clone());
+    let mut extents: Vec<Extent> = data.into_iter()
+        .map(|range| Extent { range, kind: ExtentKind::Data })
+        .chain(zero.into_iter().map(|range| Extent { range, kind: ExtentKind::Zero }))
+        .chain(free.into_iter().map(|range| Extent { range, kind: ExtentKind::Free }))
+        .collect();
+    extents.sort_by_key(|e| e.range.start);
+    Ok(Box::new(extents.into_iter()))
+}
+
+/// Leave out of the layout the extents that were found to be free or zero.
+pub fn apply<I: Iterator<Item = Extent>>(layout: Vec<Range<u64>>, extents: I) -> Vec<Range<u64>> {
+    let free = extents.filter(|e| e.kind != ExtentKind::Data).map(|e| e.range).collect();
+    subtract(layout, free)
+}
+
+/// Layout file given by the user, read from stdin if `-`.
+pub struct LayoutFile {
+    pub path: PathBuf,
+    pub format: LayoutFormat,
+    pub input_size: u64,
+    /// Alignment the entries are checked against, see `validate()`
+    pub alignment: u64,
+    pub strict: bool,
+    /// What to do with entries past the end of the input, see `validate()`
+    pub out_of_range: Option<OutOfRange>,
+}
+
+impl ExtentSource for LayoutFile {
+    fn name(&self) -> &'static str {
+        "layout file"
+    }
+
+    fn extents(&mut self, _input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let entries = if self.path.as_os_str() == "-" {
+            read_extents(std::io::stdin().lock(), self.format)?
+        } else {
+            let file = std::fs::File::open(&self.path)?;
+            read_extents(std::io::BufReader::new(file), self.format)?
+        };
+        typed_extents(entries, self.input_size, self.alignment, self.strict, self.out_of_range)
+    }
+}
+
+/// Program given by the user that prints the layout, run with the shell.
+///
+/// It gets the path and size of the input in the environment, as
+/// `STREAMING_QCOW2_INPUT` and `STREAMING_QCOW2_INPUT_SIZE`.
+pub struct LayoutCommand {
+    pub command: String,
+    pub format: LayoutFormat,
+    pub input_path: OsString,
+    pub input_size: u64,
+    /// Alignment the entries are checked against, see `validate()`
+    pub alignment: u64,
+    pub strict: bool,
+    /// What to do with entries past the end of the input, see `validate()`
+    pub out_of_range: Option<OutOfRange>,
+}
+
+impl ExtentSource for LayoutCommand {
+    fn name(&self) -> &'static str {
+        "layout command"
+    }
+
+    fn extents(&mut self, _input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let mut command = shell(&self.command);
+        let output = command
+            .env("STREAMING_QCOW2_INPUT", &self.input_path)
+            .env("STREAMING_QCOW2_INPUT_SIZE", self.input_size.to_string())
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!("command failed ({})", output.status)));
+        }
+        let entries = read_extents(&output.stdout[..], self.format)?;
+        typed_extents(entries, self.input_size, self.alignment, self.strict, self.out_of_range)
+    }
+}
+
+/// Several layouts (files, commands) merged into one, the data of each being
+/// combined with a set operation.
+///
+/// Zero entries are merged the same way, with data winning where they
+/// overlap: with `Operation::Intersect`, a range is zero if all the layouts
+/// have it as data or zero, but not all as data.
+pub struct Merged<'a> {
+    pub sources: Vec<Box<dyn ExtentSource + 'a>>,
+    pub operation: Operation,
+}
+
+impl ExtentSource for Merged<'_> {
+    fn name(&self) -> &'static str {
+        "layouts"
+    }
+
+    fn extents(&mut self, input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let mut data = Vec::with_capacity(self.sources.len());
+        // The data and zero ranges of each layout
+        let mut known = Vec::with_capacity(self.sources.len());
+        // End of the disk they describe
+        let mut end = 0;
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            let extents: Vec<Extent> = source.extents(input)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{} {}: {}", source.name(), index + 1, e)))?
+                .collect();
+            end = extents.iter().map(|e| e.range.end).fold(end, u64::max);
+            data.push(extents.iter().filter(|e| e.kind == ExtentKind::Data).map(|e| e.range.clone()).collect());
+            known.push(extents.into_iter().filter(|e| e.kind != ExtentKind::Free).map(|e| e.range).collect());
+        }
+        let data = self.operation.combine(data).into_ranges();
+        // Data is known even if the layouts it is subtracted from had it as
+        // zero, as it wins over zero
+        let known = union(self.operation.combine(known).into_ranges(), data.clone());
+        let zero = subtract(known.clone(), data.clone());
+        let free = subtract(std::iter::once(0..end).collect(), known);
+        let mut extents: Vec<Extent> = data.into_iter()
+            .map(|range| Extent { range, kind: ExtentKind::Data })
+            .chain(zero.into_iter().map(|range| Extent { range, kind: ExtentKind::Zero }))
+            .chain(free.into_iter().map(|range| Extent { range, kind: ExtentKind::Free }))
+            .collect();
+        extents.sort_by_key(|e| e.range.start);
+        Ok(Box::new(extents.into_iter()))
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Read a layout in JSON format, as a list of objects with `offset` and
+/// `length` fields (this is the format of `rbd diff --format=json`).
+///
+/// Only the data entries are returned, see `read_json_extents()`.
+pub fn read_json<R: Read>(reader: R) -> std::io::Result<Vec<Range<u64>>> {
+    Ok(read_json_extents(reader)?.into_iter()
+        .filter(|e| e.kind == ExtentKind::Data)
+        .map(|e| e.range)
+        .collect())
+}
+
+/// Read a layout in JSON format, where entries can have a `type` field:
+/// `data` (the default) to copy, `zero` to record as zero clusters without
+/// reading the input, or `discard` to leave unallocated, like the space
+/// between entries.
+pub fn read_json_extents<R: Read>(reader: R) -> std::io::Result<Vec<Extent>> {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct LayoutEntry {
+        offset: u64,
+        length: u64,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+    }
+
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let entries: Vec<LayoutEntry> = serde_json::from_reader(reader)?;
+    entries.iter().enumerate().map(|(i, e)| {
+        let end = e.offset.checked_add(e.length).ok_or_else(|| invalid(format!(
+            "entry {} (offset {}, length {}) is past the maximum size", i, e.offset, e.length,
+        )))?;
+        let kind = match e.kind.as_deref() {
+            None | Some("data") => ExtentKind::Data,
+            Some("zero") => ExtentKind::Zero,
+            Some("discard") => ExtentKind::Free,
+            Some(other) => return Err(invalid(format!("entry {} has unknown type {:?}", i, other))),
+        };
+        Ok(Extent { range: e.offset..end, kind })
+    }).collect()
+}
+
+/// Read a layout in CSV format, as lines of `offset,length`, in decimal or
+/// hexadecimal with `0x`.
+///
+/// Blank lines are skipped, as is a first line of column names.
+pub fn read_csv<R: BufRead>(reader: R) -> std::io::Result<Vec<Range<u64>>> {
+    let invalid = |line: usize, message: &str| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: {}", line, message))
+    };
+    let mut ranges = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((offset, length)) = line.split_once(',') else {
+            return Err(invalid(i + 1, "expected offset,length"));
+        };
+        let (offset, length) = (offset.trim(), length.trim());
+        let parse = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        match (parse(offset), parse(length)) {
+            (Some(offset), Some(length)) => {
+                let end = offset.checked_add(length)
+                    .ok_or_else(|| invalid(i + 1, "entry is past the maximum size"))?;
+                ranges.push(offset..end);
+            }
+            // Header
+            _ if i == 0 && offset.eq_ignore_ascii_case("offset") => {}
+            _ => return Err(invalid(i + 1, "invalid number")),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Read the blocks a GNU ddrescue mapfile marks as finished (`+`), leaving
+/// out those that were not rescued.
+///
+/// After comments, the first line is the status of the rescue, and the
+/// others are blocks as `position size status`, in hexadecimal.
+pub fn read_ddrescue<R: BufRead>(reader: R) -> std::io::Result<Vec<Range<u64>>> {
+    let invalid = |line: usize, message: &str| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: {}", line, message))
+    };
+    let mut ranges = Vec::new();
+    let mut seen_status = false;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !seen_status {
+            seen_status = true;
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [position, size, status] = fields[..] else {
+            return Err(invalid(i + 1, "expected position, size and status"));
+        };
+        let (Some(position), Some(size)) = (parse_number(position), parse_number(size)) else {
+            return Err(invalid(i + 1, "invalid number"));
+        };
+        match status {
+            "+" => {
+                let end = position.checked_add(size)
+                    .ok_or_else(|| invalid(i + 1, "block is past the maximum size"))?;
+                ranges.push(position..end);
+            }
+            "?" | "*" | "/" | "-" => {}
+            _ => return Err(invalid(i + 1, "unknown block status")),
+        }
+    }
+    if !seen_status {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "empty ddrescue mapfile"));
+    }
+    Ok(ranges)
+}
+
+/// Parse a number the way ddrescue does: hexadecimal with `0x`, octal with
+/// a leading zero, decimal otherwise.
+fn parse_number(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if s.len() > 1 && s.starts_with('0') {
+        u64::from_str_radix(&s[1..], 8).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Check a layout read from a file against the input.
+///
+/// Entries that are empty, past the end of the input, not aligned to 512-byte
+/// sectors (or `alignment` if smaller), overlapping or out of order are
+/// reported with their index. If `strict` is set, the first one is an error;
+/// otherwise they are fixed with a warning (dropped, truncated, merged).
+/// Entries are then extended to `alignment`.
+///
+/// Entries past the end of the input are handled according to
+/// `out_of_range` instead, if set.
+pub fn validate(
+    ranges: Vec<Range<u64>>,
+    size: u64,
+    alignment: u64,
+    strict: bool,
+    out_of_range: Option<OutOfRange>,
+) -> std::io::Result<Vec<Range<u64>>> {
+    let (ranges, warnings) = check(ranges, size, alignment, strict, out_of_range)?;
+    for warning in warnings {
+        message!("Warning: layout {}", warning);
+    }
+    Ok(ranges)
+}
+
+/// Fix a layout like [`validate`], returning the warnings instead of
+/// printing them.
+fn check(
+    ranges: Vec<Range<u64>>,
+    size: u64,
+    alignment: u64,
+    strict: bool,
+    out_of_range: Option<OutOfRange>,
+) -> std::io::Result<(Vec<Range<u64>>, Vec<String>)> {
+    // First problem and count for each kind
+    let mut problems: Vec<(String, usize)> = Vec::new();
+    let mut report = |kind: usize, message: String| -> std::io::Result<()> {
+        if strict {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+        }
+        if problems.len() <= kind {
+            problems.resize(kind + 1, (String::new(), 0));
+        }
+        if problems[kind].1 == 0 {
+            problems[kind].0 = message;
+        }
+        problems[kind].1 += 1;
+        Ok(())
+    };
+
+    let mut fixed = Vec::with_capacity(ranges.len());
+    // Index and range of the last entry that wasn't dropped
+    let mut previous: Option<(usize, Range<u64>)> = None;
+    for (i, range) in ranges.into_iter().enumerate() {
+        if range.start >= range.end {
+            report(0, format!("entry {} at offset {} is empty", i, range.start))?;
+            continue;
+        }
+        if range.end > size {
+            let message = format!(
+                "entry {} ({}..{}) is past the end of the input ({} bytes)",
+                i, range.start, range.end, size,
+            );
+            match out_of_range {
+                None => report(1, message)?,
+                Some(OutOfRange::Error) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+                }
+                Some(OutOfRange::Clamp | OutOfRange::Extend) => {}
+            }
+        }
+        let sector = alignment.min(SECTOR_SIZE);
+        if range.start % sector != 0 || (range.end % sector != 0 && range.end < size) {
+            report(2, format!(
+                "entry {} ({}..{}) is not aligned to {} bytes",
+                i, range.start, range.end, sector,
+            ))?;
+        }
+        if let Some((j, previous)) = &previous {
+            if range.start < previous.start {
+                report(3, format!(
+                    "entry {} ({}..{}) is before entry {} ({}..{})",
+                    i, range.start, range.end, j, previous.start, previous.end,
+                ))?;
+            } else if range.start < previous.end {
+                report(4, format!(
+                    "entry {} ({}..{}) overlaps entry {} ({}..{})",
+                    i, range.start, range.end, j, previous.start, previous.end,
+                ))?;
+            }
+        }
+        previous = Some((i, range.clone()));
+
+        let start = range.start / alignment * alignment;
+        let end = match out_of_range {
+            Some(OutOfRange::Extend) if range.end > size => range.end,
+            _ => range.end.checked_next_multiple_of(alignment).map_or(size, |end| end.min(size)),
+        };
+        if start < end {
+            fixed.push(start..end);
+        }
+    }
+
+    let warnings = problems.into_iter()
+        .filter_map(|(message, count)| match count {
+            0 => None,
+            1 => Some(message),
+            _ => Some(format!("{} (and {} similar entries)", message, count - 1)),
+        })
+        .collect();
+    Ok((normalize(fixed), warnings))
+}
+
+/// Sort ranges and merge those that overlap or touch, dropping empty ones.
+pub fn normalize(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.retain(|r| r.start < r.end);
+    ranges.sort_by_key(|r| r.start);
+    let mut result: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match result.last_mut() {
+            Some(last) if range.start <= last.end => {
+                last.end = last.end.max(range.end);
+            }
+            _ => result.push(range),
+        }
+    }
+    result
+}
+
+/// Compute the ranges present in both layouts.
+pub fn intersect(a: Vec<Range<u64>>, b: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    let a = normalize(a);
+    let b = normalize(b);
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = a[i].start.max(b[j].start);
+        let end = a[i].end.min(b[j].end);
+        if start < end {
+            result.push(start..end);
+        }
+        if a[i].end < b[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+/// Compute the ranges of the first layout that are not in the second.
+pub fn subtract(a: Vec<Range<u64>>, b: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    let a = normalize(a);
+    let b = normalize(b);
+    let mut result = Vec::new();
+    let mut j = 0;
+    for range in a {
+        let mut start = range.start;
+        // Skip ranges of b entirely before this one
+        while j < b.len() && b[j].end <= start {
+            j += 1;
+        }
+        let mut k = j;
+        while k < b.len() && b[k].start < range.end {
+            if b[k].start > start {
+                result.push(start..b[k].start);
+            }
+            start = start.max(b[k].end);
+            k += 1;
+        }
+        if start < range.end {
+            result.push(start..range.end);
+        }
+    }
+    result
+}
+
+/// Compute the ranges present in either layout.
+pub fn union(a: Vec<Range<u64>>, mut b: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    b.extend(a);
+    normalize(b)
+}
+
+/// A layout kept normalized (sorted, merged ranges), to combine layouts
+/// with set operations.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Layout {
+    ranges: Vec<Range<u64>>,
+}
+
+impl Layout {
+    pub fn new(ranges: Vec<Range<u64>>) -> Layout {
+        Layout { ranges: normalize(ranges) }
+    }
+
+    /// Read a layout file in the given format, `-` being stdin.
+    pub fn open(path: &Path, format: LayoutFormat) -> std::io::Result<Layout> {
+        let ranges = if path.as_os_str() == "-" {
+            read(std::io::stdin().lock(), format)?
+        } else {
+            read(std::io::BufReader::new(std::fs::File::open(path)?), format)?
+        };
+        Ok(Layout::new(ranges))
+    }
+
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    pub fn into_ranges(self) -> Vec<Range<u64>> {
+        self.ranges
+    }
+
+    /// Number of bytes in the ranges.
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The ranges in either layout.
+    pub fn union(&self, other: &Layout) -> Layout {
+        Layout { ranges: union(self.ranges.clone(), other.ranges.clone()) }
+    }
+
+    /// The ranges in both layouts.
+    pub fn intersect(&self, other: &Layout) -> Layout {
+        Layout { ranges: intersect(self.ranges.clone(), other.ranges.clone()) }
+    }
+
+    /// The ranges of this layout that are not in the other.
+    pub fn subtract(&self, other: &Layout) -> Layout {
+        Layout { ranges: subtract(self.ranges.clone(), other.ranges.clone()) }
+    }
+
+    /// Write the layout in JSON format, as read by `read_json()`.
+    pub fn write_json<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let entries: Vec<_> = self.ranges.iter().map(|r| {
+            serde_json::json!({"offset": r.start, "length": r.end - r.start})
+        }).collect();
+        serde_json::to_writer(&mut writer, &entries)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+}
+
+/// Set operation combining several layouts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Ranges in any of the layouts
+    Union,
+    /// Ranges in all the layouts
+    Intersect,
+    /// Ranges of the first layout that are in none of the others
+    Subtract,
+}
+
+impl Operation {
+    pub fn parse(s: &str) -> Option<Operation> {
+        match s {
+            "union" => Some(Operation::Union),
+            "intersect" => Some(Operation::Intersect),
+            "subtract" => Some(Operation::Subtract),
+            _ => None,
+        }
+    }
+
+    /// Combine the layouts, in order; no layouts give an empty one.
+    pub fn combine<I: IntoIterator<Item = Layout>>(self, layouts: I) -> Layout {
+        let mut layouts = layouts.into_iter();
+        let Some(first) = layouts.next() else {
+            return Layout::default();
+        };
+        layouts.fold(first, |result, layout| match self {
+            Operation::Union => result.union(&layout),
+            Operation::Intersect => result.intersect(&layout),
+            Operation::Subtract => result.subtract(&layout),
+        })
+    }
+}
+
+impl From<Vec<Range<u64>>> for Layout {
+    fn from(ranges: Vec<Range<u64>>) -> Layout {
+        Layout::new(ranges)
+    }
+}
+
+impl FromIterator<Range<u64>> for Layout {
+    fn from_iter<I: IntoIterator<Item = Range<u64>>>(iter: I) -> Layout {
+        Layout::new(iter.into_iter().collect())
+    }
+}
+
+/// Zero the parts of a buffer that are outside of the ranges, the buffer
+/// holding the data at `offset`.
+///
+/// The ranges have to be sorted and not overlap.
+pub fn mask(ranges: &[Range<u64>], offset: u64, buffer: &mut [u8]) {
+    let end = offset + buffer.len() as u64;
+    let mut pos = offset;
+    let first = ranges.partition_point(|r| r.end <= offset);
+    for range in ranges[first..].iter().take_while(|r| r.start < end) {
+        if range.start > pos {
+            buffer[(pos - offset) as usize..(range.start - offset) as usize].fill(0);
+        }
+        pos = pos.max(range.end);
+    }
+    if pos < end {
+        buffer[(pos - offset) as usize..].fill(0);
+    }
+}
+
+#[cfg(test)]
+// Single-range layouts are the common case here, not a mistaken `(a..b).collect()`
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    const MAX: u64 = u64::MAX;
+
+    type Ranges = Vec<Range<u64>>;
+
+    #[test]
+    fn normalize_ranges() {
+        let cases: Vec<(Ranges, Ranges)> = vec![
+            (vec![], vec![]),
+            (vec![5..5, Range { start: 7, end: 3 }], vec![]),
+            (vec![0..10, 10..20], vec![0..20]),
+            (vec![20..30, 0..10, 5..15], vec![0..15, 20..30]),
+            (vec![0..100, 10..20, 30..40], vec![0..100]),
+            (
+                vec![MAX - 10..MAX, 0..1, MAX - 20..MAX - 10],
+                vec![0..1, MAX - 20..MAX],
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(normalize(input.clone()), expected, "normalize({:?})", input);
+        }
+    }
+
+    #[test]
+    fn set_operations() {
+        // a, b, a ∩ b, a - b, a ∪ b
+        type Case = (Ranges, Ranges, Ranges, Ranges, Ranges);
+        let cases: Vec<Case> = vec![
+            (vec![], vec![0..10], vec![], vec![], vec![0..10]),
+            (vec![0..10], vec![], vec![], vec![0..10], vec![0..10]),
+            // Adjacent
+            (vec![0..10], vec![10..20], vec![], vec![0..10], vec![0..20]),
+            // Overlapping
+            (
+                vec![0..10],
+                vec![5..15],
+                vec![5..10],
+                vec![0..5],
+                vec![0..15],
+            ),
+            // Contained, splitting the range
+            (
+                vec![0..30],
+                vec![10..20],
+                vec![10..20],
+                vec![0..10, 20..30],
+                vec![0..30],
+            ),
+            // Several ranges of b in one of a, and the reverse
+            (
+                vec![0..100],
+                vec![10..20, 30..40, 90..110],
+                vec![10..20, 30..40, 90..100],
+                vec![0..10, 20..30, 40..90],
+                vec![0..110],
+            ),
+            (
+                vec![10..20, 30..40],
+                vec![0..100],
+                vec![10..20, 30..40],
+                vec![],
+                vec![0..100],
+            ),
+            // Unsorted, overlapping and empty inputs
+            (
+                vec![30..40, 0..10, 5..12, 50..50],
+                vec![35..36, 8..9],
+                vec![8..9, 35..36],
+                vec![0..8, 9..12, 30..35, 36..40],
+                vec![0..12, 30..40],
+            ),
+            // Up to the end of the address space
+            (
+                vec![MAX - 10..MAX],
+                vec![MAX - 5..MAX],
+                vec![MAX - 5..MAX],
+                vec![MAX - 10..MAX - 5],
+                vec![MAX - 10..MAX],
+            ),
+            (
+                vec![0..MAX],
+                vec![1..2],
+                vec![1..2],
+                vec![0..1, 2..MAX],
+                vec![0..MAX],
+            ),
+        ];
+        for (a, b, both, difference, either) in cases {
+            assert_eq!(
+                intersect(a.clone(), b.clone()),
+                both,
+                "intersect({:?}, {:?})",
+                a,
+                b
+            );
+            assert_eq!(
+                subtract(a.clone(), b.clone()),
+                difference,
+                "subtract({:?}, {:?})",
+                a,
+                b
+            );
+            assert_eq!(
+                union(a.clone(), b.clone()),
+                either,
+                "union({:?}, {:?})",
+                a,
+                b
+            );
+
+            let (a, b) = (Layout::new(a), Layout::new(b));
+            assert_eq!(a.intersect(&b).into_ranges(), both);
+            assert_eq!(a.subtract(&b).into_ranges(), difference);
+            assert_eq!(a.union(&b).into_ranges(), either);
+        }
+    }
+
+    #[test]
+    fn layouts() {
+        let layout: Layout = vec![10..20, 0..5, 5..6].into_iter().collect();
+        assert_eq!(layout.ranges(), &[0..6, 10..20]);
+        assert_eq!(layout.len(), 16);
+        assert!(!layout.is_empty());
+        assert!(Layout::new(vec![3..3]).is_empty());
+
+        let layouts = || {
+            vec![
+                Layout::new(vec![0..10]),
+                Layout::new(vec![5..20]),
+                Layout::new(vec![8..9]),
+            ]
+        };
+        assert_eq!(
+            Operation::Union.combine(layouts()).into_ranges(),
+            vec![0..20]
+        );
+        assert_eq!(
+            Operation::Intersect.combine(layouts()).into_ranges(),
+            vec![8..9]
+        );
+        assert_eq!(
+            Operation::Subtract.combine(layouts()).into_ranges(),
+            vec![0..5]
+        );
+        assert_eq!(Operation::Union.combine(Vec::new()), Layout::default());
+
+        let mut json = Vec::new();
+        layout.write_json(&mut json).unwrap();
+        assert_eq!(read_json(&json[..]).unwrap(), vec![0..6, 10..20]);
+    }
+
+    #[test]
+    fn csv() {
+        let cases: Vec<(&str, Ranges)> = vec![
+            ("", vec![]),
+            ("0,4096\n8192,4096\n", vec![0..4096, 8192..12288]),
+            ("offset,length\n0x1000, 0x2000\n\n  16,16  \n", vec![0x1000..0x3000, 16..32]),
+            ("OFFSET,LENGTH\n0,1", vec![0..1]),
+            // Overlapping and unsorted rows are read as they are
+            ("100,50\n0,10\n120,10\n", vec![100..150, 0..10, 120..130]),
+            ("18446744073709551614,1\n", vec![MAX - 1..MAX]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(read_csv(text.as_bytes()).unwrap(), expected, "{:?}", text);
+        }
+
+        let errors = [
+            ("0,10\n20\n", "line 2: expected offset,length"),
+            ("0,10\n\n0x,10\n", "line 3: invalid number"),
+            ("0,ten\n", "line 1: invalid number"),
+            ("-1,10\n", "line 1: invalid number"),
+            ("0,10\noffset,length\n", "line 2: invalid number"),
+            ("18446744073709551615,1\n", "line 1: entry is past the maximum size"),
+        ];
+        for (text, message) in errors {
+            let err = read_csv(text.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), message, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn ddrescue() {
+        let mapfile = "\
+# Mapfile. Created by GNU ddrescue version 1.27
+# Command line: ddrescue /dev/sdb disk.img disk.map
+# current_pos  current_status  current_pass
+0x00120000     +               1
+#      pos        size  status
+0x00000000  0x00010000  +
+0x00010000  0x00001000  -
+0x00011000  0x00001000  ?
+0x00012000  0x00001000  *
+0x00013000  0x00001000  /
+0x00014000  0x0000C000  +  # rescued on the second pass
+0x00020000  0x00100000  +
+";
+        assert_eq!(
+            read_ddrescue(mapfile.as_bytes()).unwrap(),
+            vec![0..0x10000, 0x14000..0x20000, 0x20000..0x120000],
+        );
+
+        // Hexadecimal, decimal and octal numbers
+        let cases: Vec<(&str, Ranges)> = vec![
+            ("0 +\n", vec![]),
+            ("0 +\n0x10 0X20 +\n", vec![0x10..0x30]),
+            ("0 +\n16 32 +\n", vec![16..48]),
+            ("0 +\n020 040 +\n", vec![16..48]),
+            ("0 +\n0 0 +\n", vec![0..0]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(read_ddrescue(text.as_bytes()).unwrap(), expected, "{:?}", text);
+        }
+
+        let errors = [
+            ("", "empty ddrescue mapfile"),
+            ("# only comments\n\n", "empty ddrescue mapfile"),
+            ("0 +\n0 16\n", "line 2: expected position, size and status"),
+            ("0 +\n0 16 + 1\n", "line 2: expected position, size and status"),
+            ("0 +\n0 0x +\n", "line 2: invalid number"),
+            ("0 +\n08 16 +\n", "line 2: invalid number"),
+            ("0 +\n0 16 x\n", "line 2: unknown block status"),
+            ("0 +\n0xFFFFFFFFFFFFFFFF 1 +\n", "line 2: block is past the maximum size"),
+        ];
+        for (text, message) in errors {
+            let err = read_ddrescue(text.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), message, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn validate_layouts() {
+        // Ranges, fixed ranges, warnings; the input is 100000 bytes and
+        // entries are aligned to 4096 bytes, with a warning if they are not
+        // aligned to 512 bytes
+        let cases: Vec<(Ranges, Ranges, &[&str])> = vec![
+            (vec![], vec![], &[]),
+            (vec![0..4096, 8192..16384], vec![0..4096, 8192..16384], &[]),
+            // The last entry can end with the input
+            (vec![98304..100000], vec![98304..100000], &[]),
+            (
+                vec![0..4096, 5000..5000, 8192..8192],
+                vec![0..4096],
+                &["entry 1 at offset 5000 is empty (and 1 similar entries)"],
+            ),
+            (
+                vec![0..4096, 98304..110000],
+                vec![0..4096, 98304..100000],
+                &["entry 1 (98304..110000) is past the end of the input (100000 bytes)"],
+            ),
+            (
+                vec![100..200, 4096..8000],
+                vec![0..8192],
+                &["entry 0 (100..200) is not aligned to 512 bytes (and 1 similar entries)"],
+            ),
+            // Sector-aligned entries are extended silently
+            (
+                vec![512..1024, 8192..9216, 12800..13312],
+                vec![0..4096, 8192..16384],
+                &[],
+            ),
+            (
+                vec![8192..12288, 0..4096],
+                vec![0..4096, 8192..12288],
+                &["entry 1 (0..4096) is before entry 0 (8192..12288)"],
+            ),
+            (
+                vec![0..8192, 4096..12288],
+                vec![0..12288],
+                &["entry 1 (4096..12288) overlaps entry 0 (0..8192)"],
+            ),
+            // One warning for each kind of problem, in a fixed order
+            (
+                vec![16384..20480, 4096..8192, 4096..4096, 5000..8192, 204800..307200],
+                vec![4096..8192, 16384..20480],
+                &[
+                    "entry 2 at offset 4096 is empty",
+                    "entry 4 (204800..307200) is past the end of the input (100000 bytes)",
+                    "entry 3 (5000..8192) is not aligned to 512 bytes",
+                    "entry 1 (4096..8192) is before entry 0 (16384..20480)",
+                    "entry 3 (5000..8192) overlaps entry 1 (4096..8192)",
+                ],
+            ),
+            // Up to the end of the address space
+            (
+                vec![0..4096, MAX - 4095..MAX],
+                vec![0..4096],
+                &["entry 1 (18446744073709547520..18446744073709551615) is past the end of the input (100000 bytes)"],
+            ),
+        ];
+        for (ranges, fixed, warnings) in cases {
+            let (result, messages) = check(ranges.clone(), 100000, 4096, false, None).unwrap();
+            assert_eq!(result, fixed, "{:?}", ranges);
+            assert_eq!(messages, warnings, "{:?}", ranges);
+
+            // In strict mode, the problem with the lowest entry is an error,
+            // which is the first warning for the shortest failing prefix
+            let err = check(ranges.clone(), 100000, 4096, true, None).err();
+            let first = (1..=ranges.len()).find_map(|len| {
+                let (_, warnings) = check(ranges[..len].to_vec(), 100000, 4096, false, None).unwrap();
+                warnings.into_iter().next()
+            });
+            assert_eq!(err.map(|e| e.to_string()), first, "{:?}", ranges);
+        }
+    }
+
+    #[test]
+    fn validate_out_of_range() {
+        let ranges = vec![0..4096, 98304..110000, 122880..131072];
+
+        let (fixed, warnings) = check(ranges.clone(), 100000, 4096, true, Some(OutOfRange::Clamp)).unwrap();
+        assert_eq!(fixed, vec![0..4096, 98304..100000]);
+        assert!(warnings.is_empty());
+
+        let (fixed, warnings) = check(ranges.clone(), 100000, 4096, true, Some(OutOfRange::Extend)).unwrap();
+        assert_eq!(fixed, vec![0..4096, 98304..110000, 122880..131072]);
+        assert!(warnings.is_empty());
+
+        let err = check(ranges.clone(), 100000, 4096, false, Some(OutOfRange::Error)).unwrap_err();
+        assert_eq!(err.to_string(), "entry 1 (98304..110000) is past the end of the input (100000 bytes)");
+
+        // Other problems are still reported
+        let (_, warnings) = check(vec![0..10, 0..200000], 100000, 1, false, Some(OutOfRange::Clamp)).unwrap();
+        assert_eq!(warnings, ["entry 1 (0..200000) overlaps entry 0 (0..10)"]);
+    }
+
+    fn extents(list: &[(Range<u64>, ExtentKind)]) -> Vec<Extent> {
+        list.iter().map(|(range, kind)| Extent { range: range.clone(), kind: *kind }).collect()
+    }
+
+    #[test]
+    fn typed_extents_precedence() {
+        use ExtentKind::{Data, Free, Zero};
+
+        // Entries, extents of a disk of 100 bytes
+        let cases = [
+            (vec![], vec![(0..100, Free)]),
+            (vec![(10..20, Data)], vec![(0..10, Free), (10..20, Data), (20..100, Free)]),
+            // Data wins over zero, and discard entries are free like the
+            // space between entries
+            (
+                vec![(0..40, Zero), (10..20, Data), (30..60, Data), (50..70, Free)],
+                vec![(0..10, Zero), (10..20, Data), (20..30, Zero), (30..60, Data), (60..100, Free)],
+            ),
+            (vec![(20..30, Free), (0..100, Zero)], vec![(0..100, Zero)]),
+            // Zero entries are clipped to the disk
+            (vec![(90..u64::MAX, Zero)], vec![(0..90, Free), (90..100, Zero)]),
+        ];
+        for (entries, expected) in cases {
+            let result: Vec<Extent> = typed_extents(extents(&entries), 100, 1, true, None).unwrap().collect();
+            assert_eq!(result, extents(&expected), "{:?}", entries);
+        }
+
+        // Data entries can extend the disk, zero entries can't
+        let entries = extents(&[(90..120, Data), (120..140, Zero)]);
+        let result: Vec<Extent> = typed_extents(entries, 100, 1, true, Some(OutOfRange::Extend)).unwrap().collect();
+        assert_eq!(result, extents(&[(0..90, Free), (90..120, Data)]));
+
+        // Data entries are validated
+        let entries = extents(&[(10..20, Data), (15..30, Data)]);
+        assert!(typed_extents(entries, 100, 1, true, None).is_err());
+    }
+
+    /// Layout with fixed extents, for `Merged`.
+    struct Fixed(Vec<Extent>);
+
+    impl ExtentSource for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn extents(&mut self, _input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::other("no extents"));
+            }
+            Ok(Box::new(self.0.clone().into_iter()))
+        }
+    }
+
+    #[test]
+    fn merged_precedence() {
+        use ExtentKind::{Data, Free, Zero};
+
+        let merge = |operation, layouts: &[&[(Range<u64>, ExtentKind)]]| {
+            let mut input = Input::Stream(crate::input::ForwardReader::new(Box::new(std::io::empty()), 0));
+            let mut merged = Merged {
+                sources: layouts.iter().map(|l| Box::new(Fixed(extents(l))) as Box<dyn ExtentSource>).collect(),
+                operation,
+            };
+            merged.extents(&mut input).map(|e| e.collect::<Vec<_>>())
+        };
+
+        let a: &[_] = &[(0..8, Data), (8..16, Zero), (16..32, Free)];
+        let b: &[_] = &[(0..4, Free), (4..12, Data), (12..20, Free), (20..24, Zero)];
+        // Operation, extents of a ∘ b
+        let cases = [
+            (Operation::Union, vec![(0..12, Data), (12..16, Zero), (16..20, Free), (20..24, Zero), (24..32, Free)]),
+            // Zero where both know the range but not both as data
+            (Operation::Intersect, vec![(0..4, Free), (4..8, Data), (8..12, Zero), (12..32, Free)]),
+            (Operation::Subtract, vec![(0..4, Data), (4..12, Free), (12..16, Zero), (16..32, Free)]),
+        ];
+        for (operation, expected) in cases {
+            assert_eq!(merge(operation, &[a, b]).unwrap(), extents(&expected));
+        }
+
+        // Data subtracted from zero or zero subtracted from data: data wins,
+        // and no range has two kinds
+        let zero: &[_] = &[(0..16, Zero)];
+        let data: &[_] = &[(0..8, Data), (8..16, Free)];
+        assert_eq!(merge(Operation::Subtract, &[data, zero]).unwrap(), extents(&[(0..8, Data), (8..16, Free)]));
+        assert_eq!(merge(Operation::Subtract, &[zero, data]).unwrap(), extents(&[(0..8, Free), (8..16, Zero)]));
+        assert_eq!(merge(Operation::Union, &[zero, data]).unwrap(), extents(&[(0..8, Data), (8..16, Zero)]));
+        assert_eq!(merge(Operation::Intersect, &[zero, data]).unwrap(), extents(&[(0..8, Zero), (8..16, Free)]));
+
+        // Errors name the layout
+        let err = merge(Operation::Union, &[a, &[]]).unwrap_err();
+        assert_eq!(err.to_string(), "fixed 2: no extents");
+    }
+
+    #[test]
+    fn mask_buffer() {
+        // Ranges, offset of the buffer, bytes kept
+        let cases: Vec<(Ranges, u64, &str)> = vec![
+            (vec![], 0, "........"),
+            (vec![0..100], 0, "xxxxxxxx"),
+            (vec![2..4, 6..7], 0, "..xx..x."),
+            (vec![2..4, 6..7], 2, "xx..x..."),
+            // Ranges before, around and after the buffer
+            (vec![0..11, 14..15, 17..100], 10, "x...x..x"),
+            (vec![0..5, 20..30], 10, "........"),
+            // Buffer at the end of the address space
+            (vec![MAX - 4..MAX - 2], MAX - 8, "....xx.."),
+        ];
+        for (ranges, offset, expected) in cases {
+            let mut buffer = [b'x'; 8];
+            mask(&ranges, offset, &mut buffer);
+            let kept: String = buffer
+                .iter()
+                .map(|&b| if b == 0 { '.' } else { 'x' })
+                .collect();
+            assert_eq!(kept, expected, "mask({:?}, {})", ranges, offset);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Converting disks to qcow2 images in a single pass, writing the metadata
+//! first so the image can be streamed.
+//!
+//! The command-line tool is built on these modules; `ffi` exposes the writer
+//! to C.
+
+pub mod archive;
+pub mod bench;
+pub mod buffer;
+pub mod check;
+pub mod dashboard;
+pub mod decompress;
+pub mod ebs;
+pub mod encrypt;
+pub mod ffi;
+pub mod fixture;
+pub mod fs;
+pub mod fsfreeze;
+pub mod glance;
+pub mod guestfs;
+pub mod http;
+pub mod input;
+pub mod layout;
+pub mod libvirt;
+pub mod log;
+pub mod lvm;
+pub mod manifest;
+pub mod nbd;
+pub mod ntfsclone;
+pub mod output;
+pub mod partclone;
+pub mod partition;
+pub mod priority;
+pub mod progress;
+pub mod proxmox;
+pub mod qcow2;
+pub mod qmp;
+pub mod rbd;
+pub mod readahead;
+pub mod report;
+pub mod scan;
+pub mod secret;
+pub mod seek_hole;
+pub mod sign;
+pub mod signals;
+pub mod spool;
+pub mod systemd;
+pub mod tar;
+pub mod throttle;
+pub mod torrent;
+pub mod tus;
+pub mod verify;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+pub mod vss;
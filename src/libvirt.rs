@@ -0,0 +1,141 @@
+//! Uploading the image to a libvirt storage volume (`--upload
+//! libvirt://POOL/NAME`), which can be on a remote host.
+//!
+//! This uses `virsh`: the volume is created in the pool with the virtual size
+//! of the image, then the data is streamed to `virsh vol-upload` through its
+//! standard input. The connection is the default one, or the URI given as
+//! `?connect=qemu+ssh://host/system`.
+
+use std::io::Write;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::log::message;
+
+/// An image being uploaded to a libvirt storage volume.
+pub struct Upload {
+    connect: Option<String>,
+    pool: String,
+    name: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    finished: bool,
+}
+
+impl Upload {
+    /// Create a qcow2 volume in the pool, and start uploading its data,
+    /// which should then be written to the upload.
+    pub fn start(connect: Option<&str>, pool: &str, name: &str, size: u64) -> std::io::Result<Upload> {
+        let mut command = virsh(connect);
+        command
+            .arg("vol-create-as")
+            .arg("--pool").arg(pool)
+            .arg("--name").arg(name)
+            .arg("--capacity").arg(size.to_string())
+            .arg("--format").arg("qcow2");
+        run(command)?;
+        message!("Created libvirt volume {} in pool {}", name, pool);
+
+        let child = virsh(connect)
+            .arg("vol-upload")
+            .arg("--pool").arg(pool)
+            .arg("--vol").arg(name)
+            .arg("--file").arg("/dev/stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn();
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => {
+                delete_volume(connect, pool, name);
+                return Err(e);
+            }
+        };
+        let stdin = child.stdin.take();
+        Ok(Upload {
+            connect: connect.map(ToOwned::to_owned),
+            pool: pool.to_owned(),
+            name: name.to_owned(),
+            child,
+            stdin,
+            finished: false,
+        })
+    }
+
+    /// Wait for the upload to complete, returning the path of the volume.
+    pub fn finish(mut self) -> std::io::Result<String> {
+        // Close the pipe so virsh sees the end of the data
+        self.stdin = None;
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("virsh vol-upload failed ({})", status)));
+        }
+
+        let mut command = virsh(self.connect.as_deref());
+        command
+            .arg("vol-path")
+            .arg("--pool").arg(&self.pool)
+            .arg(&self.name);
+        let path = String::from_utf8_lossy(&run(command)?).trim().to_owned();
+        self.finished = true;
+        Ok(path)
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(stdin) = &mut self.stdin else {
+            return Err(std::io::Error::other("upload is finished"));
+        };
+        stdin.write(buf).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("writing to virsh failed: {}", e))
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Don't leave an incomplete volume behind
+        if !self.finished {
+            self.stdin = None;
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            delete_volume(self.connect.as_deref(), &self.pool, &self.name);
+        }
+    }
+}
+
+fn virsh(connect: Option<&str>) -> Command {
+    let mut command = Command::new("virsh");
+    if let Some(uri) = connect {
+        command.arg("--connect").arg(uri);
+    }
+    command
+}
+
+fn delete_volume(connect: Option<&str>, pool: &str, name: &str) {
+    message!("Deleting libvirt volume {}", name);
+    let mut command = virsh(connect);
+    command.arg("vol-delete").arg("--pool").arg(pool).arg(name);
+    if run(command).is_err() {
+        message!("Warning: failed to delete libvirt volume {}", name);
+    }
+}
+
+fn run(mut command: Command) -> std::io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "virsh command failed ({})",
+            output.status,
+        )));
+    }
+    Ok(output.stdout)
+}
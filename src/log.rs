@@ -0,0 +1,69 @@
+//! Log file with timestamped lines (`--log-file`), so unattended conversions
+//! leave a trail: the messages printed on stderr, the phases, and the final
+//! result.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{dashboard, progress, report};
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Print a message on stderr, like `eprintln!`, and write it to the log file
+/// if there is one.
+#[macro_export]
+macro_rules! message {
+    ($($arg:tt)*) => {
+        $crate::log::print(format_args!($($arg)*))
+    };
+}
+pub use message;
+
+/// Start appending to the log file.
+pub fn open(path: &Path) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+pub fn print(args: std::fmt::Arguments) {
+    if !dashboard::show_message(args) {
+        progress::clear_bar();
+        eprintln!("{}", args);
+    }
+    report::add_message(args);
+    write(args);
+}
+
+/// Write a line to the log file only.
+pub fn write(args: std::fmt::Arguments) {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    let Some(file) = &mut *log_file else {
+        return;
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Writing the log is best-effort, it shouldn't fail the conversion
+    let _ = writeln!(file, "{} {}", format_timestamp(now), args);
+}
+
+/// Format a time in seconds since the Unix epoch as RFC 3339, in UTC.
+fn format_timestamp(time: u64) -> String {
+    let (days, seconds) = (time / 86400, time % 86400);
+    // Civil date from days since the epoch, from Howard Hinnant's algorithms
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60,
+    )
+}
@@ -0,0 +1,91 @@
+//! LVM snapshots: converting from a temporary snapshot of a logical volume
+//! gives a crash-consistent image of a volume that is in use.
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::log::message;
+
+/// Size of the snapshot's copy-on-write area, if none is given.
+const DEFAULT_SNAPSHOT_EXTENTS: &str = "10%ORIGIN";
+
+/// A temporary snapshot of a logical volume, removed on drop.
+pub struct Snapshot {
+    /// Name of the snapshot as `vg/lv`
+    name: String,
+    pub device: PathBuf,
+}
+
+impl Snapshot {
+    /// Create a snapshot of the logical volume at the given path.
+    ///
+    /// `size` is the size of the copy-on-write area, as understood by
+    /// `lvcreate --size`. Thin volumes get a thin snapshot if no size is
+    /// given.
+    pub fn create(volume: &OsStr, size: Option<&str>) -> std::io::Result<Snapshot> {
+        // Find the volume group and name of the volume
+        let mut command = Command::new("lvs");
+        command
+            .arg("--noheadings")
+            .arg("--separator=/")
+            .arg("-o").arg("vg_name,lv_name,segtype")
+            .arg(volume);
+        let output = run(command)?;
+        let output = String::from_utf8_lossy(&output);
+        let mut fields = output.trim().split('/');
+        let (Some(vg), Some(lv), Some(segtype)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(std::io::Error::other("unexpected output from lvs"));
+        };
+
+        let snapshot_lv = format!("{}-sqw{}", lv, std::process::id());
+        let mut command = Command::new("lvcreate");
+        command.arg("--snapshot").arg("--name").arg(&snapshot_lv).arg("--permission").arg("r");
+        match size {
+            Some(size) => {
+                command.arg("--size").arg(size);
+            }
+            None if segtype == "thin" => {
+                // Thin snapshots are skipped on activation by default
+                command.arg("--setactivationskip").arg("n");
+            }
+            None => {
+                command.arg("--extents").arg(DEFAULT_SNAPSHOT_EXTENTS);
+            }
+        }
+        command.arg(format!("{}/{}", vg, lv));
+        run(command)?;
+
+        Ok(Snapshot {
+            name: format!("{}/{}", vg, snapshot_lv),
+            device: PathBuf::from(format!("/dev/{}/{}", vg, snapshot_lv)),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let status = Command::new("lvremove")
+            .arg("--yes")
+            .arg(&self.name)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status();
+        match status {
+            Ok(s) if s.success() => {}
+            _ => message!("Warning: failed to remove snapshot {}", self.name),
+        }
+    }
+}
+
+fn run(mut command: Command) -> std::io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("LVM command failed ({})", output.status)));
+    }
+    Ok(output.stdout)
+}
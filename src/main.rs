@@ -1,120 +1,1794 @@
-mod qcow2;
+mod cli;
 
+use std::io::{IsTerminal, Read, Seek, Write};
 use std::ops::Range;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
 
-use qcow2::StreamingQcow2Writer;
+use streaming_qcow2_writer::{
+    archive, bench, buffer, check, dashboard, decompress, ebs, encrypt, fs, fsfreeze, glance,
+    guestfs, http, input, layout, libvirt, log, lvm, manifest, nbd, ntfsclone, output,
+    partition, priority, progress, proxmox, qcow2, qmp, rbd, readahead, report, scan, secret,
+    seek_hole, sign, signals, spool, systemd, tar, throttle, torrent, tus, verify, vhd, vhdx,
+    vmdk, vss,
+};
 
-const USAGE: &'static str = "Usage: streaming-qcow2-writer input.img [layout.json] > output.qcow2";
+use cli::{OutputFormat, ParseResult, SwapMode, UploadTarget, USAGE};
+use input::{Input, InputFormat};
+use layout::{Extent, ExtentKind, ExtentSource, LayoutFormat, Operation, OutOfRange};
+use log::message;
+use qcow2::{Preallocation, Provenance, StreamingQcow2Writer};
 
-#[cfg(unix)]
-const BLKGETSIZE64_CODE: u8 = 0x12; // Defined in linux/fs.h
-#[cfg(unix)]
-const BLKGETSIZE64_SEQ: u8 = 114;
-#[cfg(unix)]
-nix::ioctl_read!(ioctl_blkgetsize64, BLKGETSIZE64_CODE, BLKGETSIZE64_SEQ, u64);
+/// Same as the standard library's default for `BufWriter`
+const DEFAULT_BUFFER_SIZE: usize = 8 << 10;
 
-fn get_file_size(file: &std::fs::File) -> std::io::Result<u64> {
-    let metadata = file.metadata()?;
+/// Name of the disk in tar archives, the one expected by GCE image imports
+const TAR_MEMBER_NAME: &str = "disk.raw";
 
-    let file_type = metadata.file_type();
+fn main() {
+    // Read command-line arguments
+    let options = match cli::parse_args(std::env::args_os().skip(1)) {
+        Ok(ParseResult::Run(o)) => *o,
+        Ok(ParseResult::GenFixture(o)) => {
+            if let Err(e) = gen_fixture(o) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(ParseResult::Layout(o)) => {
+            if let Err(e) = combine_layouts(o) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(ParseResult::Check(image)) => {
+            std::process::exit(check_image(Path::new(&image)));
+        }
+        Ok(ParseResult::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::FileTypeExt;
-        use std::os::unix::io::AsRawFd;
+    if let Some(path) = &options.log_file {
+        if let Err(e) = log::open(Path::new(path)) {
+            eprintln!("Error opening log file: {}", e);
+            std::process::exit(2);
+        }
+        log::write(format_args!("Starting streaming-qcow2-writer {}", env!("CARGO_PKG_VERSION")));
+    }
 
-        if file_type.is_block_device() {
-            let fd = file.as_raw_fd();
-            let mut cap = 0u64;
-            let cap_ptr = &mut cap as *mut u64;
-            unsafe {
-                ioctl_blkgetsize64(fd, cap_ptr).unwrap();
-            }
+    // Spans of the phases and of the copies, for diagnosing performance
+    if std::env::var_os("RUST_LOG").is_some() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .init();
+    }
 
-            return Ok(cap);
+    if let Some(path) = &options.report {
+        if let Err(e) = report::create(Path::new(path)) {
+            eprintln!("Error creating report: {}", e);
+            std::process::exit(2);
         }
     }
 
-    if !metadata.file_type().is_file() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "input is not a file",
-        ));
+    systemd::init();
+
+    if let Err(e) = signals::install_handlers() {
+        message!("Warning: can't install signal handlers: {}", e);
+    }
+
+    if let Some(fd) = options.status_fd {
+        if let Err(e) = progress::set_status_fd(fd) {
+            message!("Error using status file descriptor {}: {}", fd, e);
+            std::process::exit(2);
+        }
     }
 
-    Ok(metadata.len())
+    if options.tui {
+        if !std::io::stderr().is_terminal() {
+            eprintln!("--tui requires stderr to be a terminal");
+            std::process::exit(2);
+        }
+        dashboard::start();
+    } else if options.no_progress {
+        progress::set_display(progress::Display::Off);
+    } else if std::io::stderr().is_terminal() {
+        progress::set_display(progress::Display::Bar);
+    }
+    if let Some(interval) = options.progress_interval {
+        progress::set_interval(interval);
+    }
+
+    let errors_json = options.errors_json;
+    systemd::ready();
+    let result = run(options);
+    dashboard::stop();
+    if let Err(e) = result {
+        // Exit like we were killed by the signal, after cleaning up
+        if let Some(signal) = signals::cancelled() {
+            message!("Cancelled");
+            progress::finish(Some("Cancelled"));
+            report::set("failure", "cancelled".into());
+            finish_report(Some("Cancelled"));
+            if errors_json {
+                print_error_record("cancelled", 128 + signal, "Cancelled");
+            }
+            std::process::exit(128 + signal);
+        }
+        message!("{}", e.message);
+        progress::finish(Some(&e.message));
+        report::set("failure", e.failure.name().into());
+        finish_report(Some(&e.message));
+        if errors_json {
+            print_error_record(e.failure.name(), e.failure.exit_code(), &e.message);
+        }
+        std::process::exit(e.failure.exit_code());
+    }
+    progress::finish(None);
+    finish_report(None);
+    log::write(format_args!("Done"));
 }
 
-fn main() {
-    // Read command-line arguments
-    let mut args = std::env::args_os();
-    if let None = args.next() {
-        eprintln!("Not enough arguments");
-        eprintln!("{}", USAGE);
-        std::process::exit(2);
+fn run(mut options: cli::Options) -> Result<(), Error> {
+    // Lower our priority
+    if let Some(niceness) = options.nice {
+        priority::set_niceness(niceness)
+            .map_err(|e| format!("Error setting niceness: {}", e))?;
+    }
+    if let Some(io_priority) = options.ionice {
+        priority::set_io_priority(io_priority)
+            .map_err(|e| format!("Error setting I/O priority: {}", e))?;
+    }
+    if let Some(iops) = options.iops_limit {
+        throttle::set_iops_limit(iops);
+    }
+    if let Some(threads) = options.threads {
+        readahead::set_threads(threads);
+    }
+    if let Some(size) = options.buffer_size {
+        buffer::set_size(size);
+    }
+
+    if options.ebs_snapshot && options.output.is_some() {
+        return Err(Error::usage("--output can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.preallocation != Preallocation::Off {
+        return Err(Error::usage("--preallocation can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.manifest.is_some() {
+        return Err(Error::usage("--manifest can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.sign_key.is_some() {
+        return Err(Error::usage("--sign-key can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.torrent.is_some() {
+        return Err(Error::usage("--torrent can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.virtual_size.is_some() {
+        return Err(Error::usage("--virtual-size can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.round_size.is_some() {
+        return Err(Error::usage("--round-size can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.write_timeout.is_some() {
+        return Err(Error::usage("--write-timeout can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.mask_outside_layout {
+        return Err(Error::usage("--mask-outside-layout can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.ebs_snapshot && options.exclude_ranges.is_some() {
+        return Err(Error::usage("--exclude-ranges can't be used with --ebs-snapshot".to_owned()));
+    }
+    let layout_count = options.layouts.len() + options.layout_cmd.is_some() as usize;
+    if options.layout_format != LayoutFormat::Json && layout_count == 0 {
+        return Err(Error::usage("--layout-format requires a layout file or --layout-cmd".to_owned()));
     }
-    let Some(input) = args.next() else {
-        eprintln!("Not enough arguments");
-        eprintln!("{}", USAGE);
-        std::process::exit(2);
+    if options.out_of_range.is_some() && layout_count == 0 {
+        return Err(Error::usage("--out-of-range requires a layout file or --layout-cmd".to_owned()));
+    }
+    if options.layout_merge.is_some() && layout_count < 2 {
+        return Err(Error::usage("--layout-merge requires several layout files, or layout files and --layout-cmd".to_owned()));
+    }
+    if options.ebs_snapshot && options.out_of_range == Some(OutOfRange::Extend) {
+        return Err(Error::usage("--out-of-range extend can't be used with --ebs-snapshot".to_owned()));
+    }
+    let stdin_layouts = options.layouts.iter().filter(|l| *l == "-").count();
+    if options.input == "-" && stdin_layouts > 0 {
+        return Err(Error::usage("The input and the layout can't both be read from stdin".to_owned()));
+    }
+    if stdin_layouts > 1 {
+        return Err(Error::usage("Only one layout can be read from stdin".to_owned()));
+    }
+    if options.backing_format.is_some() && options.backing_file.is_none() {
+        return Err(Error::usage("--backing-format requires --backing-file".to_owned()));
+    }
+    if options.sign_passphrase.is_some() && options.sign_key.is_none() {
+        return Err(Error::usage("--sign-passphrase requires --sign-key".to_owned()));
+    }
+    if options.backing_file.is_some() && options.exclude_ranges.is_some() {
+        return Err(Error::usage("--exclude-ranges can't be used with --backing-file, excluded clusters would be read from it".to_owned()));
+    }
+    if options.input_size.is_some() && options.input_format.is_some_and(|f| f != InputFormat::Raw) {
+        return Err(Error::usage("--input-size can't be used with --input-format, the size is read from the image".to_owned()));
+    }
+    if options.format != OutputFormat::Qcow2 {
+        let qcow2_options = [
+            ("--preallocation", options.preallocation != Preallocation::Off),
+            ("--manifest", options.manifest.is_some()),
+            ("--provenance", options.provenance),
+            ("--backing-file", options.backing_file.is_some()),
+            ("--info", options.info),
+            ("--detect-zeroes", options.detect_zeroes),
+        ];
+        if let Some((name, _)) = qcow2_options.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can only be used with --format qcow2", name)));
+        }
+    }
+    let encrypt = !options.age_recipients.is_empty() || !options.age_recipients_files.is_empty();
+    if options.fsync && options.output.is_none() {
+        return Err(Error::usage("--fsync requires --output".to_owned()));
+    }
+    if options.direct_output && options.output.is_none() {
+        return Err(Error::usage("--direct-output requires --output".to_owned()));
+    }
+    if options.verify_after_write {
+        if options.output.is_none() {
+            return Err(Error::usage("--verify-after-write requires --output".to_owned()));
+        }
+        let unsupported = [
+            ("--format", options.format != OutputFormat::Qcow2),
+            ("--age-recipient", encrypt),
+            ("--tape-block-size", options.tape_block_size.is_some()),
+            ("--bench", options.bench),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can't be used with --verify-after-write", name)));
+        }
+    }
+    if options.direct_output && encrypt {
+        return Err(Error::usage("--direct-output can't be used with --age-recipient, age writes the output itself".to_owned()));
+    }
+    if options.tape_block_size.is_some() {
+        if options.output.is_none() {
+            return Err(Error::usage("--tape-block-size requires --output".to_owned()));
+        }
+        if encrypt {
+            return Err(Error::usage("--tape-block-size can't be used with --age-recipient, age writes the output itself".to_owned()));
+        }
+        if options.direct_output || options.discard {
+            return Err(Error::usage("--tape-block-size can't be used with --direct-output or --discard".to_owned()));
+        }
+    }
+    let writes_stdout = options.output.is_none() && options.upload.is_none() && !options.ebs_snapshot && !options.bench;
+    if systemd::has_socket() {
+        if !writes_stdout {
+            message!("Warning: ignoring the socket passed by systemd, the image is not written to stdout");
+        } else {
+            systemd::socket_to_stdout()
+                .map_err(|e| Error::new(Failure::Write, format!("Error accepting a connection on the systemd socket: {}", e)))?;
+        }
+    }
+    if writes_stdout && !options.force_tty && std::io::stdout().is_terminal() {
+        return Err(Error::usage("Refusing to write the image to a terminal, use --output or redirect stdout (or --force-tty)".to_owned()));
+    }
+    if options.info && options.output.is_none() {
+        return Err(Error::usage("--info requires --output, the image is written to stdout".to_owned()));
+    }
+    if options.reproducible && encrypt {
+        return Err(Error::usage("--reproducible can't be used with --age-recipient, age encryption is randomized".to_owned()));
+    }
+    if options.ebs_snapshot && encrypt {
+        return Err(Error::usage("--age-recipient can't be used with --ebs-snapshot".to_owned()));
+    }
+    if options.torrent.is_some() && encrypt {
+        return Err(Error::usage("--torrent can't be used with --age-recipient, it would describe the unencrypted image".to_owned()));
+    }
+    if options.discard && encrypt {
+        return Err(Error::usage("Encrypted images can't be written to a block device".to_owned()));
+    }
+    if options.upload.is_some() {
+        let unsupported = [
+            ("--output", options.output.is_some()),
+            ("--ebs-snapshot", options.ebs_snapshot),
+            ("--format", options.format != OutputFormat::Qcow2),
+            ("--age-recipient", encrypt),
+            ("--bench", options.bench),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can't be used with --upload", name)));
+        }
+    }
+    if options.detect_zeroes {
+        // The header is written last, by seeking back to the start
+        let unsupported = [
+            ("--preallocation", options.preallocation != Preallocation::Off),
+            ("--ebs-snapshot", options.ebs_snapshot),
+            ("--sign-key", options.sign_key.is_some()),
+            ("--torrent", options.torrent.is_some()),
+            ("--age-recipient", encrypt),
+            ("--direct-output", options.direct_output),
+            ("--tape-block-size", options.tape_block_size.is_some()),
+            ("--write-timeout", options.write_timeout.is_some()),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can't be used with --detect-zeroes", name)));
+        }
+        if options.output.is_none() {
+            return Err(Error::usage("--detect-zeroes requires --output, stdout can't be seeked".to_owned()));
+        }
+    }
+    if options.bench {
+        // Nothing is kept, the image is only written to time it
+        let unsupported = [
+            ("--ebs-snapshot", options.ebs_snapshot),
+            ("--format", options.format != OutputFormat::Qcow2),
+            ("--preallocation", options.preallocation != Preallocation::Off),
+            ("--detect-zeroes", options.detect_zeroes),
+            ("--discard", options.discard),
+            ("--info", options.info),
+            ("--manifest", options.manifest.is_some()),
+            ("--sign-key", options.sign_key.is_some()),
+            ("--torrent", options.torrent.is_some()),
+            ("--age-recipient", encrypt),
+            ("--max-memory", options.max_memory.is_some()),
+            ("--direct-output", options.direct_output),
+            ("--tape-block-size", options.tape_block_size.is_some()),
+            ("--write-timeout", options.write_timeout.is_some()),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can't be used with --bench", name)));
+        }
+    }
+    if options.empty.is_some() {
+        // There is no input to read or analyze
+        let unsupported = [
+            ("--layout-cmd", options.layout_cmd.is_some()),
+            ("--input-format", options.input_format.is_some()),
+            ("--input-member", options.input_member.is_some()),
+            ("--input-size", options.input_size.is_some()),
+            ("--spool", options.spool),
+            ("--ebs-snapshot", options.ebs_snapshot),
+            ("--bench", options.bench),
+            ("--sparsify-mode", options.seek_hole || options.fiemap || options.scan_zeroes),
+            ("--detect-zeroes", options.detect_zeroes),
+            ("--mask-outside-layout", options.mask_outside_layout),
+            ("--exclude-ranges", options.exclude_ranges.is_some()),
+            ("--partition-table", options.partition_table || options.partitions.is_some()),
+            ("--fs-aware", options.fs_aware),
+            ("--guestfs", options.guestfs),
+            ("--swap", options.swap != SwapMode::Keep),
+            ("--snapshot-lv", options.snapshot_lv),
+            ("--snapshot-vss", options.snapshot_vss),
+            ("--fsfreeze", !options.fsfreeze.is_empty()),
+            ("--qmp", options.qmp.is_some()),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can't be used with --empty", name)));
+        }
+    }
+    let signature_path = match (&options.sign_key, &options.signature, &options.output) {
+        (None, _, _) => None,
+        (Some(_), Some(path), _) => Some(PathBuf::from(path)),
+        (Some(_), None, Some(output)) => {
+            let mut path = output.clone();
+            path.push(".sig");
+            Some(PathBuf::from(path))
+        }
+        (Some(_), None, None) => {
+            return Err(Error::usage("--signature is required to sign an image written to stdout".to_owned()));
+        }
     };
-    let layout = args.next();
-    if let Some(_) = args.next() {
-        eprintln!("Too many arguments");
-        std::process::exit(2);
+
+    // Inputs of unknown size, including compressed files, are either copied
+    // to a temporary file, or read sequentially; given --input-size, they are
+    // read in order below
+    let compressed = decompress::file_compression(&options.input)
+        .map_err(|e| Error::new(Failure::Input, format!("Error opening input file: {}", e)))?
+        .is_some();
+    if (input::is_stream(&options.input) || compressed) && (options.spool || options.input_size.is_none()) {
+        if !options.spool {
+            return run_stream(options);
+        }
+        let directory = options.spool_dir.as_ref().map_or_else(std::env::temp_dir, PathBuf::from);
+        let spool = spool::Spool::create(open_stream(&options.input)?, &directory, options.spool_max)
+            .map_err(|e| format!("Error spooling input: {}", e))?;
+        message!("Spooled {} bytes to {}", spool.size(), spool.path().display());
+        if options.source_id.is_none() {
+            options.source_id = Some(options.input.to_string_lossy().into_owned());
+        }
+        options.input = spool.path().into();
+        return run(options);
     }
 
+    // Ask for the passphrase before anything is shown or written
+    let sign_passphrase = match (&options.sign_key, &options.sign_passphrase) {
+        (Some(key), Some(source)) => Some(
+            secret::Secret::read(source, &format!("Passphrase for {}: ", key))
+                .map_err(|e| format!("Error reading the passphrase: {}", e))?,
+        ),
+        _ => None,
+    };
+
+    // Map RBD images to a local device
+    let rbd_image = match options.input.to_str().and_then(|i| i.strip_prefix("rbd:")) {
+        Some(spec) => {
+            let image = rbd::MappedImage::map(spec, options.rbd_nbd)
+                .map_err(|e| Error::new(Failure::Input, format!("Error mapping RBD image: {}", e)))?;
+            message!("Mapped RBD image {} to {}", spec, image.device.display());
+            Some(image)
+        }
+        None => None,
+    };
+
+    // Snapshot logical volumes
+    let lv_snapshot = if options.snapshot_lv {
+        if rbd_image.is_some() {
+            return Err(Error::usage("--snapshot-lv can't be used with RBD images".to_owned()));
+        }
+        let snapshot = lvm::Snapshot::create(&options.input, options.snapshot_size.as_deref())
+            .map_err(|e| format!("Error creating LVM snapshot: {}", e))?;
+        message!("Created snapshot {}", snapshot.name());
+        Some(snapshot)
+    } else {
+        None
+    };
+
+    // Snapshot Windows volumes
+    let vss_snapshot = if options.snapshot_vss {
+        if rbd_image.is_some() || lv_snapshot.is_some() {
+            return Err(Error::usage("--snapshot-vss can't be used with RBD images or --snapshot-lv".to_owned()));
+        }
+        let snapshot = vss::ShadowCopy::create(&options.input)
+            .map_err(|e| Error::new(Failure::Input, format!("Error creating shadow copy: {}", e)))?;
+        message!("Created shadow copy {}", snapshot.id());
+        Some(snapshot)
+    } else {
+        None
+    };
+
+    // Export the disk of a running QEMU along with its dirty bitmap
+    let qmp_export = match &options.qmp {
+        Some(socket) => {
+            if rbd_image.is_some() || lv_snapshot.is_some() || vss_snapshot.is_some() {
+                return Err(Error::usage("--qmp can't be used with RBD images, --snapshot-lv or --snapshot-vss".to_owned()));
+            }
+            if options.ebs_snapshot {
+                return Err(Error::usage("--qmp can't be used with --ebs-snapshot".to_owned()));
+            }
+            let Some(bitmap) = &options.bitmap else {
+                return Err(Error::usage("--qmp requires --bitmap".to_owned()));
+            };
+            let node = options.input.to_str().ok_or("Invalid node name")?;
+            let export = qmp::IncrementalExport::start(Path::new(socket), node, bitmap)
+                .map_err(|e| format!("Error exporting disk from QEMU: {}", e))?;
+            message!("Exported {} with dirty bitmap {}", node, bitmap);
+            Some(export)
+        }
+        None => None,
+    };
+
+    let input_path = match (&rbd_image, &lv_snapshot, &vss_snapshot) {
+        (Some(image), _, _) => image.device.as_os_str(),
+        (None, Some(snapshot), _) => snapshot.device.as_os_str(),
+        (None, None, Some(snapshot)) => snapshot.device.as_os_str(),
+        (None, None, None) => options.input.as_os_str(),
+    };
+
     // Open input
-    let (input, input_size) = match std::fs::File::open(input)
-        .and_then(|f| get_file_size(&f).map(|s| (f, s)))
-    {
-        Ok(o) => o,
-        Err(e) => {
-            eprintln!("Error opening input file: {}", e);
-            std::process::exit(1);
+    let nbd_address = match &qmp_export {
+        Some(export) => Some(export.address()),
+        None => options.input.to_str().and_then(nbd::Address::parse).transpose()?,
+    };
+    let mut dirty_extents = None;
+    let (mut input, input_size) = match (&nbd_address, options.empty) {
+        // Nothing is read to create an empty image
+        (_, Some(size)) => (Input::Stream(input::ForwardReader::new(Box::new(std::io::empty()), size)), size),
+        (Some(_), None) if options.input_size.is_some() => {
+            return Err(Error::usage("--input-size can't be used with NBD exports, the size is read from the server".to_owned()));
+        }
+        (Some(_), None) if options.input_member.is_some() => {
+            return Err(Error::usage("--input-member can't be used with NBD exports".to_owned()));
         }
+        (Some(address), None) => {
+            let mut client = nbd::Client::connect(address, qmp_export.as_ref().map(|e| e.bitmap()))
+                .map_err(|e| Error::new(Failure::Input, format!("Error connecting to NBD server: {}", e)))?;
+            if qmp_export.is_some() {
+                let extents = client.dirty_extents()
+                    .map_err(|e| format!("Error querying dirty bitmap: {}", e))?;
+                let dirty_bytes: u64 = extents.iter().map(|r| r.end - r.start).sum();
+                message!("Dirty bitmap marks {} bytes in {} extents", dirty_bytes, extents.len());
+                dirty_extents = Some(extents);
+            }
+            let size = client.size();
+            (Input::Nbd(client), size)
+        }
+        (None, None) => match options.input_size {
+            Some(size) if input::is_stream(input_path) || compressed => {
+                let stream = input::ForwardReader::new(open_stream(input_path)?, size);
+                (Input::Stream(stream), size)
+            }
+            Some(_) if options.input_member.is_some() => {
+                return Err(Error::usage("--input-member can't be used with an input read in one pass, the archive has to be spooled (--spool)".to_owned()));
+            }
+            _ => open_file_input(input_path, options.input_member.as_deref(), options.input_format, options.input_size, options.unsafe_backing_paths)
+                .map_err(|e| Error::new(Failure::Input, format!("Error opening input file: {}", e)))?,
+        },
+    };
+    match options.empty {
+        Some(_) => message!("Creating an empty disk of {} bytes", input_size),
+        None => message!("Input is {} bytes", input_size),
+    }
+    report::set("input", serde_json::json!({
+        "path": options.input.to_string_lossy(),
+        "size": input_size,
+    }));
+
+    // Freeze mounted filesystems
+    let mut frozen = Vec::new();
+    for mountpoint in &options.fsfreeze {
+        let filesystem = fsfreeze::FrozenFilesystem::freeze(Path::new(mountpoint))
+            .map_err(|e| format!("Error freezing {}: {}", Path::new(mountpoint).display(), e))?;
+        message!("Froze {}", filesystem.mountpoint().display());
+        frozen.push(filesystem);
+    }
+
+    let layout_started = Instant::now();
+
+    // Read partition table
+    let partition_table = if options.partition_table || options.partitions.is_some() || options.fs_aware || options.swap != SwapMode::Keep {
+        progress::set_phase("Reading partition table");
+        partition::read_partition_table(&mut input, input_size)
+            .map_err(|e| Error::new(Failure::Read, format!("Error reading partition table: {}", e)))?
+    } else {
+        None
     };
-    eprintln!("Input is {} bytes", input_size);
+    if let Some(table) = &partition_table {
+        for p in &table.partitions {
+            message!(
+                "Partition {}: type {}, {} bytes at offset {}",
+                p.number, p.partition_type, p.range.end - p.range.start, p.range.start,
+            );
+        }
+    }
 
     // Read layout
-    let layout = match layout {
-        Some(arg) => match load_layout_file(Path::new(&arg)) {
-            Ok(l) => l,
-            Err(e) => {
-                eprintln!("Error reading layout file: {}", e);
-                std::process::exit(1);
+    progress::set_phase("Reading layout");
+    let mut sources: Vec<(Box<dyn ExtentSource + '_>, Failure)> = Vec::new();
+    // Entries are rounded out to clusters if whole clusters are copied (or
+    // to blocks for a tar member), with a warning only if they are not
+    // aligned to sectors; ddrescue and partclone blocks can be smaller, they
+    // are rounded out silently
+    let sector_layout = matches!(options.layout_format, LayoutFormat::Ddrescue | LayoutFormat::Partclone);
+    let alignment = if options.mask_outside_layout || sector_layout {
+        1
+    } else if options.format == OutputFormat::TarSparse {
+        tar::BLOCK_SIZE
+    } else {
+        qcow2::CLUSTER_SIZE
+    };
+    let mut layout_sources: Vec<Box<dyn ExtentSource + '_>> = Vec::new();
+    for path in &options.layouts {
+        layout_sources.push(Box::new(layout::LayoutFile {
+            path: path.into(),
+            format: options.layout_format,
+            input_size,
+            alignment,
+            strict: options.strict_layout,
+            out_of_range: options.out_of_range,
+        }));
+    }
+    if let Some(command) = &options.layout_cmd {
+        layout_sources.push(Box::new(layout::LayoutCommand {
+            command: command.clone(),
+            format: options.layout_format,
+            input_path: input_path.to_owned(),
+            input_size,
+            alignment,
+            strict: options.strict_layout,
+            out_of_range: options.out_of_range,
+        }));
+    }
+    let (layout, layout_source) = match (layout_sources.len(), &rbd_image) {
+        (1, _) => {
+            sources.push((layout_sources.remove(0), Failure::Layout));
+            (std::iter::once(0..input_size).collect(), if options.layout_cmd.is_some() { "command" } else { "file" })
+        }
+        (2.., _) => {
+            let merged = layout::Merged {
+                sources: layout_sources,
+                operation: options.layout_merge.unwrap_or(Operation::Union),
+            };
+            sources.push((Box::new(merged), Failure::Layout));
+            (std::iter::once(0..input_size).collect(), "merged")
+        }
+        (0, Some(image)) => (
+            image.allocated_extents()
+                .map_err(|e| format!("Error querying RBD image extents: {}", e))?,
+            "rbd",
+        ),
+        (0, None) => match dirty_extents {
+            Some(extents) => (extents, "dirty-bitmap"),
+            None if options.empty.is_some() => (Vec::new(), "empty"),
+            None => match input.data_extents()
+                .map_err(|e| format!("Error querying allocated extents of input: {}", e))?
+            {
+                Some(extents) => (extents, "input"),
+                None => (std::iter::once(0..input_size).collect(), "whole-input"),
+            },
+        },
+    };
+
+    // Holes and extents are only known for local files and devices
+    if input.as_file().is_none() {
+        let unsupported = [
+            ("--seek-hole", options.seek_hole),
+            ("--sparsify-mode fiemap", options.fiemap),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::usage(format!("{} can only be used with local files and devices", name)));
+        }
+    }
+
+    // Leave out holes reported by the filesystem or device
+    if options.seek_hole {
+        sources.push((Box::new(seek_hole::SeekHole { size: input_size, path: input_path }), Failure::Read));
+    }
+
+    // Leave out unallocated and unwritten extents of the file
+    if options.fiemap {
+        sources.push((Box::new(seek_hole::ExtentMap { size: input_size }), Failure::Read));
+    }
+
+    // Leave out space outside of partitions, or of the selected ones
+    if options.partition_table || options.partitions.is_some() {
+        match &partition_table {
+            Some(table) => {
+                let used = partition::UsedSpace {
+                    table,
+                    exclude_types: &options.exclude_partition_types,
+                    numbers: options.partitions.as_deref(),
+                    size: input_size,
+                };
+                sources.push((Box::new(used), Failure::Read));
+            }
+            None if options.partitions.is_some() => {
+                return Err(Error::new(Failure::Layout, "No partition table found to select --partitions from".to_owned()));
             }
+            None => message!("No partition table found, copying the whole layout"),
         }
-        None => vec![0..input_size],
+    }
+
+    // Leave out space marked free by filesystems
+    if options.fs_aware {
+        let regions = match &partition_table {
+            Some(table) => table.partitions.iter().map(|p| p.range.clone()).collect(),
+            None => std::iter::once(0..input_size).collect(),
+        };
+        sources.push((Box::new(fs::Filesystems { regions }), Failure::Read));
+    }
+
+    // Leave out the space libguestfs finds unused
+    if options.guestfs {
+        let Some(format) = input.format().qemu_name() else {
+            return Err(Error::usage("--guestfs can't be used with --input-format ntfsclone".to_owned()));
+        };
+        if nbd_address.is_some() || options.input_member.is_some() {
+            return Err(Error::usage("--guestfs can only be used with local files and devices".to_owned()));
+        }
+        sources.push((Box::new(guestfs::FreeSpace { input_path, format }), Failure::Read));
+    }
+
+    // Leave out the contents of swap areas
+    if options.swap != SwapMode::Keep {
+        let regions = match &partition_table {
+            Some(table) => table.partitions.iter().map(|p| (p.range.clone(), Some(&p.partition_type))).collect(),
+            None => vec![(0..input_size, None)],
+        };
+        let swap = fs::swap::SwapAreas { regions, keep_header: options.swap == SwapMode::Header };
+        sources.push((Box::new(swap), Failure::Read));
+    }
+
+    let mut layout = layout;
+    let mut zero_ranges = Vec::new();
+    // End of the data, past the input with --out-of-range extend
+    let mut layout_end = input_size;
+    for (mut source, failure) in sources {
+        if let Some(phase) = source.phase() {
+            progress::set_phase(phase);
+        }
+        let extents: Vec<Extent> = source.extents(&mut input)
+            .map_err(|e| Error::new(failure, format!("Error reading {}: {}", source.name(), e)))?
+            .collect();
+        zero_ranges.extend(extents.iter().filter(|e| e.kind == ExtentKind::Zero).map(|e| e.range.clone()));
+        layout_end = extents.iter().filter(|e| e.kind == ExtentKind::Data).map(|e| e.range.end).fold(layout_end, u64::max);
+        layout = layout::apply(layout, extents.into_iter());
+    }
+    let zero_ranges = layout::normalize(zero_ranges);
+
+    // Read what is left, to leave out the zeros
+    let layout = if options.scan_zeroes {
+        let used = ranges_memory(&layout) + qcow2::CLUSTER_SIZE;
+        let extents = match (read_threads(options.max_memory, used), input.as_file()) {
+            (threads, Some(file)) if threads > 1 => file.try_clone()
+                .and_then(|file| readahead::ReadAhead::new(file, input_size, &layout, threads))
+                .and_then(|reader| scan::nonzero_extents(reader, &layout)),
+            _ => scan::nonzero_extents(&mut input, &layout),
+        };
+        let extents = extents
+            .map_err(|e| Error::new(Failure::Read, format!("Error scanning input: {}", e)))?;
+        let data_bytes: u64 = extents.iter().map(|r| r.end - r.start).sum();
+        message!("Found {} bytes of non-zero data", data_bytes);
+        extents
+    } else {
+        layout
+    };
+
+    // Leave out the excluded ranges; they are also masked when writing, in
+    // case they only cover part of a cluster
+    let exclude_ranges = match &options.exclude_ranges {
+        Some(path) => {
+            let ranges = load_exclude_ranges(Path::new(path), input_size)
+                .map_err(|e| Error::new(Failure::Layout, format!("Error reading excluded ranges: {}", e)))?;
+            let excluded_bytes: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+            message!("Excluding {} bytes in {} ranges", excluded_bytes, ranges.len());
+            Some(ranges)
+        }
+        None => None,
+    };
+    let layout = match &exclude_ranges {
+        Some(ranges) => layout::subtract(layout, ranges.clone()),
+        None => layout,
+    };
+
+    let layout_time = layout_started.elapsed();
+    report::set("layout", serde_json::json!({
+        "source": layout_source,
+        "extents": layout.len(),
+        "bytes": layout.iter().map(|r| r.end - r.start).sum::<u64>(),
+    }));
+
+    if !options.fsfreeze_copy {
+        thaw(&mut frozen);
+    }
+
+    signals::check_cancelled().map_err(|e| e.to_string())?;
+
+    if options.bench {
+        let output = match &options.output {
+            Some(path) => {
+                // Nothing is kept, so nothing is replaced
+                let output = output::open(Path::new(path), 0, false, true)
+                    .map_err(|e| Error::new(Failure::Write, format!("Error opening output: {}", e)))?;
+                if output.is_device {
+                    return Err(Error::usage("--bench can't write to a block device".to_owned()));
+                }
+                Some(output)
+            }
+            None => None,
+        };
+        let benchmark = bench::Benchmark {
+            input_size,
+            layout: &layout,
+            layout_time,
+            input_path: input.as_file().map(|_| Path::new(input_path)),
+            output: output.as_ref().map(|o| &o.file),
+        };
+        let result = benchmark.run(&mut input);
+        if let (Some(output), Some(path)) = (output, &options.output) {
+            let _ = std::fs::remove_file(output.temp_path.as_deref().unwrap_or(Path::new(path)));
+        }
+        thaw(&mut frozen);
+        return result.map_err(|e| format!("Error running benchmark: {}", e).into());
+    }
+
+    if options.ebs_snapshot {
+        let blocks = ebs::blocks_for_layout(layout.iter().cloned())
+            .map_err(|e| Error::new(Failure::Layout, format!("Invalid layout: {}", e)))?;
+        let snapshot_id = ebs::upload_snapshot(input::MarkReadErrors(input), input_size, &blocks, options.ebs_description.as_deref())
+            .map_err(|e| copy_error(e, "Error uploading snapshot"))?;
+        thaw(&mut frozen);
+        report::set("ebs_snapshot", snapshot_id.as_str().into());
+        println!("{}", snapshot_id);
+        return Ok(());
+    }
+
+    if options.tui {
+        dashboard::set_layout(&layout, input_size);
+    }
+
+    let virtual_size = match options.virtual_size {
+        Some(size) if size < input_size => {
+            return Err(Error::usage(format!("--virtual-size can't be smaller than the input, which is {} bytes", input_size)));
+        }
+        Some(size) => size,
+        None => input_size,
+    };
+    if layout_end > virtual_size {
+        message!("Extending the virtual disk to {} bytes for the layout entries past the end of the input", layout_end);
+    }
+    let virtual_size = virtual_size.max(layout_end);
+    let virtual_size = match options.round_size {
+        Some(alignment) => virtual_size.checked_next_multiple_of(alignment)
+            .ok_or("The size of the disk is too large to be rounded to --round-size")?,
+        None => virtual_size,
     };
+    if options.format == OutputFormat::Qcow2 && virtual_size > qcow2::MAX_VIRTUAL_SIZE {
+        return Err(Error::usage(format!(
+            "The virtual size of {} bytes is larger than the qcow2 maximum of {} bytes",
+            virtual_size, qcow2::MAX_VIRTUAL_SIZE,
+        )));
+    }
 
     // Initialize writer
-    let qcow2_writer = StreamingQcow2Writer::new(input_size, layout.iter().cloned());
+    let now = creation_time(options.reproducible)?;
+    let mut image = match options.format {
+        OutputFormat::Qcow2 => {
+            let mut qcow2_writer = StreamingQcow2Writer::new(input_size, layout.iter().cloned())
+                .map_err(|e| Error::new(Failure::Layout, format!("Invalid layout: {}", e)))?;
+            qcow2_writer.set_virtual_size(virtual_size);
+            qcow2_writer.set_zero_ranges(&zero_ranges)
+                .map_err(|e| format!("Error: {}", e))?;
+            qcow2_writer.set_preallocation(options.preallocation);
+            if options.mask_outside_layout {
+                qcow2_writer.set_mask(layout::normalize(layout.clone()));
+            } else if let Some(ranges) = exclude_ranges {
+                qcow2_writer.set_mask(layout::subtract(std::iter::once(0..input_size).collect(), ranges));
+            }
+            if options.provenance {
+                let source = match options.source_id {
+                    Some(s) => s,
+                    None => options.input.to_string_lossy().into_owned(),
+                };
+                qcow2_writer.set_provenance(Provenance { source, created: now })
+                    .map_err(|e| format!("Error: {}", e))?;
+            }
+            if let Some(backing_file) = &options.backing_file {
+                let format = backing_format(backing_file, options.backing_format.as_deref(), options.output.as_deref())?;
+                qcow2_writer.set_backing_file(backing_file.clone())
+                    .and_then(|()| qcow2_writer.set_backing_format(format))
+                    .map_err(|e| format!("Error: {}", e))?;
+            }
+            Image::Qcow2(Box::new(qcow2_writer))
+        }
+        OutputFormat::TarSparse => {
+            Image::TarSparse(tar::SparseTarWriter::new(TAR_MEMBER_NAME.to_owned(), virtual_size, layout.iter().cloned(), now))
+        }
+    };
+    report::set("image", match &image {
+        Image::Qcow2(w) => serde_json::json!({
+            "format": "qcow2",
+            "virtual_size": w.virtual_size(),
+            "file_size": w.file_size(),
+            "data_clusters": w.data_clusters().len(),
+            "zero_clusters": w.zero_clusters().len(),
+        }),
+        Image::TarSparse(w) => serde_json::json!({
+            "format": "tar-sparse",
+            "virtual_size": virtual_size,
+            "file_size": w.file_size(),
+        }),
+    });
+    if let Some(path) = &options.output {
+        report::set("output", Path::new(path).display().to_string().into());
+    }
 
     // Write
-    let output = std::io::stdout().lock();
-    let mut output = std::io::BufWriter::new(output);
-    if let Err(e) = qcow2_writer.write_header(&mut output)
-        .and_then(|()| qcow2_writer.copy_data(input, &mut output))
-    {
-        eprintln!("Error writing data: {}", e);
-        std::process::exit(1);
+    progress::set_phase("Writing header");
+    let (output, buffer_size, temp_path) = match &options.output {
+        Some(path) => {
+            let output = match options.tape_block_size {
+                Some(_) => output::open_tape(Path::new(path), options.force),
+                None => output::open(Path::new(path), image.file_size(), options.discard, options.force),
+            };
+            let output = output
+                .map_err(|e| Error::new(Failure::Write, format!("Error opening output: {}", e)))?;
+            if output.is_device && encrypt {
+                return Err(Error::usage("Encrypted images can't be written to a block device".to_owned()));
+            }
+            if let (true, Some(temp_path)) = (options.keep_partial, &output.temp_path) {
+                message!("Writing to {}", temp_path.display());
+            }
+            // Write whole sectors to devices
+            let buffer_size = if output.is_device { output::DEVICE_BUFFER_SIZE } else { DEFAULT_BUFFER_SIZE };
+            (Some(output.file), buffer_size, output.temp_path)
+        }
+        None => (None, DEFAULT_BUFFER_SIZE, None),
+    };
+    // Kept to flush the output once written, after the writers are done
+    let sync_file = match &output {
+        Some(file) if options.fsync => Some(
+            file.try_clone()
+                .map_err(|e| Error::new(Failure::Write, format!("Error opening output: {}", e)))?,
+        ),
+        _ => None,
+    };
+    let mut upload = match &options.upload {
+        Some(UploadTarget::Glance(name)) => {
+            // The image is created with its hash, so it is written once to
+            // hash it, unless the input can only be read once
+            let sha512 = match &mut input {
+                Input::Stream(_) => None,
+                input => {
+                    progress::set_phase("Hashing image");
+                    let hash = glance::hash(|hasher| image.write(input::MarkReadErrors(input), hasher, None))
+                        .map_err(|e| copy_error(e, "Error hashing image"))?;
+                    progress::set_phase("Writing header");
+                    Some(hash)
+                }
+            };
+            Some(Upload::Glance(Box::new(
+                glance::Upload::start(name, sha512)
+                    .map_err(|e| Error::new(Failure::Write, format!("Error starting upload to Glance: {}", e)))?,
+            )))
+        }
+        Some(UploadTarget::Proxmox { storage, vmid, attach }) => Some(Upload::Proxmox(
+            proxmox::Upload::start(storage, *vmid, virtual_size)
+                .map_err(|e| Error::new(Failure::Write, format!("Error allocating Proxmox volume: {}", e)))?,
+            attach.clone(),
+        )),
+        Some(UploadTarget::Libvirt { connect, pool, name }) => Some(Upload::Libvirt(
+            libvirt::Upload::start(connect.as_deref(), pool, name, virtual_size)
+                .map_err(|e| Error::new(Failure::Write, format!("Error starting upload to libvirt: {}", e)))?,
+        )),
+        Some(UploadTarget::Tus { url, resume: false }) => Some(Upload::Tus(
+            tus::Upload::start(url, image.file_size())
+                .map_err(|e| Error::new(Failure::Write, format!("Error creating tus upload: {}", e)))?,
+        )),
+        Some(UploadTarget::Tus { url, resume: true }) => Some(Upload::Tus(
+            tus::Upload::resume(url, image.file_size())
+                .map_err(|e| Error::new(Failure::Write, format!("Error resuming tus upload: {}", e)))?,
+        )),
+        Some(UploadTarget::Nbd(address, uri)) => {
+            let client = nbd::Client::connect(address, None)
+                .map_err(|e| Error::new(Failure::Write, format!("Error connecting to NBD export: {}", e)))?;
+            if client.is_read_only() {
+                return Err(Error::new(Failure::Write, format!("NBD export {} is read-only", uri)));
+            }
+            if client.size() < image.file_size() {
+                return Err(Error::new(
+                    Failure::Write,
+                    format!("NBD export {} is {} bytes, the image needs {}", uri, client.size(), image.file_size()),
+                ));
+            }
+            Some(Upload::Nbd(Box::new(client), uri.clone()))
+        }
+        Some(UploadTarget::Http { url, method, headers, user }) => Some(Upload::Http(
+            http::Upload::start(url, *method, headers, user.as_deref())
+                .map_err(|e| Error::new(Failure::Write, format!("Error starting HTTP upload: {}", e)))?,
+        )),
+        None => None,
+    };
+    let mut partial_output = match &options.output {
+        Some(path) if !options.keep_partial => {
+            Some(PartialOutput { path: temp_path.as_deref().unwrap_or(Path::new(path)), completed: false })
+        }
+        _ => None,
+    };
+    let mut manifest = create_manifest(options.manifest.as_deref(), options.verify_after_write)?;
+    let mut partial_manifest = match &options.manifest {
+        Some(path) if !options.keep_partial => Some(PartialOutput { path: Path::new(path), completed: false }),
+        _ => None,
+    };
+    let mut partial_signature = match &signature_path {
+        Some(path) if !options.keep_partial => Some(PartialOutput { path, completed: false }),
+        _ => None,
+    };
+    let mut torrent = options.torrent.as_ref().map(|_| torrent::Torrent::new(image.file_size()));
+    let mut partial_torrent = match &options.torrent {
+        Some(path) if !options.keep_partial => Some(PartialOutput { path: Path::new(path), completed: false }),
+        _ => None,
+    };
+    let mut used = image.memory_usage() + ranges_memory(&layout) + buffer_size as u64 + 2 * buffer::size() as u64;
+    if options.direct_output {
+        used += output::DirectWriter::memory_usage();
+    }
+    if let (Image::Qcow2(qcow2_writer), true) = (&image, options.verify_after_write) {
+        used += manifest::memory_usage(qcow2_writer.data_clusters().len());
+    }
+    if let Some(block_size) = options.tape_block_size {
+        used += block_size;
+    }
+    if let Some(max_memory) = options.max_memory.filter(|&m| m < used) {
+        return Err(format!(
+            "The lists of clusters and the buffers need {} bytes, more than --max-memory {}",
+            used, max_memory,
+        ).into());
+    }
+    let threads = read_threads(options.max_memory, used);
+    if threads < readahead::threads() {
+        message!("Reading with {} thread{} to stay under --max-memory", threads, if threads == 1 { "" } else { "s" });
+    }
+    // Data going from a local file straight to a pipe or socket can be sent
+    // by the kernel, if nothing else has to see it
+    let sendfile_target = match (&image, &input) {
+        (Image::Qcow2(_), Input::File(_)) => {
+            let plain_output = !options.detect_zeroes && !encrypt && upload.is_none() && options.sign_key.is_none()
+                && torrent.is_none() && manifest.is_none() && !report::is_enabled() && options.iops_limit.is_none()
+                && options.write_timeout.is_none() && !options.direct_output && options.tape_block_size.is_none();
+            if plain_output { output::sendfile_target(output.as_ref()) } else { None }
+        }
+        _ => None,
+    };
+    let input = match (threads, input) {
+        (threads, Input::File(file)) if threads > 1 && sendfile_target.is_none() => {
+            let reader = readahead::ReadAhead::new(file, input_size, &image.read_ranges(&layout), threads)
+                .map_err(|e| Error::new(Failure::Input, format!("Error starting read-ahead threads: {}", e)))?;
+            Input::ReadAhead(reader)
+        }
+        (_, input) => input,
+    };
+    if let (Image::Qcow2(qcow2_writer), true, Some(file)) = (&mut image, options.detect_zeroes, &output) {
+        let mut output = std::io::BufWriter::with_capacity(buffer_size, file);
+        let zero_clusters = qcow2_writer.write_detect_zeroes(input::MarkReadErrors(input), &mut output, manifest.as_mut())
+            .map_err(|e| copy_error(e, "Error writing data"))?;
+        message!("Left out {} clusters of zeros", zero_clusters);
+        report::set("zero_clusters_dropped", zero_clusters.into());
+    } else if let (Image::Qcow2(qcow2_writer), Input::File(file), Some(target)) = (&image, &input, &sendfile_target) {
+        let mut header = std::io::BufWriter::with_capacity(buffer_size, target);
+        qcow2_writer.write_header(&mut header)
+            .and_then(|()| header.flush())
+            .and_then(|()| qcow2_writer.copy_data_sendfile(file, target))
+            .map_err(|e| copy_error(e, "Error writing data"))?;
+    } else {
+        let signer = match (&options.sign_key, &signature_path) {
+            (Some(key), Some(path)) => Some(
+                sign::Signer::start(key, path, sign_passphrase.as_ref())
+                    .map_err(|e| format!("Error starting gpg: {}", e))?,
+            ),
+            _ => None,
+        };
+        // With --write-timeout, the upload is written from a thread, which
+        // gives it back at the end
+        let timeout = options.write_timeout;
+        let (timed_upload, upload_thread) = match (timeout, upload.take()) {
+            (Some(timeout), Some(u)) => {
+                let (writer, thread) = output::TimeoutWriter::spawn(u, timeout)
+                    .map_err(|e| Error::new(Failure::Write, format!("Error starting output thread: {}", e)))?;
+                (Some(writer), Some(thread))
+            }
+            (_, u) => {
+                upload = u;
+                (None, None)
+            }
+        };
+        let (output, encryptor): (Box<dyn Write + '_>, _) = if encrypt {
+            // age writes the encrypted image to the output directly
+            let destination = match output {
+                Some(file) => Stdio::from(file),
+                None => Stdio::inherit(),
+            };
+            let (encryptor, stdin) = encrypt::Encryptor::start(&options.age_recipients, &options.age_recipients_files, destination)
+                .map_err(|e| format!("Error starting age: {}", e))?;
+            (timed_output(stdin, timeout)?, Some(encryptor))
+        } else {
+            match (timed_upload, &mut upload, output) {
+                (Some(writer), _, _) => (Box::new(writer), None),
+                (None, Some(upload), _) => (Box::new(upload), None),
+                (None, None, Some(file)) if options.direct_output => {
+                    let writer = output::DirectWriter::new(file)
+                        .map_err(|e| Error::new(Failure::Write, format!("Error opening output: {}", e)))?;
+                    (timed_output(writer, timeout)?, None)
+                }
+                (None, None, Some(file)) => match options.tape_block_size {
+                    Some(block_size) => (timed_output(output::BlockWriter::new(file, block_size as usize), timeout)?, None),
+                    None => (timed_output(file, timeout)?, None),
+                },
+                (None, None, None) if timeout.is_some() => (timed_output(std::io::stdout(), timeout)?, None),
+                (None, None, None) => (Box::new(std::io::stdout().lock()), None),
+            }
+        };
+        let output = sign::SignedOutput::new(output, signer);
+        let output = torrent::TorrentOutput::new(output, torrent.as_mut());
+        let mut output = std::io::BufWriter::with_capacity(buffer_size, report::DigestOutput::new(output));
+        image.write(input::MarkReadErrors(input), &mut output, manifest.as_mut())
+            .and_then(|()| output.into_inner().map_err(|e| e.into_error()))
+            .and_then(|output| output.into_inner().into_inner().finish())
+            .and_then(|()| encryptor.map_or(Ok(()), |e| e.finish()))
+            .map_err(|e| copy_error(e, "Error writing data"))?;
+        if let Some(thread) = upload_thread {
+            upload = Some(thread.join().map_err(|_| "Output thread failed")?);
+        }
+    }
+    if let Some(upload) = upload {
+        let image_id = upload.finish()
+            .map_err(|e| Error::new(Failure::Write, format!("Error uploading image: {}", e)))?;
+        report::set("upload", image_id.as_str().into());
+        println!("{}", image_id);
     }
+    let hashes = match manifest {
+        Some(manifest) => manifest.finish()
+            .map_err(|e| Error::new(Failure::Write, format!("Error writing manifest: {}", e)))?,
+        None => Vec::new(),
+    };
+    if let (Image::Qcow2(qcow2_writer), true, Some(path)) = (&image, options.verify_after_write, &options.output) {
+        verify_output(temp_path.as_deref().unwrap_or(Path::new(path)), qcow2_writer.virtual_size(), &hashes)?;
+    }
+    if let (Some(torrent), Some(path)) = (torrent, &options.torrent) {
+        // Name the file after the output, or else the torrent
+        let name = match &options.output {
+            Some(output) => Path::new(output).file_name(),
+            None => Path::new(path).file_stem(),
+        };
+        let name = name.map_or("disk".into(), |n| n.to_string_lossy());
+        let info_hash = torrent.finish(Path::new(path), &name, &options.torrent_trackers, now)
+            .map_err(|e| Error::new(Failure::Write, format!("Error writing torrent: {}", e)))?;
+        report::set("torrent_info_hash", info_hash.into());
+    }
+    if let Some(path) = &options.output {
+        complete_output(sync_file.as_ref(), temp_path.as_deref(), Path::new(path), options.force)?;
+    }
+    let partials = [&mut partial_output, &mut partial_manifest, &mut partial_signature, &mut partial_torrent];
+    for partial in partials.into_iter().flatten() {
+        partial.completed = true;
+    }
+    thaw(&mut frozen);
+
+    if let (Image::Qcow2(qcow2_writer), true, Some(path)) = (&image, options.info, &options.output) {
+        let info = qcow2_writer.info(&path.to_string_lossy());
+        println!("{}", serde_json::to_string_pretty(&info).unwrap());
+    }
+
+    // The copy is complete, start tracking changes for the next one
+    if let Some(export) = qmp_export {
+        export.commit()
+            .map_err(|e| format!("Error resetting dirty bitmap: {}", e))?;
+    }
+
+    Ok(())
 }
 
-fn load_layout_file(path: &Path) -> std::io::Result<Vec<Range<u64>>> {
-    use serde::Deserialize;
+/// Convert an input of unknown size, such as a pipe, reading it sequentially.
+///
+/// The header can only be written once all the data has been read, so the
+/// output has to be a regular file.
+fn run_stream(options: cli::Options) -> Result<(), Error> {
+    let unsupported = [
+        ("a layout file", !options.layouts.is_empty()),
+        ("--layout-cmd", options.layout_cmd.is_some()),
+        ("--input-format", options.input_format.is_some_and(|f| f != InputFormat::Raw)),
+        ("--input-member", options.input_member.is_some()),
+        ("--seek-hole", options.seek_hole),
+        ("--sparsify-mode fiemap", options.fiemap),
+        ("--exclude-ranges", options.exclude_ranges.is_some()),
+        ("--iops-limit", options.iops_limit.is_some()),
+        ("--bench", options.bench),
+        ("--sparsify-mode scan", options.scan_zeroes),
+        ("--partition-table", options.partition_table),
+        ("--partitions", options.partitions.is_some()),
+        ("--fs-aware", options.fs_aware),
+        ("--guestfs", options.guestfs),
+        ("--swap", options.swap != SwapMode::Keep),
+        ("--snapshot-lv", options.snapshot_lv),
+        ("--fsfreeze", !options.fsfreeze.is_empty()),
+        ("--qmp", options.qmp.is_some()),
+        ("--ebs-snapshot", options.ebs_snapshot),
+        ("--format", options.format != OutputFormat::Qcow2),
+        ("--preallocation", options.preallocation != Preallocation::Off),
+        ("--sign-key", options.sign_key.is_some()),
+        ("--torrent", options.torrent.is_some()),
+        ("--age-recipient", !options.age_recipients.is_empty() || !options.age_recipients_files.is_empty()),
+        ("--upload", options.upload.is_some()),
+        ("--direct-output", options.direct_output),
+        ("--tape-block-size", options.tape_block_size.is_some()),
+        ("--write-timeout", options.write_timeout.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(Error::usage(format!("{} can't be used with an input of unknown size", name)));
+    }
+    let Some(output_path) = &options.output else {
+        return Err(Error::usage("The input size is unknown, the output has to be a file (--output)".to_owned()));
+    };
+
+    let mut qcow2_writer = StreamingQcow2Writer::new(0, std::iter::empty())
+        .map_err(|e| format!("Error: {}", e))?;
+    if let Some(size) = options.virtual_size {
+        if size > qcow2::MAX_VIRTUAL_SIZE {
+            return Err(Error::usage(format!(
+                "The virtual size of {} bytes is larger than the qcow2 maximum of {} bytes",
+                size, qcow2::MAX_VIRTUAL_SIZE,
+            )));
+        }
+        qcow2_writer.set_virtual_size(size);
+    }
+    if let Some(alignment) = options.round_size {
+        qcow2_writer.set_size_alignment(alignment)
+            .map_err(|e| Error::usage(format!("Invalid value for --round-size: {}", e)))?;
+    }
+    if options.provenance {
+        let source = match options.source_id {
+            Some(s) => s,
+            None => options.input.to_string_lossy().into_owned(),
+        };
+        let created = creation_time(options.reproducible)?;
+        qcow2_writer.set_provenance(Provenance { source, created })
+            .map_err(|e| format!("Error: {}", e))?;
+    }
+    if let Some(backing_file) = &options.backing_file {
+        let format = backing_format(backing_file, options.backing_format.as_deref(), options.output.as_deref())?;
+        qcow2_writer.set_backing_file(backing_file.clone())
+            .and_then(|()| qcow2_writer.set_backing_format(format))
+            .map_err(|e| format!("Error: {}", e))?;
+    }
+
+    let input = open_stream(&options.input)?;
+    let output = output::open(Path::new(output_path), 0, options.discard, options.force)
+        .map_err(|e| Error::new(Failure::Write, format!("Error opening output: {}", e)))?;
+    if output.is_device {
+        return Err(Error::usage("The input size is unknown, the output can't be a block device".to_owned()));
+    }
+    let temp_path = output.temp_path;
+    if let (true, Some(temp_path)) = (options.keep_partial, &temp_path) {
+        message!("Writing to {}", temp_path.display());
+    }
+    let sync_file = if options.fsync {
+        let file = output.file.try_clone()
+            .map_err(|e| Error::new(Failure::Write, format!("Error opening output: {}", e)))?;
+        Some(file)
+    } else {
+        None
+    };
+    let mut partial_output = (!options.keep_partial)
+        .then(|| PartialOutput { path: temp_path.as_deref().unwrap_or(Path::new(output_path)), completed: false });
+    let mut manifest = create_manifest(options.manifest.as_deref(), options.verify_after_write)?;
+    let mut partial_manifest = match &options.manifest {
+        Some(path) if !options.keep_partial => Some(PartialOutput { path: Path::new(path), completed: false }),
+        _ => None,
+    };
+
+    let mut output = std::io::BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, output.file);
+    qcow2_writer.write_streamed(std::io::BufReader::new(input::MarkReadErrors(input)), &mut output, manifest.as_mut())
+        .map_err(|e| copy_error(e, "Error writing data"))?;
+    let hashes = match manifest {
+        Some(manifest) => manifest.finish()
+            .map_err(|e| Error::new(Failure::Write, format!("Error writing manifest: {}", e)))?,
+        None => Vec::new(),
+    };
+    if options.verify_after_write {
+        verify_output(temp_path.as_deref().unwrap_or(Path::new(output_path)), qcow2_writer.virtual_size(), &hashes)?;
+    }
+    complete_output(sync_file.as_ref(), temp_path.as_deref(), Path::new(output_path), options.force)?;
+    for partial in [&mut partial_output, &mut partial_manifest].into_iter().flatten() {
+        partial.completed = true;
+    }
+    message!("Input was {} bytes", qcow2_writer.input_size());
+    if options.virtual_size.is_some_and(|s| s < qcow2_writer.input_size()) {
+        message!("Warning: the input is larger than --virtual-size, the image has the size of the input");
+    }
+
+    if options.info {
+        let info = qcow2_writer.info(&output_path.to_string_lossy());
+        println!("{}", serde_json::to_string_pretty(&info).unwrap());
+    }
+
+    Ok(())
+}
+
+/// Create the manifest, if one is written, or if the hashes are needed to
+/// verify the image (--verify-after-write).
+fn create_manifest(path: Option<&std::ffi::OsStr>, verify: bool) -> Result<Option<manifest::Manifest>, Error> {
+    let mut manifest = match path {
+        Some(path) => manifest::Manifest::create(Path::new(path))
+            .map_err(|e| format!("Error creating manifest: {}", e))?,
+        None if verify => manifest::Manifest::in_memory(),
+        None => return Ok(None),
+    };
+    if verify {
+        manifest.keep_hashes();
+    }
+    Ok(Some(manifest))
+}
+
+/// Read the image back from the output file and compare it with the hashes of
+/// the data written, for --verify-after-write.
+fn verify_output(path: &Path, virtual_size: u64, hashes: &[manifest::ClusterHash]) -> Result<(), Error> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| Error::new(Failure::Write, format!("Error opening the image to verify it: {}", e)))?;
+    verify::verify(&file, virtual_size, hashes)
+        .map_err(|e| Error::new(Failure::Verify, format!("Error verifying the image: {}", e)))?;
+    message!("Verified {} data clusters", hashes.len());
+    report::set("verified", true.into());
+    Ok(())
+}
+
+/// Open an input of unknown size, `-` being stdin, decompressing it if it is
+/// compressed.
+fn open_stream(path: &std::ffi::OsStr) -> Result<Box<dyn Read + Send>, Error> {
+    let stream = if path == "-" {
+        decompress::open(std::io::stdin())
+    } else {
+        std::fs::File::open(path).and_then(decompress::open)
+    };
+    stream.map_err(|e| Error::new(Failure::Input, format!("Error opening input file: {}", e)))
+}
+
+/// Check an image, for the check subcommand, returning the exit status.
+fn check_image(path: &Path) -> i32 {
+    let report = match std::fs::File::open(path).and_then(|f| check::check(std::io::BufReader::new(f))) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error checking image: {}", e);
+            return 1;
+        }
+    };
+    for warning in &report.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    for error in &report.errors {
+        println!("ERROR {}", error);
+    }
+    println!(
+        "{}/{} clusters allocated",
+        report.allocated_clusters, report.guest_clusters,
+    );
+    if report.leaked_clusters > 0 {
+        println!("{} leaked clusters were found on the image.", report.leaked_clusters);
+    }
+    if !report.errors.is_empty() {
+        println!("{} errors were found on the image.", report.errors.len());
+        2
+    } else if report.leaked_clusters > 0 {
+        3
+    } else {
+        println!("No errors were found on the image.");
+        0
+    }
+}
+
+/// Cause of a failed conversion, which picks the exit status, so wrappers can
+/// tell a bad source apart from a full disk or a closed pipe.
+#[derive(Clone, Copy)]
+enum Failure {
+    Other,
+    /// Invalid or incompatible options
+    Usage,
+    /// The input can't be opened
+    Input,
+    /// The layout is invalid or can't be read
+    Layout,
+    /// Reading the input failed
+    Read,
+    /// Writing the output failed, including closed pipes and full disks
+    Write,
+    /// The output accepted no data for `--write-timeout`
+    Stalled,
+    /// The image read back for `--verify-after-write` doesn't match
+    Verify,
+}
+
+impl Failure {
+    fn exit_code(self) -> i32 {
+        match self {
+            Failure::Other => 1,
+            Failure::Usage => 2,
+            Failure::Input => 3,
+            Failure::Layout => 4,
+            Failure::Read => 5,
+            Failure::Write => 6,
+            Failure::Stalled => 7,
+            Failure::Verify => 8,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Other => "other",
+            Failure::Usage => "usage",
+            Failure::Input => "input",
+            Failure::Layout => "layout",
+            Failure::Read => "read",
+            Failure::Write => "write",
+            Failure::Stalled => "stalled",
+            Failure::Verify => "verify",
+        }
+    }
+}
+
+struct Error {
+    failure: Failure,
+    message: String,
+}
+
+impl Error {
+    fn new(failure: Failure, message: String) -> Error {
+        Error { failure, message }
+    }
+
+    fn usage(message: String) -> Error {
+        Error::new(Failure::Usage, message)
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::new(Failure::Other, message)
+    }
+}
 
-    #[derive(Deserialize)]
-    struct LayoutEntry {
-        offset: u64,
-        length: u64,
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        Error::new(Failure::Other, message.to_owned())
     }
+}
+
+/// Sort an error of the copy into reading the input or writing the output.
+fn copy_error(e: std::io::Error, context: &str) -> Error {
+    if input::ReadError::is_read_error(&e) {
+        Error::new(Failure::Read, format!("Error reading input: {}", e))
+    } else if e.kind() == std::io::ErrorKind::TimedOut {
+        Error::new(Failure::Stalled, format!("{}: {}", context, e))
+    } else {
+        Error::new(Failure::Write, format!("{}: {}", context, e))
+    }
+}
+
+/// Write to the output from a thread with `--write-timeout`, so a stalled
+/// output can be given up on.
+fn timed_output<'a, W: Write + Send + 'static>(writer: W, timeout: Option<Duration>) -> Result<Box<dyn Write + 'a>, Error> {
+    let Some(timeout) = timeout else {
+        return Ok(Box::new(writer));
+    };
+    let (writer, _) = output::TimeoutWriter::spawn(writer, timeout)
+        .map_err(|e| Error::new(Failure::Write, format!("Error starting output thread: {}", e)))?;
+    Ok(Box::new(writer))
+}
+
+/// Print the final record of `--errors-json`.
+fn print_error_record(failure: &str, exit_code: i32, message: &str) {
+    let record = serde_json::json!({"error": failure, "exit_code": exit_code, "message": message});
+    eprintln!("{}", record);
+}
+
+/// Number of threads to read the input with, within what `--max-memory`
+/// leaves after the memory already used.
+fn read_threads(max_memory: Option<u64>, used: u64) -> usize {
+    match max_memory {
+        Some(max_memory) => readahead::threads_within(max_memory.saturating_sub(used)),
+        None => readahead::threads(),
+    }
+}
+
+/// Memory used by a list of ranges, in bytes.
+fn ranges_memory(ranges: &Vec<Range<u64>>) -> u64 {
+    (ranges.capacity() * std::mem::size_of::<Range<u64>>()) as u64
+}
+
+fn finish_report(error: Option<&str>) {
+    if let Err(e) = report::finish(error) {
+        message!("Warning: failed to write report: {}", e);
+    }
+}
+
+/// Combine layout files, for the layout subcommand.
+fn combine_layouts(options: cli::LayoutOptions) -> Result<(), String> {
+    let mut layouts = Vec::with_capacity(options.layouts.len());
+    for path in &options.layouts {
+        let path = Path::new(path);
+        let layout = layout::Layout::open(path, options.format)
+            .map_err(|e| format!("Error reading layout {}: {}", path.display(), e))?;
+        layouts.push(layout);
+    }
+    let layout = options.operation.combine(layouts);
+    let result = match &options.output {
+        Some(path) => std::fs::File::create(path).and_then(|f| layout.write_json(std::io::BufWriter::new(f))),
+        None => layout.write_json(std::io::stdout().lock()),
+    };
+    result.map_err(|e| format!("Error writing layout: {}", e))
+}
+
+/// Create a test image, for the gen-fixture subcommand.
+fn gen_fixture(options: cli::GenFixtureOptions) -> Result<(), String> {
+    options.fixture.write(Path::new(&options.output))
+        .map_err(|e| format!("Error writing image: {}", e))?;
+    if let Some(path) = &options.layout {
+        options.fixture.write_layout(Path::new(path))
+            .map_err(|e| format!("Error writing layout: {}", e))?;
+    }
+    let data: u64 = options.fixture.layout().iter().map(|e| e.end - e.start).sum();
+    eprintln!("Wrote {} bytes of data in a {}-byte image", data, options.fixture.size);
+    Ok(())
+}
+
+/// Time to record in the image, in seconds since the Unix epoch.
+fn creation_time(reproducible: bool) -> Result<u64, String> {
+    if reproducible {
+        // Use the time set by the build system, if any
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(t) => t.parse().map_err(|_| format!("Invalid SOURCE_DATE_EPOCH: {}", t)),
+            Err(_) => Ok(0),
+        }
+    } else {
+        Ok(std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0))
+    }
+}
+
+/// Writer for the selected output format.
+enum Image {
+    Qcow2(Box<StreamingQcow2Writer>),
+    TarSparse(tar::SparseTarWriter),
+}
+
+impl Image {
+    fn file_size(&self) -> u64 {
+        match self {
+            Image::Qcow2(w) => w.file_size(),
+            Image::TarSparse(w) => w.file_size(),
+        }
+    }
+
+    /// Memory used by the writer, in bytes.
+    fn memory_usage(&self) -> u64 {
+        match self {
+            Image::Qcow2(w) => w.memory_usage(),
+            Image::TarSparse(w) => w.memory_usage(),
+        }
+    }
+
+    /// Ranges of the input that will be read, in order.
+    fn read_ranges(&self, layout: &[Range<u64>]) -> Vec<Range<u64>> {
+        match self {
+            // Whole clusters are read
+            Image::Qcow2(_) => layout::normalize(layout.iter()
+                .map(|r| r.start / qcow2::CLUSTER_SIZE * qcow2::CLUSTER_SIZE..r.end.next_multiple_of(qcow2::CLUSTER_SIZE))
+                .collect()),
+            Image::TarSparse(_) => layout.to_vec(),
+        }
+    }
+
+    fn write<R: Read + Seek + Send, W: Write>(&self, input: R, mut output: W, manifest: Option<&mut manifest::Manifest>) -> std::io::Result<()> {
+        match self {
+            Image::Qcow2(w) => {
+                w.write_header(&mut output)?;
+                w.copy_data(input, output, manifest)
+            }
+            Image::TarSparse(w) => w.write(input, output),
+        }
+    }
+}
+
+/// Destination the image is uploaded to instead of an output file.
+enum Upload {
+    Glance(Box<glance::Upload>),
+    Libvirt(libvirt::Upload),
+    /// Disk on a Proxmox VE storage, and the drive to attach it as
+    Proxmox(proxmox::Upload, Option<String>),
+    Tus(tus::Upload),
+    /// NBD export, and its URI
+    Nbd(Box<nbd::Client>, String),
+    Http(http::Upload),
+}
+
+impl Upload {
+    /// Complete the upload, returning the ID of the new image.
+    fn finish(self) -> std::io::Result<String> {
+        match self {
+            Upload::Glance(u) => u.finish(),
+            Upload::Libvirt(u) => u.finish(),
+            Upload::Proxmox(u, attach) => u.finish(attach.as_deref()),
+            Upload::Tus(u) => u.finish(),
+            Upload::Nbd(mut c, uri) => c.sync().map(|()| uri),
+            Upload::Http(u) => u.finish(),
+        }
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Upload::Glance(u) => u.write(buf),
+            Upload::Libvirt(u) => u.write(buf),
+            Upload::Proxmox(u, _) => u.write(buf),
+            Upload::Tus(u) => u.write(buf),
+            Upload::Nbd(c, _) => c.write(buf),
+            Upload::Http(u) => u.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Upload::Glance(u) => u.flush(),
+            Upload::Libvirt(u) => u.flush(),
+            Upload::Proxmox(u, _) => u.flush(),
+            Upload::Tus(u) => u.flush(),
+            Upload::Nbd(c, _) => c.flush(),
+            Upload::Http(u) => u.flush(),
+        }
+    }
+}
+
+/// Give the written output its final name, flushing it to disk first and its
+/// new directory entry after if `sync_file` is given (--fsync). An existing
+/// file is only replaced if `replace` is set (--force).
+fn complete_output(
+    sync_file: Option<&std::fs::File>,
+    temp_path: Option<&Path>,
+    path: &Path,
+    replace: bool,
+) -> Result<(), Error> {
+    if let Some(file) = sync_file {
+        file.sync_data()
+            .map_err(|e| Error::new(Failure::Write, format!("Error syncing output: {}", e)))?;
+    }
+    output::commit(temp_path, path, replace)
+        .map_err(|e| Error::new(Failure::Write, format!("Error renaming output: {}", e)))?;
+    if sync_file.is_some() && temp_path.is_some() {
+        output::sync_directory(path)
+            .map_err(|e| Error::new(Failure::Write, format!("Error syncing output directory: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// An output file that is removed on drop, unless it was completed.
+struct PartialOutput<'a> {
+    path: &'a Path,
+    completed: bool,
+}
+
+impl Drop for PartialOutput<'_> {
+    fn drop(&mut self) {
+        let is_file = std::fs::metadata(self.path).is_ok_and(|m| m.is_file());
+        if !self.completed && is_file {
+            message!("Removing partial output {}", self.path.display());
+            let _ = std::fs::remove_file(self.path);
+        }
+    }
+}
+
+fn thaw(frozen: &mut Vec<fsfreeze::FrozenFilesystem>) {
+    for filesystem in frozen.drain(..) {
+        message!("Thawing {}", filesystem.mountpoint().display());
+    }
+}
+
+/// Open a local input, or a member of the archive it is, `size` overriding
+/// the size of raw files.
+///
+/// Backing files of qcow2 images have to be in their directory, unless
+/// `any_backing_path` is set (--unsafe-backing-paths).
+fn open_file_input(
+    path: &std::ffi::OsStr,
+    member: Option<&str>,
+    format: Option<InputFormat>,
+    size: Option<u64>,
+    any_backing_path: bool,
+) -> std::io::Result<(Input, u64)> {
+    let mut file = std::fs::File::open(path)?;
+    let probed = format.is_none();
+    let (file, format) = match member {
+        None => {
+            // Only regular files are probed, devices are read as raw
+            let format = match format {
+                Some(format) => format,
+                None if size.is_none() && file.metadata()?.is_file() => detect_format(&mut file)?,
+                None => InputFormat::Raw,
+            };
+            if format == InputFormat::Raw {
+                let size = match size {
+                    Some(s) => s,
+                    None => input::get_file_size(&file)?,
+                };
+                return Ok((Input::File(file), size));
+            }
+            (input::FileRange::whole(file)?, format)
+        }
+        Some(name) => {
+            let member = archive::find_member(&mut file, name)?;
+            message!("Reading archive member {} ({} bytes at offset {})", name, member.stored_size, member.offset);
+            let mut range = input::FileRange::new(file, member.offset, member.stored_size);
+            if member.method == archive::Method::Deflated {
+                if format.is_some_and(|f| f != InputFormat::Raw) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "compressed zip members can only be read as raw images",
+                    ));
+                }
+                let size = size.unwrap_or(member.size);
+                let stream = Box::new(flate2::read::DeflateDecoder::new(range));
+                return Ok((Input::Stream(input::ForwardReader::new(stream, member.size)), size));
+            }
+            let format = match format {
+                Some(format) => format,
+                None if size.is_none() => detect_format(&mut range)?,
+                None => InputFormat::Raw,
+            };
+            (range, format)
+        }
+    };
+    match format {
+        InputFormat::Raw => {
+            let size = size.unwrap_or(file.size());
+            Ok((Input::FileRange(file), size))
+        }
+        InputFormat::Vmdk => {
+            let reader = vmdk::Reader::new(file)?;
+            let size = reader.size();
+            Ok((Input::Vmdk(reader), size))
+        }
+        InputFormat::Vhd => {
+            let reader = vhd::Reader::new(file)?;
+            let size = reader.size();
+            Ok((Input::Vhd(reader), size))
+        }
+        InputFormat::Vhdx => {
+            let reader = vhdx::Reader::new(file)?;
+            let size = reader.size();
+            Ok((Input::Vhdx(reader), size))
+        }
+        // The header of a file that was only probed could have been written
+        // by a guest, naming any file of the host as its backing file
+        InputFormat::Qcow2 if probed => {
+            let reader = qcow2::reader::Reader::new(file)?;
+            if reader.has_backing_file() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the input has a qcow2 header with a backing file, which is only read with \
+                     --input-format qcow2 (or use --input-format raw to copy the file as is)",
+                ));
+            }
+            let size = reader.size();
+            Ok((Input::Qcow2(reader), size))
+        }
+        InputFormat::Qcow2 => {
+            let reader = qcow2::reader::Reader::open(file, Path::new(path), any_backing_path)?;
+            let size = reader.size();
+            Ok((Input::Qcow2(reader), size))
+        }
+        InputFormat::Ntfsclone => {
+            let reader = ntfsclone::Reader::new(file)?;
+            let size = reader.size();
+            Ok((Input::Ntfsclone(reader), size))
+        }
+    }
+}
+
+/// Format of the --backing-file to record in the image: --backing-format, or
+/// else detected from the file, which readers look for next to the image.
+fn backing_format(name: &str, format: Option<&str>, output: Option<&std::ffi::OsStr>) -> Result<String, Error> {
+    if let Some(format) = format {
+        return Ok(format.to_owned());
+    }
+    let directory = output.and_then(|o| Path::new(o).parent()).unwrap_or(Path::new(""));
+    let path = directory.join(name);
+    let mut magic = [0u8; 4];
+    let read = std::fs::File::open(&path).and_then(|mut f| qcow2::read_full(&mut f, &mut magic))
+        .map_err(|e| Error::usage(format!(
+            "Can't read the backing file {} to detect its format, use --backing-format: {}",
+            path.display(), e,
+        )))?;
+    let format = if qcow2::reader::is_qcow2(&magic[..read]) { "qcow2" } else { "raw" };
+    message!("Recording the format of the backing file as {}", format);
+    Ok(format.to_owned())
+}
+
+/// Detect the format of a file input, warning the user if it is an image.
+fn detect_format<R: Read + Seek>(reader: R) -> std::io::Result<InputFormat> {
+    let format = input::detect_format(reader)?;
+    if format != InputFormat::Raw {
+        message!(
+            "Warning: reading the input as {}, guessed from its header (use --input-format to set it, \
+             raw to copy the file as is)",
+            format.name(),
+        );
+    }
+    Ok(format)
+}
 
+/// Read ranges to exclude, in the format of the layout, clipped to the input.
+fn load_exclude_ranges(path: &Path, input_size: u64) -> std::io::Result<Vec<Range<u64>>> {
     let file = std::fs::File::open(path)?;
-    let file = std::io::BufReader::new(file);
-    let entries: Vec<LayoutEntry> = serde_json::from_reader(file)?;
-    let entries = entries.iter().map(|e| e.offset..(e.offset + e.length)).collect();
-    Ok(entries)
+    let ranges = layout::read_json(std::io::BufReader::new(file))?;
+    Ok(layout::intersect(ranges, std::iter::once(0..input_size).collect()))
 }
@@ -0,0 +1,151 @@
+//! Manifest listing the SHA-256 hash of every data cluster written to the
+//! image, so it can be verified or compared without reading the source.
+//!
+//! The manifest is a JSON document:
+//!
+//! ```json
+//! {
+//! "cluster_size": 65536,
+//! "clusters": [
+//! {"guest": 0, "host": 262144, "sha256": "..."},
+//! ...
+//! ]
+//! }
+//! ```
+//!
+//! where `guest` is the offset of the cluster in the disk and `host` its
+//! offset in the qcow2 file. It is written as the image is, one line per
+//! cluster.
+//!
+//! The hashes can also be kept in memory, with or without a file, to verify
+//! the image once written (`--verify-after-write`).
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::qcow2::CLUSTER_SIZE;
+
+/// Hash of a cluster written to the image.
+pub struct ClusterHash {
+    /// Offset of the cluster in the disk
+    pub guest: u64,
+    /// Offset of the cluster in the qcow2 file
+    pub host: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Memory used to keep the hashes of this many clusters.
+pub fn memory_usage(clusters: u64) -> u64 {
+    clusters * std::mem::size_of::<ClusterHash>() as u64
+}
+
+pub struct Manifest {
+    writer: Option<BufWriter<File>>,
+    empty: bool,
+    /// Hashes kept in memory, if requested
+    hashes: Option<Vec<ClusterHash>>,
+}
+
+impl Manifest {
+    pub fn create(path: &Path) -> std::io::Result<Manifest> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "{{\n\"cluster_size\": {},\n\"clusters\": [", CLUSTER_SIZE)?;
+        Ok(Manifest {
+            writer: Some(writer),
+            empty: true,
+            hashes: None,
+        })
+    }
+
+    /// Only keep the hashes in memory, without writing a file.
+    pub fn in_memory() -> Manifest {
+        Manifest {
+            writer: None,
+            empty: true,
+            hashes: Some(Vec::new()),
+        }
+    }
+
+    /// Also keep the hashes in memory, returned by `finish()`.
+    pub fn keep_hashes(&mut self) {
+        self.hashes.get_or_insert_with(Vec::new);
+    }
+
+    /// Record the hash of a cluster.
+    pub fn add(&mut self, guest: u64, host: u64, data: &[u8]) -> std::io::Result<()> {
+        let sha256: [u8; 32] = Sha256::digest(data).into();
+        if let Some(hashes) = &mut self.hashes {
+            hashes.push(ClusterHash { guest, host, sha256 });
+        }
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        if !self.empty {
+            writer.write_all(b",")?;
+        }
+        self.empty = false;
+        write!(writer, "\n{{\"guest\": {}, \"host\": {}, \"sha256\": \"", guest, host)?;
+        for byte in sha256 {
+            write!(writer, "{:02x}", byte)?;
+        }
+        writer.write_all(b"\"}")
+    }
+
+    /// Terminate the document and flush it to disk, returning the hashes kept
+    /// in memory, if any.
+    pub fn finish(self) -> std::io::Result<Vec<ClusterHash>> {
+        if let Some(mut writer) = self.writer {
+            writer.write_all(b"\n]\n}\n")?;
+            writer.flush()?;
+        }
+        Ok(self.hashes.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qcow2::StreamingQcow2Writer;
+    use std::io::Cursor;
+
+    #[test]
+    fn small_layout() {
+        let size = 5 * CLUSTER_SIZE;
+        let disk: Vec<u8> = (0..size).map(|i| (i / CLUSTER_SIZE) as u8 + 1).collect();
+        let ranges = [100..200, 3 * CLUSTER_SIZE..3 * CLUSTER_SIZE + 1];
+        let writer = StreamingQcow2Writer::new(size, ranges.iter().cloned()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.manifest.json", std::process::id()));
+        let mut manifest = Manifest::create(&path).unwrap();
+        manifest.keep_hashes();
+        let mut image = Vec::new();
+        writer.write_header(&mut image).unwrap();
+        writer.copy_data(Cursor::new(&disk), &mut image, Some(&mut manifest)).unwrap();
+        let hashes = manifest.finish().unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Data clusters follow the header and metadata, in order
+        let first = image.len() as u64 - 2 * CLUSTER_SIZE;
+        let hash = |cluster: u64| -> String {
+            let data = &disk[(cluster * CLUSTER_SIZE) as usize..((cluster + 1) * CLUSTER_SIZE) as usize];
+            Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+        };
+        assert_eq!(document, serde_json::json!({
+            "cluster_size": CLUSTER_SIZE,
+            "clusters": [
+                {"guest": 0, "host": first, "sha256": hash(0)},
+                {"guest": 3 * CLUSTER_SIZE, "host": first + CLUSTER_SIZE, "sha256": hash(3)},
+            ],
+        }));
+
+        // The same hashes are kept in memory
+        let kept: Vec<_> = hashes.iter().map(|h| (h.guest, h.host, h.sha256)).collect();
+        let first_data = &image[first as usize..(first + CLUSTER_SIZE) as usize];
+        assert_eq!(kept[0], (0, first, Sha256::digest(first_data).into()));
+        assert_eq!(kept[1].0, 3 * CLUSTER_SIZE);
+        assert_eq!(kept.len(), 2);
+    }
+}
@@ -0,0 +1,1084 @@
+//! Minimal NBD client, used to read from exports served by qemu-nbd, nbdkit
+//! and the like, and to write images to them (`--upload nbd://`).
+//!
+//! Only the fixed newstyle handshake is supported. If the server supports
+//! it, the `base:allocation` metadata context is used to find holes, and
+//! QEMU's `qemu:dirty-bitmap:` contexts to find changed blocks.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::input::seek_position;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const OPT_SET_META_CONTEXT: u32 = 10;
+
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_META_CONTEXT: u32 = 4;
+const REP_FLAG_ERROR: u32 = 1 << 31;
+
+const INFO_EXPORT: u16 = 0;
+
+const TRANSMISSION_FLAG_READ_ONLY: u16 = 1 << 1;
+const TRANSMISSION_FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_BLOCK_STATUS: u16 = 7;
+
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+const STATE_ZERO: u32 = 1 << 1;
+const STATE_DIRTY: u32 = 1 << 0;
+
+/// Largest read issued at once, servers commonly reject more than 32 MiB
+const MAX_READ: usize = 32 << 20;
+
+/// Largest write request, the limit of most servers
+const MAX_WRITE: usize = 32 << 20;
+
+/// Most write requests sent before waiting for their replies
+const MAX_WRITES_IN_FLIGHT: usize = 16;
+
+/// Largest range queried at once for block status
+const MAX_BLOCK_STATUS: u64 = 1 << 30;
+
+/// Largest option reply accepted, they only hold names and small structures
+const MAX_OPTION_REPLY: u32 = 64 << 10;
+
+/// Largest reply chunk accepted: the data of a read with its offset, or the
+/// block status of `MAX_BLOCK_STATUS` bytes
+const MAX_CHUNK: u32 = MAX_READ as u32 + 8;
+
+const DEFAULT_PORT: u16 = 10809;
+
+enum Stream {
+    Tcp(std::net::TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Where to connect, parsed from an `nbd://` or `nbd+unix://` URI.
+pub struct Address {
+    target: Target,
+    export: String,
+}
+
+enum Target {
+    Tcp(String),
+    Unix(std::path::PathBuf),
+}
+
+impl Address {
+    /// Parse `nbd://host[:port][/export]` or
+    /// `nbd+unix:///[export]?socket=/path/to/socket`.
+    ///
+    /// Returns `None` if the string is not an NBD URI.
+    pub fn parse(uri: &str) -> Option<Result<Address, String>> {
+        if let Some(rest) = uri.strip_prefix("nbd://") {
+            let (host, export) = rest.split_once('/').unwrap_or((rest, ""));
+            if host.is_empty() {
+                return Some(Err(format!("Missing host in {}", uri)));
+            }
+            // Add the default port, unless one is given (IPv6 addresses
+            // are in brackets)
+            let has_port = match host.rfind(']') {
+                Some(i) => host[i..].contains(':'),
+                None => host.contains(':'),
+            };
+            let host = if has_port {
+                host.to_owned()
+            } else {
+                format!("{}:{}", host, DEFAULT_PORT)
+            };
+            return Some(Ok(Address {
+                target: Target::Tcp(host),
+                export: export.to_owned(),
+            }));
+        }
+        if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            #[cfg(unix)]
+            {
+                let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+                let export = path.strip_prefix('/').unwrap_or(path);
+                let socket = query.split('&').find_map(|p| p.strip_prefix("socket="));
+                let Some(socket) = socket else {
+                    return Some(Err(format!("Missing socket= in {}", uri)));
+                };
+                return Some(Ok(Address {
+                    target: Target::Unix(socket.into()),
+                    export: export.to_owned(),
+                }));
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = rest;
+                return Some(Err("Unix sockets are not supported on this platform".to_owned()));
+            }
+        }
+        None
+    }
+
+    pub fn unix(socket: std::path::PathBuf, export: String) -> Address {
+        Address {
+            target: Target::Unix(socket),
+            export,
+        }
+    }
+}
+
+/// A connection to an NBD export, readable, writable and seekable like a
+/// file.
+///
+/// Writes are pipelined, their replies are only waited for when too many are
+/// in flight, on `flush()` and before reading.
+pub struct Client {
+    stream: Stream,
+    size: u64,
+    transmission_flags: u16,
+    position: u64,
+    cookie: u64,
+    writes_in_flight: usize,
+    structured_replies: bool,
+    allocation_context: Option<u32>,
+    dirty_bitmap_context: Option<u32>,
+    /// Whether the handshake is over, requests can't be sent before
+    transmission: bool,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn errno_error(errno: u32) -> std::io::Error {
+    std::io::Error::other(format!("NBD server returned error {}", errno))
+}
+
+impl Client {
+    /// Connect to an export, optionally requesting the metadata context of
+    /// a QEMU dirty bitmap.
+    pub fn connect(address: &Address, dirty_bitmap: Option<&str>) -> std::io::Result<Client> {
+        let stream = match &address.target {
+            Target::Tcp(host) => {
+                let stream = std::net::TcpStream::connect(host)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            Target::Unix(path) => Stream::Unix(std::os::unix::net::UnixStream::connect(path)?),
+            #[cfg(not(unix))]
+            Target::Unix(path) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("can't connect to {}, Unix sockets are not supported on this platform", path.display()),
+                ));
+            }
+        };
+        Client::handshake(stream, &address.export, dirty_bitmap)
+    }
+
+    /// Negotiate the export over a connected stream.
+    fn handshake(mut stream: Stream, export: &str, dirty_bitmap: Option<&str>) -> std::io::Result<Client> {
+        if stream.read_u64::<BigEndian>()? != NBD_MAGIC {
+            return Err(invalid("not an NBD server"));
+        }
+        if stream.read_u64::<BigEndian>()? != IHAVEOPT {
+            return Err(invalid("NBD server doesn't support the newstyle handshake"));
+        }
+        let handshake_flags = stream.read_u16::<BigEndian>()?;
+        if handshake_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(invalid("NBD server doesn't support the fixed newstyle handshake"));
+        }
+        let client_flags = FLAG_FIXED_NEWSTYLE as u32 | (handshake_flags & FLAG_NO_ZEROES) as u32;
+        stream.write_u32::<BigEndian>(client_flags)?;
+
+        let mut client = Client {
+            stream,
+            size: 0,
+            transmission_flags: 0,
+            position: 0,
+            cookie: 0,
+            writes_in_flight: 0,
+            structured_replies: false,
+            allocation_context: None,
+            dirty_bitmap_context: None,
+            transmission: false,
+        };
+
+        // Structured replies are needed for block status
+        client.send_option(OPT_STRUCTURED_REPLY, &[])?;
+        let (reply, _) = client.read_option_reply(OPT_STRUCTURED_REPLY)?;
+        if reply == REP_ACK {
+            client.structured_replies = true;
+
+            let allocation_query = "base:allocation".to_owned();
+            let bitmap_query = dirty_bitmap.map(|b| format!("qemu:dirty-bitmap:{}", b));
+            let queries: Vec<&String> = std::iter::once(&allocation_query).chain(&bitmap_query).collect();
+            let mut data = Vec::new();
+            data.write_u32::<BigEndian>(export.len() as u32)?;
+            data.extend_from_slice(export.as_bytes());
+            data.write_u32::<BigEndian>(queries.len() as u32)?;
+            for query in queries {
+                data.write_u32::<BigEndian>(query.len() as u32)?;
+                data.extend_from_slice(query.as_bytes());
+            }
+            client.send_option(OPT_SET_META_CONTEXT, &data)?;
+            loop {
+                let (reply, data) = client.read_option_reply(OPT_SET_META_CONTEXT)?;
+                match reply {
+                    REP_META_CONTEXT if data.len() >= 4 => {
+                        let id = Some(u32::from_be_bytes(data[0..4].try_into().unwrap()));
+                        if data[4..] == *allocation_query.as_bytes() {
+                            client.allocation_context = id;
+                        } else if bitmap_query.as_ref().is_some_and(|q| data[4..] == *q.as_bytes()) {
+                            client.dirty_bitmap_context = id;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        // Select the export
+        let mut data = Vec::new();
+        data.write_u32::<BigEndian>(export.len() as u32)?;
+        data.extend_from_slice(export.as_bytes());
+        data.write_u16::<BigEndian>(0)?;
+        client.send_option(OPT_GO, &data)?;
+        let mut size = None;
+        loop {
+            let (reply, data) = client.read_option_reply(OPT_GO)?;
+            match reply {
+                REP_INFO if data.len() >= 10 && data[0..2] == INFO_EXPORT.to_be_bytes() => {
+                    size = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()));
+                    if let Some(flags) = data.get(10..12) {
+                        client.transmission_flags = u16::from_be_bytes(flags.try_into().unwrap());
+                    }
+                }
+                REP_ACK => break,
+                r if r & REP_FLAG_ERROR != 0 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("NBD server refused export {:?}", export),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        client.size = size.ok_or_else(|| invalid("NBD server didn't send the export size"))?;
+        client.transmission = true;
+
+        Ok(client)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.transmission_flags & TRANSMISSION_FLAG_READ_ONLY != 0
+    }
+
+    fn send_option(&mut self, option: u32, data: &[u8]) -> std::io::Result<()> {
+        let mut message = Vec::with_capacity(16 + data.len());
+        message.write_u64::<BigEndian>(IHAVEOPT)?;
+        message.write_u32::<BigEndian>(option)?;
+        message.write_u32::<BigEndian>(data.len() as u32)?;
+        message.extend_from_slice(data);
+        self.stream.write_all(&message)
+    }
+
+    fn read_option_reply(&mut self, option: u32) -> std::io::Result<(u32, Vec<u8>)> {
+        if self.stream.read_u64::<BigEndian>()? != OPTION_REPLY_MAGIC {
+            return Err(invalid("invalid NBD option reply"));
+        }
+        if self.stream.read_u32::<BigEndian>()? != option {
+            return Err(invalid("NBD option reply for the wrong option"));
+        }
+        let reply = self.stream.read_u32::<BigEndian>()?;
+        let length = self.stream.read_u32::<BigEndian>()?;
+        let data = self.read_payload(length, MAX_OPTION_REPLY)?;
+        Ok((reply, data))
+    }
+
+    /// Read the payload of a reply, whose length was sent by the server.
+    fn read_payload(&mut self, length: u32, max: u32) -> std::io::Result<Vec<u8>> {
+        if length > max {
+            return Err(invalid("NBD reply is too large"));
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    fn send_request(&mut self, command: u16, offset: u64, length: u32) -> std::io::Result<u64> {
+        self.cookie += 1;
+        let mut message = Vec::with_capacity(28);
+        message.write_u32::<BigEndian>(REQUEST_MAGIC)?;
+        message.write_u16::<BigEndian>(0)?;
+        message.write_u16::<BigEndian>(command)?;
+        message.write_u64::<BigEndian>(self.cookie)?;
+        message.write_u64::<BigEndian>(offset)?;
+        message.write_u32::<BigEndian>(length)?;
+        self.stream.write_all(&message)?;
+        Ok(self.cookie)
+    }
+
+    /// Read a chunk of a structured reply, returning its flags, type and
+    /// payload.
+    fn read_structured_chunk(&mut self, cookie: u64) -> std::io::Result<(u16, u16, Vec<u8>)> {
+        let flags = self.stream.read_u16::<BigEndian>()?;
+        let reply_type = self.stream.read_u16::<BigEndian>()?;
+        if self.stream.read_u64::<BigEndian>()? != cookie {
+            return Err(invalid("NBD reply for the wrong request"));
+        }
+        let length = self.stream.read_u32::<BigEndian>()?;
+        let payload = self.read_payload(length, MAX_CHUNK)?;
+        if reply_type & REPLY_TYPE_ERROR_BIT != 0 {
+            let errno = payload.get(0..4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+            return Err(errno_error(errno.unwrap_or(0)));
+        }
+        Ok((flags, reply_type, payload))
+    }
+
+    /// Read the reply to a command that returns no data, such as a write or
+    /// a flush.
+    ///
+    /// Several such commands can be in flight, so the reply may be to any of
+    /// them.
+    fn read_status_reply(&mut self) -> std::io::Result<()> {
+        let magic = self.stream.read_u32::<BigEndian>()?;
+        if magic == SIMPLE_REPLY_MAGIC {
+            let error = self.stream.read_u32::<BigEndian>()?;
+            if self.stream.read_u64::<BigEndian>()? > self.cookie {
+                return Err(invalid("NBD reply for the wrong request"));
+            }
+            if error != 0 {
+                return Err(errno_error(error));
+            }
+            return Ok(());
+        }
+        if magic != STRUCTURED_REPLY_MAGIC {
+            return Err(invalid("invalid NBD reply"));
+        }
+        loop {
+            let flags = self.stream.read_u16::<BigEndian>()?;
+            let reply_type = self.stream.read_u16::<BigEndian>()?;
+            if self.stream.read_u64::<BigEndian>()? > self.cookie {
+                return Err(invalid("NBD reply for the wrong request"));
+            }
+            let length = self.stream.read_u32::<BigEndian>()?;
+            let payload = self.read_payload(length, MAX_CHUNK)?;
+            if reply_type & REPLY_TYPE_ERROR_BIT != 0 {
+                let errno = payload.get(0..4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+                return Err(errno_error(errno.unwrap_or(0)));
+            }
+            if reply_type != REPLY_TYPE_NONE {
+                return Err(invalid("unexpected NBD reply chunk"));
+            }
+            if flags & REPLY_FLAG_DONE != 0 {
+                return Ok(());
+            }
+            if self.stream.read_u32::<BigEndian>()? != STRUCTURED_REPLY_MAGIC {
+                return Err(invalid("invalid NBD reply"));
+            }
+        }
+    }
+
+    /// Wait for the replies to all the writes in flight.
+    fn wait_for_writes(&mut self) -> std::io::Result<()> {
+        while self.writes_in_flight > 0 {
+            self.writes_in_flight -= 1;
+            self.read_status_reply()?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if self.writes_in_flight >= MAX_WRITES_IN_FLIGHT {
+            self.writes_in_flight -= 1;
+            self.read_status_reply()?;
+        }
+        self.send_request(CMD_WRITE, offset, data.len() as u32)?;
+        self.stream.write_all(data)?;
+        self.writes_in_flight += 1;
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        self.wait_for_writes()?;
+        let cookie = self.send_request(CMD_READ, offset, buffer.len() as u32)?;
+        match self.stream.read_u32::<BigEndian>()? {
+            SIMPLE_REPLY_MAGIC => {
+                let error = self.stream.read_u32::<BigEndian>()?;
+                if self.stream.read_u64::<BigEndian>()? != cookie {
+                    return Err(invalid("NBD reply for the wrong request"));
+                }
+                if error != 0 {
+                    return Err(errno_error(error));
+                }
+                self.stream.read_exact(buffer)
+            }
+            STRUCTURED_REPLY_MAGIC => loop {
+                let (flags, reply_type, payload) = self.read_structured_chunk(cookie)?;
+                // Part of the buffer a chunk is for
+                let chunk = |buffer: &mut [u8], length: u64| -> std::io::Result<Range<usize>> {
+                    let chunk_offset = u64::from_be_bytes(payload[0..8].try_into().unwrap());
+                    chunk_offset.checked_sub(offset)
+                        .and_then(|start| Some(start..start.checked_add(length)?))
+                        .filter(|range| range.end <= buffer.len() as u64)
+                        .map(|range| range.start as usize..range.end as usize)
+                        .ok_or_else(|| invalid("NBD read chunk out of range"))
+                };
+                match reply_type {
+                    REPLY_TYPE_OFFSET_DATA if payload.len() >= 8 => {
+                        let data = &payload[8..];
+                        let range = chunk(buffer, data.len() as u64)?;
+                        buffer[range].copy_from_slice(data);
+                    }
+                    REPLY_TYPE_OFFSET_HOLE if payload.len() >= 12 => {
+                        let length = u32::from_be_bytes(payload[8..12].try_into().unwrap());
+                        let range = chunk(buffer, length as u64)?;
+                        buffer[range].fill(0);
+                    }
+                    REPLY_TYPE_NONE => {}
+                    _ => return Err(invalid("unexpected NBD reply chunk")),
+                }
+                if flags & REPLY_FLAG_DONE != 0 {
+                    return Ok(());
+                }
+                // Further chunks of the reply
+                if self.stream.read_u32::<BigEndian>()? != STRUCTURED_REPLY_MAGIC {
+                    return Err(invalid("invalid NBD reply"));
+                }
+            },
+            _ => Err(invalid("invalid NBD reply")),
+        }
+    }
+
+    /// Wait for the writes to complete, and have the server flush them to
+    /// stable storage if it supports it.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.wait_for_writes()?;
+        if self.transmission_flags & TRANSMISSION_FLAG_SEND_FLUSH != 0 {
+            self.send_request(CMD_FLUSH, 0, 0)?;
+            self.read_status_reply()?;
+        }
+        Ok(())
+    }
+
+    /// Find the extents of the export that hold data, using the
+    /// `base:allocation` metadata context.
+    ///
+    /// Returns `None` if the server doesn't support it.
+    pub fn data_extents(&mut self) -> std::io::Result<Option<Vec<Range<u64>>>> {
+        let Some(context) = self.allocation_context else {
+            return Ok(None);
+        };
+        self.block_status_extents(context, |state| state & STATE_ZERO == 0).map(Some)
+    }
+
+    /// Find the extents of the export marked in the dirty bitmap requested
+    /// on connection.
+    pub fn dirty_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+        let Some(context) = self.dirty_bitmap_context else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "NBD server doesn't provide the dirty bitmap",
+            ));
+        };
+        self.block_status_extents(context, |state| state & STATE_DIRTY != 0)
+    }
+
+    /// Query block status over the whole export, returning the extents
+    /// whose state matches.
+    fn block_status_extents<F: Fn(u32) -> bool>(
+        &mut self,
+        context: u32,
+        selected: F,
+    ) -> std::io::Result<Vec<Range<u64>>> {
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        let mut offset = 0;
+        while offset < self.size {
+            let length = (self.size - offset).min(MAX_BLOCK_STATUS);
+            let cookie = self.send_request(CMD_BLOCK_STATUS, offset, length as u32)?;
+            let mut next_offset = offset;
+            loop {
+                if self.stream.read_u32::<BigEndian>()? != STRUCTURED_REPLY_MAGIC {
+                    return Err(invalid("invalid NBD block status reply"));
+                }
+                let (flags, reply_type, payload) = self.read_structured_chunk(cookie)?;
+                if reply_type == REPLY_TYPE_BLOCK_STATUS && payload.len() >= 4
+                    && payload[0..4] == context.to_be_bytes()
+                {
+                    for descriptor in payload[4..].chunks_exact(8) {
+                        let length = u32::from_be_bytes(descriptor[0..4].try_into().unwrap()) as u64;
+                        let state = u32::from_be_bytes(descriptor[4..8].try_into().unwrap());
+                        let end = next_offset.saturating_add(length).min(self.size);
+                        if selected(state) {
+                            match extents.last_mut() {
+                                Some(last) if last.end == next_offset => last.end = end,
+                                _ => extents.push(next_offset..end),
+                            }
+                        }
+                        next_offset = end;
+                    }
+                }
+                if flags & REPLY_FLAG_DONE != 0 {
+                    break;
+                }
+            }
+            if next_offset <= offset {
+                return Err(invalid("NBD block status made no progress"));
+            }
+            offset = next_offset;
+        }
+        Ok(extents)
+    }
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = (buf.len() as u64).min(self.size.saturating_sub(self.position)).min(MAX_READ as u64) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+        self.read_at(self.position, &mut buf[..length])?;
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.is_read_only() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "NBD export is read-only",
+            ));
+        }
+        let length = (buf.len() as u64).min(self.size.saturating_sub(self.position)).min(MAX_WRITE as u64) as usize;
+        if length == 0 && !buf.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("NBD export is too small, it is {} bytes", self.size),
+            ));
+        }
+        self.write_at(self.position, &buf[..length])?;
+        self.position += length as u64;
+        Ok(length)
+    }
+
+    /// Wait for the writes to complete.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.wait_for_writes()
+    }
+}
+
+impl Seek for Client {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.transmission {
+            let _ = self.send_request(CMD_DISC, 0, 0);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread::JoinHandle;
+
+    const REP_ERR_UNSUP: u32 = REP_FLAG_ERROR | 1;
+    const REP_ERR_UNKNOWN: u32 = REP_FLAG_ERROR | 6;
+    const INFO_FLAG_HAS_FLAGS: u16 = 1 << 0;
+    const EIO: u32 = 5;
+
+    /// What the fake server supports and how it misbehaves.
+    #[derive(Clone, Default)]
+    struct Options {
+        structured_replies: bool,
+        dirty_bitmap: bool,
+        read_only: bool,
+        flush: bool,
+        /// Reads and writes at or past this offset fail with EIO
+        fail_at: Option<u64>,
+    }
+
+    /// What the fake server saw, returned when the client disconnects.
+    struct Server {
+        disk: Vec<u8>,
+        flushes: usize,
+        largest_write_batch: usize,
+    }
+
+    /// Read a request, or return `None` if writes are held and none comes
+    /// shortly.
+    fn read_request(stream: &mut UnixStream, writes_held: bool) -> std::io::Result<Option<(u16, u64, u64, u32)>> {
+        let mut request = [0u8; 28];
+        if writes_held {
+            stream.set_read_timeout(Some(std::time::Duration::from_millis(50)))?;
+            let first = stream.read(&mut request[..1]);
+            stream.set_read_timeout(None)?;
+            match first {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+            stream.read_exact(&mut request[1..])?;
+        } else {
+            stream.read_exact(&mut request)?;
+        }
+        let mut request = &request[..];
+        assert_eq!(request.read_u32::<BigEndian>()?, REQUEST_MAGIC);
+        request.read_u16::<BigEndian>()?;
+        let command = request.read_u16::<BigEndian>()?;
+        let cookie = request.read_u64::<BigEndian>()?;
+        let offset = request.read_u64::<BigEndian>()?;
+        let length = request.read_u32::<BigEndian>()?;
+        Ok(Some((command, cookie, offset, length)))
+    }
+
+    fn option_reply(stream: &mut UnixStream, option: u32, reply: u32, data: &[u8]) {
+        stream.write_u64::<BigEndian>(OPTION_REPLY_MAGIC).unwrap();
+        stream.write_u32::<BigEndian>(option).unwrap();
+        stream.write_u32::<BigEndian>(reply).unwrap();
+        stream.write_u32::<BigEndian>(data.len() as u32).unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    fn simple_reply(stream: &mut UnixStream, cookie: u64, error: u32) {
+        stream.write_u32::<BigEndian>(SIMPLE_REPLY_MAGIC).unwrap();
+        stream.write_u32::<BigEndian>(error).unwrap();
+        stream.write_u64::<BigEndian>(cookie).unwrap();
+    }
+
+    fn chunk(stream: &mut UnixStream, cookie: u64, flags: u16, reply_type: u16, payload: &[u8]) {
+        stream.write_u32::<BigEndian>(STRUCTURED_REPLY_MAGIC).unwrap();
+        stream.write_u16::<BigEndian>(flags).unwrap();
+        stream.write_u16::<BigEndian>(reply_type).unwrap();
+        stream.write_u64::<BigEndian>(cookie).unwrap();
+        stream.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    fn error_chunk(stream: &mut UnixStream, cookie: u64, errno: u32) {
+        let mut payload = errno.to_be_bytes().to_vec();
+        payload.extend_from_slice(&0u16.to_be_bytes());
+        chunk(stream, cookie, REPLY_FLAG_DONE, REPLY_TYPE_ERROR_BIT | 1, &payload);
+    }
+
+    /// Serve `disk` as the export "disk" on one end of a socket pair.
+    ///
+    /// Blocks of 4 KiB that are all zeroes are reported and read as holes,
+    /// blocks past the first half are dirty.
+    fn serve(mut stream: UnixStream, mut disk: Vec<u8>, options: Options) -> std::io::Result<Server> {
+        stream.write_u64::<BigEndian>(NBD_MAGIC)?;
+        stream.write_u64::<BigEndian>(IHAVEOPT)?;
+        stream.write_u16::<BigEndian>(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)?;
+        assert_eq!(stream.read_u32::<BigEndian>()?, (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES) as u32);
+
+        let mut structured = false;
+        // Negotiation
+        loop {
+            assert_eq!(stream.read_u64::<BigEndian>()?, IHAVEOPT);
+            let option = stream.read_u32::<BigEndian>()?;
+            let mut data = vec![0u8; stream.read_u32::<BigEndian>()? as usize];
+            stream.read_exact(&mut data)?;
+            let mut data = &data[..];
+            match option {
+                OPT_STRUCTURED_REPLY if options.structured_replies => {
+                    structured = true;
+                    option_reply(&mut stream, option, REP_ACK, &[]);
+                }
+                OPT_SET_META_CONTEXT => {
+                    assert!(structured);
+                    let mut export = vec![0u8; data.read_u32::<BigEndian>()? as usize];
+                    data.read_exact(&mut export)?;
+                    assert_eq!(export, b"disk");
+                    for _ in 0..data.read_u32::<BigEndian>()? {
+                        let mut query = vec![0u8; data.read_u32::<BigEndian>()? as usize];
+                        data.read_exact(&mut query)?;
+                        let id: u32 = match &query[..] {
+                            b"base:allocation" => 1,
+                            b"qemu:dirty-bitmap:backup" if options.dirty_bitmap => 2,
+                            _ => continue,
+                        };
+                        let mut reply = id.to_be_bytes().to_vec();
+                        reply.extend_from_slice(&query);
+                        option_reply(&mut stream, option, REP_META_CONTEXT, &reply);
+                    }
+                    option_reply(&mut stream, option, REP_ACK, &[]);
+                }
+                OPT_GO => {
+                    let mut export = vec![0u8; data.read_u32::<BigEndian>()? as usize];
+                    data.read_exact(&mut export)?;
+                    if export != b"disk" {
+                        option_reply(&mut stream, option, REP_ERR_UNKNOWN, &[]);
+                        continue;
+                    }
+                    let mut flags = INFO_FLAG_HAS_FLAGS;
+                    if options.read_only {
+                        flags |= TRANSMISSION_FLAG_READ_ONLY;
+                    }
+                    if options.flush {
+                        flags |= TRANSMISSION_FLAG_SEND_FLUSH;
+                    }
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&(disk.len() as u64).to_be_bytes());
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    option_reply(&mut stream, option, REP_INFO, &info);
+                    option_reply(&mut stream, option, REP_ACK, &[]);
+                    break;
+                }
+                _ => option_reply(&mut stream, option, REP_ERR_UNSUP, &[]),
+            }
+        }
+
+        // Transmission
+        let mut server = Server { disk: Vec::new(), flushes: 0, largest_write_batch: 0 };
+        let mut pending_writes = Vec::new();
+        loop {
+            // Replies to writes are held until another command comes or the
+            // client waits for them, to check that it pipelines them
+            let request = read_request(&mut stream, !pending_writes.is_empty())?;
+            if request.is_none_or(|(command, ..)| command != CMD_WRITE) {
+                server.largest_write_batch = server.largest_write_batch.max(pending_writes.len());
+                for (cookie, error) in pending_writes.drain(..) {
+                    simple_reply(&mut stream, cookie, error);
+                }
+            }
+            let Some((command, cookie, offset, length)) = request else {
+                continue;
+            };
+            let range = offset as usize..(offset + length as u64) as usize;
+            let fails = options.fail_at.is_some_and(|f| range.end as u64 > f);
+            match command {
+                CMD_READ if fails && structured => error_chunk(&mut stream, cookie, EIO),
+                CMD_READ if fails => simple_reply(&mut stream, cookie, EIO),
+                CMD_READ if structured => {
+                    // One chunk per block, out of order
+                    let blocks: Vec<usize> = range.clone().step_by(4096).collect();
+                    for (i, &start) in blocks.iter().rev().enumerate() {
+                        let data = &disk[start..(start + 4096).min(range.end)];
+                        let mut payload = (start as u64).to_be_bytes().to_vec();
+                        let reply_type = if data.iter().all(|&b| b == 0) {
+                            payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                            REPLY_TYPE_OFFSET_HOLE
+                        } else {
+                            payload.extend_from_slice(data);
+                            REPLY_TYPE_OFFSET_DATA
+                        };
+                        let flags = if i == blocks.len() - 1 { REPLY_FLAG_DONE } else { 0 };
+                        chunk(&mut stream, cookie, flags, reply_type, &payload);
+                    }
+                }
+                CMD_READ => {
+                    simple_reply(&mut stream, cookie, 0);
+                    stream.write_all(&disk[range])?;
+                }
+                CMD_WRITE => {
+                    let mut data = vec![0u8; length as usize];
+                    stream.read_exact(&mut data)?;
+                    if !fails {
+                        disk[range].copy_from_slice(&data);
+                    }
+                    pending_writes.push((cookie, if fails { EIO } else { 0 }));
+                }
+                CMD_FLUSH => {
+                    server.flushes += 1;
+                    chunk(&mut stream, cookie, REPLY_FLAG_DONE, REPLY_TYPE_NONE, &[]);
+                }
+                CMD_BLOCK_STATUS => {
+                    // Report both contexts, the client picks the one it asked
+                    for (context, flags) in [(1u32, 0), (2, REPLY_FLAG_DONE)] {
+                        let mut payload = context.to_be_bytes().to_vec();
+                        for start in range.clone().step_by(4096) {
+                            let state = if context == 1 {
+                                if disk[start..start + 4096].iter().all(|&b| b == 0) { STATE_ZERO | 1 } else { 0 }
+                            } else {
+                                (start >= disk.len() / 2) as u32 * STATE_DIRTY
+                            };
+                            payload.extend_from_slice(&4096u32.to_be_bytes());
+                            payload.extend_from_slice(&state.to_be_bytes());
+                        }
+                        chunk(&mut stream, cookie, flags, REPLY_TYPE_BLOCK_STATUS, &payload);
+                    }
+                }
+                CMD_DISC => {
+                    server.disk = disk;
+                    return Ok(server);
+                }
+                _ => panic!("unexpected command {}", command),
+            }
+        }
+    }
+
+    fn disk() -> Vec<u8> {
+        // Data in blocks 0, 2 and 3 of 8
+        let mut disk = vec![0u8; 8 * 4096];
+        disk[..4096].fill(0xAA);
+        disk[2 * 4096..4 * 4096].iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        disk
+    }
+
+    fn start(
+        disk: Vec<u8>,
+        options: Options,
+        export: &str,
+        dirty_bitmap: Option<&str>,
+    ) -> (std::io::Result<Client>, JoinHandle<std::io::Result<Server>>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve(server, disk, options));
+        (Client::handshake(Stream::Unix(client), export, dirty_bitmap), server)
+    }
+
+    #[test]
+    fn structured_replies() {
+        let options = Options { structured_replies: true, dirty_bitmap: true, ..Default::default() };
+        let (client, server) = start(disk(), options, "disk", Some("backup"));
+        let mut client = client.unwrap();
+        assert_eq!(client.size(), 8 * 4096);
+        assert!(!client.is_read_only());
+        assert_eq!(client.data_extents().unwrap(), Some(vec![0..4096, 2 * 4096..4 * 4096]));
+        assert_eq!(client.dirty_extents().unwrap(), vec![4 * 4096..8 * 4096]);
+
+        // Reads assembled from data and hole chunks
+        let mut read = vec![0xFFu8; 6 * 4096];
+        client.seek(SeekFrom::Start(1024)).unwrap();
+        client.read_exact(&mut read[..5 * 4096]).unwrap();
+        assert!(read[..5 * 4096] == disk()[1024..1024 + 5 * 4096]);
+        // Past the end
+        client.seek(SeekFrom::Start(7 * 4096)).unwrap();
+        assert_eq!(client.read(&mut read).unwrap(), 4096);
+        assert_eq!(client.read(&mut read).unwrap(), 0);
+
+        drop(client);
+        assert!(server.join().unwrap().unwrap().disk == disk());
+    }
+
+    #[test]
+    fn simple_replies() {
+        let (client, server) = start(disk(), Options::default(), "disk", Some("backup"));
+        let mut client = client.unwrap();
+        assert_eq!(client.data_extents().unwrap(), None);
+        assert_eq!(client.dirty_extents().unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+
+        let mut read = Vec::new();
+        client.read_to_end(&mut read).unwrap();
+        assert!(read == disk());
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn write_and_flush() {
+        let options = Options { structured_replies: true, flush: true, ..Default::default() };
+        let (client, server) = start(vec![0u8; 64 * 4096], options, "disk", None);
+        let mut client = client.unwrap();
+
+        // Writes of one block, more than can be in flight at once
+        let data: Vec<u8> = (0..40 * 4096).map(|i| (i / 4096) as u8 + 1).collect();
+        client.seek(SeekFrom::Start(4096)).unwrap();
+        for block in data.chunks(4096) {
+            client.write_all(block).unwrap();
+        }
+        client.sync().unwrap();
+
+        // Reads wait for the writes
+        client.write_all(&[0xEE; 100]).unwrap();
+        let mut read = vec![0u8; 100];
+        client.seek(SeekFrom::Start(41 * 4096)).unwrap();
+        client.read_exact(&mut read).unwrap();
+        assert!(read == [0xEE; 100]);
+
+        // Writing past the end of the export
+        client.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(client.write(&[1; 100]).unwrap(), 10);
+        assert_eq!(client.write(&[1; 100]).unwrap_err().kind(), std::io::ErrorKind::WriteZero);
+        client.flush().unwrap();
+
+        drop(client);
+        let server = server.join().unwrap().unwrap();
+        assert_eq!(server.flushes, 1);
+        assert_eq!(server.largest_write_batch, MAX_WRITES_IN_FLIGHT);
+        assert!(server.disk[4096..41 * 4096] == data[..]);
+        assert!(server.disk[41 * 4096..41 * 4096 + 100] == [0xEE; 100]);
+        assert!(server.disk[64 * 4096 - 10..] == [1; 10]);
+    }
+
+    #[test]
+    fn no_flush() {
+        // Without flush support, sync only waits for the writes
+        let (client, server) = start(vec![0u8; 4096], Options::default(), "disk", None);
+        let mut client = client.unwrap();
+        client.write_all(&[1; 4096]).unwrap();
+        client.sync().unwrap();
+        drop(client);
+        let server = server.join().unwrap().unwrap();
+        assert_eq!(server.flushes, 0);
+        assert!(server.disk == [1; 4096]);
+    }
+
+    #[test]
+    fn error_replies() {
+        for structured_replies in [false, true] {
+            let options = Options { structured_replies, flush: true, fail_at: Some(4 * 4096), ..Default::default() };
+            let (client, server) = start(disk(), options, "disk", None);
+            let mut client = client.unwrap();
+
+            let mut read = vec![0u8; 4096];
+            client.seek(SeekFrom::Start(4 * 4096)).unwrap();
+            let err = client.read_exact(&mut read).unwrap_err();
+            assert_eq!(err.to_string(), "NBD server returned error 5");
+            // The connection is still usable
+            client.seek(SeekFrom::Start(0)).unwrap();
+            client.read_exact(&mut read).unwrap();
+            assert!(read == [0xAA; 4096]);
+
+            // Write errors are reported when waiting for the replies
+            client.seek(SeekFrom::Start(5 * 4096)).unwrap();
+            client.write_all(&[1; 4096]).unwrap();
+            let err = client.sync().unwrap_err();
+            assert_eq!(err.to_string(), "NBD server returned error 5");
+
+            drop(client);
+            assert!(server.join().unwrap().unwrap().disk == disk());
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let options = Options { read_only: true, ..Default::default() };
+        let (client, server) = start(disk(), options, "disk", None);
+        let mut client = client.unwrap();
+        assert!(client.is_read_only());
+        assert_eq!(client.write(&[0; 10]).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn unknown_export() {
+        let (client, server) = start(disk(), Options::default(), "other", None);
+        let err = client.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "NBD server refused export \"other\"");
+        // The server waits for another option, and sees the connection close
+        assert!(server.join().unwrap().is_err());
+    }
+
+    /// Run the handshake against a server that sends `data` and then
+    /// closes the connection.
+    fn handshake_with(data: Vec<u8>) -> std::io::Error {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            server.write_all(&data).unwrap();
+            server.shutdown(std::net::Shutdown::Write).unwrap();
+            // Drain what the client sends
+            let _ = std::io::copy(&mut server, &mut std::io::sink());
+        });
+        let err = Client::handshake(Stream::Unix(client), "disk", None).err().unwrap();
+        server.join().unwrap();
+        err
+    }
+
+    #[test]
+    fn invalid_handshakes() {
+        let greeting = |flags: u16| {
+            let mut data = Vec::new();
+            data.write_u64::<BigEndian>(NBD_MAGIC).unwrap();
+            data.write_u64::<BigEndian>(IHAVEOPT).unwrap();
+            data.write_u16::<BigEndian>(flags).unwrap();
+            data
+        };
+        let option_reply = |option: u32, reply: u32, length: u32| {
+            let mut data = greeting(FLAG_FIXED_NEWSTYLE);
+            data.write_u64::<BigEndian>(OPTION_REPLY_MAGIC).unwrap();
+            data.write_u32::<BigEndian>(option).unwrap();
+            data.write_u32::<BigEndian>(reply).unwrap();
+            data.write_u32::<BigEndian>(length).unwrap();
+            data
+        };
+
+        let cases = [
+            (b"NBDMAGIX".repeat(3), "not an NBD server"),
+            ([&NBD_MAGIC.to_be_bytes()[..], b"\0\0\x42\x02\x81\x86\x12\x53"].concat(), "NBD server doesn't support the newstyle handshake"),
+            (greeting(FLAG_NO_ZEROES), "NBD server doesn't support the fixed newstyle handshake"),
+            (option_reply(OPT_GO, REP_ACK, 0), "NBD option reply for the wrong option"),
+            // A length that would need a huge allocation
+            (option_reply(OPT_STRUCTURED_REPLY, REP_ACK, u32::MAX), "NBD reply is too large"),
+            (option_reply(OPT_STRUCTURED_REPLY, REP_ACK, 10), "failed to fill whole buffer"),
+            (greeting(FLAG_FIXED_NEWSTYLE)[..10].to_vec(), "failed to fill whole buffer"),
+        ];
+        for (data, message) in cases {
+            assert_eq!(handshake_with(data).to_string(), message);
+        }
+
+        // An export without a size
+        let mut data = option_reply(OPT_STRUCTURED_REPLY, REP_ERR_UNSUP, 0);
+        data.write_u64::<BigEndian>(OPTION_REPLY_MAGIC).unwrap();
+        data.write_u32::<BigEndian>(OPT_GO).unwrap();
+        data.write_u32::<BigEndian>(REP_ACK).unwrap();
+        data.write_u32::<BigEndian>(0).unwrap();
+        assert_eq!(handshake_with(data).to_string(), "NBD server didn't send the export size");
+    }
+}
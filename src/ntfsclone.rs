@@ -0,0 +1,271 @@
+//! Reader for the "special image" format of ntfsclone (`ntfsclone --save-image`).
+//!
+//! After the header, the image is a stream of records: a data record is a
+//! command byte followed by one cluster of the volume, and a gap record
+//! gives a number of unused clusters to skip. The records are indexed once
+//! when the image is opened, which only reads the command bytes.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::input::seek_position;
+
+const MAGIC: &[u8] = b"\0ntfsclone-image";
+
+const HEADER_SIZE: usize = 50;
+/// Offset of the image data in version 10.0 images, which don't record it
+const HEADER_SIZE_V10_0: u64 = 46;
+
+const MAJOR_VERSION: u8 = 10;
+
+const CMD_GAP: u8 = 0;
+const CMD_NEXT: u8 = 1;
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Check whether the start of a file is an ntfsclone image header.
+pub fn is_ntfsclone(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+/// Consecutive clusters of the volume stored in consecutive records.
+struct Run {
+    /// First cluster
+    cluster: u64,
+    count: u64,
+    /// Offset of the data of the first cluster in the image
+    offset: u64,
+}
+
+/// Reads the volume saved in an ntfsclone image.
+pub struct Reader<R: Read + Seek> {
+    inner: R,
+    size: u64,
+    cluster_size: u64,
+    runs: Vec<Run>,
+    position: u64,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+        let mut header = [0u8; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        if !is_ntfsclone(&header) {
+            return Err(invalid("not an ntfsclone image"));
+        }
+        if header[16] != MAJOR_VERSION {
+            return Err(invalid("unsupported ntfsclone image version"));
+        }
+        let cluster_size = LittleEndian::read_u32(&header[18..22]) as u64;
+        let size = LittleEndian::read_i64(&header[22..30]);
+        let nr_clusters = LittleEndian::read_i64(&header[30..38]);
+        let data_offset = match header[17] {
+            0 => HEADER_SIZE_V10_0,
+            _ => LittleEndian::read_u32(&header[46..50]) as u64,
+        };
+        if cluster_size == 0 || !cluster_size.is_power_of_two() || size < 0 || nr_clusters < 0 {
+            return Err(invalid("invalid ntfsclone image header"));
+        }
+        let size = size as u64;
+        let nr_clusters = nr_clusters as u64;
+        // The backup boot sector may be past the last cluster
+        let total_clusters = size.div_ceil(cluster_size);
+
+        // Index the records
+        let file_size = inner.seek(SeekFrom::End(0))?;
+        let mut runs: Vec<Run> = Vec::new();
+        let mut reader = BufReader::with_capacity(1 << 20, &mut inner);
+        reader.seek(SeekFrom::Start(data_offset))?;
+        let mut offset = data_offset;
+        let mut cluster = 0;
+        while cluster < total_clusters {
+            let mut command = [0u8; 1];
+            match reader.read_exact(&mut command) {
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && cluster >= nr_clusters => break,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(invalid("ntfsclone image is truncated"));
+                }
+                r => r?,
+            }
+            offset += 1;
+            match command[0] {
+                CMD_GAP => {
+                    let mut count = [0u8; 8];
+                    reader.read_exact(&mut count)?;
+                    offset += 8;
+                    let count = LittleEndian::read_i64(&count);
+                    cluster = u64::try_from(count).ok()
+                        .and_then(|count| cluster.checked_add(count))
+                        .filter(|&end| end <= total_clusters)
+                        .ok_or_else(|| invalid("invalid ntfsclone gap"))?;
+                }
+                CMD_NEXT => {
+                    if file_size - offset < cluster_size {
+                        return Err(invalid("ntfsclone image is truncated"));
+                    }
+                    match runs.last_mut() {
+                        Some(run) if run.cluster + run.count == cluster
+                            && run.offset + run.count * (cluster_size + 1) == offset =>
+                        {
+                            run.count += 1;
+                        }
+                        _ => runs.push(Run { cluster, count: 1, offset }),
+                    }
+                    reader.seek_relative(cluster_size as i64)?;
+                    offset += cluster_size;
+                    cluster += 1;
+                }
+                _ => return Err(invalid("invalid ntfsclone record")),
+            }
+        }
+        drop(reader);
+
+        Ok(Reader {
+            inner,
+            size,
+            cluster_size,
+            runs,
+            position: 0,
+        })
+    }
+
+    /// Size of the volume.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Ranges of the volume that are stored in the image.
+    pub fn data_extents(&self) -> Vec<Range<u64>> {
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        for run in &self.runs {
+            // Runs are within the volume, which is smaller than 2^63 bytes,
+            // so this can't overflow
+            let start = run.cluster * self.cluster_size;
+            let end = ((run.cluster + run.count) * self.cluster_size).min(self.size);
+            if start >= end {
+                continue;
+            }
+            match extents.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => extents.push(start..end),
+            }
+        }
+        extents
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let cluster = self.position / self.cluster_size;
+        let offset_in_cluster = self.position % self.cluster_size;
+        let length = (buf.len() as u64)
+            .min(self.cluster_size - offset_in_cluster)
+            .min(self.size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        let index = self.runs.partition_point(|r| r.cluster + r.count <= cluster);
+        match self.runs.get(index) {
+            Some(run) if run.cluster <= cluster => {
+                let record = cluster - run.cluster;
+                let offset = run.offset + record * (self.cluster_size + 1) + offset_in_cluster;
+                self.inner.seek(SeekFrom::Start(offset))?;
+                self.inner.read_exact(buf)?;
+            }
+            // Unused cluster
+            _ => buf.fill(0),
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const CLUSTER: usize = 512;
+
+    fn gap(count: i64) -> Vec<u8> {
+        let mut record = vec![CMD_GAP];
+        record.write_i64::<LittleEndian>(count).unwrap();
+        record
+    }
+
+    fn data(byte: u8) -> Vec<u8> {
+        let mut record = vec![CMD_NEXT];
+        record.extend_from_slice(&[byte; CLUSTER]);
+        record
+    }
+
+    /// An image of a volume of 8 clusters of 512 bytes, the data starting at
+    /// offset 56.
+    fn image(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut image = MAGIC.to_vec();
+        image.extend_from_slice(&[MAJOR_VERSION, 1]);
+        image.write_u32::<LittleEndian>(CLUSTER as u32).unwrap();
+        image.write_i64::<LittleEndian>(8 * CLUSTER as i64).unwrap();
+        image.write_i64::<LittleEndian>(8).unwrap();
+        image.write_i64::<LittleEndian>(3).unwrap();
+        image.write_u32::<LittleEndian>(56).unwrap();
+        image.resize(56, 0);
+        image.extend(records.iter().flatten());
+        image
+    }
+
+    #[test]
+    fn reads_records() {
+        let image = image(&[data(0x11), gap(2), data(0x22), data(0x33), gap(3)]);
+        let mut reader = Reader::new(Cursor::new(image)).unwrap();
+        assert_eq!(reader.size(), 8 * CLUSTER as u64);
+        assert_eq!(reader.data_extents(), vec![0..512, 1536..2560]);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        let mut expected = vec![0u8; 8 * CLUSTER];
+        expected[..512].fill(0x11);
+        expected[1536..2048].fill(0x22);
+        expected[2048..2560].fill(0x33);
+        assert!(read == expected);
+    }
+
+    #[test]
+    fn truncated() {
+        // Missing records
+        let err = Reader::new(Cursor::new(image(&[data(0x11), gap(1)]))).err().unwrap();
+        assert_eq!(err.to_string(), "ntfsclone image is truncated");
+
+        // A data cluster cut short
+        let mut image = image(&[data(0x11), gap(6), data(0x22)]);
+        image.truncate(image.len() - 100);
+        let err = Reader::new(Cursor::new(image)).err().unwrap();
+        assert_eq!(err.to_string(), "ntfsclone image is truncated");
+    }
+
+    #[test]
+    fn invalid_gaps() {
+        for records in [
+            vec![gap(-1)],
+            // Past the end of the volume
+            vec![gap(9)],
+            vec![data(0x11), gap(i64::MAX)],
+        ] {
+            let err = Reader::new(Cursor::new(image(&records))).err().unwrap();
+            assert_eq!(err.to_string(), "invalid ntfsclone gap");
+        }
+    }
+}
@@ -0,0 +1,532 @@
+//! Opening the output file, which can also be a block device.
+//!
+//! Regular files are written under a temporary name next to the output, and
+//! renamed once complete, so an interrupted conversion never leaves a
+//! truncated image under the final name.
+
+use std::ffi::OsString;
+use std::fs::{File, Metadata, OpenOptions};
+use std::hash::{BuildHasher, RandomState};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::input::get_file_size;
+use crate::qcow2::CLUSTER_SIZE;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+nix::ioctl_write_ptr_bad!(ioctl_blkdiscard, nix::request_code_none!(0x12, 119), [u64; 2]); // Defined in linux/fs.h
+
+/// Size of the writes to block devices, a multiple of any sector size.
+pub const DEVICE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Alignment of the buffer and writes of [`DirectWriter`], a multiple of any
+/// block size.
+const DIRECT_ALIGNMENT: usize = CLUSTER_SIZE as usize;
+
+pub struct Output {
+    pub file: File,
+    /// Whether this is a block device rather than a regular file
+    pub is_device: bool,
+    /// File written instead of the output, to rename with [`commit`]
+    pub temp_path: Option<PathBuf>,
+}
+
+/// Create a new file named `prefix`, random characters then `suffix`,
+/// trying other names if it already exists.
+///
+/// The name can't be guessed beforehand, so nothing can be planted there in
+/// a shared directory.
+pub fn create_unique(options: &OpenOptions, prefix: &Path, suffix: &str) -> std::io::Result<(PathBuf, File)> {
+    let mut options = options.clone();
+    options.create_new(true);
+    let mut attempt = 0;
+    loop {
+        let random = RandomState::new().hash_one((std::process::id(), attempt, SystemTime::now()));
+        let mut path = OsString::from(prefix);
+        path.push(format!("{:016x}{}", random, suffix));
+        let path = PathBuf::from(path);
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_device(metadata: &Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        let file_type = metadata.file_type();
+        file_type.is_block_device() || file_type.is_char_device()
+    }
+    #[cfg(not(unix))]
+    {
+        !metadata.is_file() && !metadata.is_dir()
+    }
+}
+
+/// Open the output, checking that devices are large enough for the image.
+///
+/// An existing regular file is only replaced if `force` is set. If `discard`
+/// is set, the whole device is discarded first, so it doesn't keep the
+/// previous data after the image.
+pub fn open(path: &Path, image_size: u64, discard: bool, force: bool) -> std::io::Result<Output> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(m) => Some(m),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if !metadata.as_ref().is_some_and(is_device) {
+        if discard {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "can only discard block devices",
+            ));
+        }
+        let mut options = OpenOptions::new();
+        options.write(true);
+        let (file, temp_path) = match &metadata {
+            // Pipes are written to directly
+            Some(m) if !m.is_file() => (options.open(path)?, None),
+            Some(_) if !force => return Err(already_exists()),
+            _ => {
+                // Written to PATH.<random>.tmp, never an existing file
+                let mut prefix = OsString::from(path);
+                prefix.push(".");
+                let (temp_path, file) = create_unique(&options, Path::new(&prefix), ".tmp")?;
+                (file, Some(temp_path))
+            }
+        };
+        return Ok(Output {
+            file,
+            is_device: false,
+            temp_path,
+        });
+    }
+
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let device_size = get_file_size(&file)?;
+    if device_size < image_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("device is too small, {} bytes needed but it has {}", image_size, device_size),
+        ));
+    }
+    if discard {
+        discard_device(&file, device_size)?;
+    }
+    Ok(Output {
+        file,
+        is_device: true,
+        temp_path: None,
+    })
+}
+
+/// Open the output to write in fixed blocks: a tape drive, which has no size
+/// to check, or a file like [`open`] does.
+pub fn open_tape(path: &Path, force: bool) -> std::io::Result<Output> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_char_device()) {
+            return Ok(Output {
+                file: OpenOptions::new().write(true).open(path)?,
+                is_device: true,
+                temp_path: None,
+            });
+        }
+    }
+    open(path, 0, false, force)
+}
+
+/// Flush the directory entry of a file to disk, after it was created or
+/// renamed.
+#[cfg(unix)]
+pub fn sync_directory(path: &Path) -> std::io::Result<()> {
+    let directory = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+/// Flush the directory entry of a file to disk, which Windows does with the
+/// file itself.
+#[cfg(not(unix))]
+pub fn sync_directory(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn already_exists() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "the file already exists, use --force to replace it",
+    )
+}
+
+/// Give the complete output its final name.
+///
+/// Unless `replace` is set, this fails if a file was created under that name
+/// since the output was opened, rather than replacing it.
+pub fn commit(temp_path: Option<&Path>, path: &Path, replace: bool) -> std::io::Result<()> {
+    match temp_path {
+        Some(temp_path) if replace => std::fs::rename(temp_path, path),
+        Some(temp_path) => rename_noreplace(temp_path, path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => already_exists(),
+            _ => e,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn rename_noreplace(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        use nix::errno::Errno;
+        use nix::fcntl::{renameat2, RenameFlags};
+
+        match renameat2(None, from, None, to, RenameFlags::RENAME_NOREPLACE) {
+            // Not supported by this filesystem or kernel, use a link instead
+            Err(Errno::EINVAL | Errno::ENOSYS) => {}
+            r => return r.map_err(Into::into),
+        }
+    }
+    std::fs::hard_link(from, to)?;
+    std::fs::remove_file(from)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn discard_device(file: &File, size: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = [0, size];
+    unsafe {
+        ioctl_blkdiscard(file.as_raw_fd(), &range)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn discard_device(_file: &File, _size: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "discarding devices is only supported on Linux",
+    ))
+}
+
+/// The output to send the data to with `sendfile()`, if it is a pipe or a
+/// socket: the output file, or stdout if there is none (Linux only).
+#[cfg(target_os = "linux")]
+pub fn sendfile_target(output: Option<&File>) -> Option<File> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsFd;
+
+    let file = match output {
+        Some(file) => file.try_clone().ok()?,
+        None => File::from(std::io::stdout().as_fd().try_clone_to_owned().ok()?),
+    };
+    let file_type = file.metadata().ok()?.file_type();
+    (file_type.is_fifo() || file_type.is_socket()).then_some(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sendfile_target(_output: Option<&File>) -> Option<File> {
+    None
+}
+
+/// Output opened with O_DIRECT, so the image doesn't go through the page
+/// cache.
+///
+/// O_DIRECT only takes whole blocks at aligned offsets, from aligned memory,
+/// so the data is gathered in a cluster-aligned buffer and written in
+/// buffers of [`DEVICE_BUFFER_SIZE`]. The end of the image, which may not be
+/// a whole block, is written on flush without O_DIRECT.
+pub struct DirectWriter {
+    file: File,
+    /// Allocation holding the aligned buffer
+    buffer: Vec<u8>,
+    /// Offset of the aligned buffer in the allocation
+    start: usize,
+    filled: usize,
+    direct: bool,
+}
+
+impl DirectWriter {
+    pub fn new(file: File) -> std::io::Result<DirectWriter> {
+        set_direct(&file, true)?;
+        let buffer = vec![0u8; DEVICE_BUFFER_SIZE + DIRECT_ALIGNMENT];
+        let start = buffer.as_ptr().align_offset(DIRECT_ALIGNMENT);
+        Ok(DirectWriter { file, buffer, start, filled: 0, direct: true })
+    }
+
+    /// Memory used by the buffer, in bytes.
+    pub fn memory_usage() -> u64 {
+        (DEVICE_BUFFER_SIZE + DIRECT_ALIGNMENT) as u64
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = (DEVICE_BUFFER_SIZE - self.filled).min(buf.len());
+        let position = self.start + self.filled;
+        self.buffer[position..position + length].copy_from_slice(&buf[..length]);
+        self.filled += length;
+        if self.filled == DEVICE_BUFFER_SIZE {
+            self.file.write_all(&self.buffer[self.start..self.start + DEVICE_BUFFER_SIZE])?;
+            self.filled = 0;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut data = &self.buffer[self.start..self.start + self.filled];
+        if self.direct && !data.len().is_multiple_of(DIRECT_ALIGNMENT) {
+            let aligned = data.len() / DIRECT_ALIGNMENT * DIRECT_ALIGNMENT;
+            self.file.write_all(&data[..aligned])?;
+            data = &data[aligned..];
+            // Later writes would be unaligned too
+            set_direct(&self.file, false)?;
+            self.direct = false;
+        }
+        self.file.write_all(data)?;
+        self.filled = 0;
+        self.file.flush()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_direct(file: &File, direct: bool) -> std::io::Result<()> {
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    use std::os::unix::io::AsRawFd;
+
+    let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    let flags = if direct { flags | OFlag::O_DIRECT } else { flags - OFlag::O_DIRECT };
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(flags))?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_direct(_file: &File, _direct: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "direct output is only supported on Linux",
+    ))
+}
+
+/// Output written in records of a fixed size, as tape drives take them: each
+/// write is a whole block, and the last one is padded with zeros.
+pub struct BlockWriter {
+    file: File,
+    block: Vec<u8>,
+    filled: usize,
+}
+
+impl BlockWriter {
+    pub fn new(file: File, block_size: usize) -> BlockWriter {
+        BlockWriter { file, block: vec![0u8; block_size], filled: 0 }
+    }
+
+    /// Write the block as a single record; tape drives don't take the rest
+    /// of a record in another write.
+    fn write_block(&mut self) -> std::io::Result<()> {
+        let written = loop {
+            match self.file.write(&self.block) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                result => break result?,
+            }
+        };
+        if written != self.block.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("short write of {} bytes, the block size is {}", written, self.block.len()),
+            ));
+        }
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+impl Write for BlockWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = (self.block.len() - self.filled).min(buf.len());
+        self.block[self.filled..self.filled + length].copy_from_slice(&buf[..length]);
+        self.filled += length;
+        if self.filled == self.block.len() {
+            self.write_block()?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.filled > 0 {
+            self.block[self.filled..].fill(0);
+            self.write_block()?;
+        }
+        self.file.flush()
+    }
+}
+
+enum Request {
+    Write(Vec<u8>),
+    Flush,
+}
+
+/// Output written from a thread, failing with `ErrorKind::TimedOut` when it
+/// stops accepting data for too long, as when the reader of a pipe or the
+/// server of an upload hangs: the conversion can then stop and clean up,
+/// while the thread stays blocked until the process exits.
+pub struct TimeoutWriter {
+    requests: SyncSender<Request>,
+    results: Receiver<(Vec<u8>, std::io::Result<()>)>,
+    /// Bytes written by the thread so far
+    written: Arc<AtomicU64>,
+    timeout: Duration,
+    /// Buffer given back by the thread, to reuse
+    buffer: Vec<u8>,
+    stalled: bool,
+}
+
+impl TimeoutWriter {
+    /// Start writing to `inner` from a thread, which gives it back once the
+    /// `TimeoutWriter` is dropped.
+    pub fn spawn<W: Write + Send + 'static>(
+        mut inner: W,
+        timeout: Duration,
+    ) -> std::io::Result<(TimeoutWriter, JoinHandle<W>)> {
+        let (requests, receiver) = sync_channel(0);
+        let (sender, results) = sync_channel(1);
+        let written = Arc::new(AtomicU64::new(0));
+        let thread_written = written.clone();
+        let thread = std::thread::Builder::new()
+            .name("output".to_owned())
+            .spawn(move || {
+                for request in receiver {
+                    let (buffer, result) = match request {
+                        Request::Write(buffer) => {
+                            // Count the data as it goes, so slow writes aren't
+                            // taken for stalled ones
+                            let result = buffer.chunks(CLUSTER_SIZE as usize).try_for_each(|piece| {
+                                inner.write_all(piece)?;
+                                thread_written.fetch_add(piece.len() as u64, Ordering::Relaxed);
+                                Ok(())
+                            });
+                            (buffer, result)
+                        }
+                        Request::Flush => (Vec::new(), inner.flush()),
+                    };
+                    if sender.send((buffer, result)).is_err() {
+                        break;
+                    }
+                }
+                inner
+            })?;
+        let writer = TimeoutWriter {
+            requests,
+            results,
+            written,
+            timeout,
+            buffer: Vec::new(),
+            stalled: false,
+        };
+        Ok((writer, thread))
+    }
+
+    /// Have the thread carry out a request, waiting as long as it makes
+    /// progress.
+    fn call(&mut self, request: Request) -> std::io::Result<()> {
+        if self.stalled || self.requests.send(request).is_err() {
+            return Err(self.stalled_error());
+        }
+        let mut written = self.written.load(Ordering::Relaxed);
+        loop {
+            match self.results.recv_timeout(self.timeout) {
+                Ok((buffer, result)) => {
+                    if buffer.capacity() > 0 {
+                        self.buffer = buffer;
+                    }
+                    return result;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let now_written = self.written.load(Ordering::Relaxed);
+                    if now_written == written {
+                        self.stalled = true;
+                        return Err(self.stalled_error());
+                    }
+                    written = now_written;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(std::io::Error::other("output thread failed")),
+            }
+        }
+    }
+
+    fn stalled_error(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("the output accepted no data for {} s", self.timeout.as_secs_f64()),
+        )
+    }
+}
+
+impl Write for TimeoutWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        buffer.extend_from_slice(buf);
+        self.call(Request::Write(buffer))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.call(Request::Flush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{commit, open};
+
+    #[test]
+    fn temp_file() {
+        let directory = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.output", std::process::id()));
+        std::fs::create_dir(&directory).unwrap();
+        let path = directory.join("image.qcow2");
+
+        // A file left under the old fixed name is not truncated
+        let stale = directory.join("image.qcow2.tmp");
+        std::fs::write(&stale, b"stale").unwrap();
+        let mut first = open(&path, 0, false, false).unwrap();
+        let mut second = open(&path, 0, false, false).unwrap();
+        let first_path = first.temp_path.clone().unwrap();
+        let second_path = second.temp_path.clone().unwrap();
+        assert_ne!(first_path, second_path);
+        assert_eq!(first_path.parent(), Some(directory.as_path()));
+        assert_eq!(std::fs::read(&stale).unwrap(), b"stale");
+        first.file.write_all(b"first").unwrap();
+        second.file.write_all(b"second").unwrap();
+
+        // The first one to finish is renamed, the other doesn't replace it
+        commit(Some(&first_path), &path, false).unwrap();
+        let error = commit(Some(&second_path), &path, false).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+        assert!(second_path.exists());
+        assert!(open(&path, 0, false, false).is_err());
+
+        // Unless --force is used
+        commit(Some(&second_path), &path, true).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert!(!second_path.exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
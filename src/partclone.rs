@@ -0,0 +1,251 @@
+//! Reading the used-block bitmap from the header of partclone images, as
+//! made by Clonezilla, to use as a layout.
+//!
+//! The image is read up to the end of the bitmap, so the start of an image
+//! is enough. Images compressed with gzip are decompressed on the fly.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{BufRead, Read};
+use std::ops::Range;
+
+const MAGIC: &[u8] = b"partclone-image";
+
+/// Size of the header of version 1 images, a C struct with a large buffer
+const HEADER_V1_SIZE: usize = 4160;
+/// Size of the header of version 2 images, including its CRC
+const HEADER_V2_SIZE: usize = 110;
+
+/// Marks the end of the bitmap in version 1 images
+const BITMAP_MAGIC_V1: &[u8] = b"BiTmAgIc";
+
+/// Bitmap modes of version 2 images: one bit or one byte per block
+const BITMAP_MODE_BIT: u8 = 1;
+const BITMAP_MODE_BYTE: u8 = 8;
+
+const ENDIANNESS_LITTLE: u16 = 0xC0DE;
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the ranges of the partition that are used according to the bitmap
+/// of a partclone image.
+pub fn read_layout<R: BufRead>(mut reader: R) -> std::io::Result<Vec<Range<u64>>> {
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        return read_layout_uncompressed(flate2::bufread::MultiGzDecoder::new(reader));
+    }
+    read_layout_uncompressed(reader)
+}
+
+fn read_layout_uncompressed<R: Read>(mut reader: R) -> std::io::Result<Vec<Range<u64>>> {
+    let mut start = [0u8; 36];
+    reader.read_exact(&mut start)?;
+    if &start[0..15] != MAGIC {
+        return Err(invalid("not a partclone image"));
+    }
+    match &start[30..34] {
+        b"0001" => {
+            // Native C struct, only written on little-endian 64-bit systems
+            // in practice
+            let mut header = vec![0u8; HEADER_V1_SIZE];
+            header[..36].copy_from_slice(&start);
+            reader.read_exact(&mut header[36..])?;
+            let block_size = LittleEndian::read_u32(&header[36..40]) as u64;
+            let total_blocks = LittleEndian::read_u64(&header[48..56]);
+            check_geometry(block_size, total_blocks)?;
+            let bitmap = read_vec(&mut reader, total_blocks)?;
+            let mut magic = [0u8; 8];
+            reader.read_exact(&mut magic)?;
+            if magic != BITMAP_MAGIC_V1 {
+                return Err(invalid("invalid partclone bitmap"));
+            }
+            used_ranges(block_size, total_blocks, |b| bitmap[b as usize] != 0)
+        }
+        b"0002" => {
+            let mut header = [0u8; HEADER_V2_SIZE];
+            header[..36].copy_from_slice(&start);
+            reader.read_exact(&mut header[36..])?;
+            if LittleEndian::read_u16(&header[34..36]) != ENDIANNESS_LITTLE {
+                return Err(invalid("big-endian partclone images are not supported"));
+            }
+            let total_blocks = LittleEndian::read_u64(&header[60..68]);
+            let block_size = LittleEndian::read_u32(&header[84..88]) as u64;
+            let bitmap_mode = header[105];
+            check_geometry(block_size, total_blocks)?;
+            match bitmap_mode {
+                BITMAP_MODE_BIT => {
+                    let bitmap = read_vec(&mut reader, total_blocks.div_ceil(8))?;
+                    used_ranges(block_size, total_blocks, |b| {
+                        bitmap[(b / 8) as usize] & (1 << (b % 8)) != 0
+                    })
+                }
+                BITMAP_MODE_BYTE => {
+                    let bitmap = read_vec(&mut reader, total_blocks)?;
+                    used_ranges(block_size, total_blocks, |b| bitmap[b as usize] != 0)
+                }
+                _ => Err(invalid("partclone image has no bitmap")),
+            }
+        }
+        _ => Err(invalid("unsupported partclone image version")),
+    }
+}
+
+fn read_vec<R: Read>(reader: &mut R, size: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(size).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < size {
+        return Err(invalid("partclone bitmap is truncated"));
+    }
+    Ok(buf)
+}
+
+/// Check the block size and count from the header, before reading the
+/// bitmap they describe.
+fn check_geometry(block_size: u64, total_blocks: u64) -> std::io::Result<()> {
+    if block_size == 0 || total_blocks.checked_mul(block_size).is_none() {
+        return Err(invalid("invalid partclone block size"));
+    }
+    Ok(())
+}
+
+/// Turn the used blocks into byte ranges, merging consecutive ones.
+fn used_ranges(block_size: u64, total_blocks: u64, used: impl Fn(u64) -> bool) -> std::io::Result<Vec<Range<u64>>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for block in (0..total_blocks).filter(|&b| used(b)) {
+        let start = block * block_size;
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end += block_size,
+            _ => ranges.push(start..start + block_size),
+        }
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Write;
+
+    /// Build a version 2 image header followed by its bitmap.
+    fn image_v2(block_size: u32, total_blocks: u64, bitmap_mode: u8, bitmap: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; HEADER_V2_SIZE];
+        image[..15].copy_from_slice(MAGIC);
+        image[16..23].copy_from_slice(b"0.3.20\0");
+        image[30..34].copy_from_slice(b"0002");
+        LittleEndian::write_u16(&mut image[34..36], ENDIANNESS_LITTLE);
+        image[36..40].copy_from_slice(b"EXT4");
+        LittleEndian::write_u64(&mut image[52..60], total_blocks.wrapping_mul(block_size as u64));
+        LittleEndian::write_u64(&mut image[60..68], total_blocks);
+        LittleEndian::write_u32(&mut image[84..88], block_size);
+        image[105] = bitmap_mode;
+        image.extend_from_slice(bitmap);
+        image
+    }
+
+    /// Build a version 1 image header followed by its byte bitmap.
+    fn image_v1(block_size: u32, bitmap: &[u8]) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend_from_slice(MAGIC);
+        image.resize(30, 0);
+        image.extend_from_slice(b"0001");
+        image.resize(36, 0);
+        image.write_u32::<LittleEndian>(block_size).unwrap();
+        image.resize(48, 0);
+        image.write_u64::<LittleEndian>(bitmap.len() as u64).unwrap();
+        image.resize(HEADER_V1_SIZE, 0);
+        image.extend_from_slice(bitmap);
+        image.extend_from_slice(BITMAP_MAGIC_V1);
+        image
+    }
+
+    #[test]
+    fn bit_bitmap() {
+        // Blocks 0-2, 7-8 and 15 are used
+        let image = image_v2(4096, 16, BITMAP_MODE_BIT, &[0b1000_0111, 0b1000_0001]);
+        assert_eq!(
+            read_layout(&image[..]).unwrap(),
+            vec![0..3 * 4096, 7 * 4096..9 * 4096, 15 * 4096..16 * 4096],
+        );
+
+        // The bitmap is rounded up to whole bytes
+        let image = image_v2(512, 3, BITMAP_MODE_BIT, &[0b1111_1110]);
+        assert_eq!(read_layout(&image[..]).unwrap(), vec![512..1536]);
+    }
+
+    #[test]
+    fn byte_bitmap() {
+        let image = image_v2(1024, 5, BITMAP_MODE_BYTE, &[1, 1, 0, 0, 1]);
+        assert_eq!(read_layout(&image[..]).unwrap(), vec![0..2048, 4096..5120]);
+
+        let image = image_v1(4096, &[0, 1, 1, 0]);
+        assert_eq!(read_layout(&image[..]).unwrap(), vec![4096..12288]);
+    }
+
+    #[test]
+    fn gzip() {
+        let image = image_v2(4096, 8, BITMAP_MODE_BIT, &[0b0000_0110]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&image).unwrap();
+        // Data past the bitmap is not needed
+        encoder.write_all(&[0xAA; 10000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(read_layout(&compressed[..]).unwrap(), vec![4096..12288]);
+    }
+
+    #[test]
+    fn invalid_images() {
+        let bitmap = [0xFF; 2];
+        let errors: Vec<(Vec<u8>, &str)> = vec![
+            (b"partclone-imagf\0".repeat(10), "not a partclone image"),
+            (
+                {
+                    let mut image = image_v2(4096, 16, BITMAP_MODE_BIT, &bitmap);
+                    image[30..34].copy_from_slice(b"0003");
+                    image
+                },
+                "unsupported partclone image version",
+            ),
+            (
+                {
+                    let mut image = image_v2(4096, 16, BITMAP_MODE_BIT, &bitmap);
+                    image[34..36].copy_from_slice(&[0xC0, 0xDE]);
+                    image
+                },
+                "big-endian partclone images are not supported",
+            ),
+            (image_v2(4096, 16, 0, &bitmap), "partclone image has no bitmap"),
+            (image_v2(4096, 17, BITMAP_MODE_BIT, &bitmap), "partclone bitmap is truncated"),
+            // A huge block count in the header must not be allocated up front
+            (image_v2(4096, u64::MAX / 4096, BITMAP_MODE_BYTE, &bitmap), "partclone bitmap is truncated"),
+            (image_v2(0, 16, BITMAP_MODE_BIT, &bitmap), "invalid partclone block size"),
+            (
+                {
+                    let mut image = image_v1(4096, &bitmap);
+                    let len = image.len();
+                    image[len - 1] = b'X';
+                    image
+                },
+                "invalid partclone bitmap",
+            ),
+        ];
+        for (image, message) in errors {
+            let err = read_layout(&image[..]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), message);
+        }
+
+        // The block count times the block size must fit, which is checked
+        // before reading the bitmap
+        let image = image_v2(1 << 20, 1 << 44, BITMAP_MODE_BIT, &[]);
+        assert_eq!(read_layout(&image[..]).unwrap_err().to_string(), "invalid partclone block size");
+
+        // Truncated headers
+        for image in [image_v2(4096, 16, BITMAP_MODE_BIT, &bitmap), image_v1(4096, &bitmap)] {
+            for len in [0, 20, 36, 100] {
+                let err = read_layout(&image[..len]).unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+        }
+    }
+}
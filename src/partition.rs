@@ -0,0 +1,419 @@
+//! Parsing of MBR and GPT partition tables.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::input::Input;
+use crate::layout::{self, Extent, ExtentSource};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Maximum number of logical partitions followed in an extended partition.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// Largest GPT partition entry array read, far more than partitioning tools
+/// create (128 entries of 128 bytes)
+const MAX_GPT_ENTRIES_SIZE: u64 = 16 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR partition type byte
+    Mbr(u8),
+    /// GPT partition type GUID, in the canonical textual form (lowercase)
+    Gpt(String),
+}
+
+impl PartitionType {
+    /// Parse a type given on the command line, either a hex MBR type byte
+    /// (`82`, `0x82`) or a GPT type GUID.
+    pub fn parse(s: &str) -> Option<PartitionType> {
+        if s.len() == 36 {
+            let valid = s.char_indices().all(|(i, c)| match i {
+                8 | 13 | 18 | 23 => c == '-',
+                _ => c.is_ascii_hexdigit(),
+            });
+            if valid {
+                return Some(PartitionType::Gpt(s.to_ascii_lowercase()));
+            }
+            return None;
+        }
+        let hex = s.strip_prefix("0x").unwrap_or(s);
+        u8::from_str_radix(hex, 16).ok().map(PartitionType::Mbr)
+    }
+}
+
+impl std::fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PartitionType::Mbr(t) => write!(f, "0x{:02x}", t),
+            PartitionType::Gpt(guid) => write!(f, "{}", guid),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    /// Partition number, as Linux would number it (starting from 1, logical
+    /// MBR partitions starting from 5)
+    pub number: u32,
+    pub partition_type: PartitionType,
+    /// Byte range of the partition on the disk
+    pub range: Range<u64>,
+}
+
+#[derive(Debug)]
+pub struct PartitionTable {
+    pub partitions: Vec<Partition>,
+    /// Areas holding the partition table itself, and data before the first
+    /// partition (boot loaders are commonly embedded there)
+    pub metadata: Vec<Range<u64>>,
+}
+
+/// The partition table areas and partitions to keep, as an extent source.
+pub struct UsedSpace<'a> {
+    pub table: &'a PartitionTable,
+    pub exclude_types: &'a [PartitionType],
+    /// Numbers of the partitions to keep, all of them if not set
+    pub numbers: Option<&'a [u32]>,
+    pub size: u64,
+}
+
+impl ExtentSource for UsedSpace<'_> {
+    fn name(&self) -> &'static str {
+        "partition table"
+    }
+
+    fn extents(&mut self, _input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        if let Some(numbers) = self.numbers {
+            let missing: Vec<String> = numbers.iter()
+                .filter(|&&n| !self.table.partitions.iter().any(|p| p.number == n))
+                .map(|n| n.to_string())
+                .collect();
+            if !missing.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no partition{} {} on the disk", if missing.len() > 1 { "s" } else { "" }, missing.join(", ")),
+                ));
+            }
+        }
+        Ok(layout::data_extents(self.table.used_ranges(self.exclude_types, self.numbers), self.size))
+    }
+}
+
+impl PartitionTable {
+    /// Ranges of the disk that should be kept: the partition table areas and
+    /// the partitions not matching any of the excluded types, only those
+    /// with the given numbers if set.
+    pub fn used_ranges(&self, exclude_types: &[PartitionType], numbers: Option<&[u32]>) -> Vec<Range<u64>> {
+        let mut ranges = self.metadata.clone();
+        for partition in &self.partitions {
+            let selected = numbers.is_none_or(|n| n.contains(&partition.number));
+            if selected && !exclude_types.contains(&partition.partition_type) {
+                ranges.push(partition.range.clone());
+            }
+        }
+        ranges.sort_by_key(|r| r.start);
+        ranges
+    }
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buffer)
+}
+
+/// Read the partition table of a disk.
+///
+/// Returns `None` if the disk has no recognizable partition table.
+pub fn read_partition_table<R: Read + Seek>(
+    mut reader: R,
+    disk_size: u64,
+) -> std::io::Result<Option<PartitionTable>> {
+    if disk_size < SECTOR_SIZE * 2 {
+        return Ok(None);
+    }
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    read_at(&mut reader, 0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Ok(None);
+    }
+
+    // Filesystem boot sectors also carry the signature; real MBR entries have
+    // a status byte of 0x00 or 0x80
+    let entries_valid = (0..4).all(|i| matches!(mbr[446 + i * 16], 0x00 | 0x80));
+    let has_entries = (0..4).any(|i| mbr[446 + i * 16 + 4] != 0);
+    if !entries_valid || !has_entries || &mbr[3..11] == b"NTFS    " {
+        return Ok(None);
+    }
+
+    // A protective MBR indicates GPT, which might use 512 or 4096-byte sectors
+    if (0..4).any(|i| mbr[446 + i * 16 + 4] == 0xEE) {
+        for sector_size in [512, 4096] {
+            if let Some(table) = read_gpt(&mut reader, disk_size, sector_size)? {
+                return Ok(Some(table));
+            }
+        }
+        return Err(invalid("protective MBR found but no valid GPT header"));
+    }
+
+    read_mbr(&mut reader, disk_size, &mbr).map(Some)
+}
+
+fn read_mbr<R: Read + Seek>(
+    reader: &mut R,
+    disk_size: u64,
+    mbr: &[u8],
+) -> std::io::Result<PartitionTable> {
+    let mut partitions = Vec::new();
+    let mut metadata = Vec::new();
+
+    let mut extended = None;
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        let partition_type = entry[4];
+        let start = LittleEndian::read_u32(&entry[8..12]) as u64 * SECTOR_SIZE;
+        let length = LittleEndian::read_u32(&entry[12..16]) as u64 * SECTOR_SIZE;
+        if partition_type == 0 || length == 0 {
+            continue;
+        }
+        if matches!(partition_type, 0x05 | 0x0F | 0x85) {
+            extended = Some(start);
+            continue;
+        }
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            partition_type: PartitionType::Mbr(partition_type),
+            range: start.min(disk_size)..(start + length).min(disk_size),
+        });
+    }
+
+    // Follow the chain of extended boot records
+    if let Some(extended_start) = extended {
+        let mut ebr_offset = extended_start;
+        let mut ebr = [0u8; SECTOR_SIZE as usize];
+        for number in 5..(5 + MAX_LOGICAL_PARTITIONS as u32) {
+            if ebr_offset + SECTOR_SIZE > disk_size {
+                break;
+            }
+            read_at(reader, ebr_offset, &mut ebr)?;
+            if ebr[510..512] != [0x55, 0xAA] {
+                return Err(invalid("invalid extended boot record"));
+            }
+            metadata.push(ebr_offset..ebr_offset + SECTOR_SIZE);
+
+            let entry = &ebr[446..462];
+            let partition_type = entry[4];
+            let start = ebr_offset + LittleEndian::read_u32(&entry[8..12]) as u64 * SECTOR_SIZE;
+            let length = LittleEndian::read_u32(&entry[12..16]) as u64 * SECTOR_SIZE;
+            if partition_type != 0 && length != 0 {
+                partitions.push(Partition {
+                    number,
+                    partition_type: PartitionType::Mbr(partition_type),
+                    range: start.min(disk_size)..(start + length).min(disk_size),
+                });
+            }
+
+            let next = &ebr[462..478];
+            let next_start = LittleEndian::read_u32(&next[8..12]) as u64 * SECTOR_SIZE;
+            if next[4] == 0 || next_start == 0 {
+                break;
+            }
+            ebr_offset = extended_start + next_start;
+        }
+    }
+
+    let first_partition = partitions.iter().map(|p| p.range.start).min().unwrap_or(SECTOR_SIZE);
+    metadata.push(0..first_partition.max(SECTOR_SIZE));
+
+    partitions.sort_by_key(|p| p.range.start);
+    Ok(PartitionTable { partitions, metadata })
+}
+
+fn read_gpt<R: Read + Seek>(
+    reader: &mut R,
+    disk_size: u64,
+    sector_size: u64,
+) -> std::io::Result<Option<PartitionTable>> {
+    let mut header = [0u8; 92];
+    read_at(reader, sector_size, &mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        return Ok(None);
+    }
+    let alternate_lba = LittleEndian::read_u64(&header[32..40]);
+    let entries_lba = LittleEndian::read_u64(&header[72..80]);
+    let num_entries = LittleEndian::read_u32(&header[80..84]) as u64;
+    let entry_size = LittleEndian::read_u32(&header[84..88]) as u64;
+    // Entries are 128 bytes times a power of two, and can span sectors
+    let entries_size = num_entries * entry_size;
+    if entry_size < 128 || !entry_size.is_power_of_two() || entries_size > MAX_GPT_ENTRIES_SIZE {
+        return Err(invalid("invalid GPT header"));
+    }
+    let entries_end = entries_lba.checked_mul(sector_size).and_then(|start| start.checked_add(entries_size));
+    if entries_end.is_none_or(|end| end > disk_size) {
+        return Err(invalid("GPT partition entries are past the end of the disk"));
+    }
+
+    let mut entries = vec![0u8; entries_size as usize];
+    read_at(reader, entries_lba * sector_size, &mut entries)?;
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks(entry_size as usize).enumerate() {
+        let type_guid = &entry[0..16];
+        if type_guid.iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first_lba = LittleEndian::read_u64(&entry[32..40]);
+        let last_lba = LittleEndian::read_u64(&entry[40..48]);
+        if last_lba < first_lba {
+            return Err(invalid("invalid GPT partition entry"));
+        }
+        let start = first_lba.saturating_mul(sector_size).min(disk_size);
+        let end = last_lba.saturating_add(1).saturating_mul(sector_size).min(disk_size);
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            partition_type: PartitionType::Gpt(format_guid(type_guid)),
+            range: start..end,
+        });
+    }
+    partitions.sort_by_key(|p| p.range.start);
+
+    let first_partition = partitions.first().map(|p| p.range.start).unwrap_or(0);
+    let primary_end = (entries_lba * sector_size + entries_size).max(first_partition);
+    let mut metadata: Vec<_> = std::iter::once(0..primary_end).collect();
+
+    // Backup header and the entries preceding it
+    let backup_header = alternate_lba.saturating_mul(sector_size);
+    if backup_header > primary_end && backup_header < disk_size {
+        let backup_start = backup_header.saturating_sub(entries_size).max(primary_end);
+        metadata.push(backup_start..(backup_header + sector_size).min(disk_size));
+    }
+
+    Ok(Some(PartitionTable { partitions, metadata }))
+}
+
+/// Format a GUID as stored on disk (mixed-endian) to its textual form.
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        LittleEndian::read_u32(&bytes[0..4]),
+        LittleEndian::read_u16(&bytes[4..6]),
+        LittleEndian::read_u16(&bytes[6..8]),
+        bytes[8], bytes[9],
+        bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1 << 20;
+
+    fn mbr_entry(disk: &mut [u8], offset: usize, partition_type: u8, start: u64, length: u64) {
+        let entry = &mut disk[offset..offset + 16];
+        entry[4] = partition_type;
+        (&mut entry[8..12]).write_u32::<LittleEndian>((start / SECTOR_SIZE) as u32).unwrap();
+        (&mut entry[12..16]).write_u32::<LittleEndian>((length / SECTOR_SIZE) as u32).unwrap();
+    }
+
+    /// A 4 MiB disk with an MBR: a primary partition from 1 MiB to 2 MiB and
+    /// an extended partition from 2 MiB, whose logical partition is
+    /// `logical_length` bytes from 2 MiB + 4 KiB.
+    fn mbr_disk(logical_length: u64) -> Vec<u8> {
+        let mut disk = vec![0u8; 4 * MIB as usize];
+        mbr_entry(&mut disk, 446, 0x83, MIB, MIB);
+        mbr_entry(&mut disk, 462, 0x05, 2 * MIB, 2 * MIB);
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        let ebr = 2 * MIB as usize;
+        mbr_entry(&mut disk, ebr + 446, 0x82, 4096, logical_length);
+        disk[ebr + 510..ebr + 512].copy_from_slice(&[0x55, 0xAA]);
+        disk
+    }
+
+    /// A 4 MiB disk with a GPT of `num_entries` entries of `entry_size`
+    /// bytes at LBA 2, one partition from LBA 2048 to `last_lba`.
+    fn gpt_disk(num_entries: u32, entry_size: u32, last_lba: u64) -> Vec<u8> {
+        let mut disk = vec![0u8; 4 * MIB as usize];
+        mbr_entry(&mut disk, 446, 0xEE, SECTOR_SIZE, 4 * MIB - SECTOR_SIZE);
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        let header = &mut disk[SECTOR_SIZE as usize..][..92];
+        header[0..8].copy_from_slice(b"EFI PART");
+        (&mut header[32..40]).write_u64::<LittleEndian>(4 * MIB / SECTOR_SIZE - 1).unwrap();
+        (&mut header[72..80]).write_u64::<LittleEndian>(2).unwrap();
+        (&mut header[80..84]).write_u32::<LittleEndian>(num_entries).unwrap();
+        (&mut header[84..88]).write_u32::<LittleEndian>(entry_size).unwrap();
+        let entry = &mut disk[2 * SECTOR_SIZE as usize..][..128];
+        entry[0..16].copy_from_slice(&[0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+        (&mut entry[32..40]).write_u64::<LittleEndian>(2048).unwrap();
+        (&mut entry[40..48]).write_u64::<LittleEndian>(last_lba).unwrap();
+        disk
+    }
+
+    fn ranges(table: &PartitionTable) -> Vec<Range<u64>> {
+        table.partitions.iter().map(|p| p.range.clone()).collect()
+    }
+
+    #[test]
+    fn mbr_partition_at_end_of_disk() {
+        let table = read_partition_table(Cursor::new(mbr_disk(2 * MIB - 4096)), 4 * MIB).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB..2 * MIB, 2 * MIB + 4096..4 * MIB]);
+        assert_eq!(table.partitions[1].number, 5);
+        assert_eq!(table.partitions[1].partition_type, PartitionType::Mbr(0x82));
+        assert_eq!(table.metadata, vec![2 * MIB..2 * MIB + SECTOR_SIZE, 0..MIB]);
+    }
+
+    #[test]
+    fn mbr_truncated_disk() {
+        // Partitions are clipped to the disk, even if they start past its end
+        let disk = mbr_disk(2 * MIB - 4096);
+        let table = read_partition_table(Cursor::new(&disk[..3 * MIB as usize]), 3 * MIB).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB..2 * MIB, 2 * MIB + 4096..3 * MIB]);
+
+        // The extended boot record is past the end, its partition is left out
+        let table = read_partition_table(Cursor::new(&disk[..3 * MIB as usize / 2]), 3 * MIB / 2).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB..3 * MIB / 2]);
+
+        let table = read_partition_table(Cursor::new(&disk[..MIB as usize / 2]), MIB / 2).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB / 2..MIB / 2]);
+    }
+
+    #[test]
+    fn gpt_partition_at_end_of_disk() {
+        let table = read_partition_table(Cursor::new(gpt_disk(128, 128, 8191)), 4 * MIB).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB..4 * MIB]);
+        assert_eq!(
+            table.partitions[0].partition_type,
+            PartitionType::Gpt("0fc63daf-8483-4772-8e79-3d69d8477de4".to_owned()),
+        );
+
+        // Past the end of the disk
+        let table = read_partition_table(Cursor::new(gpt_disk(128, 128, u64::MAX)), 4 * MIB).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB..4 * MIB]);
+    }
+
+    #[test]
+    fn gpt_entries_spanning_sectors() {
+        let table = read_partition_table(Cursor::new(gpt_disk(4, 1024, 8191)), 4 * MIB).unwrap().unwrap();
+        assert_eq!(ranges(&table), vec![MIB..4 * MIB]);
+    }
+
+    #[test]
+    fn gpt_corrupt_counts() {
+        for (num_entries, entry_size) in [(65536, 512), (128, u32::MAX), (128, 1 << 31), (u32::MAX, 128), (128, 64), (128, 192)] {
+            let disk = gpt_disk(num_entries, entry_size, 8191);
+            assert!(read_partition_table(Cursor::new(disk), 4 * MIB).is_err());
+        }
+    }
+
+    #[test]
+    fn not_partitioned() {
+        assert!(read_partition_table(Cursor::new(vec![0u8; 4096]), 4096).unwrap().is_none());
+    }
+}
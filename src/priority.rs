@@ -0,0 +1,74 @@
+//! Lowering the CPU and I/O priority of the process, so conversions don't
+//! compete with other workloads on the machine.
+
+/// I/O scheduling class, as set by `ionice`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Best-effort, with a level from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+    /// Only get disk time when no other process needs it
+    Idle,
+}
+
+impl IoPriority {
+    /// Parse `idle` or `best-effort[:LEVEL]`.
+    pub fn parse(s: &str) -> Option<IoPriority> {
+        match s.split_once(':') {
+            None if s == "idle" => Some(IoPriority::Idle),
+            None if s == "best-effort" => Some(IoPriority::BestEffort(4)),
+            Some(("best-effort", level)) => match level.parse() {
+                Ok(l) if l <= 7 => Some(IoPriority::BestEffort(l)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Set the I/O scheduling class of the process.
+#[cfg(target_os = "linux")]
+pub fn set_io_priority(priority: IoPriority) -> std::io::Result<()> {
+    // Defined in linux/ioprio.h
+    const IOPRIO_WHO_PROCESS: nix::libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    const IOPRIO_CLASS_BE: u32 = 2;
+    const IOPRIO_CLASS_IDLE: u32 = 3;
+
+    let ioprio = match priority {
+        IoPriority::BestEffort(level) => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level as u32,
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+    };
+    let result = unsafe {
+        nix::libc::syscall(nix::libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio)
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_priority(_priority: IoPriority) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "setting the I/O priority is only supported on Linux",
+    ))
+}
+
+/// Set the CPU niceness of the process, from -20 (highest priority) to 19.
+#[cfg(unix)]
+pub fn set_niceness(niceness: i32) -> std::io::Result<()> {
+    let result = unsafe { nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, niceness) };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_niceness(_niceness: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "setting the niceness is not supported on this platform",
+    ))
+}
@@ -0,0 +1,436 @@
+//! Progress reporting on demand: sending SIGUSR1 (or SIGINFO, on systems
+//! that have it) prints the current phase and the amount copied, like dd.
+//!
+//! The signals themselves are handled in the `signals` module.
+//!
+//! Progress can also be sent to a status file descriptor (`--status-fd`), as
+//! JSON records, one per line, and programs using the library can get it as
+//! events, through a handler or a channel. When running as a systemd
+//! service, the phase and progress are also the status of the unit.
+//!
+//! On stderr, copies either print a line at intervals (every 500 MB by
+//! default), or redraw a progress bar in place when stderr is a terminal.
+//!
+//! Each phase is also a `tracing` span, entered on the thread that started
+//! it until the next phase, so it can be timed with standard subscribers.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dashboard::format_size;
+use crate::log::{self, message};
+use crate::systemd;
+
+struct Phase {
+    name: &'static str,
+    started: Instant,
+}
+
+static PHASE: Mutex<Option<Phase>> = Mutex::new(None);
+
+thread_local! {
+    /// Span of the current phase, if it was started on this thread
+    static PHASE_SPAN: RefCell<Option<tracing::span::EnteredSpan>> = const { RefCell::new(None) };
+}
+/// Previous phases and how long they took
+static HISTORY: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+static COPIED: AtomicU64 = AtomicU64::new(0);
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+static COPYING: AtomicBool = AtomicBool::new(false);
+
+/// Minimum time between progress records on the status file descriptor
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Status {
+    file: File,
+    last_progress: Option<Instant>,
+}
+
+static STATUS_ENABLED: AtomicBool = AtomicBool::new(false);
+static STATUS: Mutex<Option<Status>> = Mutex::new(None);
+
+/// Event sent to the progress handler.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A new phase of the conversion started
+    Phase(&'static str),
+    /// Bytes copied so far in the current phase, out of the total (0 if
+    /// unknown)
+    Progress { copied: u64, total: u64 },
+}
+
+/// How the progress of copies is shown on stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Display {
+    /// Nothing is shown
+    Off,
+    /// A line is printed at each interval
+    Lines,
+    /// A progress bar is redrawn in place, for terminals
+    Bar,
+}
+
+static DISPLAY: AtomicU8 = AtomicU8::new(Display::Lines as u8);
+
+/// How often copies print a line with their progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interval {
+    /// Every time this many bytes have been copied
+    Bytes(u64),
+    /// Every time this much time has passed
+    Time(Duration),
+}
+
+struct Lines {
+    interval: Interval,
+    last_line: Option<Instant>,
+}
+
+static LINES: Mutex<Lines> = Mutex::new(Lines {
+    interval: Interval::Bytes(500_000_000), // 500 MB
+    last_line: None,
+});
+
+/// Set how often copies print a line with their progress.
+pub fn set_interval(interval: Interval) {
+    LINES.lock().unwrap().interval = interval;
+}
+
+/// Whether copies print a line with their progress after going from
+/// `previous` to `copied` bytes.
+pub(crate) fn line_due(previous: u64, copied: u64) -> bool {
+    if has_handler() || display() != Display::Lines {
+        return false;
+    }
+    let mut lines = LINES.lock().unwrap();
+    match lines.interval {
+        Interval::Bytes(bytes) => copied / bytes != previous / bytes,
+        Interval::Time(interval) => {
+            let now = Instant::now();
+            let last_line = *lines.last_line.get_or_insert(now);
+            if now - last_line < interval {
+                return false;
+            }
+            lines.last_line = Some(now);
+            true
+        }
+    }
+}
+
+/// Minimum time between redraws of the progress bar
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Width of the bar itself, in characters
+const BAR_WIDTH: usize = 30;
+
+struct Bar {
+    last_draw: Option<Instant>,
+    /// Whether the bar is on the current line of stderr
+    shown: bool,
+}
+
+static BAR: Mutex<Bar> = Mutex::new(Bar { last_draw: None, shown: false });
+
+/// Set how the progress of copies is shown on stderr.
+pub fn set_display(display: Display) {
+    DISPLAY.store(display as u8, Ordering::Relaxed);
+}
+
+pub fn display() -> Display {
+    match DISPLAY.load(Ordering::Relaxed) {
+        d if d == Display::Off as u8 => Display::Off,
+        d if d == Display::Bar as u8 => Display::Bar,
+        _ => Display::Lines,
+    }
+}
+
+/// Redraw the progress bar, if enough time has passed since the last time,
+/// unless `force` is set.
+fn draw_bar(copied: u64, total: u64, force: bool) {
+    // Messages are printed with the phase locked, so it's not locked along
+    // with the bar
+    let Some((name, started)) = PHASE.lock().unwrap().as_ref().map(|p| (p.name, p.started)) else {
+        return;
+    };
+    let mut bar = BAR.lock().unwrap();
+    let now = Instant::now();
+    if !force && bar.last_draw.is_some_and(|t| now - t < BAR_INTERVAL) {
+        return;
+    }
+    bar.last_draw = Some(now);
+    let elapsed = (now - started).as_secs_f64();
+    let rate = if elapsed > 0.0 { copied as f64 / elapsed } else { 0.0 };
+    let line = if total > 0 {
+        let filled = ((copied.min(total) as f64 / total as f64) * BAR_WIDTH as f64) as usize;
+        format!(
+            "{} [{}{}] {:5.1}% {}/s",
+            name,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            copied as f64 * 100.0 / total as f64,
+            format_size(rate as u64),
+        )
+    } else {
+        format!("{}: {}, {}/s", name, format_size(copied), format_size(rate as u64))
+    };
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(stderr, "\r\x1b[K{}", line);
+    let _ = stderr.flush();
+    bar.shown = true;
+}
+
+/// Move past the progress bar, keeping it on screen.
+fn end_bar() {
+    let mut bar = BAR.lock().unwrap();
+    if bar.shown {
+        eprintln!();
+        bar.shown = false;
+    }
+    bar.last_draw = None;
+}
+
+/// Clear the progress bar from the current line, so a message can be
+/// printed; it is drawn again with the next progress.
+pub(crate) fn clear_bar() {
+    let mut bar = BAR.lock().unwrap();
+    if bar.shown {
+        eprint!("\r\x1b[K");
+        bar.shown = false;
+    }
+}
+
+/// Minimum time between progress events sent to the handler
+const HANDLER_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Handler {
+    handler: Box<dyn Fn(&Event) + Send>,
+    last_progress: Option<Instant>,
+}
+
+static HANDLER_ENABLED: AtomicBool = AtomicBool::new(false);
+static HANDLER: Mutex<Option<Handler>> = Mutex::new(None);
+
+/// Call this function with the phases and the progress of copies, at most
+/// every 100 ms (and at the start and end of each copy).
+///
+/// Copies then stop printing lines with their progress.
+pub fn set_handler<F: Fn(&Event) + Send + 'static>(handler: F) {
+    *HANDLER.lock().unwrap() = Some(Handler {
+        handler: Box::new(handler),
+        last_progress: None,
+    });
+    HANDLER_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Send the events to a channel, like `set_handler()`.
+pub fn set_channel(sender: std::sync::mpsc::Sender<Event>) {
+    // The receiver going away shouldn't stop the conversion
+    set_handler(move |event| {
+        let _ = sender.send(event.clone());
+    });
+}
+
+/// Whether a handler was set, which replaces the periodic messages.
+pub fn has_handler() -> bool {
+    HANDLER_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Send an event to the handler, if set, with the same rules as
+/// `send_status()`.
+fn send_event(event: Event, force: bool) {
+    if !HANDLER_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut handler = HANDLER.lock().unwrap();
+    let Some(handler) = &mut *handler else {
+        return;
+    };
+    if let Event::Progress { .. } = event {
+        let now = Instant::now();
+        if !force && handler.last_progress.is_some_and(|t| now - t < HANDLER_INTERVAL) {
+            return;
+        }
+        handler.last_progress = Some(now);
+    }
+    (handler.handler)(&event);
+}
+
+/// Send status records to an inherited file descriptor.
+///
+/// The records are written to a duplicate, so `fd` itself is never closed.
+#[cfg(unix)]
+pub fn set_status_fd(fd: i32) -> std::io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(3))?;
+    let file = unsafe { File::from_raw_fd(fd) };
+    *STATUS.lock().unwrap() = Some(Status {
+        file,
+        last_progress: None,
+    });
+    STATUS_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_status_fd(_fd: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "status file descriptors are only supported on Unix",
+    ))
+}
+
+/// Write a record to the status file descriptor, if set.
+///
+/// Progress records are only written if enough time has passed since the
+/// last one, unless `force` is set.
+fn send_status(record: serde_json::Value, progress: bool, force: bool) {
+    if !STATUS_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut status = STATUS.lock().unwrap();
+    let Some(status) = &mut *status else {
+        return;
+    };
+    if progress {
+        let now = Instant::now();
+        if !force && status.last_progress.is_some_and(|t| now - t < STATUS_INTERVAL) {
+            return;
+        }
+        status.last_progress = Some(now);
+    }
+    // The reader going away shouldn't stop the conversion
+    let _ = writeln!(status.file, "{}", record);
+}
+
+fn send_progress(force: bool) {
+    let copied = COPIED.load(Ordering::Relaxed);
+    let total = TOTAL.load(Ordering::Relaxed);
+    send_status(serde_json::json!({"event": "progress", "copied": copied, "total": total}), true, force);
+    send_event(Event::Progress { copied, total }, force);
+    if display() == Display::Bar {
+        draw_bar(copied, total, force);
+    }
+    if systemd::enabled() {
+        let phase = PHASE.lock().unwrap().as_ref().map_or("Copying", |p| p.name);
+        let status = if total > 0 {
+            format!("{}: {:.1}% ({}/{} bytes)", phase, copied as f64 * 100.0 / total as f64, copied, total)
+        } else {
+            format!("{}: {} bytes", phase, copied)
+        };
+        systemd::status(&status, force);
+    }
+}
+
+/// Send the last progress record of a copy phase.
+fn end_copy() {
+    if COPYING.swap(false, Ordering::Relaxed) {
+        send_progress(true);
+        end_bar();
+    }
+}
+
+/// Send the final record to the status file descriptor.
+pub fn finish(error: Option<&str>) {
+    end_copy();
+    PHASE_SPAN.with(|span| span.borrow_mut().take());
+    match error {
+        None => send_status(serde_json::json!({"event": "done"}), false, false),
+        Some(message) => send_status(serde_json::json!({"event": "error", "message": message}), false, false),
+    }
+    systemd::stopping(error.unwrap_or("Done"));
+}
+
+/// Record that a new phase of the conversion started.
+pub fn set_phase(name: &'static str) {
+    end_copy();
+    let previous = PHASE.lock().unwrap().replace(Phase {
+        name,
+        started: Instant::now(),
+    });
+    if let Some(previous) = previous {
+        HISTORY.lock().unwrap().push((previous.name, previous.started.elapsed()));
+    }
+    TOTAL.store(0, Ordering::Relaxed);
+    PHASE_SPAN.with(|span| {
+        // Close the previous span before opening the next, so they are
+        // siblings
+        let mut span = span.borrow_mut();
+        span.take();
+        *span = Some(tracing::info_span!("phase", name).entered());
+    });
+    log::write(format_args!("{}", name));
+    send_status(serde_json::json!({"event": "phase", "phase": name}), false, false);
+    send_event(Event::Phase(name), false);
+    systemd::status(name, true);
+}
+
+/// Start a copy phase, with the number of bytes that will be copied.
+pub fn start_copy(name: &'static str, total: u64) {
+    set_phase(name);
+    COPIED.store(0, Ordering::Relaxed);
+    TOTAL.store(total, Ordering::Relaxed);
+    LINES.lock().unwrap().last_line = Some(Instant::now());
+    COPYING.store(true, Ordering::Relaxed);
+    send_progress(true);
+}
+
+/// Record that bytes were copied.
+pub fn add_copied(bytes: u64) {
+    let copied = COPIED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    let total = TOTAL.load(Ordering::Relaxed);
+    if total > 0 && copied >= total {
+        end_copy();
+    } else {
+        send_progress(false);
+    }
+}
+
+/// The current phase, how long it has been running, and the number of bytes
+/// copied out of the total (0 if unknown).
+pub fn current() -> Option<(&'static str, Duration, u64, u64)> {
+    let phase = PHASE.lock().unwrap();
+    let phase = phase.as_ref()?;
+    Some((
+        phase.name,
+        phase.started.elapsed(),
+        COPIED.load(Ordering::Relaxed),
+        TOTAL.load(Ordering::Relaxed),
+    ))
+}
+
+/// The previous phases and how long they took.
+pub fn history() -> Vec<(&'static str, Duration)> {
+    HISTORY.lock().unwrap().clone()
+}
+
+/// Print the current phase and progress.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn report() {
+    let phase = PHASE.lock().unwrap();
+    let Some(phase) = &*phase else {
+        message!("Starting");
+        return;
+    };
+    let total = TOTAL.load(Ordering::Relaxed);
+    if total == 0 {
+        message!("{} ({:.1} s)", phase.name, phase.started.elapsed().as_secs_f64());
+        return;
+    }
+    let copied = COPIED.load(Ordering::Relaxed);
+    let elapsed = phase.started.elapsed().as_secs_f64();
+    let rate = if elapsed > 0.0 { copied as f64 / elapsed } else { 0.0 };
+    message!(
+        "{}: {}/{} bytes ({:.1}%) in {:.1} s, {:.1} MB/s",
+        phase.name,
+        copied,
+        total,
+        copied as f64 * 100.0 / total as f64,
+        elapsed,
+        rate / 1_000_000.0,
+    );
+}
@@ -0,0 +1,143 @@
+//! Writing the image as a new VM disk on a Proxmox VE storage (`--upload
+//! pve://STORAGE/VMID`), optionally attaching it to the VM.
+//!
+//! This runs on the Proxmox node: the volume is allocated with `pvesm`, so it
+//! is registered with the storage, then the image is written over the file
+//! that backs it. Only storages keeping disks as files can hold qcow2 images
+//! (directory, NFS, CIFS, CephFS...), `pvesm` refuses the others.
+
+use std::fs::File;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::log::message;
+
+/// A disk being written to a Proxmox VE storage.
+pub struct Upload {
+    volid: String,
+    vmid: u32,
+    file: File,
+    finished: bool,
+}
+
+impl Upload {
+    /// Allocate a qcow2 disk for the VM on the storage, with the virtual size
+    /// of the image, which should then be written to the upload.
+    pub fn start(storage: &str, vmid: u32, size: u64) -> std::io::Result<Upload> {
+        use serde::Deserialize;
+
+        #[derive(Deserialize)]
+        struct Volume {
+            volid: String,
+        }
+
+        // Pick the first free disk name, as Proxmox does
+        let mut command = Command::new("pvesm");
+        command
+            .arg("list").arg(storage)
+            .arg("--vmid").arg(vmid.to_string())
+            .arg("--output-format").arg("json");
+        let volumes: Vec<Volume> = serde_json::from_slice(&run(command)?)?;
+        let name = (0..)
+            .map(|n| format!("vm-{}-disk-{}", vmid, n))
+            .find(|name| {
+                !volumes.iter().any(|v| {
+                    let file = v.volid.rsplit('/').next().unwrap_or(&v.volid);
+                    let file = file.rsplit(':').next().unwrap_or(file);
+                    file.split('.').next() == Some(name.as_str())
+                })
+            })
+            .unwrap();
+
+        // The size is given in KiB
+        let mut command = Command::new("pvesm");
+        command
+            .arg("alloc").arg(storage)
+            .arg(vmid.to_string())
+            .arg(format!("{}.qcow2", name))
+            .arg(size.div_ceil(1024).to_string())
+            .arg("--format").arg("qcow2");
+        let output = String::from_utf8_lossy(&run(command)?).into_owned();
+        // It prints "successfully created 'VOLID'"
+        let Some(volid) = output.split('\'').nth(1).filter(|v| !v.is_empty()) else {
+            return Err(std::io::Error::other(format!("unexpected output from pvesm alloc: {}", output.trim())));
+        };
+        let volid = volid.to_owned();
+        message!("Allocated Proxmox volume {}", volid);
+
+        let mut command = Command::new("pvesm");
+        command.arg("path").arg(&volid);
+        let file = run(command).and_then(|path| {
+            // Replace the empty image pvesm created
+            File::create(String::from_utf8_lossy(&path).trim())
+        });
+        let file = match file {
+            Ok(f) => f,
+            Err(e) => {
+                free_volume(&volid);
+                return Err(e);
+            }
+        };
+        Ok(Upload {
+            volid,
+            vmid,
+            file,
+            finished: false,
+        })
+    }
+
+    /// Flush the disk and attach it to the VM if requested, returning the
+    /// volume ID.
+    pub fn finish(mut self, attach: Option<&str>) -> std::io::Result<String> {
+        self.file.sync_all()?;
+        if let Some(drive) = attach {
+            let mut command = Command::new("qm");
+            command
+                .arg("set").arg(self.vmid.to_string())
+                .arg(format!("--{}", drive)).arg(&self.volid);
+            run(command)?;
+            message!("Attached {} to VM {} as {}", self.volid, self.vmid, drive);
+        }
+        self.finished = true;
+        Ok(std::mem::take(&mut self.volid))
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Don't leave an incomplete disk behind
+        if !self.finished {
+            free_volume(&self.volid);
+        }
+    }
+}
+
+fn free_volume(volid: &str) {
+    message!("Freeing Proxmox volume {}", volid);
+    let mut command = Command::new("pvesm");
+    command.arg("free").arg(volid);
+    if run(command).is_err() {
+        message!("Warning: failed to free Proxmox volume {}", volid);
+    }
+}
+
+fn run(mut command: Command) -> std::io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "Proxmox command failed ({})",
+            output.status,
+        )));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,136 @@
+//! Sorted sets of clusters, stored as runs of consecutive clusters so that
+//! their size depends on how fragmented the disk is rather than on how much
+//! data it has.
+
+use std::ops::Range;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Clusters {
+    /// Sorted, neither overlapping nor adjacent
+    extents: Vec<Range<u64>>,
+    len: u64,
+}
+
+impl Clusters {
+    pub fn new() -> Clusters {
+        Clusters::default()
+    }
+
+    /// Number of clusters.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add clusters after the last one; those before the end of the last one
+    /// are left out.
+    pub fn push_range(&mut self, range: Range<u64>) {
+        let start = match self.extents.last_mut() {
+            Some(last) if range.start <= last.end => {
+                if range.end > last.end {
+                    self.len += range.end - last.end;
+                    last.end = range.end;
+                }
+                return;
+            }
+            _ => range.start,
+        };
+        if start < range.end {
+            self.len += range.end - start;
+            self.extents.push(start..range.end);
+        }
+    }
+
+    /// Add a cluster after the last one.
+    pub fn push(&mut self, cluster: u64) {
+        self.push_range(cluster..cluster + 1);
+    }
+
+    pub fn clear(&mut self) {
+        self.extents.clear();
+        self.len = 0;
+    }
+
+    pub fn contains(&self, cluster: u64) -> bool {
+        let index = self.extents.partition_point(|e| e.end <= cluster);
+        self.extents.get(index).is_some_and(|e| e.start <= cluster)
+    }
+
+    /// The first cluster after `cluster`, or the first cluster if `None`.
+    pub fn next_after(&self, cluster: Option<u64>) -> Option<u64> {
+        let Some(cluster) = cluster else {
+            return self.extents.first().map(|e| e.start);
+        };
+        let index = self.extents.partition_point(|e| e.end <= cluster + 1);
+        self.extents.get(index).map(|e| e.start.max(cluster + 1))
+    }
+
+    /// Runs of consecutive clusters, in order.
+    pub fn extents(&self) -> &[Range<u64>] {
+        &self.extents
+    }
+
+    /// Runs of consecutive clusters that overlap `range`, clipped to it.
+    pub fn overlapping(&self, range: Range<u64>) -> impl Iterator<Item = Range<u64>> + '_ {
+        let first = self.extents.partition_point(|e| e.end <= range.start);
+        self.extents[first..].iter()
+            .take_while(move |e| e.start < range.end)
+            .map(move |e| e.start.max(range.start)..e.end.min(range.end))
+    }
+
+    /// Every cluster, in order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + Clone + '_ {
+        self.extents.iter().flat_map(Range::clone)
+    }
+
+    /// Memory used, in bytes.
+    pub fn memory_usage(&self) -> u64 {
+        (self.extents.capacity() * std::mem::size_of::<Range<u64>>()) as u64
+    }
+}
+
+impl From<Range<u64>> for Clusters {
+    fn from(range: Range<u64>) -> Clusters {
+        let mut clusters = Clusters::new();
+        clusters.push_range(range);
+        clusters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clusters() {
+        let mut clusters = Clusters::new();
+        clusters.push_range(2..4);
+        clusters.push(4);
+        clusters.push_range(3..6);
+        clusters.push_range(1..2);
+        clusters.push_range(8..8);
+        clusters.push(9);
+        assert_eq!(clusters.extents(), [2..6, 9..10]);
+        assert_eq!(clusters.len(), 5);
+        assert_eq!(clusters.iter().collect::<Vec<_>>(), [2, 3, 4, 5, 9]);
+
+        assert!(!clusters.contains(1));
+        assert!(clusters.contains(2));
+        assert!(clusters.contains(5));
+        assert!(!clusters.contains(6));
+        assert!(clusters.contains(9));
+
+        assert_eq!(clusters.next_after(None), Some(2));
+        assert_eq!(clusters.next_after(Some(2)), Some(3));
+        assert_eq!(clusters.next_after(Some(5)), Some(9));
+        assert_eq!(clusters.next_after(Some(7)), Some(9));
+        assert_eq!(clusters.next_after(Some(9)), None);
+
+        assert!(clusters.overlapping(3..9).eq(std::iter::once(3..6)));
+        assert_eq!(clusters.overlapping(0..20).collect::<Vec<_>>(), [2..6, 9..10]);
+        assert_eq!(clusters.overlapping(6..9).count(), 0);
+    }
+}
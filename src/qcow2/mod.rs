@@ -0,0 +1,1128 @@
+mod clusters;
+pub mod reader;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::buffer::{self, Buffer};
+use crate::log::message;
+use crate::manifest::Manifest;
+use crate::{layout, progress, signals};
+
+pub use clusters::Clusters;
+
+pub const CLUSTER_SIZE: u64 = 65536;
+
+/// Largest virtual size of the images written: QEMU won't open images whose
+/// L1 table is over 32 MiB, which maps 2 PiB with our cluster size
+pub const MAX_VIRTUAL_SIZE: u64 = (32 << 20) / 8 * (CLUSTER_SIZE / 8) * CLUSTER_SIZE;
+
+/// Most clusters sent with one `sendfile()` run, so progress is reported
+/// and cancellation checked regularly
+#[cfg(target_os = "linux")]
+const SENDFILE_CLUSTERS: u64 = 256;
+
+/// Size of the fixed part of the version 2 header
+const HEADER_SIZE: usize = 72;
+
+/// Size of the fixed part of the version 3 header, used for zero clusters
+const HEADER_SIZE_V3: usize = 104;
+
+/// L2 entry flag of a cluster that reads as zeros (version 3 only)
+const L2_ZERO: u64 = 1;
+
+/// Header extension type for the provenance record ("SQCW")
+const PROVENANCE_EXTENSION: u32 = 0x5351_4357;
+
+/// Header extension type giving the format of the backing file
+const BACKING_FORMAT_EXTENSION: u32 = 0xe279_2aca;
+
+/// Information about the conversion run, recorded in a header extension.
+///
+/// QEMU ignores unknown header extensions, so this does not affect how the
+/// image is used.
+pub struct Provenance {
+    /// Identifier of the source, such as the input path or a volume name
+    pub source: String,
+    /// Creation time, in seconds since the Unix epoch
+    pub created: u64,
+}
+
+/// Which clusters are allocated in the image.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Preallocation {
+    /// Only the clusters containing data
+    Off,
+    /// Every guest cluster, zero-filled where the input has no data
+    Full,
+}
+
+impl Provenance {
+    fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "tool": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "source": self.source,
+            "created": self.created,
+        })).unwrap()
+    }
+}
+
+pub struct StreamingQcow2Writer {
+    input_size: u64,
+    /// Size of the virtual disk, if larger than the input
+    virtual_size: Option<u64>,
+    /// Multiple the virtual size is rounded up to
+    size_alignment: Option<u64>,
+    l1_clusters: u64,
+    l1_offset: u64,
+    refcount_table_clusters: u64,
+    /// Number of clusters of the refcount table, refcount blocks, L1 and L2
+    /// tables
+    metadata_clusters: u64,
+    /// Whether the metadata goes after the data rather than before it
+    metadata_at_end: bool,
+    /// First cluster of the metadata, where the refcount table is
+    metadata_cluster: u64,
+    first_data_cluster: u64,
+    /// Guest clusters stored in the image
+    data_clusters: Clusters,
+    /// With full preallocation, the guest clusters read from the input; the
+    /// other data clusters are written as zeros
+    source_clusters: Option<Clusters>,
+    /// Byte ranges to copy from the data clusters, the rest being zeroed
+    mask: Option<Vec<Range<u64>>>,
+    /// Guest clusters without data that read as zeros rather than from the
+    /// backing file
+    zero_clusters: Clusters,
+    provenance: Option<Provenance>,
+    backing_file: Option<String>,
+    backing_format: Option<String>,
+}
+
+fn divide_and_round_up(a: u64, b: u64) -> u64 {
+    a.div_ceil(b)
+}
+
+/// Convert a count to a 32-bit header field, which it has to fit in.
+fn header_u32(value: u64) -> std::io::Result<u32> {
+    u32::try_from(value).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "the image is too large for the qcow2 header",
+    ))
+}
+
+impl StreamingQcow2Writer {
+    /// Create a writer for an input of `input_size` bytes, with data in
+    /// `ranges`.
+    ///
+    /// The ranges have to be sorted and not overlap; empty ranges are
+    /// ignored.
+    pub fn new<I: Iterator<Item=Range<u64>>>(input_size: u64, ranges: I) -> std::io::Result<StreamingQcow2Writer> {
+        // Build the set of clusters
+        let mut data_clusters = Clusters::new();
+        let mut last_cluster = None;
+        for range in ranges {
+            if range.start >= range.end {
+                continue;
+            }
+
+            // Compute the range of clusters containing those bytes
+            let mut from_cluster = range.start / CLUSTER_SIZE;
+            let to_cluster = divide_and_round_up(range.end, CLUSTER_SIZE);
+
+            if let Some(last_cluster) = last_cluster {
+                if from_cluster < last_cluster {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "data ranges are not sorted",
+                    ));
+                } else if from_cluster == last_cluster {
+                    // It is possible for the start of this range to fall in
+                    // the same cluster where the last range ended
+                    from_cluster += 1;
+                }
+            }
+            last_cluster = Some(to_cluster - 1);
+
+            data_clusters.push_range(from_cluster..to_cluster);
+        }
+
+        let mut writer = StreamingQcow2Writer {
+            input_size,
+            virtual_size: None,
+            size_alignment: None,
+            l1_clusters: 0,
+            l1_offset: 0,
+            refcount_table_clusters: 0,
+            metadata_clusters: 0,
+            metadata_at_end: false,
+            metadata_cluster: 0,
+            first_data_cluster: 0,
+            data_clusters,
+            source_clusters: None,
+            mask: None,
+            zero_clusters: Clusters::new(),
+            provenance: None,
+            backing_file: None,
+            backing_format: None,
+        };
+        writer.compute_layout();
+        Ok(writer)
+    }
+
+    /// Compute the position of the metadata from the list of data clusters.
+    fn compute_layout(&mut self) {
+        // Compute the number of L2 tables required
+        let guest_clusters = self.total_guest_clusters();
+        let l2_tables = divide_and_round_up(guest_clusters * 8, CLUSTER_SIZE);
+
+        // Compute the size of the L1 table in clusters
+        let l1_clusters = divide_and_round_up(l2_tables * 8, CLUSTER_SIZE);
+
+        // Picking a number of refcount blocks changes the number of allocated
+        // clusters, which changes the number of refcount blocks
+        let mut refcount_blocks = 1;
+        let mut refcount_table_clusters = 1;
+        loop {
+            let total_clusters =
+                1 // Header
+                + refcount_table_clusters
+                + refcount_blocks
+                + l1_clusters
+                + l2_tables
+                + self.data_clusters.len(); // Data
+            let new_refcount_blocks = divide_and_round_up(total_clusters * 2, CLUSTER_SIZE);
+            if new_refcount_blocks == refcount_blocks {
+                break;
+            }
+            refcount_blocks = new_refcount_blocks;
+            refcount_table_clusters = divide_and_round_up(refcount_blocks * 8, CLUSTER_SIZE);
+        }
+
+        let metadata_clusters =
+            refcount_table_clusters
+            + refcount_blocks
+            + l1_clusters
+            + l2_tables;
+
+        // The header is always first, followed by either the metadata or the
+        // data
+        let (metadata_cluster, first_data_cluster) = if self.metadata_at_end {
+            (1 + self.data_clusters.len(), 1)
+        } else {
+            (1, 1 + metadata_clusters)
+        };
+
+        let l1_offset = CLUSTER_SIZE * (
+            metadata_cluster
+            + refcount_table_clusters
+            + refcount_blocks
+        );
+
+        self.l1_clusters = l1_clusters;
+        self.l1_offset = l1_offset;
+        self.refcount_table_clusters = refcount_table_clusters;
+        self.metadata_clusters = metadata_clusters;
+        self.metadata_cluster = metadata_cluster;
+        self.first_data_cluster = first_data_cluster;
+    }
+
+    /// Set which clusters are allocated in the image.
+    ///
+    /// With full preallocation, every guest cluster gets a data cluster, for
+    /// storage and hypervisors that perform poorly with sparse images.
+    pub fn set_preallocation(&mut self, preallocation: Preallocation) {
+        match (preallocation, self.source_clusters.is_some()) {
+            (Preallocation::Full, false) => {
+                let all_clusters = Clusters::from(0..self.total_guest_clusters());
+                self.source_clusters = Some(std::mem::replace(&mut self.data_clusters, all_clusters));
+            }
+            (Preallocation::Off, true) => {
+                self.data_clusters = self.source_clusters.take().unwrap();
+            }
+            _ => return,
+        }
+        self.compute_layout();
+    }
+
+    /// Make the virtual disk larger than the input, leaving the extra space
+    /// unallocated. It is never made smaller than the input.
+    pub fn set_virtual_size(&mut self, size: u64) {
+        self.virtual_size = Some(size);
+        self.compute_layout();
+    }
+
+    /// Round the virtual size up to a multiple of `alignment`, leaving the
+    /// extra space unallocated.
+    pub fn set_size_alignment(&mut self, alignment: u64) -> std::io::Result<()> {
+        if alignment == 0 || alignment > MAX_VIRTUAL_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the size alignment has to be between 1 and {} bytes", MAX_VIRTUAL_SIZE),
+            ));
+        }
+        self.size_alignment = Some(alignment);
+        self.compute_layout();
+        Ok(())
+    }
+
+    /// Only copy these byte ranges, zeroing the rest of the data clusters.
+    ///
+    /// The ranges have to be sorted and not overlap.
+    pub fn set_mask(&mut self, ranges: Vec<Range<u64>>) {
+        self.mask = Some(ranges);
+    }
+
+    /// Record the clusters entirely in these byte ranges as zero clusters,
+    /// which read as zeros without being stored, even over a backing file.
+    /// Data clusters are left as they are.
+    ///
+    /// The ranges have to be sorted, and are clipped to the virtual size.
+    /// Zero clusters need a version 3 header.
+    pub fn set_zero_ranges(&mut self, ranges: &[Range<u64>]) -> std::io::Result<()> {
+        let previous = std::mem::take(&mut self.zero_clusters);
+        let data_clusters = self.source_clusters.as_ref().unwrap_or(&self.data_clusters);
+        for range in ranges {
+            // The clusters of the range between data clusters
+            let mut start = range.start.div_ceil(CLUSTER_SIZE);
+            let end = (range.end / CLUSTER_SIZE).min(self.total_guest_clusters());
+            if start >= end {
+                continue;
+            }
+            for data in data_clusters.overlapping(start..end) {
+                self.zero_clusters.push_range(start..data.start);
+                start = data.end;
+            }
+            self.zero_clusters.push_range(start..end);
+        }
+        self.check_header_size().inspect_err(|_| self.zero_clusters = previous)
+    }
+
+    /// Guest clusters recorded as zero clusters.
+    pub fn zero_clusters(&self) -> &Clusters {
+        &self.zero_clusters
+    }
+
+    /// Version of the image format, 3 only if needed for zero clusters so
+    /// that older software can read the image otherwise.
+    fn version(&self) -> u32 {
+        if self.zero_clusters.is_empty() { 2 } else { 3 }
+    }
+
+    /// Size of the fixed part of the header.
+    fn header_size(&self) -> usize {
+        match self.version() {
+            2 => HEADER_SIZE,
+            _ => HEADER_SIZE_V3,
+        }
+    }
+
+    /// Zero the parts of a data cluster that are outside of the mask.
+    fn apply_mask(&self, cluster: u64, buffer: &mut [u8]) {
+        if let Some(mask) = &self.mask {
+            layout::mask(mask, cluster * CLUSTER_SIZE, buffer);
+        }
+    }
+
+    /// Record provenance information in a header extension.
+    pub fn set_provenance(&mut self, provenance: Provenance) -> std::io::Result<()> {
+        let previous = self.provenance.replace(provenance);
+        self.check_header_size().inspect_err(|_| self.provenance = previous)
+    }
+
+    /// Make the image an overlay of another image, which clusters that are
+    /// not copied are read from.
+    pub fn set_backing_file(&mut self, backing_file: String) -> std::io::Result<()> {
+        let previous = self.backing_file.replace(backing_file);
+        self.check_header_size().inspect_err(|_| self.backing_file = previous)
+    }
+
+    /// Record the format of the backing file (raw or qcow2), so readers
+    /// don't have to guess it.
+    pub fn set_backing_format(&mut self, format: String) -> std::io::Result<()> {
+        let previous = self.backing_format.replace(format);
+        self.check_header_size().inspect_err(|_| self.backing_format = previous)
+    }
+
+    /// Header extensions, including the end marker, or empty if none.
+    fn header_extensions(&self) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if let (Some(_), Some(format)) = (&self.backing_file, &self.backing_format) {
+            extensions.extend_from_slice(&BACKING_FORMAT_EXTENSION.to_be_bytes());
+            extensions.extend_from_slice(&(format.len() as u32).to_be_bytes());
+            extensions.extend_from_slice(format.as_bytes());
+            extensions.resize(extensions.len().next_multiple_of(8), 0);
+        }
+        if let Some(provenance) = &self.provenance {
+            let data = provenance.to_json();
+            extensions.extend_from_slice(&PROVENANCE_EXTENSION.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            extensions.extend_from_slice(&data);
+            extensions.resize(extensions.len().next_multiple_of(8), 0);
+        }
+        if !extensions.is_empty() {
+            // End of header extensions
+            extensions.extend_from_slice(&[0; 8]);
+        }
+        extensions
+    }
+
+    /// Check that the header extensions and backing file name fit in the
+    /// header cluster.
+    fn check_header_size(&self) -> std::io::Result<()> {
+        let backing_file_size = self.backing_file.as_ref().map_or(0, |b| b.len());
+        if self.header_size() + self.header_extensions().len() + backing_file_size > CLUSTER_SIZE as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "header extensions and backing file name are too large for the header",
+            ));
+        }
+        Ok(())
+    }
+
+    fn total_clusters(&self) -> u64 {
+        1 + self.metadata_clusters + self.data_clusters.len()
+    }
+
+    pub fn file_size(&self) -> u64 {
+        CLUSTER_SIZE * self.total_clusters()
+    }
+
+    /// Describe the image like `qemu-img info --output=json` would.
+    pub fn info(&self, filename: &str) -> serde_json::Value {
+        let mut info = serde_json::json!({
+            "virtual-size": self.virtual_size(),
+            "filename": filename,
+            "cluster-size": CLUSTER_SIZE,
+            "format": "qcow2",
+            "actual-size": self.file_size(),
+            "format-specific": {
+                "type": "qcow2",
+                "data": {
+                    "compat": if self.version() == 2 { "0.10" } else { "1.1" },
+                    "compression-type": "zlib",
+                    "refcount-bits": 16,
+                },
+            },
+            "dirty-flag": false,
+        });
+        if let Some(backing_file) = &self.backing_file {
+            info["backing-filename"] = backing_file.as_str().into();
+            if let Some(format) = &self.backing_format {
+                info["backing-filename-format"] = format.as_str().into();
+            }
+        }
+        info
+    }
+
+    /// Size of the disk.
+    pub fn input_size(&self) -> u64 {
+        self.input_size
+    }
+
+    /// Size of the virtual disk, the space past the input being unallocated.
+    pub fn virtual_size(&self) -> u64 {
+        let size = self.virtual_size.map_or(self.input_size, |s| s.max(self.input_size));
+        // Too large to round, this is caught when writing the header
+        self.size_alignment.map_or(size, |a| size.checked_next_multiple_of(a).unwrap_or(u64::MAX))
+    }
+
+    /// Guest clusters that are stored in the image, in the order their data
+    /// is written after the header.
+    pub fn data_clusters(&self) -> &Clusters {
+        &self.data_clusters
+    }
+
+    /// Memory used by the sets of clusters and the mask, in bytes.
+    pub fn memory_usage(&self) -> u64 {
+        let clusters = self.data_clusters.memory_usage()
+            + self.source_clusters.as_ref().map_or(0, |c| c.memory_usage())
+            + self.zero_clusters.memory_usage();
+        let mask = self.mask.as_ref().map_or(0, |m| m.capacity());
+        clusters + (mask * std::mem::size_of::<Range<u64>>()) as u64
+    }
+
+    pub fn total_guest_clusters(&self) -> u64 {
+        divide_and_round_up(self.virtual_size(), CLUSTER_SIZE)
+    }
+
+    /// Write the header and the metadata, which go before the data.
+    pub fn write_header<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        self.write_header_cluster(&mut writer)?;
+        self.write_metadata(&mut writer)
+    }
+
+    fn write_header_cluster<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        // Magic
+        writer.write_all(b"QFI\xFB")?;
+
+        // Version
+        writer.write_u32::<BigEndian>(self.version())?;
+
+        // Backing file name offset (0 = no backing file), the name goes
+        // right after the header extensions
+        let extensions = self.header_extensions();
+        let backing_file = self.backing_file.as_deref().unwrap_or("").as_bytes();
+        if backing_file.is_empty() {
+            writer.write_u64::<BigEndian>(0)?;
+        } else {
+            writer.write_u64::<BigEndian>((self.header_size() + extensions.len()) as u64)?;
+        }
+
+        // Backing file name length
+        writer.write_u32::<BigEndian>(backing_file.len() as u32)?;
+
+        // Number of bits per cluster address, 1<<bits is the cluster size
+        assert_eq!(CLUSTER_SIZE, 1 << 16);
+        writer.write_u32::<BigEndian>(16)?;
+
+        // Virtual disk size in bytes
+        writer.write_u64::<BigEndian>(self.virtual_size())?;
+
+        // Encryption method (none)
+        writer.write_u32::<BigEndian>(0)?;
+
+        // L1 table size (number of entries)
+        let l2_entries_per_cluster = CLUSTER_SIZE / 8;
+        let l1_entries = divide_and_round_up(self.total_guest_clusters(), l2_entries_per_cluster);
+        if self.virtual_size() > MAX_VIRTUAL_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the virtual size is larger than the qcow2 maximum of {} bytes", MAX_VIRTUAL_SIZE),
+            ));
+        }
+        writer.write_u32::<BigEndian>(header_u32(l1_entries)?)?;
+
+        // L1 table offset
+        writer.write_u64::<BigEndian>(self.l1_offset)?;
+
+        // Refcount table offset
+        writer.write_u64::<BigEndian>(self.metadata_cluster * CLUSTER_SIZE)?;
+
+        // Refcount table length in clusters
+        writer.write_u32::<BigEndian>(header_u32(self.refcount_table_clusters)?)?;
+
+        // Number of snapshots in the image
+        writer.write_u32::<BigEndian>(0)?;
+
+        // Offset of the snapshot table (must be aligned to clusters)
+        writer.write_u64::<BigEndian>(0)?;
+
+        if self.version() >= 3 {
+            // Incompatible, compatible and autoclear feature bits (none)
+            writer.write_u64::<BigEndian>(0)?;
+            writer.write_u64::<BigEndian>(0)?;
+            writer.write_u64::<BigEndian>(0)?;
+
+            // Refcount order, refcounts are 1<<4 = 16 bits
+            writer.write_u32::<BigEndian>(4)?;
+
+            // Header length
+            writer.write_u32::<BigEndian>(HEADER_SIZE_V3 as u32)?;
+        }
+
+        // Header extensions, then backing file name
+        writer.write_all(&extensions)?;
+        writer.write_all(backing_file)?;
+
+        writer.write_all(&vec![
+            0u8;
+            CLUSTER_SIZE as usize - self.header_size() - extensions.len() - backing_file.len()
+        ])?;
+
+        Ok(())
+    }
+
+    fn write_metadata<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        self.write_refcount_table(&mut writer)?;
+        self.write_mapping_table(&mut writer)
+    }
+
+    fn write_refcount_table<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let _span = tracing::debug_span!("refcounts").entered();
+        let refcount_blocks = divide_and_round_up(self.total_clusters() * 2, CLUSTER_SIZE);
+
+        // Table
+        let first_block = self.metadata_cluster + self.refcount_table_clusters;
+        write_table(&mut writer, (0..refcount_blocks).map(|block| CLUSTER_SIZE * (first_block + block)), 8)?;
+
+        // Blocks
+        write_table(&mut writer, (0..self.total_clusters()).map(|_| 1), 2)
+    }
+
+    fn write_mapping_table<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let _span = tracing::debug_span!("mapping").entered();
+        // L1 table
+        let l1_entries_per_cluster = CLUSTER_SIZE / 8;
+        let l1_entries = divide_and_round_up(self.total_guest_clusters(), l1_entries_per_cluster);
+        let first_l2 = self.l1_offset + self.l1_clusters * CLUSTER_SIZE;
+        write_table(&mut writer, (0..l1_entries).map(|entry| (first_l2 + entry * CLUSTER_SIZE) | (1 << 63)), 8)?;
+
+        // L2 table, mapping guest to host clusters by walking the sorted
+        // sets of clusters along with the guest clusters
+        let mut data_clusters = self.data_clusters.iter().zip(self.first_data_cluster..).peekable();
+        let mut zero_clusters = self.zero_clusters.iter().peekable();
+        let l2_entries = (0..self.total_guest_clusters()).map(|guest_cluster| {
+            let zero = zero_clusters.next_if_eq(&guest_cluster).is_some();
+            match data_clusters.next_if(|&(c, _)| c == guest_cluster) {
+                Some((_, host_cluster)) => {
+                    let offset = host_cluster * CLUSTER_SIZE;
+                    // Bit 62 clear: standard (uncompressed) cluster
+                    offset
+                        | (1 << 63) // Standard cluster with refcount=1
+                }
+                None if zero => L2_ZERO,
+                None => 0,
+            }
+        });
+        write_table(&mut writer, l2_entries, 8)
+    }
+
+    /// Copy the data clusters, recording their hashes in the manifest if any.
+    ///
+    /// Runs of consecutive clusters are read and written together, through
+    /// buffers from the pool. The next run is read from another thread while
+    /// the previous one is written, so the latency of the input and of the
+    /// output overlap.
+    pub fn copy_data<R: Read + Seek + Send, W: Write>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        mut manifest: Option<&mut Manifest>,
+    ) -> std::io::Result<()> {
+        let total_clusters = self.data_clusters.len();
+        progress::start_copy("Copying data", total_clusters * CLUSTER_SIZE);
+        if cfg!(not(any(unix, windows))) {
+            for (index, first, count) in self.runs() {
+                signals::check_cancelled()?;
+                let run = self.read_run(&mut reader, first, count)?;
+                self.write_run(&mut writer, manifest.as_deref_mut(), index, first, &run)?;
+            }
+            return Ok(());
+        }
+
+        // The reads are part of the phase, on the reader thread too
+        let phase = tracing::Span::current();
+        std::thread::scope(|scope| {
+            // Rendezvous channel: one run is read while the other is written
+            let (sender, receiver) = std::sync::mpsc::sync_channel(0);
+            scope.spawn(move || {
+                let _phase = phase.enter();
+                for (index, first, count) in self.runs() {
+                    let run = self.read_run(&mut reader, first, count).map(|run| (index, first, run));
+                    let failed = run.is_err();
+                    // Stop if the writer is gone
+                    if sender.send(run).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            for run in receiver {
+                signals::check_cancelled()?;
+                let (index, first, run) = run?;
+                self.write_run(&mut writer, manifest.as_deref_mut(), index, first, &run)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs of consecutive data clusters that are read together, as the
+    /// index of their first cluster among the data clusters, that cluster,
+    /// and their length.
+    fn runs(&self) -> impl Iterator<Item = (u64, u64, usize)> + '_ {
+        let run_clusters = buffer::size() / CLUSTER_SIZE as usize;
+        let mut clusters = self.data_clusters.iter().zip(0..).peekable();
+        std::iter::from_fn(move || {
+            let (first, index) = clusters.next()?;
+            let source = self.reads_cluster(first);
+            let mut count = 1;
+            while count < run_clusters
+                && clusters.next_if(|&(c, _)| c == first + count as u64 && self.reads_cluster(c) == source).is_some()
+            {
+                count += 1;
+            }
+            Some((index, first, count))
+        })
+    }
+
+    /// Whether a data cluster is read from the input; with full
+    /// preallocation, only the clusters that have data are.
+    fn reads_cluster(&self, cluster: u64) -> bool {
+        match &self.source_clusters {
+            Some(source) => source.contains(cluster),
+            None => true,
+        }
+    }
+
+    /// Read a run of clusters, masked, or zeros if it is not read from the
+    /// input.
+    fn read_run<R: Read + Seek>(&self, mut reader: R, first: u64, count: usize) -> std::io::Result<Buffer> {
+        let _span = tracing::debug_span!("read_run", first, count).entered();
+        let mut run = Buffer::with_len(count * CLUSTER_SIZE as usize);
+        if self.reads_cluster(first) {
+            reader.seek(SeekFrom::Start(first * CLUSTER_SIZE))?;
+            read_full(&mut reader, &mut run)?;
+            for (i, data) in run.chunks_mut(CLUSTER_SIZE as usize).enumerate() {
+                self.apply_mask(first + i as u64, data);
+            }
+        }
+        Ok(run)
+    }
+
+    /// Write a run of clusters starting at guest cluster `first`, which is
+    /// at this index among the data clusters.
+    fn write_run<W: Write>(
+        &self,
+        mut writer: W,
+        manifest: Option<&mut Manifest>,
+        index: u64,
+        first: u64,
+        run: &[u8],
+    ) -> std::io::Result<()> {
+        let _span = tracing::debug_span!("write_run", first, count = run.len() / CLUSTER_SIZE as usize).entered();
+        writer.write_all(run)?;
+        let clusters = run.chunks(CLUSTER_SIZE as usize).zip(0..);
+        if let Some(manifest) = manifest {
+            for (data, i) in clusters.clone() {
+                let host = (self.first_data_cluster + index + i) * CLUSTER_SIZE;
+                manifest.add((first + i) * CLUSTER_SIZE, host, data)?;
+            }
+        }
+        let total_clusters = self.data_clusters.len();
+        for (_, i) in clusters {
+            progress::add_copied(CLUSTER_SIZE);
+            report_copied(index + i, total_clusters);
+        }
+        Ok(())
+    }
+
+    /// Copy the data clusters from a local file with `sendfile()`, so the
+    /// data doesn't go through userspace buffers, which saves CPU when the
+    /// output is a fast pipe or socket (Linux only).
+    ///
+    /// Clusters that have to be modified (masked, or zero-filled with full
+    /// preallocation) are written normally.
+    #[cfg(target_os = "linux")]
+    pub fn copy_data_sendfile(&self, input: &std::fs::File, mut output: &std::fs::File) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let input_size = crate::input::get_file_size(input)?;
+        let total_clusters = self.data_clusters.len();
+        progress::start_copy("Copying data", total_clusters * CLUSTER_SIZE);
+        let mut clusters = self.data_clusters.iter().peekable();
+        let mut buffer = [0u8; CLUSTER_SIZE as usize];
+        let mut index = 0;
+        while let Some(&first) = clusters.peek() {
+            signals::check_cancelled()?;
+            // Find a run of consecutive clusters to send as they are
+            let mut run = 0;
+            while run < SENDFILE_CLUSTERS
+                && clusters.next_if(|&c| c == first + run && self.reads_cluster(c) && !self.is_masked(c)).is_some()
+            {
+                run += 1;
+            }
+
+            let _span = tracing::debug_span!("sendfile_run", first, count = run).entered();
+            if run == 0 {
+                let cluster = clusters.next().unwrap();
+                buffer.fill(0);
+                if self.reads_cluster(cluster) {
+                    let mut reader = crate::input::MarkReadErrors(input);
+                    reader.seek(SeekFrom::Start(cluster * CLUSTER_SIZE))?;
+                    read_full(reader, &mut buffer)?;
+                    self.apply_mask(cluster, &mut buffer);
+                }
+                output.write_all(&buffer)?;
+                run = 1;
+            } else {
+                let start = first * CLUSTER_SIZE;
+                let end = start + run * CLUSTER_SIZE;
+                let mut offset = start as i64;
+                while (offset as u64) < end.min(input_size) {
+                    let count = (end.min(input_size) - offset as u64) as usize;
+                    match nix::sys::sendfile::sendfile64(output.as_raw_fd(), input.as_raw_fd(), Some(&mut offset), count) {
+                        Ok(0) => break,
+                        Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                // Past the end of the input, the last cluster is padded
+                let mut padding = end - offset as u64;
+                while padding > 0 {
+                    let length = padding.min(CLUSTER_SIZE) as usize;
+                    output.write_all(&[0u8; CLUSTER_SIZE as usize][..length])?;
+                    padding -= length as u64;
+                }
+            }
+            for i in index..index + run {
+                progress::add_copied(CLUSTER_SIZE);
+                report_copied(i, total_clusters);
+            }
+            index += run;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn copy_data_sendfile(&self, _input: &std::fs::File, _output: &std::fs::File) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "sendfile is only supported on Linux",
+        ))
+    }
+
+    /// Whether part of a cluster is masked, and so has to be modified.
+    #[cfg(target_os = "linux")]
+    fn is_masked(&self, cluster: u64) -> bool {
+        let Some(mask) = &self.mask else {
+            return false;
+        };
+        let (start, end) = (cluster * CLUSTER_SIZE, (cluster + 1) * CLUSTER_SIZE);
+        let first = mask.partition_point(|r| r.end <= start);
+        !mask.get(first).is_some_and(|r| r.start <= start && end <= r.end)
+    }
+
+    /// Copy the data clusters, leaving out those that turn out to be all
+    /// zeros, unless the image has a backing file.
+    ///
+    /// As with `write_streamed()`, the data is written first and the header
+    /// last, so the output has to be seekable. Returns the number of clusters
+    /// that were left out.
+    pub fn write_detect_zeroes<R: Read + Seek, W: Write + Seek>(
+        &mut self,
+        mut reader: R,
+        mut writer: W,
+        mut manifest: Option<&mut Manifest>,
+    ) -> std::io::Result<u64> {
+        let layout_clusters = std::mem::take(&mut self.data_clusters);
+        let total_clusters = layout_clusters.len();
+        writer.seek(SeekFrom::Start(CLUSTER_SIZE))?;
+        progress::start_copy("Copying data", total_clusters * CLUSTER_SIZE);
+        let mut buffer = [0u8; CLUSTER_SIZE as usize];
+        for (cluster, index) in layout_clusters.iter().zip(0..) {
+            signals::check_cancelled()?;
+            reader.seek(SeekFrom::Start(cluster * CLUSTER_SIZE))?;
+            read_full(&mut reader, &mut buffer)?;
+            self.apply_mask(cluster, &mut buffer);
+            if self.keeps_cluster(&buffer) {
+                let host = (1 + self.data_clusters.len()) * CLUSTER_SIZE;
+                writer.write_all(&buffer)?;
+                if let Some(manifest) = &mut manifest {
+                    manifest.add(cluster * CLUSTER_SIZE, host, &buffer)?;
+                }
+                self.data_clusters.push(cluster);
+            }
+            progress::add_copied(CLUSTER_SIZE);
+            report_copied(index, total_clusters);
+        }
+
+        self.write_metadata_at_end(writer)?;
+        Ok(total_clusters - self.data_clusters.len())
+    }
+
+    /// Convert an input of unknown size, read sequentially until EOF.
+    ///
+    /// The data is written first, after an empty header cluster, then the
+    /// metadata after it; the header is written last by seeking back to the
+    /// start. Clusters that are all zeros are left out, unless the image has
+    /// a backing file.
+    pub fn write_streamed<R: Read, W: Write + Seek>(
+        &mut self,
+        mut reader: R,
+        mut writer: W,
+        mut manifest: Option<&mut Manifest>,
+    ) -> std::io::Result<()> {
+        self.data_clusters.clear();
+        self.source_clusters = None;
+        writer.seek(SeekFrom::Start(CLUSTER_SIZE))?;
+        progress::start_copy("Copying data", 0);
+        let mut size = 0;
+        let mut buffer = [0u8; CLUSTER_SIZE as usize];
+        loop {
+            signals::check_cancelled()?;
+            let read = read_full(&mut reader, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            if self.keeps_cluster(&buffer) {
+                let host = (1 + self.data_clusters.len()) * CLUSTER_SIZE;
+                writer.write_all(&buffer)?;
+                if let Some(manifest) = &mut manifest {
+                    manifest.add(size, host, &buffer)?;
+                }
+                self.data_clusters.push(size / CLUSTER_SIZE);
+            }
+            size += read as u64;
+            progress::add_copied(read as u64);
+            if read < buffer.len() {
+                break;
+            }
+        }
+
+        self.input_size = size;
+        self.write_metadata_at_end(writer)
+    }
+
+    /// Whether a cluster read from the input has to be stored in the image.
+    ///
+    /// Clusters of zeros can be left out, except over a backing file, whose
+    /// data they have to hide.
+    fn keeps_cluster(&self, data: &[u8]) -> bool {
+        self.backing_file.is_some() || data.iter().any(|&b| b != 0)
+    }
+
+    /// Write the metadata after the data clusters, then the header at the
+    /// start of the output.
+    fn write_metadata_at_end<W: Write + Seek>(&mut self, mut writer: W) -> std::io::Result<()> {
+        self.metadata_at_end = true;
+        self.compute_layout();
+        self.write_metadata(&mut writer)?;
+        writer.seek(SeekFrom::Start(0))?;
+        self.write_header_cluster(&mut writer)?;
+        writer.flush()
+    }
+}
+
+/// Print the progress of a copy at the interval set in `progress`, after
+/// cluster `index` out of `total_clusters`, unless the progress is shown
+/// another way.
+fn report_copied(index: u64, total_clusters: u64) {
+    // Report on the data only, not the metadata
+    let copied = index * CLUSTER_SIZE;
+    if progress::line_due(copied, copied + CLUSTER_SIZE) {
+        message!(
+            "{}/{} data clusters copied ({}/{} bytes)",
+            index + 1,
+            total_clusters,
+            copied + CLUSTER_SIZE,
+            total_clusters * CLUSTER_SIZE,
+        );
+    }
+}
+
+/// Write a table of big-endian entries of `size` bytes, padded with zeros to
+/// a whole number of clusters.
+///
+/// The entries are put in place in a buffer from the pool, written whenever
+/// it is full, so tables of any size take constant memory and large ones are
+/// written with few large writes rather than one per cluster.
+fn write_table<W: Write, I: Iterator<Item = u64>>(mut writer: W, entries: I, size: usize) -> std::io::Result<()> {
+    let mut buffer = Buffer::get();
+    let mut pos = 0;
+    for entry in entries {
+        buffer[pos..pos + size].copy_from_slice(&entry.to_be_bytes()[8 - size..]);
+        pos += size;
+        if pos == buffer.len() {
+            writer.write_all(&buffer)?;
+            pos = 0;
+        }
+    }
+    if pos > 0 {
+        let end = pos.next_multiple_of(CLUSTER_SIZE as usize);
+        buffer[pos..end].fill(0);
+        writer.write_all(&buffer[..end])?;
+    }
+    Ok(())
+}
+
+/// Fill the buffer from the reader, leaving the end zeroed if EOF is reached.
+///
+/// Returns the number of bytes read.
+pub fn read_full<R: Read>(mut reader: R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match reader.read(&mut buffer[pos..]) {
+            Ok(0) => {
+                buffer[pos..].fill(0);
+                break;
+            }
+            Ok(n) => pos += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::reader::{Cluster, Reader};
+    use super::{StreamingQcow2Writer, CLUSTER_SIZE};
+    use crate::check;
+
+    /// Input of `size` bytes, non-zero everywhere.
+    fn input(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    /// Write the image, and check that its metadata is consistent.
+    fn write(writer: &StreamingQcow2Writer, input: &[u8]) -> Vec<u8> {
+        let mut image = Vec::new();
+        writer.write_header(&mut image).unwrap();
+        writer.copy_data(Cursor::new(input), &mut image, None).unwrap();
+        assert_eq!(image.len() as u64, writer.file_size());
+
+        let report = check::check(Cursor::new(&image)).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(report.leaked_clusters, 0);
+        assert_eq!(report.allocated_clusters, writer.data_clusters().len());
+        assert_eq!(report.guest_clusters, writer.total_guest_clusters());
+        image
+    }
+
+    /// The disk the image should read as: the input where clusters were
+    /// copied, `fill` elsewhere, then zeros for the zero clusters.
+    fn expected(writer: &StreamingQcow2Writer, input: &[u8], fill: u8) -> Vec<u8> {
+        let size = writer.virtual_size() as usize;
+        let mut disk = vec![fill; size];
+        for cluster in writer.data_clusters().iter() {
+            let start = (cluster * CLUSTER_SIZE) as usize;
+            let end = (start + CLUSTER_SIZE as usize).min(input.len());
+            disk[start..end].copy_from_slice(&input[start..end]);
+            disk[end..(start + CLUSTER_SIZE as usize).min(size)].fill(0);
+        }
+        for cluster in writer.zero_clusters().iter() {
+            let start = (cluster * CLUSTER_SIZE) as usize;
+            disk[start..(start + CLUSTER_SIZE as usize).min(size)].fill(0);
+        }
+        disk
+    }
+
+    fn read(reader: &mut Reader<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut disk = Vec::new();
+        reader.read_to_end(&mut disk).unwrap();
+        disk
+    }
+
+    #[test]
+    fn empty_image() {
+        let writer = StreamingQcow2Writer::new(0, std::iter::empty()).unwrap();
+        let mut reader = Reader::new(Cursor::new(write(&writer, &[]))).unwrap();
+        assert_eq!(reader.size(), 0);
+        assert!(reader.data_extents().unwrap().is_empty());
+        assert!(read(&mut reader).is_empty());
+
+        // Without data
+        let writer = StreamingQcow2Writer::new(3 * CLUSTER_SIZE, std::iter::empty()).unwrap();
+        let mut reader = Reader::new(Cursor::new(write(&writer, &input(3 * CLUSTER_SIZE)))).unwrap();
+        assert_eq!(reader.size(), 3 * CLUSTER_SIZE);
+        assert!(reader.data_extents().unwrap().is_empty());
+        assert_eq!(read(&mut reader), vec![0; 3 * CLUSTER_SIZE as usize]);
+    }
+
+    #[test]
+    fn sparse_image() {
+        let size = 10 * CLUSTER_SIZE;
+        let input = input(size);
+        let ranges = [100..200, 4 * CLUSTER_SIZE - 10..5 * CLUSTER_SIZE + 10, 9 * CLUSTER_SIZE..size];
+        let writer = StreamingQcow2Writer::new(size, ranges.into_iter()).unwrap();
+        assert_eq!(writer.data_clusters().iter().collect::<Vec<_>>(), [0, 3, 4, 5, 9]);
+        let mut reader = Reader::new(Cursor::new(write(&writer, &input))).unwrap();
+        assert!(reader.cluster(1).unwrap() == Cluster::Unallocated);
+        assert!(matches!(reader.cluster(3).unwrap(), Cluster::Data(_)));
+        assert_eq!(
+            reader.data_extents().unwrap(),
+            vec![0..CLUSTER_SIZE, 3 * CLUSTER_SIZE..6 * CLUSTER_SIZE, 9 * CLUSTER_SIZE..size],
+        );
+        assert_eq!(read(&mut reader), expected(&writer, &input, 0));
+    }
+
+    #[test]
+    fn large_virtual_size() {
+        // Several L2 tables, most of them not allocated
+        let input = input(3 * CLUSTER_SIZE);
+        let mut writer = StreamingQcow2Writer::new(input.len() as u64, std::iter::once(CLUSTER_SIZE..2 * CLUSTER_SIZE)).unwrap();
+        writer.set_virtual_size(16 << 30);
+        let mut reader = Reader::new(Cursor::new(write(&writer, &input))).unwrap();
+        assert_eq!(reader.size(), 16 << 30);
+        assert_eq!(reader.data_extents().unwrap(), vec![CLUSTER_SIZE..2 * CLUSTER_SIZE]);
+    }
+
+    #[test]
+    fn zero_ranges() {
+        let size = 8 * CLUSTER_SIZE;
+        let input = input(size);
+        let mut writer = StreamingQcow2Writer::new(size, std::iter::once(2 * CLUSTER_SIZE..3 * CLUSTER_SIZE)).unwrap();
+        // Only whole clusters become zero clusters, and data clusters stay
+        writer.set_zero_ranges(&[CLUSTER_SIZE..4 * CLUSTER_SIZE, 5 * CLUSTER_SIZE + 1..size]).unwrap();
+        assert_eq!(writer.zero_clusters().iter().collect::<Vec<_>>(), [1, 3, 6, 7]);
+        let mut reader = Reader::new(Cursor::new(write(&writer, &input))).unwrap();
+        assert!(reader.cluster(1).unwrap() == Cluster::Zero(None));
+        assert!(matches!(reader.cluster(2).unwrap(), Cluster::Data(_)));
+        assert!(reader.cluster(5).unwrap() == Cluster::Unallocated);
+        assert_eq!(read(&mut reader), expected(&writer, &input, 0));
+    }
+
+    #[test]
+    fn backing_file() {
+        let directory = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.backing", std::process::id()));
+        std::fs::create_dir(&directory).unwrap();
+        std::fs::write(directory.join("base.raw"), vec![0xAA; 6 * CLUSTER_SIZE as usize]).unwrap();
+
+        let size = 6 * CLUSTER_SIZE;
+        let input = input(size);
+        let mut writer = StreamingQcow2Writer::new(size, std::iter::once(CLUSTER_SIZE..2 * CLUSTER_SIZE)).unwrap();
+        writer.set_zero_ranges(std::slice::from_ref(&(4 * CLUSTER_SIZE..5 * CLUSTER_SIZE))).unwrap();
+        writer.set_backing_file("base.raw".to_owned()).unwrap();
+        let image = write(&writer, &input);
+
+        // Clusters that are not copied read from the backing file, unless
+        // they are zero clusters
+        let mut reader = Reader::open(Cursor::new(image), &directory.join("overlay.qcow2"), false).unwrap();
+        assert_eq!(reader.data_extents().unwrap(), vec![0..4 * CLUSTER_SIZE, 5 * CLUSTER_SIZE..size]);
+        let disk = read(&mut reader);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(disk, expected(&writer, &input, 0xAA));
+    }
+
+    #[test]
+    fn unaligned_size() {
+        let size = 2 * CLUSTER_SIZE + 1000;
+        let input = input(size);
+        let writer = StreamingQcow2Writer::new(size, std::iter::once(0..size)).unwrap();
+        assert_eq!(writer.virtual_size(), size);
+        let mut reader = Reader::new(Cursor::new(write(&writer, &input))).unwrap();
+        assert_eq!(reader.data_extents().unwrap(), vec![0..size]);
+        assert_eq!(read(&mut reader), input);
+
+        // Rounded up to an alignment, the rest reading as zeros
+        let mut writer = StreamingQcow2Writer::new(size, std::iter::once(CLUSTER_SIZE..size)).unwrap();
+        writer.set_size_alignment(1 << 20).unwrap();
+        assert_eq!(writer.virtual_size(), 1 << 20);
+        let mut reader = Reader::new(Cursor::new(write(&writer, &input))).unwrap();
+        assert_eq!(read(&mut reader), expected(&writer, &input, 0));
+    }
+
+    #[test]
+    fn empty_ranges() {
+        let writer = StreamingQcow2Writer::new(
+            4 * CLUSTER_SIZE,
+            [0..0, 0..100, 100..100, CLUSTER_SIZE + 5..CLUSTER_SIZE + 5, 3 * CLUSTER_SIZE..4 * CLUSTER_SIZE].into_iter(),
+        ).unwrap();
+        assert_eq!(writer.data_clusters().iter().collect::<Vec<_>>(), [0, 3]);
+
+        let writer = StreamingQcow2Writer::new(CLUSTER_SIZE, std::iter::once(0..0)).unwrap();
+        assert!(writer.data_clusters().is_empty());
+    }
+
+    #[test]
+    fn unsorted_ranges() {
+        let error = StreamingQcow2Writer::new(
+            4 * CLUSTER_SIZE,
+            [2 * CLUSTER_SIZE..3 * CLUSTER_SIZE, 0..CLUSTER_SIZE].into_iter(),
+        ).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Ranges sharing a cluster are fine
+        let writer = StreamingQcow2Writer::new(
+            4 * CLUSTER_SIZE,
+            [0..100, 200..CLUSTER_SIZE + 1, CLUSTER_SIZE + 10..CLUSTER_SIZE + 20].into_iter(),
+        ).unwrap();
+        assert_eq!(writer.data_clusters().iter().collect::<Vec<_>>(), [0, 1]);
+    }
+}
@@ -0,0 +1,603 @@
+//! Reader for existing qcow2 images: parsing the header, walking the L1 and
+//! L2 tables, and reading guest clusters, so images can be re-streamed or
+//! checked.
+//!
+//! Images with a backing file are read along with their whole backing chain,
+//! giving the flattened disk. Backing files are raw unless the image records
+//! their format, and have to be in the directory of the image unless
+//! allowed otherwise.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path};
+
+use crate::input::{get_file_size, seek_position};
+use crate::layout;
+use crate::qcow2::{read_full, BACKING_FORMAT_EXTENSION};
+
+/// "QFI\xfb"
+const MAGIC: u32 = 0x5146_49fb;
+
+pub const INCOMPAT_DIRTY: u64 = 1 << 0;
+pub const INCOMPAT_CORRUPT: u64 = 1 << 1;
+
+pub const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+pub const L2_COMPRESSED: u64 = 1 << 62;
+pub const L2_ZERO: u64 = 1;
+
+/// Longest backing chain that is followed, to stop on loops
+const MAX_CHAIN_LENGTH: usize = 64;
+
+/// Largest L1 table accepted, in bytes, the same limit as QEMU
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+
+/// Check whether the start of a file is a qcow2 header.
+pub fn is_qcow2(header: &[u8]) -> bool {
+    header.len() >= 4 && BigEndian::read_u32(&header[0..4]) == MAGIC
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Fields of the image header.
+pub struct Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u64,
+    pub cluster_bits: u32,
+    /// Size of the virtual disk
+    pub size: u64,
+    pub crypt_method: u32,
+    /// Number of entries in the L1 table
+    pub l1_size: u64,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u64,
+    pub nb_snapshots: u32,
+    /// Incompatible feature bits, always 0 for version 2
+    pub incompatible_features: u64,
+    /// Refcounts are `1 << refcount_order` bits, always 4 for version 2
+    pub refcount_order: u32,
+    /// Size of the header, where the header extensions start
+    pub header_length: u64,
+}
+
+impl Header {
+    /// Parse the header at the start of the image.
+    ///
+    /// Only the fields needed to interpret the rest of the image are
+    /// validated; features are left for the caller to check.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> std::io::Result<Header> {
+        let mut header = [0u8; 104];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header[..72])?;
+        if !is_qcow2(&header) {
+            return Err(invalid("not a qcow2 image"));
+        }
+        let mut parsed = Header {
+            version: BigEndian::read_u32(&header[4..8]),
+            backing_file_offset: BigEndian::read_u64(&header[8..16]),
+            backing_file_size: BigEndian::read_u32(&header[16..20]) as u64,
+            cluster_bits: BigEndian::read_u32(&header[20..24]),
+            size: BigEndian::read_u64(&header[24..32]),
+            crypt_method: BigEndian::read_u32(&header[32..36]),
+            l1_size: BigEndian::read_u32(&header[36..40]) as u64,
+            l1_table_offset: BigEndian::read_u64(&header[40..48]),
+            refcount_table_offset: BigEndian::read_u64(&header[48..56]),
+            refcount_table_clusters: BigEndian::read_u32(&header[56..60]) as u64,
+            nb_snapshots: BigEndian::read_u32(&header[60..64]),
+            incompatible_features: 0,
+            refcount_order: 4,
+            header_length: 72,
+        };
+        match parsed.version {
+            2 => {}
+            3 => {
+                reader.read_exact(&mut header[72..104])?;
+                parsed.incompatible_features = BigEndian::read_u64(&header[72..80]);
+                parsed.refcount_order = BigEndian::read_u32(&header[96..100]);
+                if parsed.refcount_order > 6 {
+                    return Err(invalid("invalid qcow2 refcount order"));
+                }
+                parsed.header_length = BigEndian::read_u32(&header[100..104]) as u64;
+                if parsed.header_length < 104 {
+                    return Err(invalid("invalid qcow2 header length"));
+                }
+            }
+            _ => return Err(invalid("unsupported qcow2 version")),
+        }
+        if !(9..=21).contains(&parsed.cluster_bits) {
+            return Err(invalid("invalid qcow2 cluster size"));
+        }
+        Ok(parsed)
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Number of entries in each L2 table.
+    pub fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Number of clusters of the virtual disk.
+    pub fn guest_clusters(&self) -> u64 {
+        self.size.div_ceil(self.cluster_size())
+    }
+
+    /// Number of L1 entries needed to map the virtual disk.
+    pub fn l1_entries_needed(&self) -> u64 {
+        self.guest_clusters().div_ceil(self.l2_entries())
+    }
+}
+
+/// Read a table of big-endian 64-bit entries, such as an L1 or L2 table.
+///
+/// The table has to be within the file, so a corrupt count doesn't make us
+/// allocate more memory than the file could hold.
+pub fn read_table<R: Read + Seek>(reader: &mut R, offset: u64, entries: u64) -> std::io::Result<Vec<u64>> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let end = entries.checked_mul(8).and_then(|len| len.checked_add(offset));
+    if end.is_none_or(|end| end > file_size) {
+        return Err(invalid("qcow2 table is past the end of the file"));
+    }
+    let mut table = vec![0u8; entries as usize * 8];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut table)?;
+    Ok(table.chunks_exact(8).map(BigEndian::read_u64).collect())
+}
+
+/// Where the data of a guest cluster is, decoded from its L2 entry.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Cluster {
+    /// Not allocated
+    Unallocated,
+    /// Reads as zeros, possibly with a host cluster still allocated
+    Zero(Option<u64>),
+    /// Stored as-is at this host offset
+    Data(u64),
+    /// Deflate-compressed data, at this host offset and spanning this many
+    /// bytes (up to the end of its last 512-byte sector)
+    Compressed { offset: u64, size: u64 },
+}
+
+impl Cluster {
+    pub fn from_entry(entry: u64, cluster_bits: u32) -> Cluster {
+        if entry & L2_COMPRESSED != 0 {
+            // The entry holds the offset, then the number of additional
+            // 512-byte sectors the compressed data spans
+            let offset_bits = 62 - (cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & (L2_COMPRESSED - 1)) >> offset_bits) + 1;
+            return Cluster::Compressed { offset, size: sectors * 512 - (offset & 511) };
+        }
+        let offset = entry & OFFSET_MASK;
+        if entry & L2_ZERO != 0 {
+            Cluster::Zero(if offset == 0 { None } else { Some(offset) })
+        } else if offset == 0 {
+            Cluster::Unallocated
+        } else {
+            Cluster::Data(offset)
+        }
+    }
+
+    fn has_data(self) -> bool {
+        matches!(self, Cluster::Data(_) | Cluster::Compressed { .. })
+    }
+}
+
+/// Image that a qcow2 image is an overlay of, which its unallocated clusters
+/// are read from.
+enum Backing {
+    Raw { file: File, size: u64 },
+    Qcow2(Box<Reader<File>>),
+}
+
+impl Backing {
+    /// Read from the backing image at a position, past its end reading as
+    /// zeros.
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        match self {
+            Backing::Raw { file, size } => {
+                if position >= *size {
+                    buf.fill(0);
+                    return Ok(());
+                }
+                file.seek(SeekFrom::Start(position))?;
+                let length = ((*size - position) as usize).min(buf.len());
+                read_full(&mut *file, &mut buf[..length])?;
+                buf[length..].fill(0);
+            }
+            Backing::Qcow2(reader) => {
+                reader.seek(SeekFrom::Start(position))?;
+                read_full(&mut **reader, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+        match self {
+            Backing::Raw { size, .. } => Ok(std::iter::once(0..*size).collect()),
+            Backing::Qcow2(reader) => reader.data_extents(),
+        }
+    }
+}
+
+/// Reads the virtual disk of a qcow2 image.
+pub struct Reader<R: Read + Seek> {
+    inner: R,
+    header: Header,
+    /// Offset of each L2 table, 0 if unallocated
+    l1: Vec<u64>,
+    /// Last L2 table read, by L1 index; tables are read as they are needed
+    /// rather than mapping the whole disk upfront
+    l2_cache: Option<(u64, Vec<u64>)>,
+    position: u64,
+    /// Last decompressed cluster
+    cache: Option<(u64, Vec<u8>)>,
+    backing: Option<Backing>,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Read an image, opening the chain of images it is an overlay of, from
+    /// the directory of `path`.
+    ///
+    /// Backing file names that are absolute or go up with `..` are refused,
+    /// unless `any_path` is set: they can reach any file of the host.
+    pub fn open(inner: R, path: &Path, any_path: bool) -> std::io::Result<Reader<R>> {
+        Reader::open_chain(inner, path, any_path, 0)
+    }
+
+    fn open_chain(inner: R, path: &Path, any_path: bool, depth: usize) -> std::io::Result<Reader<R>> {
+        let mut reader = Reader::new(inner)?;
+        if reader.header.backing_file_offset == 0 {
+            return Ok(reader);
+        }
+        if depth + 1 >= MAX_CHAIN_LENGTH {
+            return Err(invalid("qcow2 backing chain is too long"));
+        }
+
+        // Relative names are relative to the directory of the overlay
+        let name = reader.backing_file_name()?;
+        let below = Path::new(&name).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !below && !any_path {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("qcow2 backing file {} is outside the directory of the image", name),
+            ));
+        }
+        let backing_path = path.parent().unwrap_or(Path::new("")).join(&name);
+        let context = |e: std::io::Error| {
+            std::io::Error::new(e.kind(), format!("backing file {}: {}", backing_path.display(), e))
+        };
+        let file = File::open(&backing_path).map_err(context)?;
+        // Without a recorded format, the backing file is not probed: a raw
+        // disk could look like a qcow2 image naming other files
+        let format = reader.backing_format()?.unwrap_or_else(|| "raw".to_owned());
+        let backing = match format.as_str() {
+            "raw" => {
+                let size = get_file_size(&file).map_err(context)?;
+                Backing::Raw { file, size }
+            }
+            "qcow2" => {
+                let backing = Reader::<File>::open_chain(file, &backing_path, any_path, depth + 1)?;
+                Backing::Qcow2(Box::new(backing))
+            }
+            _ => return Err(invalid(&format!("unsupported backing file format {}", format))),
+        };
+        reader.backing = Some(backing);
+        Ok(reader)
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Read an image without its backing chain, which `open()` attaches;
+    /// its unallocated clusters then read as zeros.
+    pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+        let header = Header::read(&mut inner)?;
+        // Dirty images only have stale refcounts, which we don't use
+        if header.incompatible_features & !INCOMPAT_DIRTY != 0 {
+            return Err(invalid("qcow2 image uses unsupported features"));
+        }
+        if header.crypt_method != 0 {
+            return Err(invalid("encrypted qcow2 images are not supported"));
+        }
+        if header.l1_size < header.l1_entries_needed() {
+            return Err(invalid("qcow2 L1 table is too small"));
+        }
+        if header.l1_size * 8 > MAX_L1_TABLE_SIZE {
+            return Err(invalid("qcow2 L1 table is too large"));
+        }
+
+        let l1 = read_table(&mut inner, header.l1_table_offset, header.l1_entries_needed())?
+            .into_iter()
+            .map(|entry| entry & OFFSET_MASK)
+            .collect();
+
+        Ok(Reader {
+            inner,
+            header,
+            l1,
+            l2_cache: None,
+            position: 0,
+            cache: None,
+            backing: None,
+        })
+    }
+
+    fn backing_file_name(&mut self) -> std::io::Result<String> {
+        if self.header.backing_file_size > 1023 {
+            return Err(invalid("qcow2 backing file name is too long"));
+        }
+        let mut name = vec![0u8; self.header.backing_file_size as usize];
+        self.inner.seek(SeekFrom::Start(self.header.backing_file_offset))?;
+        self.inner.read_exact(&mut name)?;
+        String::from_utf8(name).map_err(|_| invalid("invalid qcow2 backing file name"))
+    }
+
+    /// Read the format of the backing file from the header extensions, if
+    /// recorded.
+    fn backing_format(&mut self) -> std::io::Result<Option<String>> {
+        let mut offset = self.header.header_length;
+        while offset + 8 <= self.header.cluster_size() {
+            let mut extension = [0u8; 8];
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.inner.read_exact(&mut extension)?;
+            let kind = BigEndian::read_u32(&extension[0..4]);
+            let length = BigEndian::read_u32(&extension[4..8]) as u64;
+            if kind == 0 {
+                break;
+            }
+            if kind == BACKING_FORMAT_EXTENSION && length <= 16 {
+                let mut format = vec![0u8; length as usize];
+                self.inner.read_exact(&mut format)?;
+                return String::from_utf8(format).map(Some)
+                    .map_err(|_| invalid("invalid qcow2 backing file format"));
+            }
+            offset += 8 + length.next_multiple_of(8);
+        }
+        Ok(None)
+    }
+
+    /// Size of the virtual disk.
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Whether the image is an overlay, which [`Reader::new`] doesn't read
+    /// the backing file of.
+    pub fn has_backing_file(&self) -> bool {
+        self.header.backing_file_offset != 0
+    }
+
+    /// Where the data of a guest cluster is.
+    pub fn cluster(&mut self, index: u64) -> std::io::Result<Cluster> {
+        let l2_entries = self.header.l2_entries();
+        let l1_index = index / l2_entries;
+        let table_offset = self.l1.get(l1_index as usize).copied().unwrap_or(0);
+        if table_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        if self.l2_cache.as_ref().is_none_or(|(i, _)| *i != l1_index) {
+            // The last table only needs the entries up to the virtual size
+            let entries = (self.header.guest_clusters() - l1_index * l2_entries).min(l2_entries);
+            let table = read_table(&mut self.inner, table_offset, entries)?;
+            self.l2_cache = Some((l1_index, table));
+        }
+        let table = &self.l2_cache.as_ref().unwrap().1;
+        let entry = table.get((index % l2_entries) as usize).copied().unwrap_or(0);
+        Ok(Cluster::from_entry(entry, self.header.cluster_bits))
+    }
+
+    /// Ranges of the virtual disk backed by allocated clusters, in this
+    /// image or in its backing chain.
+    pub fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+        fn push(list: &mut Vec<Range<u64>>, range: Range<u64>) {
+            match list.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => list.push(range),
+            }
+        }
+
+        let cluster_size = self.header.cluster_size();
+        let size = self.header.size;
+        let l2_entries = self.header.l2_entries();
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        let mut unallocated: Vec<Range<u64>> = Vec::new();
+        for l1_index in 0..self.l1.len() as u64 {
+            let first = l1_index * l2_entries;
+            let last = (first + l2_entries).min(self.header.guest_clusters());
+            // Skip unallocated tables whole, they can map a lot of clusters
+            if self.l1[l1_index as usize] == 0 {
+                push(&mut unallocated, first * cluster_size..(last * cluster_size).min(size));
+                continue;
+            }
+            for i in first..last {
+                let cluster = self.cluster(i)?;
+                let list = match cluster {
+                    _ if cluster.has_data() => &mut extents,
+                    Cluster::Unallocated => &mut unallocated,
+                    _ => continue,
+                };
+                let start = i * cluster_size;
+                push(list, start..(start + cluster_size).min(size));
+            }
+        }
+        if let Some(backing) = &mut self.backing {
+            extents.extend(layout::intersect(unallocated, backing.data_extents()?));
+            extents = layout::normalize(extents);
+        }
+        Ok(extents)
+    }
+
+    fn read_compressed_cluster(&mut self, index: u64, offset: u64, size: u64) -> std::io::Result<&[u8]> {
+        if self.cache.as_ref().is_none_or(|(i, _)| *i != index) {
+            let cluster_size = self.header.cluster_size();
+            let mut data = Vec::with_capacity(cluster_size as usize);
+            self.inner.seek(SeekFrom::Start(offset))?;
+            let compressed = (&mut self.inner).take(size);
+            flate2::read::DeflateDecoder::new(compressed)
+                .take(cluster_size)
+                .read_to_end(&mut data)?;
+            data.resize(cluster_size as usize, 0);
+            self.cache = Some((index, data));
+        }
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.header.size;
+        if self.position >= size {
+            return Ok(0);
+        }
+        let cluster_size = self.header.cluster_size();
+        let index = self.position >> self.header.cluster_bits;
+        let offset_in_cluster = self.position % cluster_size;
+        let length = (buf.len() as u64)
+            .min(cluster_size - offset_in_cluster)
+            .min(size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        match self.cluster(index)? {
+            Cluster::Unallocated => match &mut self.backing {
+                Some(backing) => backing.read_at(self.position, buf)?,
+                None => buf.fill(0),
+            },
+            Cluster::Zero(_) => buf.fill(0),
+            Cluster::Data(offset) => {
+                self.inner.seek(SeekFrom::Start(offset + offset_in_cluster))?;
+                self.inner.read_exact(buf)?;
+            }
+            Cluster::Compressed { offset, size } => {
+                let data = self.read_compressed_cluster(index, offset, size)?;
+                buf.copy_from_slice(&data[offset_in_cluster as usize..offset_in_cluster as usize + length]);
+            }
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.header.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    const CLUSTER_SIZE: u64 = 1 << 16;
+
+    /// A version 2 image with 64 KiB clusters, its L1 table in cluster 1 and
+    /// an L2 table in cluster 2 mapping guest cluster 1 to cluster 3.
+    fn image(size: u64, l1_size: u32, l2_offset: u64) -> Vec<u8> {
+        let mut image = vec![0u8; 4 * CLUSTER_SIZE as usize];
+        image[0..4].copy_from_slice(b"QFI\xfb");
+        (&mut image[4..8]).write_u32::<BigEndian>(2).unwrap();
+        (&mut image[20..24]).write_u32::<BigEndian>(16).unwrap();
+        (&mut image[24..32]).write_u64::<BigEndian>(size).unwrap();
+        (&mut image[36..40]).write_u32::<BigEndian>(l1_size).unwrap();
+        (&mut image[40..48]).write_u64::<BigEndian>(CLUSTER_SIZE).unwrap();
+        (&mut image[CLUSTER_SIZE as usize..][..8]).write_u64::<BigEndian>(l2_offset).unwrap();
+        (&mut image[2 * CLUSTER_SIZE as usize + 8..][..8]).write_u64::<BigEndian>(3 * CLUSTER_SIZE).unwrap();
+        image[3 * CLUSTER_SIZE as usize..].fill(0x55);
+        image
+    }
+
+    #[test]
+    fn reads_clusters() {
+        let mut reader = Reader::new(Cursor::new(image(4 * CLUSTER_SIZE, 1, 2 * CLUSTER_SIZE))).unwrap();
+        assert!(reader.cluster(0).unwrap() == Cluster::Unallocated);
+        assert!(reader.cluster(1).unwrap() == Cluster::Data(3 * CLUSTER_SIZE));
+        assert_eq!(reader.data_extents().unwrap(), vec![CLUSTER_SIZE..2 * CLUSTER_SIZE]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, 4 * CLUSTER_SIZE);
+        assert!(data[..CLUSTER_SIZE as usize].iter().all(|&b| b == 0));
+        assert!(data[CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize].iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn l1_table_past_end_of_file() {
+        // An L1 table of 8 MiB, mapping 512 TiB, in a file of 256 KiB
+        let size = (1 << 20) * (CLUSTER_SIZE / 8) * CLUSTER_SIZE;
+        assert!(Reader::new(Cursor::new(image(size, 1 << 20, 2 * CLUSTER_SIZE))).is_err());
+    }
+
+    #[test]
+    fn corrupt_sizes() {
+        assert!(Reader::new(Cursor::new(image(u64::MAX, u32::MAX, 2 * CLUSTER_SIZE))).is_err());
+        assert!(Reader::new(Cursor::new(image(u64::MAX, 1, 2 * CLUSTER_SIZE))).is_err());
+    }
+
+    #[test]
+    fn l2_table_past_end_of_file() {
+        let mut reader = Reader::new(Cursor::new(image(4 * CLUSTER_SIZE, 1, 1 << 40))).unwrap();
+        assert!(reader.cluster(1).is_err());
+        assert!(reader.data_extents().is_err());
+    }
+
+    /// An overlay of `backing` with no data, written by the writer.
+    fn overlay(backing: &str, format: Option<&str>) -> Cursor<Vec<u8>> {
+        let mut writer = crate::qcow2::StreamingQcow2Writer::new(CLUSTER_SIZE, std::iter::empty()).unwrap();
+        writer.set_backing_file(backing.to_owned()).unwrap();
+        if let Some(format) = format {
+            writer.set_backing_format(format.to_owned()).unwrap();
+        }
+        let mut image = Vec::new();
+        writer.write_header(&mut image).unwrap();
+        Cursor::new(image)
+    }
+
+    #[test]
+    fn backing_chain() {
+        let directory = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.chain", std::process::id()));
+        std::fs::create_dir_all(directory.join("sub")).unwrap();
+        let path = directory.join("sub/overlay.qcow2");
+        std::fs::write(directory.join("base.raw"), vec![0x55; CLUSTER_SIZE as usize]).unwrap();
+        std::fs::write(directory.join("sub/base.raw"), vec![0x66; CLUSTER_SIZE as usize]).unwrap();
+        // A qcow2 image over base.raw, with the base image's data
+        let mut base = crate::qcow2::StreamingQcow2Writer::new(CLUSTER_SIZE, std::iter::once(0..CLUSTER_SIZE)).unwrap();
+        base.set_backing_file("base.raw".to_owned()).unwrap();
+        base.set_backing_format("raw".to_owned()).unwrap();
+        let mut image = Vec::new();
+        base.write_header(&mut image).unwrap();
+        base.copy_data(Cursor::new(vec![0x77; CLUSTER_SIZE as usize]), &mut image, None).unwrap();
+        std::fs::write(directory.join("sub/base.qcow2"), &image).unwrap();
+
+        let read = |image: Cursor<Vec<u8>>, any_path: bool| -> std::io::Result<Vec<u8>> {
+            let mut reader = Reader::open(image, &path, any_path)?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            Ok(data)
+        };
+        assert_eq!(read(overlay("base.raw", Some("raw")), false).unwrap(), vec![0x66; CLUSTER_SIZE as usize]);
+        assert_eq!(read(overlay("./base.qcow2", Some("qcow2")), false).unwrap(), vec![0x77; CLUSTER_SIZE as usize]);
+        // Without a recorded format, the backing file is raw, even if it
+        // looks like a qcow2 image
+        assert_eq!(read(overlay("base.qcow2", None), false).unwrap(), image[..CLUSTER_SIZE as usize]);
+
+        // Backing files outside the directory of the image
+        let absolute = directory.join("base.raw").to_str().unwrap().to_owned();
+        for name in [absolute.as_str(), "../base.raw", "sub/../../base.raw"] {
+            let error = read(overlay(name, Some("raw")), false).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied, "{}", name);
+        }
+        assert_eq!(read(overlay(&absolute, Some("raw")), true).unwrap(), vec![0x55; CLUSTER_SIZE as usize]);
+        assert_eq!(read(overlay("../base.raw", Some("raw")), true).unwrap(), vec![0x55; CLUSTER_SIZE as usize]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//! Live incremental copies from a running QEMU, through QMP: the changes
+//! tracked by a dirty bitmap are read over an NBD export of the disk.
+
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::log::message;
+use crate::nbd;
+
+const EXPORT_NAME: &str = "streaming-qcow2-writer";
+
+/// A connection to a QEMU monitor socket, in command mode.
+pub struct Qmp {
+    reader: BufReader<Box<dyn Read>>,
+    writer: Box<dyn Write>,
+}
+
+impl Qmp {
+    pub fn connect(path: &Path) -> std::io::Result<Qmp> {
+        let (reader, writer) = connect_socket(path)?;
+        let mut qmp = Qmp {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let greeting = qmp.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a QMP socket"));
+        }
+        qmp.execute("qmp_capabilities", json!({}))?;
+        Ok(qmp)
+    }
+
+    fn read_message(&mut self) -> std::io::Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "QMP connection closed"));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Run a command, returning its result.
+    pub fn execute(&mut self, command: &str, arguments: Value) -> std::io::Result<Value> {
+        let message = json!({"execute": command, "arguments": arguments});
+        let mut data = serde_json::to_vec(&message)?;
+        data.push(b'\n');
+        self.writer.write_all(&data)?;
+        loop {
+            let mut reply = self.read_message()?;
+            // Skip asynchronous events
+            if let Some(result) = reply.get_mut("return") {
+                return Ok(result.take());
+            }
+            if let Some(error) = reply.get("error") {
+                let desc = error.get("desc").and_then(Value::as_str).unwrap_or("unknown error");
+                return Err(std::io::Error::other(format!("{} failed: {}", command, desc)));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_socket(path: &Path) -> std::io::Result<(Box<dyn Read>, Box<dyn Write>)> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+#[cfg(not(unix))]
+fn connect_socket(_path: &Path) -> std::io::Result<(Box<dyn Read>, Box<dyn Write>)> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "QMP sockets are not supported on this platform",
+    ))
+}
+
+/// An NBD export of a disk of a running QEMU, with a dirty bitmap frozen
+/// for the duration of the copy.
+///
+/// While the export exists, the bitmap is disabled and new writes are
+/// tracked in a temporary bitmap. On success, the bitmap is reset to that
+/// temporary bitmap, so the next copy picks up from there; otherwise it is
+/// merged back, so no change is lost.
+pub struct IncrementalExport {
+    qmp: Qmp,
+    node: String,
+    bitmap: String,
+    temp_bitmap: String,
+    socket: PathBuf,
+    server_started: bool,
+    committed: bool,
+}
+
+impl IncrementalExport {
+    /// Export the disk `node` over NBD along with the dirty bitmap.
+    pub fn start(qmp_socket: &Path, node: &str, bitmap: &str) -> std::io::Result<IncrementalExport> {
+        let mut qmp = Qmp::connect(qmp_socket)?;
+
+        let temp_bitmap = format!("{}-{}", bitmap, std::process::id());
+        qmp.execute("transaction", json!({"actions": [
+            {"type": "block-dirty-bitmap-add", "data": {"node": node, "name": temp_bitmap}},
+            {"type": "block-dirty-bitmap-disable", "data": {"node": node, "name": bitmap}},
+        ]}))?;
+
+        let socket = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.sock", std::process::id()));
+        let mut export = IncrementalExport {
+            qmp,
+            node: node.to_owned(),
+            bitmap: bitmap.to_owned(),
+            temp_bitmap,
+            socket,
+            server_started: false,
+            committed: false,
+        };
+        export.qmp.execute("nbd-server-start", json!({
+            "addr": {"type": "unix", "data": {"path": export.socket}},
+        }))?;
+        export.server_started = true;
+        export.qmp.execute("block-export-add", json!({
+            "type": "nbd",
+            "id": EXPORT_NAME,
+            "node-name": node,
+            "name": EXPORT_NAME,
+            "writable": false,
+            "bitmaps": [bitmap],
+        }))?;
+        Ok(export)
+    }
+
+    /// Where to connect to read the export.
+    pub fn address(&self) -> nbd::Address {
+        nbd::Address::unix(self.socket.clone(), EXPORT_NAME.to_owned())
+    }
+
+    pub fn bitmap(&self) -> &str {
+        &self.bitmap
+    }
+
+    /// Remove the export, which keeps the bitmap busy.
+    fn stop_server(&mut self) {
+        if self.server_started {
+            self.server_started = false;
+            let _ = self.qmp.execute("block-export-del", json!({"id": EXPORT_NAME, "mode": "hard"}));
+            // Deletion completes asynchronously
+            for _ in 0..100 {
+                let exports = self.qmp.execute("query-block-exports", json!({}));
+                let present = match &exports {
+                    Ok(Value::Array(list)) => list.iter().any(|e| e["id"] == EXPORT_NAME),
+                    _ => false,
+                };
+                if !present {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            let _ = self.qmp.execute("nbd-server-stop", json!({}));
+            let _ = std::fs::remove_file(&self.socket);
+        }
+    }
+
+    /// Reset the dirty bitmap after a successful copy.
+    pub fn commit(mut self) -> std::io::Result<()> {
+        self.stop_server();
+        self.committed = true;
+        self.qmp.execute("transaction", json!({"actions": [
+            {"type": "block-dirty-bitmap-clear", "data": {"node": self.node, "name": self.bitmap}},
+            {"type": "block-dirty-bitmap-merge", "data": {
+                "node": self.node, "target": self.bitmap, "bitmaps": [self.temp_bitmap],
+            }},
+            {"type": "block-dirty-bitmap-enable", "data": {"node": self.node, "name": self.bitmap}},
+            {"type": "block-dirty-bitmap-remove", "data": {"node": self.node, "name": self.temp_bitmap}},
+        ]}))?;
+        Ok(())
+    }
+}
+
+impl Drop for IncrementalExport {
+    fn drop(&mut self) {
+        self.stop_server();
+        if !self.committed {
+            let result = self.qmp.execute("transaction", json!({"actions": [
+                {"type": "block-dirty-bitmap-merge", "data": {
+                    "node": self.node, "target": self.bitmap, "bitmaps": [self.temp_bitmap],
+                }},
+                {"type": "block-dirty-bitmap-enable", "data": {"node": self.node, "name": self.bitmap}},
+                {"type": "block-dirty-bitmap-remove", "data": {"node": self.node, "name": self.temp_bitmap}},
+            ]}));
+            if let Err(e) = result {
+                message!("Warning: failed to restore dirty bitmap {}: {}", self.bitmap, e);
+            }
+        }
+    }
+}
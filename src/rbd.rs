@@ -0,0 +1,74 @@
+//! Ceph RBD input: maps the image to a local block device with the `rbd`
+//! tool, and queries its allocated extents to build the layout.
+
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::log::message;
+use crate::layout;
+
+/// An RBD image mapped read-only to a local block device, unmapped on drop.
+pub struct MappedImage {
+    spec: String,
+    pub device: PathBuf,
+}
+
+impl MappedImage {
+    /// Map the image, given as `pool/image[@snapshot]`.
+    ///
+    /// If `nbd` is set, the image is mapped with rbd-nbd (which uses librbd)
+    /// rather than with the kernel RBD driver.
+    pub fn map(spec: &str, nbd: bool) -> std::io::Result<MappedImage> {
+        let mut command = Command::new("rbd");
+        command.arg("device").arg("map").arg("--read-only");
+        if nbd {
+            command.arg("--device-type").arg("nbd");
+        }
+        command.arg(spec);
+        let output = run(command)?;
+        let device = String::from_utf8_lossy(&output).trim().to_owned();
+        if device.is_empty() {
+            return Err(std::io::Error::other("rbd did not return a device"));
+        }
+        Ok(MappedImage {
+            spec: spec.to_owned(),
+            device: PathBuf::from(device),
+        })
+    }
+
+    /// Get the allocated extents of the image, using `rbd diff`.
+    pub fn allocated_extents(&self) -> std::io::Result<Vec<Range<u64>>> {
+        let mut command = Command::new("rbd");
+        command
+            .arg("diff")
+            .arg("--whole-object")
+            .arg("--format=json")
+            .arg(&self.spec);
+        let output = run(command)?;
+        layout::read_json(&output[..])
+    }
+}
+
+impl Drop for MappedImage {
+    fn drop(&mut self) {
+        let status = Command::new("rbd")
+            .arg("device")
+            .arg("unmap")
+            .arg(&self.device)
+            .stdin(Stdio::null())
+            .status();
+        match status {
+            Ok(s) if s.success() => {}
+            _ => message!("Warning: failed to unmap {}", self.device.display()),
+        }
+    }
+}
+
+fn run(mut command: Command) -> std::io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("rbd command failed ({})", output.status)));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,293 @@
+//! Reading a local input ahead from several threads (`--threads`), for
+//! storage that is faster with several requests in flight, such as SSDs,
+//! RAID arrays and network block devices.
+//!
+//! The ranges that will be read are known in advance from the layout. They
+//! are cut into chunks of the buffer size (`--buffer-size`), which the
+//! threads read into buffers from the pool in turn and hand back in order;
+//! reads outside of them go to the file directly.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+use crate::buffer::{self, Buffer};
+use crate::input::seek_position;
+use crate::throttle;
+
+/// Number of chunks each thread reads before they are used
+const DEPTH: usize = 2;
+
+/// Most threads to use by default, more rarely help
+const MAX_DEFAULT_THREADS: usize = 8;
+
+/// Number of threads set with `set_threads()`, 0 for the default
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Read the input with this many threads.
+pub fn set_threads(threads: usize) {
+    THREADS.store(threads, Ordering::Relaxed);
+}
+
+/// Number of threads to read the input with, 1 to only read it from the
+/// calling thread.
+pub fn threads() -> usize {
+    if cfg!(not(any(unix, windows))) {
+        return 1;
+    }
+    match THREADS.load(Ordering::Relaxed) {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_DEFAULT_THREADS),
+        n => n,
+    }
+}
+
+/// Memory used to read ahead with this many threads, in bytes.
+pub fn memory_usage(threads: usize) -> u64 {
+    match threads {
+        0 | 1 => 0,
+        // The chunks queued and being read by each thread, and the one in use
+        n => (n * (DEPTH + 1) + 1) as u64 * buffer::size() as u64,
+    }
+}
+
+/// Number of threads to read the input with, using at most this much memory
+/// to read ahead.
+pub fn threads_within(memory: u64) -> usize {
+    let mut threads = threads();
+    while threads > 1 && memory_usage(threads) > memory {
+        threads -= 1;
+    }
+    threads
+}
+
+type Chunk = std::io::Result<Buffer>;
+
+/// Reader for a file that reads the given ranges ahead from several threads.
+pub struct ReadAhead {
+    file: File,
+    size: u64,
+    chunks: Vec<Range<u64>>,
+    /// Chunks from each thread, which reads every nth one
+    receivers: Vec<Receiver<Chunk>>,
+    workers: Vec<JoinHandle<()>>,
+    /// Index of the next chunk to receive
+    next: usize,
+    /// Last chunk received, and its data
+    current: Option<(Range<u64>, Buffer)>,
+    position: u64,
+}
+
+impl ReadAhead {
+    /// Start reading these ranges of a file of this size, which will be read
+    /// in this order.
+    pub fn new(file: File, size: u64, ranges: &[Range<u64>], threads: usize) -> std::io::Result<ReadAhead> {
+        let chunk_size = buffer::size() as u64;
+        let chunks: Vec<Range<u64>> = ranges.iter()
+            .map(|r| r.start.min(size)..r.end.min(size))
+            .flat_map(|r| {
+                (r.start..r.end).step_by(chunk_size as usize)
+                    .map(move |start| start..(start + chunk_size).min(r.end))
+            })
+            .collect();
+        let mut receivers = Vec::with_capacity(threads);
+        let mut workers = Vec::with_capacity(threads);
+        for first in 0..threads {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(DEPTH);
+            let file = file.try_clone()?;
+            let chunks: Vec<Range<u64>> = chunks.iter().skip(first).step_by(threads).cloned().collect();
+            workers.push(std::thread::spawn(move || read_chunks(file, chunks, sender)));
+            receivers.push(receiver);
+        }
+        Ok(ReadAhead {
+            file,
+            size,
+            chunks,
+            receivers,
+            workers,
+            next: 0,
+            current: None,
+            position: 0,
+        })
+    }
+
+    /// Receive chunks until one that doesn't end before the position, if any.
+    fn advance(&mut self) -> std::io::Result<()> {
+        while self.next < self.chunks.len() {
+            if let Some((range, _)) = &self.current {
+                if range.end > self.position {
+                    break;
+                }
+            }
+            let data = self.receivers[self.next % self.receivers.len()].recv()
+                .map_err(|_| std::io::Error::other("read-ahead thread failed"))??;
+            self.current = Some((self.chunks[self.next].clone(), data));
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+fn read_chunks(file: File, chunks: Vec<Range<u64>>, sender: SyncSender<Chunk>) {
+    for chunk in chunks {
+        throttle::wait_for_read();
+        let mut data = Buffer::with_len((chunk.end - chunk.start) as usize);
+        let result = read_exact_at(&file, &mut data, chunk.start).map(|()| data);
+        let failed = result.is_err();
+        // Stop if the reader is gone
+        if sender.send(result).is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Fill the buffer from this offset of the file, with zeros past its end.
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match read_at(file, &mut buffer[pos..], offset + pos as u64) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(_file: &File, _buffer: &mut [u8], _offset: u64) -> std::io::Result<usize> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "positional reads are not supported"))
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.advance()?;
+        match &self.current {
+            Some((range, data)) if range.contains(&self.position) => {
+                let offset = (self.position - range.start) as usize;
+                let length = buf.len().min(data.len() - offset);
+                buf[..length].copy_from_slice(&data[offset..offset + length]);
+                self.position += length as u64;
+                Ok(length)
+            }
+            current => {
+                // Not read ahead, read up to the next chunk directly
+                let length = match current {
+                    Some((range, _)) if range.start > self.position => {
+                        buf.len().min((range.start - self.position) as usize)
+                    }
+                    _ => buf.len(),
+                };
+                throttle::wait_for_read();
+                self.file.seek(SeekFrom::Start(self.position))?;
+                let read = self.file.read(&mut buf[..length])?;
+                self.position += read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+impl Seek for ReadAhead {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        // Threads stop once their receiver is gone
+        self.receivers.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, data: &[u8]) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        let file = File::open(&path).unwrap();
+        (path, file)
+    }
+
+    fn read_at(reader: &mut ReadAhead, offset: u64, length: usize) -> Vec<u8> {
+        let mut data = vec![0u8; length];
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        reader.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn sequential() {
+        let chunk = buffer::size();
+        let data: Vec<u8> = (0..6 * chunk).map(|i| (i % 251) as u8).collect();
+        let (path, file) = file("readahead-sequential", &data);
+        let ranges = [100..3 * chunk as u64 + 7, 4 * chunk as u64..6 * chunk as u64];
+        let mut reader = ReadAhead::new(file, data.len() as u64, &ranges, 3).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Read ahead, in small reads across chunks
+        reader.seek(SeekFrom::Start(100)).unwrap();
+        let mut read = vec![0u8; 3 * chunk + 7 - 100];
+        for part in read.chunks_mut(chunk / 3) {
+            reader.read_exact(part).unwrap();
+        }
+        assert!(read == data[100..3 * chunk + 7]);
+
+        // Between the ranges, read from the file directly
+        assert!(read_at(&mut reader, 3 * chunk as u64 + 7, 1000) == data[3 * chunk + 7..3 * chunk + 1007]);
+        assert!(read_at(&mut reader, 4 * chunk as u64, 2 * chunk) == data[4 * chunk..]);
+
+        // Past the end
+        let mut buffer = [0u8; 10];
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek_back() {
+        let chunk = buffer::size();
+        let data: Vec<u8> = (0..4 * chunk).map(|i| (i % 253) as u8).collect();
+        let (path, file) = file("readahead-seek", &data);
+        let ranges = [0..2 * chunk as u64, 2 * chunk as u64..4 * chunk as u64];
+        let mut reader = ReadAhead::new(file, data.len() as u64, &ranges, 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(read_at(&mut reader, 2 * chunk as u64, 1000) == data[2 * chunk..2 * chunk + 1000]);
+        // Chunks already handed back are read from the file again
+        assert!(read_at(&mut reader, 10, chunk) == data[10..chunk + 10]);
+        assert!(read_at(&mut reader, 3 * chunk as u64, chunk) == data[3 * chunk..]);
+    }
+
+    #[test]
+    fn worker_error() {
+        // Reads fail on a file opened for writing only
+        let chunk = buffer::size();
+        let path = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.readahead-error", std::process::id()));
+        let file = File::create(&path).unwrap();
+        file.set_len(2 * chunk as u64).unwrap();
+        let mut reader = ReadAhead::new(file, 2 * chunk as u64, std::slice::from_ref(&(0..2 * chunk as u64)), 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buffer = vec![0u8; 1000];
+        assert!(reader.read(&mut buffer).is_err());
+    }
+}
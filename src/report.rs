@@ -0,0 +1,121 @@
+//! Machine-readable report of a conversion (`--report FILE`), written at the
+//! end whether it succeeded or not, so batch jobs can archive exactly what
+//! was produced.
+//!
+//! Facts are added as they become known; the warnings and the phases are
+//! collected from the log and progress modules.
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::progress;
+
+struct Report {
+    file: File,
+    fields: serde_json::Map<String, serde_json::Value>,
+    warnings: Vec<String>,
+}
+
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Start collecting a report, to be written to this file.
+pub fn create(path: &Path) -> std::io::Result<()> {
+    let file = File::create(path)?;
+    let mut fields = serde_json::Map::new();
+    fields.insert("version".to_owned(), env!("CARGO_PKG_VERSION").into());
+    let arguments: Vec<String> = std::env::args_os().skip(1).map(|a| a.to_string_lossy().into_owned()).collect();
+    fields.insert("arguments".to_owned(), arguments.into());
+    *REPORT.lock().unwrap() = Some(Report {
+        file,
+        fields,
+        warnings: Vec::new(),
+    });
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    REPORT.lock().unwrap().is_some()
+}
+
+/// Record a fact in the report, if one is being collected.
+pub fn set(key: &str, value: serde_json::Value) {
+    if let Some(report) = &mut *REPORT.lock().unwrap() {
+        report.fields.insert(key.to_owned(), value);
+    }
+}
+
+/// Record a message, keeping it if it is a warning.
+pub fn add_message(args: std::fmt::Arguments) {
+    if let Some(report) = &mut *REPORT.lock().unwrap() {
+        if let Some(warning) = args.to_string().strip_prefix("Warning: ") {
+            report.warnings.push(warning.to_owned());
+        }
+    }
+}
+
+/// Write the report, with the result of the conversion.
+pub fn finish(error: Option<&str>) -> std::io::Result<()> {
+    let Some(mut report) = REPORT.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let mut phases: Vec<serde_json::Value> = progress::history().into_iter()
+        .map(|(name, duration)| serde_json::json!({"phase": name, "seconds": duration.as_secs_f64()}))
+        .collect();
+    if let Some((name, elapsed, _, _)) = progress::current() {
+        phases.push(serde_json::json!({"phase": name, "seconds": elapsed.as_secs_f64()}));
+    }
+    report.fields.insert("phases".to_owned(), phases.into());
+    report.fields.insert("warnings".to_owned(), report.warnings.into());
+    match error {
+        None => {
+            report.fields.insert("result".to_owned(), "success".into());
+        }
+        Some(message) => {
+            report.fields.insert("result".to_owned(), "error".into());
+            report.fields.insert("error".to_owned(), message.into());
+        }
+    }
+    serde_json::to_writer_pretty(&mut report.file, &report.fields)?;
+    report.file.write_all(b"\n")?;
+    report.file.sync_all()
+}
+
+/// Output that computes the SHA-256 digest of the image for the report, if
+/// one is being collected.
+pub struct DigestOutput<W: Write> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> DigestOutput<W> {
+    pub fn new(inner: W) -> DigestOutput<W> {
+        let hasher = is_enabled().then(Sha256::new);
+        DigestOutput { inner, hasher }
+    }
+
+    /// Record the digest of what was written, and return the output.
+    pub fn into_inner(self) -> W {
+        if let Some(hasher) = self.hasher {
+            let digest: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+            set("sha256", digest.into());
+        }
+        self.inner
+    }
+}
+
+impl<W: Write> Write for DigestOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,39 @@
+//! Sparsifying by reading the data: leaving out the parts of the layout that
+//! are all zeros, in a pass before the image is written.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::qcow2::{read_full, CLUSTER_SIZE};
+use crate::{progress, signals};
+
+/// Read the extents of the layout, and return the parts of them that are not
+/// all zeros.
+///
+/// The extents are checked in pieces that don't cross cluster boundaries, so
+/// whole clusters of zeros are left out of the image.
+pub fn nonzero_extents<R: Read + Seek>(mut reader: R, layout: &[Range<u64>]) -> std::io::Result<Vec<Range<u64>>> {
+    let total: u64 = layout.iter().map(|r| r.end - r.start).sum();
+    progress::start_copy("Scanning for zeros", total);
+    let mut extents: Vec<Range<u64>> = Vec::new();
+    let mut buffer = vec![0u8; CLUSTER_SIZE as usize];
+    for extent in layout {
+        reader.seek(SeekFrom::Start(extent.start))?;
+        let mut pos = extent.start;
+        while pos < extent.end {
+            signals::check_cancelled()?;
+            let end = ((pos / CLUSTER_SIZE + 1) * CLUSTER_SIZE).min(extent.end);
+            let data = &mut buffer[..(end - pos) as usize];
+            read_full(&mut reader, data)?;
+            if data.iter().any(|&b| b != 0) {
+                match extents.last_mut() {
+                    Some(last) if last.end == pos => last.end = end,
+                    _ => extents.push(pos..end),
+                }
+            }
+            progress::add_copied(end - pos);
+            pos = end;
+        }
+    }
+    Ok(extents)
+}
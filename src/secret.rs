@@ -0,0 +1,207 @@
+//! Passphrase of the signing key (`--sign-passphrase`), read from a file, an
+//! environment variable, the terminal or the OS keyring.
+//!
+//! It is handed to gpg through a pipe rather than on its command line, where
+//! other users could see it, and overwritten in memory once dropped.
+
+use std::io::{Error, ErrorKind, Read};
+use std::process::{Command, Stdio};
+
+/// Longest passphrase accepted, so it can be read without reallocating.
+const MAX_LENGTH: usize = 1024;
+
+/// Where to read a passphrase from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// First line of a file
+    File(String),
+    /// Environment variable
+    Env(String),
+    /// Typed on the terminal
+    Prompt,
+    /// Secret Service (with `secret-tool`), or the keychain on macOS
+    Keyring(String),
+}
+
+impl Source {
+    /// Parse `file:PATH`, `env:VARIABLE`, `prompt` or `keyring:SERVICE`.
+    pub fn parse(s: &str) -> Option<Source> {
+        match s.split_once(':') {
+            None if s == "prompt" => Some(Source::Prompt),
+            Some(("file", path)) if !path.is_empty() => Some(Source::File(path.to_owned())),
+            Some(("env", name)) if !name.is_empty() => Some(Source::Env(name.to_owned())),
+            Some(("keyring", service)) if !service.is_empty() => Some(Source::Keyring(service.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+/// A passphrase, overwritten with zeros when dropped.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    /// Read the passphrase from its source; `prompt` is shown on the terminal.
+    pub fn read(source: &Source, prompt: &str) -> std::io::Result<Secret> {
+        match source {
+            Source::File(path) => read_line(std::fs::File::open(path)?),
+            Source::Env(name) => {
+                let value = std::env::var_os(name).ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, format!("environment variable {} is not set", name))
+                })?;
+                #[cfg(unix)]
+                let secret = Secret(std::os::unix::ffi::OsStringExt::into_vec(value));
+                #[cfg(not(unix))]
+                let secret = Secret(value.into_string().map_err(|_| {
+                    Error::new(ErrorKind::InvalidData, format!("environment variable {} is not UTF-8", name))
+                })?.into_bytes());
+                if secret.0.contains(&b'\n') {
+                    return Err(Error::new(ErrorKind::InvalidData, "passphrase contains a newline"));
+                }
+                Ok(secret)
+            }
+            Source::Prompt => read_terminal(prompt),
+            Source::Keyring(service) => read_keyring(service),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // The whole allocation, as reading may have left bytes past the end
+        let ptr = self.0.as_mut_ptr();
+        for i in 0..self.0.capacity() {
+            // Volatile, so the writes are not optimized out
+            unsafe { std::ptr::write_volatile(ptr.add(i), 0) };
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Read up to the end of the first line, without a trailing `\r`.
+fn read_line<R: Read>(mut reader: R) -> std::io::Result<Secret> {
+    let mut secret = Secret(vec![0; MAX_LENGTH]);
+    let mut len = 0;
+    loop {
+        if let Some(end) = secret.0[..len].iter().position(|&b| b == b'\n') {
+            len = end;
+            break;
+        }
+        if len == MAX_LENGTH {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("passphrase is longer than {} bytes", MAX_LENGTH),
+            ));
+        }
+        match reader.read(&mut secret.0[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if secret.0[..len].ends_with(b"\r") {
+        len -= 1;
+    }
+    secret.0.truncate(len);
+    Ok(secret)
+}
+
+/// Read a line from the terminal with echo turned off.
+#[cfg(unix)]
+fn read_terminal(prompt: &str) -> std::io::Result<Secret> {
+    use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    let mut tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty")
+        .map_err(|e| Error::new(e.kind(), format!("can't open the terminal: {}", e)))?;
+    let fd = tty.as_raw_fd();
+    let saved = tcgetattr(fd)?;
+    let mut silent = saved.clone();
+    silent.local_flags.remove(LocalFlags::ECHO);
+    silent.local_flags.insert(LocalFlags::ICANON);
+    tty.write_all(prompt.as_bytes())?;
+    tcsetattr(fd, SetArg::TCSAFLUSH, &silent)?;
+    let secret = read_line(&tty);
+    tcsetattr(fd, SetArg::TCSAFLUSH, &saved)?;
+    tty.write_all(b"\n")?;
+    secret
+}
+
+#[cfg(not(unix))]
+fn read_terminal(_prompt: &str) -> std::io::Result<Secret> {
+    Err(Error::new(ErrorKind::Unsupported, "prompting for a passphrase is only supported on Unix"))
+}
+
+/// Look the passphrase up with the keyring tool of the system.
+fn read_keyring(service: &str) -> std::io::Result<Secret> {
+    let tool = if cfg!(target_os = "macos") { "security" } else { "secret-tool" };
+    let mut command = Command::new(tool);
+    if cfg!(target_os = "macos") {
+        command.arg("find-generic-password").arg("-w").arg("-s").arg(service);
+    } else {
+        command.arg("lookup").arg("service").arg(service);
+    }
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("can't run {}: {}", tool, e)))?;
+    let secret = read_line(child.stdout.take().unwrap());
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no passphrase found in the keyring for service {} ({})", service, status),
+        ));
+    }
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Source::parse("prompt"), Some(Source::Prompt));
+        assert_eq!(Source::parse("file:/run/key:1"), Some(Source::File("/run/key:1".to_owned())));
+        assert_eq!(Source::parse("env:SIGN_PASSPHRASE"), Some(Source::Env("SIGN_PASSPHRASE".to_owned())));
+        assert_eq!(Source::parse("keyring:backups"), Some(Source::Keyring("backups".to_owned())));
+        assert_eq!(Source::parse("file:"), None);
+        assert_eq!(Source::parse("passphrase"), None);
+        assert_eq!(Source::parse("url:https://example.org/"), None);
+    }
+
+    #[test]
+    fn read_file() {
+        let path = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.passphrase", std::process::id()));
+        let source = Source::File(path.to_str().unwrap().to_owned());
+
+        std::fs::write(&path, b"correct horse\r\nbattery staple\n").unwrap();
+        assert_eq!(Secret::read(&source, "").unwrap().as_bytes(), b"correct horse");
+        std::fs::write(&path, b"no newline").unwrap();
+        assert_eq!(Secret::read(&source, "").unwrap().as_bytes(), b"no newline");
+        std::fs::write(&path, vec![b'a'; MAX_LENGTH + 1]).unwrap();
+        assert_eq!(Secret::read(&source, "").err().unwrap().kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Secret::read(&source, "").err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn read_env() {
+        let name = format!("STREAMING_QCOW2_TEST_PASSPHRASE_{}", std::process::id());
+        let source = Source::Env(name.clone());
+        assert_eq!(Secret::read(&source, "").err().unwrap().kind(), ErrorKind::NotFound);
+        std::env::set_var(&name, "correct horse");
+        assert_eq!(Secret::read(&source, "").unwrap().as_bytes(), b"correct horse");
+        std::env::set_var(&name, "two\nlines");
+        assert_eq!(Secret::read(&source, "").err().unwrap().kind(), ErrorKind::InvalidData);
+        std::env::remove_var(&name);
+    }
+}
@@ -0,0 +1,307 @@
+//! Building a layout from the holes reported by the filesystem or device,
+//! using `lseek(SEEK_DATA)` and `lseek(SEEK_HOLE)`, or
+//! `FSCTL_QUERY_ALLOCATED_RANGES` on Windows, or from the extent map of the
+//! file (`FIEMAP` on Linux).
+
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::process::{Command, Stdio};
+
+use crate::input::Input;
+use crate::layout::{self, Extent, ExtentSource};
+use crate::log::message;
+
+fn local_file(input: &Input) -> std::io::Result<&std::fs::File> {
+    input.as_file().ok_or_else(|| std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the input is not a local file",
+    ))
+}
+
+fn log_data(extents: &[Range<u64>]) {
+    let data_bytes: u64 = extents.iter().map(|r| r.end - r.start).sum();
+    message!("Found {} bytes of data in {} extents", data_bytes, extents.len());
+}
+
+/// The holes of the input file or device, as an extent source.
+pub struct SeekHole<'a> {
+    pub size: u64,
+    /// Path of the input, to recognize ZFS volumes
+    pub path: &'a OsStr,
+}
+
+impl ExtentSource for SeekHole<'_> {
+    fn name(&self) -> &'static str {
+        "holes of input"
+    }
+
+    fn extents(&mut self, input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let mut extents = data_extents(local_file(input)?, self.size)?;
+        if let Some(block_size) = zvol_block_size(self.path) {
+            message!("Input is a ZFS volume with {}-byte blocks", block_size);
+            extents = align_extents(extents, block_size, self.size);
+        }
+        log_data(&extents);
+        Ok(layout::data_extents(extents, self.size))
+    }
+}
+
+/// The extent map of the input file, as an extent source.
+pub struct ExtentMap {
+    pub size: u64,
+}
+
+impl ExtentSource for ExtentMap {
+    fn name(&self) -> &'static str {
+        "extent map of input"
+    }
+
+    fn extents(&mut self, input: &mut Input) -> std::io::Result<Box<dyn Iterator<Item = Extent>>> {
+        let extents = fiemap_extents(local_file(input)?, self.size)?;
+        log_data(&extents);
+        Ok(layout::data_extents(extents, self.size))
+    }
+}
+
+/// Find the data extents of a file.
+///
+/// Files or devices that don't report holes appear as a single extent.
+#[cfg(any(target_os = "dragonfly", target_os = "freebsd", target_os = "illumos",
+          target_os = "linux", target_os = "solaris"))]
+pub fn data_extents(file: &std::fs::File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = match lseek(fd, offset as i64, Whence::SeekData) {
+            Ok(o) => o as u64,
+            // No data after offset
+            Err(Errno::ENXIO) => break,
+            // Not supported, everything is data
+            Err(Errno::EINVAL) if offset == 0 => return Ok(std::iter::once(0..size).collect()),
+            Err(e) => return Err(e.into()),
+        };
+        let end = match lseek(fd, start as i64, Whence::SeekHole) {
+            Ok(o) => (o as u64).min(size),
+            Err(Errno::ENXIO) => size,
+            Err(e) => return Err(e.into()),
+        };
+        if start >= end {
+            break;
+        }
+        extents.push(start..end);
+        offset = end;
+    }
+    Ok(extents)
+}
+
+/// Find the data extents of a file.
+///
+/// Files that are not sparse appear as a single extent.
+#[cfg(windows)]
+pub fn data_extents(file: &std::fs::File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::{GetLastError, ERROR_MORE_DATA};
+    use windows_sys::Win32::System::Ioctl::{FILE_ALLOCATED_RANGE_BUFFER, FSCTL_QUERY_ALLOCATED_RANGES};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    const BATCH: usize = 512;
+
+    let mut extents: Vec<Range<u64>> = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let query = FILE_ALLOCATED_RANGE_BUFFER {
+            FileOffset: offset as i64,
+            Length: (size - offset) as i64,
+        };
+        let mut ranges = [FILE_ALLOCATED_RANGE_BUFFER { FileOffset: 0, Length: 0 }; BATCH];
+        let mut returned = 0u32;
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle(),
+                FSCTL_QUERY_ALLOCATED_RANGES,
+                &query as *const FILE_ALLOCATED_RANGE_BUFFER as *const _,
+                std::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>() as u32,
+                ranges.as_mut_ptr() as *mut _,
+                std::mem::size_of_val(&ranges) as u32,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        // ERROR_MORE_DATA means the output buffer was filled, query again
+        // from the end of the last range
+        let more = ok == 0 && unsafe { GetLastError() } == ERROR_MORE_DATA;
+        if ok == 0 && !more {
+            if offset == 0 {
+                // Not supported (e.g. a disk device), everything is data
+                return Ok(std::iter::once(0..size).collect());
+            }
+            return Err(std::io::Error::last_os_error());
+        }
+        let count = returned as usize / std::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>();
+        for range in &ranges[..count] {
+            let start = range.FileOffset as u64;
+            let end = (start + range.Length as u64).min(size);
+            match extents.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => extents.push(start..end),
+            }
+            offset = end;
+        }
+        if !more || count == 0 {
+            break;
+        }
+    }
+    Ok(extents)
+}
+
+#[cfg(not(any(target_os = "dragonfly", target_os = "freebsd", target_os = "illumos",
+              target_os = "linux", target_os = "solaris", windows)))]
+pub fn data_extents(_file: &std::fs::File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    Ok(std::iter::once(0..size).collect())
+}
+
+/// Header of a FIEMAP request, from linux/fiemap.h
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FIEMAP_BATCH: usize = 256;
+
+/// The header followed by room for the extents, which the kernel fills
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct FiemapRequest {
+    header: Fiemap,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+nix::ioctl_readwrite!(ioctl_fiemap, b'f', 11, Fiemap); // FS_IOC_FIEMAP, defined in linux/fs.h
+
+/// Find the data extents of a file from its extent map.
+///
+/// Unlike `SEEK_HOLE`, this also leaves out extents that are allocated but
+/// unwritten (preallocated with `fallocate`), which read as zeros. Files or
+/// devices that have no extent map appear as a single extent.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn fiemap_extents(file: &std::fs::File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    use nix::errno::Errno;
+    use std::os::unix::io::AsRawFd;
+
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+
+    let empty_extent = FiemapExtent {
+        logical: 0,
+        physical: 0,
+        length: 0,
+        reserved64: [0; 2],
+        flags: 0,
+        reserved: [0; 3],
+    };
+    let mut extents: Vec<Range<u64>> = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let mut request = FiemapRequest {
+            header: Fiemap {
+                start: offset,
+                length: size - offset,
+                flags: FIEMAP_FLAG_SYNC,
+                mapped_extents: 0,
+                extent_count: FIEMAP_BATCH as u32,
+                reserved: 0,
+            },
+            extents: [empty_extent; FIEMAP_BATCH],
+        };
+        match unsafe { ioctl_fiemap(file.as_raw_fd(), &mut request as *mut FiemapRequest as *mut Fiemap) } {
+            Ok(_) => {}
+            // Not supported, everything is data
+            Err(Errno::EOPNOTSUPP | Errno::ENOTTY | Errno::EINVAL) if offset == 0 => {
+                return Ok(std::iter::once(0..size).collect());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let count = request.header.mapped_extents as usize;
+        if count == 0 {
+            break;
+        }
+        for extent in &request.extents[..count] {
+            let start = extent.logical.min(size);
+            let end = extent.logical.saturating_add(extent.length).min(size);
+            if extent.flags & FIEMAP_EXTENT_UNWRITTEN == 0 && start < end {
+                match extents.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => extents.push(start..end),
+                }
+            }
+            offset = end;
+        }
+        if request.extents[count - 1].flags & FIEMAP_EXTENT_LAST != 0 {
+            break;
+        }
+    }
+    Ok(extents)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn fiemap_extents(_file: &std::fs::File, _size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "FIEMAP is only supported on Linux",
+    ))
+}
+
+/// Get the block size of a ZFS volume, given its `/dev/zvol/...` path.
+///
+/// Holes in a zvol are whole blocks, so extents can be aligned to it.
+pub fn zvol_block_size(path: &OsStr) -> Option<u64> {
+    let dataset = path.to_str()?.strip_prefix("/dev/zvol/")?;
+    let output = Command::new("zfs")
+        .arg("get").arg("-Hp").arg("-o").arg("value").arg("volblocksize")
+        .arg(dataset)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok().filter(|&s: &u64| s > 0)
+}
+
+/// Round extents outwards to a multiple of the block size.
+pub fn align_extents(extents: Vec<Range<u64>>, block_size: u64, size: u64) -> Vec<Range<u64>> {
+    extents.into_iter()
+        .map(|r| {
+            let start = r.start / block_size * block_size;
+            let end = (r.end.div_ceil(block_size) * block_size).min(size);
+            start..end
+        })
+        .collect()
+}
@@ -0,0 +1,139 @@
+//! Detached OpenPGP signature of the image, computed by GnuPG as the image
+//! is written, so it doesn't have to be read a second time.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::secret::Secret;
+
+/// A running `gpg --detach-sign`, which is fed the image on its stdin.
+pub struct Signer {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Signer {
+    /// Start signing with the given key, writing the signature to a file.
+    ///
+    /// The passphrase of the key, if given, is written to gpg through a pipe
+    /// instead of letting it ask with its pinentry.
+    pub fn start(key: &str, signature: &Path, passphrase: Option<&Secret>) -> std::io::Result<Signer> {
+        let mut command = Command::new("gpg");
+        command
+            .arg("--batch")
+            .arg("--yes")
+            .arg("--detach-sign")
+            .arg("--local-user")
+            .arg(key)
+            .arg("--output")
+            .arg(signature);
+        let passphrase_pipe = match passphrase {
+            Some(_) => {
+                let (fd, read, write) = passphrase_pipe()?;
+                command
+                    .arg("--pinentry-mode")
+                    .arg("loopback")
+                    .arg("--passphrase-fd")
+                    .arg(fd.to_string());
+                Some((read, write))
+            }
+            None => None,
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let signer = Signer { child, stdin };
+        if let (Some(passphrase), Some((read, mut write))) = (passphrase, passphrase_pipe) {
+            // Only gpg keeps the read end, so writing fails if it exits
+            drop(read);
+            write.write_all(passphrase.as_bytes())
+                .and_then(|()| write.write_all(b"\n"))
+                .map_err(|e| std::io::Error::new(e.kind(), format!("writing the passphrase to gpg failed: {}", e)))?;
+        }
+        Ok(signer)
+    }
+
+    /// Finish feeding the image, and wait for the signature to be written.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        // Close the pipe so gpg sees the end of the data
+        self.stdin = None;
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("gpg command failed ({})", status)));
+        }
+        Ok(())
+    }
+}
+
+/// Pipe for the passphrase, whose read end (with the descriptor number
+/// returned first) is inherited by gpg.
+#[cfg(unix)]
+fn passphrase_pipe() -> std::io::Result<(i32, File, File)> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use std::os::unix::io::FromRawFd;
+
+    let (read, write) = nix::unistd::pipe()?;
+    let (read_file, write_file) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+    fcntl(write, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    Ok((read, read_file, write_file))
+}
+
+#[cfg(not(unix))]
+fn passphrase_pipe() -> std::io::Result<(i32, File, File)> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "passing the passphrase to gpg is only supported on Unix",
+    ))
+}
+
+impl Drop for Signer {
+    fn drop(&mut self) {
+        // Don't let gpg sign an incomplete image
+        if self.stdin.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Output that also feeds what is written to a signer, if any.
+pub struct SignedOutput<W: Write> {
+    inner: W,
+    signer: Option<Signer>,
+}
+
+impl<W: Write> SignedOutput<W> {
+    pub fn new(inner: W, signer: Option<Signer>) -> SignedOutput<W> {
+        SignedOutput { inner, signer }
+    }
+
+    /// Flush the output, and wait for the signature to be written.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.inner.flush()?;
+        match self.signer.take() {
+            Some(signer) => signer.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Write for SignedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(stdin) = self.signer.as_mut().and_then(|s| s.stdin.as_mut()) {
+            stdin.write_all(&buf[..written]).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("writing to gpg failed: {}", e))
+            })?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
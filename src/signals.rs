@@ -0,0 +1,87 @@
+//! Signal handling: progress reports on SIGUSR1 or SIGINFO, and graceful
+//! cancellation on SIGINT or SIGTERM (Ctrl+C on Windows).
+//!
+//! Cancellation only sets a flag, which long-running loops check so they
+//! can stop at a clean point.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Number of the signal that cancelled the conversion, 0 if none.
+static CANCELLED: AtomicI32 = AtomicI32::new(0);
+
+/// The signal that cancelled the conversion, if any.
+pub fn cancelled() -> Option<i32> {
+    match CANCELLED.load(Ordering::Relaxed) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Return an error if the conversion was cancelled.
+pub fn check_cancelled() -> std::io::Result<()> {
+    match cancelled() {
+        Some(_) => Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "cancelled")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(any(unix, windows))]
+fn cancel(signal: i32) {
+    use crate::log::message;
+
+    if CANCELLED.swap(signal, Ordering::Relaxed) != 0 {
+        // Second time, give up on cleaning up
+        std::process::exit(128 + signal);
+    }
+    message!("Cancelling, send the signal again to exit immediately");
+}
+
+/// Start handling signals.
+///
+/// The signals are blocked and waited for in a separate thread, so this has
+/// to be called before any other thread is started.
+#[cfg(unix)]
+pub fn install_handlers() -> std::io::Result<()> {
+    use nix::sys::signal::{SigSet, Signal};
+
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGUSR1);
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd"))]
+    signals.add(Signal::SIGINFO);
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+    std::thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            while let Ok(signal) = signals.wait() {
+                match signal {
+                    Signal::SIGINT | Signal::SIGTERM => cancel(signal as i32),
+                    _ => crate::progress::report(),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn install_handlers() -> std::io::Result<()> {
+    use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+
+    unsafe extern "system" fn handler(_ctrl_type: u32) -> i32 {
+        // Report it as SIGINT
+        cancel(2);
+        1
+    }
+
+    if unsafe { SetConsoleCtrlHandler(Some(handler), 1) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn install_handlers() -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Spooling an input of unknown size to a temporary file, so it can be
+//! converted like a regular file when the output can't be seeked.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::qcow2::read_full;
+use crate::{output, progress, signals};
+
+const SPOOL_BUFFER_SIZE: usize = 65536;
+
+/// A temporary copy of the input, removed on drop.
+pub struct Spool {
+    path: PathBuf,
+    size: u64,
+}
+
+impl Spool {
+    /// Copy the input to a new file in `directory`, failing if it is larger
+    /// than `max_size`.
+    ///
+    /// Blocks of zeros are skipped rather than written, so the file is
+    /// sparse on filesystems that support it.
+    pub fn create<R: Read>(mut reader: R, directory: &Path, max_size: Option<u64>) -> std::io::Result<Spool> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let (path, mut file) = output::create_unique(&options, &directory.join("streaming-qcow2-writer-"), ".spool")?;
+        let mut spool = Spool { path, size: 0 };
+
+        progress::start_copy("Spooling input", 0);
+        let mut buffer = vec![0u8; SPOOL_BUFFER_SIZE];
+        loop {
+            signals::check_cancelled()?;
+            let read = read_full(&mut reader, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            if max_size.is_some_and(|max| spool.size + read as u64 > max) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::FileTooLarge,
+                    "input is larger than the maximum spool size",
+                ));
+            }
+            if buffer[..read].iter().any(|&b| b != 0) {
+                file.write_all(&buffer[..read])?;
+            } else {
+                file.seek(SeekFrom::Current(read as i64))?;
+            }
+            spool.size += read as u64;
+            progress::add_copied(read as u64);
+            if read < buffer.len() {
+                break;
+            }
+        }
+        file.set_len(spool.size)?;
+        Ok(spool)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Spool;
+
+    #[test]
+    fn spool() {
+        let directory = std::env::temp_dir();
+        let data: Vec<u8> = [vec![1u8; 1000], vec![0u8; 200_000], vec![2u8; 10]].concat();
+        let spool = Spool::create(&data[..], &directory, None).unwrap();
+        let path = spool.path().to_owned();
+        assert_eq!(path.parent(), Some(directory.as_path()));
+        assert_eq!(spool.size(), data.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let other = Spool::create(&data[..10], &directory, None).unwrap();
+        assert_ne!(other.path(), path);
+        drop(spool);
+        assert!(!path.exists());
+
+        assert!(Spool::create(&data[..], &directory, Some(100_000)).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//! Notifications to systemd (`sd_notify`), when running as a service of
+//! type `notify`: readiness, the current phase and progress as the status of
+//! the unit, and keep-alive pings for its watchdog (`WatchdogSec=`).
+//!
+//! The watchdog is only pinged when the conversion moves to a new phase or
+//! makes progress, so a conversion stuck on a hung device gets restarted.
+//! Nothing is sent unless systemd set `NOTIFY_SOCKET`.
+//!
+//! With socket activation (`LISTEN_FDS`), the image is written to the socket
+//! passed by systemd instead of stdout, accepting a connection first if it is
+//! listening (`Accept=no`).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::log::message;
+#[cfg(unix)]
+use crate::signals;
+
+/// Minimum time between status updates, unless forced
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Notifier {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+    #[cfg(unix)]
+    address: std::os::unix::net::SocketAddr,
+    /// Whether systemd expects keep-alive pings
+    watchdog: bool,
+    last_status: Option<Instant>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NOTIFIER: Mutex<Option<Notifier>> = Mutex::new(None);
+
+/// First file descriptor passed with socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Socket passed with socket activation, until it is used
+#[cfg(unix)]
+static SOCKET: Mutex<Option<std::os::fd::OwnedFd>> = Mutex::new(None);
+
+/// Connect to systemd if `NOTIFY_SOCKET` is set, and take the socket passed
+/// if `LISTEN_FDS` is set, removing them and the watchdog settings from the
+/// environment so the programs we run don't use them.
+///
+/// This has to be called before any other thread is started.
+pub fn init() {
+    take_sockets();
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+    let watchdog = std::env::var("WATCHDOG_USEC").is_ok_and(|usec| usec.parse::<u64>().is_ok_and(|u| u > 0))
+        && watchdog_pid.is_none_or(|pid| pid == std::process::id().to_string());
+    for name in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+        std::env::remove_var(name);
+    }
+    match connect(&path, watchdog) {
+        Ok(notifier) => {
+            *NOTIFIER.lock().unwrap() = Some(notifier);
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+        Err(e) => message!("Warning: can't notify systemd: {}", e),
+    }
+}
+
+/// Take the sockets passed for us with socket activation, keeping the first
+/// one.
+fn take_sockets() {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let Some(count) = count.filter(|&n| n > 0) else {
+        return;
+    };
+    if pid.is_none_or(|pid| pid != std::process::id().to_string()) {
+        return;
+    }
+    if count > 1 {
+        message!("Warning: systemd passed {} sockets, only the first one is used", count);
+    }
+    #[cfg(unix)]
+    {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        // Don't leak them to the programs we run; the others are closed
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            if fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).is_err() {
+                message!("Warning: socket {} passed by systemd is not open", fd);
+                continue;
+            }
+            let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+            if fd == LISTEN_FDS_START {
+                *SOCKET.lock().unwrap() = Some(socket);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    message!("Warning: socket activation is only supported on Unix");
+}
+
+/// Whether systemd passed a socket to write the image to.
+pub fn has_socket() -> bool {
+    #[cfg(unix)]
+    return SOCKET.lock().unwrap().is_some();
+    #[cfg(not(unix))]
+    false
+}
+
+/// Make the socket passed by systemd our standard output, so the image is
+/// written to it, waiting for a connection if it is a listening socket.
+#[cfg(unix)]
+pub fn socket_to_stdout() -> std::io::Result<()> {
+    use nix::errno::Errno;
+    use nix::sys::socket::{accept, getsockopt, sockopt::AcceptConn};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let Some(socket) = SOCKET.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let connection = if getsockopt(socket.as_raw_fd(), AcceptConn)? {
+        message!("Waiting for a connection on the socket passed by systemd");
+        status("Waiting for a connection", true);
+        let fd = loop {
+            match accept(socket.as_raw_fd()) {
+                Ok(fd) => break fd,
+                Err(Errno::EINTR) => signals::check_cancelled()?,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    } else {
+        socket
+    };
+    nix::unistd::dup2(connection.as_raw_fd(), 1)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn socket_to_stdout() -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn connect(path: &std::ffi::OsStr, watchdog: bool) -> std::io::Result<Notifier> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let address = match path.as_bytes() {
+        // Socket in the abstract namespace
+        [b'@', name @ ..] => abstract_address(name)?,
+        _ => SocketAddr::from_pathname(path)?,
+    };
+    Ok(Notifier {
+        socket: UnixDatagram::unbound()?,
+        address,
+        watchdog,
+        last_status: None,
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn abstract_address(name: &[u8]) -> std::io::Result<std::os::unix::net::SocketAddr> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    std::os::unix::net::SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn abstract_address(_name: &[u8]) -> std::io::Result<std::os::unix::net::SocketAddr> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+#[cfg(not(unix))]
+fn connect(_path: &std::ffi::OsStr, _watchdog: bool) -> std::io::Result<Notifier> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "systemd notifications are only supported on Unix",
+    ))
+}
+
+impl Notifier {
+    fn send(&self, state: &str) {
+        // systemd going away shouldn't stop the conversion
+        #[cfg(unix)]
+        let _ = self.socket.send_to_addr(state.as_bytes(), &self.address);
+        #[cfg(not(unix))]
+        let _ = state;
+    }
+}
+
+/// Whether systemd is notified, to skip formatting the status otherwise.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Tell systemd that the service started.
+pub fn ready() {
+    if let Some(notifier) = &*NOTIFIER.lock().unwrap() {
+        notifier.send("READY=1");
+    }
+}
+
+/// Update the status of the unit, and ping the watchdog.
+///
+/// Updates are only sent if enough time has passed since the last one,
+/// unless `force` is set.
+pub fn status(status: &str, force: bool) {
+    if !enabled() {
+        return;
+    }
+    let mut notifier = NOTIFIER.lock().unwrap();
+    let Some(notifier) = &mut *notifier else {
+        return;
+    };
+    let now = Instant::now();
+    if !force && notifier.last_status.is_some_and(|t| now - t < STATUS_INTERVAL) {
+        return;
+    }
+    notifier.last_status = Some(now);
+    let status = status.replace('\n', " ");
+    if notifier.watchdog {
+        notifier.send(&format!("STATUS={}\nWATCHDOG=1", status));
+    } else {
+        notifier.send(&format!("STATUS={}", status));
+    }
+}
+
+/// Tell systemd that the service is stopping, with its final status.
+pub fn stopping(status: &str) {
+    if let Some(notifier) = &*NOTIFIER.lock().unwrap() {
+        notifier.send(&format!("STOPPING=1\nSTATUS={}", status.replace('\n', " ")));
+    }
+}
@@ -0,0 +1,347 @@
+//! Writing the raw disk as a GNU sparse tar member, as created by
+//! `tar --format=oldgnu --sparse`, which some import pipelines (OpenStack,
+//! GCE) take instead of qcow2.
+//!
+//! The member header (type 'S') holds the sparse map: the offset and length
+//! of each data extent, continued in extension headers if there are more
+//! than 4. Only the data extents are stored after it, back to back; GNU tar
+//! reads each of them in whole blocks, so they are rounded out to blocks.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::qcow2::read_full;
+use crate::{layout, progress, signals};
+
+pub const BLOCK_SIZE: u64 = 512;
+
+/// Size of a record, which the archive is padded to (20 blocks, the default
+/// blocking factor of GNU tar)
+const RECORD_SIZE: u64 = 20 * BLOCK_SIZE;
+
+/// Number of sparse map entries in the member header
+const HEADER_SPARSE_ENTRIES: usize = 4;
+
+/// Number of sparse map entries in each extension header
+const EXTENSION_SPARSE_ENTRIES: usize = 21;
+
+const COPY_BUFFER_SIZE: usize = 65536;
+
+pub struct SparseTarWriter {
+    name: String,
+    input_size: u64,
+    /// Sparse map, ending with an empty extent at the end of the disk if it
+    /// ends with a hole
+    extents: Vec<Range<u64>>,
+    /// Ranges to copy from the input, the rest of the extents being zeroed
+    ranges: Vec<Range<u64>>,
+    mtime: u64,
+}
+
+impl SparseTarWriter {
+    pub fn new<I: Iterator<Item=Range<u64>>>(name: String, input_size: u64, ranges: I, mtime: u64) -> SparseTarWriter {
+        let ranges = layout::normalize(ranges.map(|r| r.start.min(input_size)..r.end.min(input_size)).collect());
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        for range in &ranges {
+            let start = range.start / BLOCK_SIZE * BLOCK_SIZE;
+            let end = range.end.next_multiple_of(BLOCK_SIZE).min(input_size);
+            match extents.last_mut() {
+                Some(last) if last.end >= start => last.end = last.end.max(end),
+                _ => extents.push(start..end),
+            }
+        }
+        if extents.last().is_none_or(|last| last.end < input_size) {
+            extents.push(input_size..input_size);
+        }
+        SparseTarWriter {
+            name,
+            input_size,
+            extents,
+            ranges,
+            mtime,
+        }
+    }
+
+    /// Number of bytes of data stored in the member.
+    fn data_size(&self) -> u64 {
+        self.extents.iter().map(|e| e.end - e.start).sum()
+    }
+
+    fn extension_headers(&self) -> u64 {
+        let extra = self.extents.len().saturating_sub(HEADER_SPARSE_ENTRIES);
+        extra.div_ceil(EXTENSION_SPARSE_ENTRIES) as u64
+    }
+
+    /// Memory used by the sparse map and the copy buffer, in bytes.
+    pub fn memory_usage(&self) -> u64 {
+        let ranges = self.extents.capacity() + self.ranges.capacity();
+        (ranges * std::mem::size_of::<Range<u64>>() + COPY_BUFFER_SIZE) as u64
+    }
+
+    pub fn file_size(&self) -> u64 {
+        let size =
+            BLOCK_SIZE // Header
+            + self.extension_headers() * BLOCK_SIZE
+            + self.data_size().next_multiple_of(BLOCK_SIZE)
+            + 2 * BLOCK_SIZE; // End of archive
+        size.next_multiple_of(RECORD_SIZE)
+    }
+
+    fn write_header<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let mut header = [0u8; BLOCK_SIZE as usize];
+        let name = self.name.as_bytes();
+        if name.len() > 100 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "member name is too long",
+            ));
+        }
+        header[0..name.len()].copy_from_slice(name);
+        write_number(&mut header[100..108], 0o644); // Mode
+        write_number(&mut header[108..116], 0); // Owner
+        write_number(&mut header[116..124], 0); // Group
+        write_number(&mut header[124..136], self.data_size());
+        write_number(&mut header[136..148], self.mtime);
+        header[156] = b'S'; // Sparse file
+        header[257..265].copy_from_slice(b"ustar  \0"); // GNU magic
+
+        // Sparse map
+        for (i, extent) in self.extents.iter().take(HEADER_SPARSE_ENTRIES).enumerate() {
+            let entry = 386 + i * 24;
+            write_number(&mut header[entry..entry + 12], extent.start);
+            write_number(&mut header[entry + 12..entry + 24], extent.end - extent.start);
+        }
+        header[482] = (self.extents.len() > HEADER_SPARSE_ENTRIES) as u8;
+        write_number(&mut header[483..495], self.input_size);
+
+        // Checksum, computed with the field set to spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        writer.write_all(&header)?;
+
+        // Extension headers
+        let mut remaining = self.extents.iter().skip(HEADER_SPARSE_ENTRIES).peekable();
+        while remaining.peek().is_some() {
+            let mut extension = [0u8; BLOCK_SIZE as usize];
+            for (i, extent) in remaining.by_ref().take(EXTENSION_SPARSE_ENTRIES).enumerate() {
+                let entry = i * 24;
+                write_number(&mut extension[entry..entry + 12], extent.start);
+                write_number(&mut extension[entry + 12..entry + 24], extent.end - extent.start);
+            }
+            extension[504] = remaining.peek().is_some() as u8;
+            writer.write_all(&extension)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the whole archive.
+    pub fn write<R: Read + Seek, W: Write>(&self, mut reader: R, mut writer: W) -> std::io::Result<()> {
+        self.write_header(&mut writer)?;
+
+        progress::start_copy("Copying data", self.data_size());
+        let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+        for extent in &self.extents {
+            reader.seek(SeekFrom::Start(extent.start))?;
+            let mut pos = extent.start;
+            while pos < extent.end {
+                signals::check_cancelled()?;
+                let len = (extent.end - pos).min(COPY_BUFFER_SIZE as u64) as usize;
+                read_full(&mut reader, &mut buffer[..len])?;
+                layout::mask(&self.ranges, pos, &mut buffer[..len]);
+                writer.write_all(&buffer[..len])?;
+                progress::add_copied(len as u64);
+                pos += len as u64;
+            }
+        }
+
+        // Padding and end of archive
+        let written =
+            BLOCK_SIZE
+            + self.extension_headers() * BLOCK_SIZE
+            + self.data_size();
+        let padding = self.file_size() - written;
+        std::io::copy(&mut std::io::repeat(0).take(padding), &mut writer)?;
+
+        Ok(())
+    }
+}
+
+/// Write a number in a header field, as octal if it fits or in GNU's base-256
+/// encoding otherwise.
+fn write_number(field: &mut [u8], value: u64) {
+    let octal = format!("{:0width$o}", value, width = field.len() - 1);
+    if octal.len() < field.len() {
+        field[..octal.len()].copy_from_slice(octal.as_bytes());
+        field[octal.len()] = 0;
+    } else {
+        field.fill(0);
+        let bytes = value.to_be_bytes();
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&bytes);
+        field[0] |= 0x80;
+    }
+}
+
+/// Read a number from a header field, in octal or in GNU's base-256
+/// encoding.
+pub fn read_number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let mut bytes = field.to_vec();
+        bytes[0] &= 0x7f;
+        let (high, low) = bytes.split_at(bytes.len().saturating_sub(8));
+        if high.iter().any(|&b| b != 0) {
+            return None;
+        }
+        return Some(low.iter().fold(0, |value, &b| value << 8 | b as u64));
+    }
+    let digits = field.iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+    let mut value: u64 = 0;
+    let mut any = false;
+    for &b in digits {
+        if !(b'0'..=b'7').contains(&b) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add((b - b'0') as u64)?;
+        any = true;
+    }
+    any.then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn input(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    fn archive(size: u64, ranges: &[Range<u64>]) -> (SparseTarWriter, Vec<u8>) {
+        let writer = SparseTarWriter::new("disk.raw".to_owned(), size, ranges.iter().cloned(), 1_700_000_000);
+        let mut output = Vec::new();
+        writer.write(Cursor::new(input(size)), &mut output).unwrap();
+        assert_eq!(output.len() as u64, writer.file_size());
+        (writer, output)
+    }
+
+    /// Read `count` sparse map entries from a block.
+    fn sparse_map(block: &[u8], count: usize) -> Vec<Range<u64>> {
+        (0..count)
+            .map(|i| {
+                let offset = read_number(&block[i * 24..i * 24 + 12]).unwrap();
+                let length = read_number(&block[i * 24 + 12..i * 24 + 24]).unwrap();
+                offset..offset + length
+            })
+            .collect()
+    }
+
+    /// Ranges of a block each, every other block.
+    fn every_other_block(count: u64) -> Vec<Range<u64>> {
+        (0..count).map(|i| i * 2 * BLOCK_SIZE..(i * 2 + 1) * BLOCK_SIZE).collect()
+    }
+
+    #[test]
+    fn header() {
+        let (_, output) = archive(4096, std::slice::from_ref(&(0..1024)));
+        let header = &output[..BLOCK_SIZE as usize];
+        assert_eq!(&header[..9], b"disk.raw\0");
+        assert_eq!(header[156], b'S');
+        assert_eq!(&header[257..265], b"ustar  \0");
+        assert_eq!(read_number(&header[124..136]), Some(1024));
+        assert_eq!(read_number(&header[136..148]), Some(1_700_000_000));
+
+        // Checksum of the header with the field as spaces, as 6 octal digits,
+        // NUL and space
+        let mut blank = header.to_vec();
+        blank[148..156].fill(b' ');
+        let sum: u64 = blank.iter().map(|&b| b as u64).sum();
+        assert_eq!(read_number(&header[148..156]), Some(sum));
+        assert_eq!(&header[154..156], b"\0 ");
+    }
+
+    #[test]
+    fn inline_sparse_map() {
+        // 4 extents fit in the header, the disk ends with data
+        let ranges = [0..512, 1024..2048, 4096..4608, 7680..8192];
+        let (writer, output) = archive(8192, &ranges);
+        let header = &output[..BLOCK_SIZE as usize];
+        assert_eq!(sparse_map(&header[386..482], 4), ranges);
+        assert_eq!(header[482], 0);
+        assert_eq!(read_number(&header[483..495]), Some(8192));
+        assert_eq!(writer.extension_headers(), 0);
+
+        // Data follows the header directly
+        let data = &output[BLOCK_SIZE as usize..];
+        assert_eq!(&data[..512], &input(512)[..]);
+        assert_eq!(&data[512..1536], &input(2048)[1024..]);
+    }
+
+    #[test]
+    fn extension_headers() {
+        // 4 extents in the header, 21 in the first extension header and the
+        // last one in a second
+        let ranges = every_other_block(26);
+        let size = 51 * BLOCK_SIZE;
+        let (writer, output) = archive(size, &ranges);
+        assert_eq!(writer.extension_headers(), 2);
+        let block = |i: usize| &output[i * BLOCK_SIZE as usize..(i + 1) * BLOCK_SIZE as usize];
+        assert_eq!(sparse_map(&block(0)[386..482], 4), ranges[..4]);
+        assert_eq!(block(0)[482], 1);
+        assert_eq!(sparse_map(block(1), 21), ranges[4..25]);
+        assert_eq!(block(1)[504], 1);
+        assert_eq!(sparse_map(block(2), 1), ranges[25..]);
+        assert!(block(2)[24..504].iter().all(|&b| b == 0));
+        assert_eq!(block(2)[504], 0);
+
+        // Data follows the extension headers
+        assert_eq!(block(3), &input(BLOCK_SIZE)[..]);
+    }
+
+    #[test]
+    fn ending_in_hole() {
+        // The sparse map ends with an empty extent at the end of the disk
+        let (writer, output) = archive(1 << 20, std::slice::from_ref(&(0..1000)));
+        assert_eq!(writer.extents, [0..1024, 1 << 20..1 << 20]);
+        let header = &output[..BLOCK_SIZE as usize];
+        assert_eq!(sparse_map(&header[386..482], 2), writer.extents);
+        assert_eq!(read_number(&header[124..136]), Some(1024));
+        assert_eq!(read_number(&header[483..495]), Some(1 << 20));
+
+        // Data outside the ranges is zeroed
+        let data = &output[BLOCK_SIZE as usize..];
+        assert_eq!(&data[..1000], &input(1000)[..]);
+        assert!(data[1000..1024].iter().all(|&b| b == 0));
+        assert_eq!(output.len() as u64 % RECORD_SIZE, 0);
+    }
+
+    #[test]
+    fn extract() {
+        // Extracted by GNU tar, if installed
+        if std::process::Command::new("tar").arg("--version").output().is_err() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.tar", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let size = 64 * BLOCK_SIZE + 300;
+        let mut ranges = every_other_block(30);
+        ranges.push(61 * BLOCK_SIZE + 7..62 * BLOCK_SIZE + 100);
+        let (_, output) = archive(size, &ranges);
+        std::fs::write(dir.join("disk.tar"), &output).unwrap();
+        let status = std::process::Command::new("tar")
+            .arg("-xf").arg("disk.tar")
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let extracted = std::fs::read(dir.join("disk.raw")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut expected = input(size);
+        layout::mask(&ranges, 0, &mut expected);
+        assert!(extracted == expected);
+    }
+}
@@ -0,0 +1,36 @@
+//! Limiting the rate of read requests to the input, for storage where the
+//! number of requests is the bottleneck rather than the bandwidth (such as
+//! cloud block storage with provisioned IOPS).
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Limiter {
+    interval: Duration,
+    /// When the next request is allowed
+    next: Instant,
+}
+
+static LIMITER: Mutex<Option<Limiter>> = Mutex::new(None);
+
+/// Allow at most `iops` read requests per second.
+pub fn set_iops_limit(iops: u32) {
+    *LIMITER.lock().unwrap() = Some(Limiter {
+        interval: Duration::from_secs(1) / iops,
+        next: Instant::now(),
+    });
+}
+
+/// Wait until a read request is allowed.
+pub fn wait_for_read() {
+    let mut limiter = LIMITER.lock().unwrap();
+    let Some(limiter) = &mut *limiter else {
+        return;
+    };
+    let now = Instant::now();
+    if limiter.next > now {
+        std::thread::sleep(limiter.next - now);
+    }
+    // Don't bank unused requests while idle
+    limiter.next = limiter.next.max(now) + limiter.interval;
+}
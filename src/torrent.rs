@@ -0,0 +1,297 @@
+//! BitTorrent v2 metadata for the image (`--torrent PATH`), computed as the
+//! image is written, so large images can be distributed without hashing
+//! them again.
+//!
+//! Version 2 torrents (BEP 52) hash the file with a SHA-256 merkle tree over
+//! 16 KiB blocks. The `.torrent` holds the root of that tree and the layer of
+//! hashes covering each piece, so clients can verify pieces on their own.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::log::message;
+
+/// Size of the leaves of the merkle tree, fixed by the specification
+const BLOCK_SIZE: usize = 16 << 10;
+
+/// Smallest and largest piece sizes to pick
+const MIN_PIECE_LENGTH: u64 = 256 << 10;
+const MAX_PIECE_LENGTH: u64 = 16 << 20;
+
+/// Number of pieces to aim for, so the piece layer stays small
+const TARGET_PIECES: u64 = 2048;
+
+type Hash = [u8; 32];
+
+/// Hashes of an image being written, to describe it in a torrent.
+pub struct Torrent {
+    piece_length: u64,
+    length: u64,
+    /// Current block, until it is full
+    block: Vec<u8>,
+    /// Hashes of the blocks of the current piece
+    leaves: Vec<Hash>,
+    /// Merkle roots of the pieces completed so far
+    pieces: Vec<Hash>,
+}
+
+impl Torrent {
+    /// Start hashing a file of this size, which picks the piece length.
+    pub fn new(file_size: u64) -> Torrent {
+        let piece_length = file_size.div_ceil(TARGET_PIECES).next_power_of_two()
+            .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH);
+        Torrent {
+            piece_length,
+            length: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            leaves: Vec::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut buf: &[u8]) {
+        self.length += buf.len() as u64;
+        while !buf.is_empty() {
+            let n = (BLOCK_SIZE - self.block.len()).min(buf.len());
+            self.block.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.block.len() == BLOCK_SIZE {
+                self.end_block();
+            }
+        }
+    }
+
+    fn blocks_per_piece(&self) -> usize {
+        (self.piece_length / BLOCK_SIZE as u64) as usize
+    }
+
+    fn end_block(&mut self) {
+        self.leaves.push(Sha256::digest(&self.block).into());
+        self.block.clear();
+        if self.leaves.len() == self.blocks_per_piece() {
+            self.pieces.push(merkle_root(std::mem::take(&mut self.leaves), self.blocks_per_piece(), [0; 32]));
+        }
+    }
+
+    /// Write the `.torrent` file for the image, named `name` in it.
+    ///
+    /// Returns the info hash, which identifies the torrent.
+    pub fn finish(mut self, path: &Path, name: &str, trackers: &[String], created: u64) -> std::io::Result<String> {
+        if self.length == 0 {
+            return Err(std::io::Error::other("can't make a torrent of an empty file"));
+        }
+        let root = self.root();
+        self.write(path, name, trackers, created, root)
+    }
+
+    /// Hash the rest of the file, returning the root of its merkle tree.
+    fn root(&mut self) -> Hash {
+        if !self.block.is_empty() {
+            self.end_block();
+        }
+        let blocks_per_piece = self.blocks_per_piece();
+        if self.length > self.piece_length {
+            if !self.leaves.is_empty() {
+                let leaves = std::mem::take(&mut self.leaves);
+                self.pieces.push(merkle_root(leaves, blocks_per_piece, [0; 32]));
+            }
+            // Pieces past the end are made of blocks of zero hashes
+            let padding = merkle_root(Vec::new(), blocks_per_piece, [0; 32]);
+            merkle_root(self.pieces.clone(), self.pieces.len().next_power_of_two(), padding)
+        } else if let Some(&root) = self.pieces.first() {
+            root
+        } else {
+            // The tree only extends to the blocks of the file
+            let width = self.leaves.len().next_power_of_two();
+            merkle_root(std::mem::take(&mut self.leaves), width, [0; 32])
+        }
+    }
+
+    fn write(&self, path: &Path, name: &str, trackers: &[String], created: u64, root: Hash) -> std::io::Result<String> {
+        let mut file_entry = BTreeMap::new();
+        file_entry.insert(b"length".to_vec(), Value::Int(self.length));
+        file_entry.insert(b"pieces root".to_vec(), Value::Bytes(root.to_vec()));
+        let mut file_tree = BTreeMap::new();
+        file_tree.insert(
+            name.as_bytes().to_vec(),
+            Value::Dict([(Vec::new(), Value::Dict(file_entry))].into()),
+        );
+        let mut info = BTreeMap::new();
+        info.insert(b"file tree".to_vec(), Value::Dict(file_tree));
+        info.insert(b"meta version".to_vec(), Value::Int(2));
+        info.insert(b"name".to_vec(), Value::Bytes(name.as_bytes().to_vec()));
+        info.insert(b"piece length".to_vec(), Value::Int(self.piece_length));
+        let info = Value::Dict(info);
+
+        let mut torrent = BTreeMap::new();
+        if let Some(first) = trackers.first() {
+            torrent.insert(b"announce".to_vec(), Value::Bytes(first.as_bytes().to_vec()));
+        }
+        if trackers.len() > 1 {
+            let tiers = trackers.iter()
+                .map(|t| Value::List(vec![Value::Bytes(t.as_bytes().to_vec())]))
+                .collect();
+            torrent.insert(b"announce-list".to_vec(), Value::List(tiers));
+        }
+        torrent.insert(
+            b"created by".to_vec(),
+            Value::Bytes(format!("streaming-qcow2-writer {}", env!("CARGO_PKG_VERSION")).into_bytes()),
+        );
+        torrent.insert(b"creation date".to_vec(), Value::Int(created));
+        let info_hash: String = Sha256::digest(info.encode()).iter().map(|b| format!("{:02x}", b)).collect();
+        torrent.insert(b"info".to_vec(), info);
+        // Files of a single piece don't need their piece layer
+        if self.length > self.piece_length {
+            torrent.insert(
+                b"piece layers".to_vec(),
+                Value::Dict([(root.to_vec(), Value::Bytes(self.pieces.concat()))].into()),
+            );
+        }
+
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&Value::Dict(torrent).encode())?;
+        file.sync_all()?;
+        message!("Wrote torrent {}, info hash {}", path.display(), info_hash);
+        Ok(info_hash)
+    }
+}
+
+/// Output that also hashes what is written into a torrent, if any.
+pub struct TorrentOutput<'a, W: Write> {
+    inner: W,
+    torrent: Option<&'a mut Torrent>,
+}
+
+impl<'a, W: Write> TorrentOutput<'a, W> {
+    pub fn new(inner: W, torrent: Option<&'a mut Torrent>) -> TorrentOutput<'a, W> {
+        TorrentOutput { inner, torrent }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for TorrentOutput<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(torrent) = &mut self.torrent {
+            torrent.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hash the leaves up to the root of a tree of the given width, filling the
+/// missing leaves with `padding`.
+fn merkle_root(mut layer: Vec<Hash>, width: usize, padding: Hash) -> Hash {
+    layer.resize(width, padding);
+    while layer.len() > 1 {
+        layer = layer.chunks(2)
+            .map(|pair| Sha256::new().chain_update(pair[0]).chain_update(pair[1]).finalize().into())
+            .collect();
+    }
+    layer[0]
+}
+
+/// A bencoded value.
+enum Value {
+    Int(u64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Keys are sorted, as required
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+            Value::Bytes(b) => {
+                out.extend_from_slice(format!("{}:", b.len()).as_bytes());
+                out.extend_from_slice(b);
+            }
+            Value::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Value::Bytes(key.clone()).encode_into(out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hash: &Hash) -> String {
+        hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Hash a file of this size, returning the root and the piece layer.
+    fn hash(size: usize) -> (String, Vec<String>) {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let mut torrent = Torrent::new(size as u64);
+        assert_eq!(torrent.piece_length, MIN_PIECE_LENGTH);
+        // Written in pieces that don't line up with blocks
+        for part in data.chunks(10000) {
+            torrent.update(part);
+        }
+        let root = torrent.root();
+        (hex(&root), torrent.pieces.iter().map(hex).collect())
+    }
+
+    #[test]
+    fn one_piece() {
+        // 3 blocks, the last one partial, in a tree of 4 leaves
+        let (root, pieces) = hash(40000);
+        assert_eq!(root, "ab671631a9fa97a1fdac651fff6c68773b9acf0735b9c7f6ecdd54cbf1bf5dc2");
+        assert!(pieces.is_empty());
+    }
+
+    #[test]
+    fn several_pieces() {
+        // 3 whole pieces, in a tree of 4
+        let (root, pieces) = hash(3 * MIN_PIECE_LENGTH as usize);
+        assert_eq!(root, "695916fff504cbf756a252ed2d4a68f160a974683a7e2056df74dd737d1ba8e3");
+        assert_eq!(pieces, [
+            "936f20cb9f0648c16ce2556795f1cc50ad21a3e3ec53ed8a6e554a75f8a4357f",
+            "b7bc45d83358af780e2bd8afdc3221ce321f9ce6ec6a22c3dd8e95ce7d55c348",
+            "8655ab479a3933dce25a45ba693dadd3f73b1a295f828f95deb11af9723a33fd",
+        ]);
+    }
+
+    #[test]
+    fn padded_last_piece() {
+        // The last piece has 2 blocks, and is padded with zero hashes
+        let (root, pieces) = hash(2 * MIN_PIECE_LENGTH as usize + 20000);
+        assert_eq!(root, "4352028220342a90b64b8d5577f65f45fe089b8d017f7776088ecb2d7478f526");
+        assert_eq!(pieces, [
+            "936f20cb9f0648c16ce2556795f1cc50ad21a3e3ec53ed8a6e554a75f8a4357f",
+            "b7bc45d83358af780e2bd8afdc3221ce321f9ce6ec6a22c3dd8e95ce7d55c348",
+            "e29f7c166dfc631d233e553d7f96577c9feefe31110724a1dcd5316a1796ac7f",
+        ]);
+    }
+}
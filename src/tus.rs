@@ -0,0 +1,471 @@
+//! Uploading the image to a server implementing the tus resumable upload
+//! protocol (`--upload tus+https://host/files/`), so an upload survives
+//! network errors.
+//!
+//! The upload is created with the size of the image, then the image is sent
+//! in chunks with PATCH requests. A chunk is kept until the server has
+//! acknowledged it: if a request fails, the offset the server has is asked
+//! with HEAD, and the upload resumes from there. Requests are made with
+//! `curl`, which reads `~/.curlrc`, where authentication headers can be set.
+//!
+//! An upload that fails or is interrupted is left on the server, and its URL
+//! printed: a later run producing the same image can resume it
+//! (`--tus-resume`), skipping the data the server already has.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::http::resolve;
+use crate::log::message;
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Size of the PATCH requests, and of the data kept to resend
+const CHUNK_SIZE: usize = 16 << 20;
+
+/// Attempts at sending a chunk without progress before giving up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled at each attempt
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[cfg(windows)]
+const NULL_DEVICE: &str = "NUL";
+#[cfg(not(windows))]
+const NULL_DEVICE: &str = "/dev/null";
+
+/// Response to a request, with its header names in lowercase.
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    fn offset(&self) -> std::io::Result<u64> {
+        self.header("upload-offset")
+            .and_then(|o| o.parse().ok())
+            .ok_or_else(|| std::io::Error::other("server didn't send a valid Upload-Offset"))
+    }
+}
+
+/// Error of a request that can be retried after checking the offset.
+fn is_transient(error: &std::io::Error) -> bool {
+    error.kind() != std::io::ErrorKind::InvalidData
+}
+
+/// Make a request with curl, sending `body` if given.
+fn request(method: &str, url: &str, headers: &[String], body: Option<&[u8]>) -> std::io::Result<Response> {
+    let mut command = Command::new("curl");
+    command
+        .arg("--silent").arg("--show-error")
+        .arg("--dump-header").arg("-")
+        .arg("--output").arg(NULL_DEVICE)
+        .arg("--header").arg(format!("Tus-Resumable: {}", TUS_VERSION));
+    for header in headers {
+        command.arg("--header").arg(header);
+    }
+    if method == "HEAD" {
+        command.arg("--head");
+    } else {
+        command.arg("--request").arg(method);
+    }
+    if body.is_some() {
+        command
+            .arg("--header").arg("Content-Type: application/offset+octet-stream")
+            .arg("--data-binary").arg("@-");
+    }
+    command.arg(url);
+    let mut child = command
+        .stdin(if body.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("can't run curl: {}", e)))?;
+    if let (Some(mut stdin), Some(body)) = (child.stdin.take(), body) {
+        // If curl fails, its status tells why
+        let _ = stdin.write_all(body);
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("curl failed ({})", output.status)));
+    }
+
+    // Keep the last response, after any 100 Continue
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut response = None;
+    for line in text.lines().map(str::trim_end) {
+        if line.starts_with("HTTP/") {
+            let status = line.split_whitespace().nth(1).and_then(|s| s.parse().ok());
+            response = status.map(|status| Response { status, headers: Vec::new() });
+        } else if let (Some(response), Some((name, value))) = (&mut response, line.split_once(':')) {
+            response.headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+    }
+    response.ok_or_else(|| std::io::Error::other("no HTTP response from server"))
+}
+
+/// Check the status of a response: server errors and conflicting offsets can
+/// be retried, other errors can't.
+fn check_status(response: &Response, expected: u16, what: &str) -> std::io::Result<()> {
+    match response.status {
+        s if s == expected => Ok(()),
+        409 | 423 | 460 | 500..=599 => Err(std::io::Error::other(format!("{} failed with status {}", what, response.status))),
+        s => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} failed with status {}", what, s),
+        )),
+    }
+}
+
+/// An image being uploaded with the tus protocol.
+pub struct Upload {
+    url: String,
+    size: u64,
+    /// Offset acknowledged by the server
+    offset: u64,
+    /// Bytes of the image written so far, which are skipped until they reach
+    /// the offset of a resumed upload
+    position: u64,
+    /// Data after the acknowledged offset
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    retry_delay: Duration,
+    finished: bool,
+}
+
+impl Upload {
+    /// Create an upload of this size on the tus endpoint.
+    pub fn start(endpoint: &str, size: u64) -> std::io::Result<Upload> {
+        let headers = [format!("Upload-Length: {}", size)];
+        let response = request("POST", endpoint, &headers, None)?;
+        check_status(&response, 201, "creating the upload")?;
+        let location = response.header("location")
+            .ok_or_else(|| std::io::Error::other("server didn't send the Location of the upload"))?;
+        let url = resolve(endpoint, location);
+        message!("Created upload {}", url);
+        Ok(Upload::new(url, size, 0))
+    }
+
+    /// Resume an existing upload of this size, from the offset the server
+    /// has. The image written has to be the same as the one it was created
+    /// for.
+    pub fn resume(url: &str, size: u64) -> std::io::Result<Upload> {
+        let response = request("HEAD", url, &[], None)?;
+        check_status(&response, 200, "getting the upload offset")?;
+        if let Some(length) = response.header("upload-length") {
+            if length.parse() != Ok(size) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("upload has a length of {} bytes, but the image has {}", length, size),
+                ));
+            }
+        }
+        let offset = response.offset()?;
+        if offset > size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("upload has offset {}, past the end of the image ({})", offset, size),
+            ));
+        }
+        message!("Resuming upload {} from offset {}", url, offset);
+        Ok(Upload::new(url.to_owned(), size, offset))
+    }
+
+    fn new(url: String, size: u64, offset: u64) -> Upload {
+        Upload {
+            url,
+            size,
+            offset,
+            position: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            chunk_size: CHUNK_SIZE,
+            retry_delay: RETRY_DELAY,
+            finished: false,
+        }
+    }
+
+    /// Send the chunk, resuming from the offset the server has after errors.
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        let start = self.offset;
+        let end = start + self.chunk.len() as u64;
+        let mut attempts = 0;
+        while self.offset < end {
+            let headers = [format!("Upload-Offset: {}", self.offset)];
+            let data = &self.chunk[(self.offset - start) as usize..];
+            let result = request("PATCH", &self.url, &headers, Some(data))
+                .and_then(|r| check_status(&r, 204, "sending data").and_then(|()| r.offset()));
+            let error = match result {
+                Ok(offset) if offset > self.offset && offset <= end => {
+                    self.offset = offset;
+                    attempts = 0;
+                    continue;
+                }
+                Ok(offset) => std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("server acknowledged offset {} after data was sent from {}", offset, self.offset),
+                ),
+                Err(e) => e,
+            };
+            attempts += 1;
+            if !is_transient(&error) || attempts >= MAX_ATTEMPTS {
+                return Err(error);
+            }
+            message!("Upload interrupted at offset {} ({}), resuming", self.offset, error);
+            std::thread::sleep(self.retry_delay * (1 << (attempts - 1)));
+
+            // Ask where the server is, it may have received part of the data
+            let response = match request("HEAD", &self.url, &[], None) {
+                Ok(r) => r,
+                Err(e) => {
+                    message!("Error getting the upload offset: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = check_status(&response, 200, "getting the upload offset") {
+                if !is_transient(&e) {
+                    return Err(e);
+                }
+                continue;
+            }
+            let offset = response.offset()?;
+            if offset < start || offset > end {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("server has offset {}, outside of the data kept ({}..{})", offset, start, end),
+                ));
+            }
+            self.offset = offset;
+        }
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Send the rest of the image, returning the URL of the upload.
+    pub fn finish(mut self) -> std::io::Result<String> {
+        self.flush()?;
+        if self.offset != self.size {
+            return Err(std::io::Error::other(format!(
+                "upload is incomplete, {} bytes were sent out of {}",
+                self.offset, self.size,
+            )));
+        }
+        self.finished = true;
+        Ok(std::mem::take(&mut self.url))
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // The server already has the start of a resumed upload
+        if self.position < self.offset {
+            let length = (self.offset - self.position).min(buf.len() as u64);
+            self.position += length;
+            return Ok(length as usize);
+        }
+        let length = (self.chunk_size - self.chunk.len()).min(buf.len());
+        self.chunk.extend_from_slice(&buf[..length]);
+        self.position += length as u64;
+        if self.chunk.len() == self.chunk_size {
+            self.send_chunk()?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.chunk.is_empty() {
+            self.send_chunk()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // The upload stays on the server, so it can be resumed
+        if !self.finished {
+            message!(
+                "Upload {} is incomplete, the server has {} bytes out of {}; resume it with --upload tus+{} --tus-resume",
+                self.url, self.offset, self.size, self.url,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    /// Upload stored by the fake server, and how it misbehaves.
+    #[derive(Default)]
+    struct Server {
+        length: u64,
+        data: Vec<u8>,
+        /// PATCH requests that only store half of their data and fail
+        failing_patches: usize,
+        /// Method and Upload-Offset of the requests received
+        requests: Vec<(String, Option<u64>)>,
+    }
+
+    fn has_curl() -> bool {
+        Command::new("curl").arg("--version").output().is_ok()
+    }
+
+    fn respond(stream: &mut TcpStream, status: &str, headers: &[String]) {
+        let mut response = format!("HTTP/1.1 {}\r\nTus-Resumable: 1.0.0\r\n", status);
+        for header in headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+        stream.write_all(response.as_bytes()).unwrap();
+    }
+
+    fn handle(stream: TcpStream, server: &Mutex<Server>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let method = line.split_whitespace().next().unwrap_or_default().to_owned();
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.push((name.to_ascii_lowercase(), value.trim().to_owned())),
+                None => break,
+            }
+        }
+        let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        let offset = header("upload-offset").map(|o| o.parse().unwrap());
+        let length = header("content-length").map_or(0, |l| l.parse().unwrap());
+        if header("expect").is_some() {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut server = server.lock().unwrap();
+        server.requests.push((method.clone(), offset));
+        match method.as_str() {
+            "POST" => {
+                server.length = header("upload-length").unwrap().parse().unwrap();
+                respond(&mut stream, "201 Created", &["Location: /files/1".to_owned()]);
+            }
+            "HEAD" => {
+                let headers = [
+                    format!("Upload-Offset: {}", server.data.len()),
+                    format!("Upload-Length: {}", server.length),
+                ];
+                respond(&mut stream, "200 OK", &headers);
+            }
+            "PATCH" if offset != Some(server.data.len() as u64) => respond(&mut stream, "409 Conflict", &[]),
+            "PATCH" if server.failing_patches > 0 => {
+                server.failing_patches -= 1;
+                server.data.extend_from_slice(&body[..body.len() / 2]);
+                respond(&mut stream, "500 Internal Server Error", &[]);
+            }
+            "PATCH" => {
+                server.data.extend_from_slice(&body);
+                respond(&mut stream, "204 No Content", &[format!("Upload-Offset: {}", server.data.len())]);
+            }
+            _ => respond(&mut stream, "405 Method Not Allowed", &[]),
+        }
+    }
+
+    /// Start the fake server, returning its endpoint.
+    fn serve(server: Server) -> (String, Arc<Mutex<Server>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/files/", listener.local_addr().unwrap());
+        let server = Arc::new(Mutex::new(server));
+        let shared = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                handle(stream.unwrap(), &shared);
+            }
+        });
+        (endpoint, server)
+    }
+
+    fn image(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn upload(mut upload: Upload, data: &[u8]) -> std::io::Result<String> {
+        upload.chunk_size = 1000;
+        upload.retry_delay = Duration::from_millis(1);
+        upload.write_all(data)?;
+        upload.finish()
+    }
+
+    fn patches(server: &Server) -> Vec<u64> {
+        server.requests.iter().filter(|(m, _)| m == "PATCH").map(|(_, o)| o.unwrap()).collect()
+    }
+
+    #[test]
+    fn retry_from_offset() {
+        if !has_curl() {
+            return;
+        }
+        let data = image(3500);
+        let (endpoint, server) = serve(Server::default());
+
+        // The first request only stores half of its data, then fails
+        let started = Upload::start(&endpoint, data.len() as u64).unwrap();
+        server.lock().unwrap().failing_patches = 1;
+        let url = upload(started, &data).unwrap();
+        assert_eq!(url, format!("{}1", endpoint));
+        let server = server.lock().unwrap();
+        assert_eq!(server.data, data);
+        assert_eq!(patches(&server), [0, 500, 1000, 2000, 3000]);
+        assert!(server.requests.iter().any(|(m, _)| m == "HEAD"));
+    }
+
+    #[test]
+    fn give_up() {
+        if !has_curl() {
+            return;
+        }
+        let data = image(3500);
+        let (endpoint, server) = serve(Server { failing_patches: usize::MAX, ..Server::default() });
+        let started = Upload::start(&endpoint, data.len() as u64).unwrap();
+        assert!(upload(started, &data).is_err());
+
+        // The upload is left on the server to be resumed
+        let server = server.lock().unwrap();
+        assert_eq!(patches(&server).len(), MAX_ATTEMPTS as usize);
+        assert!(server.requests.iter().all(|(m, _)| m != "DELETE"));
+    }
+
+    #[test]
+    fn resume() {
+        if !has_curl() {
+            return;
+        }
+        let data = image(3500);
+        let (endpoint, server) = serve(Server {
+            length: data.len() as u64,
+            data: data[..1700].to_vec(),
+            ..Server::default()
+        });
+        let url = format!("{}1", endpoint);
+
+        // The image has to have the length of the upload
+        let error = Upload::resume(&url, 4000).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // Only the data after the offset of the server is sent
+        let resumed = Upload::resume(&url, data.len() as u64).unwrap();
+        assert_eq!(upload(resumed, &data).unwrap(), url);
+        let server = server.lock().unwrap();
+        assert_eq!(server.data, data);
+        assert_eq!(patches(&server), [1700, 2700]);
+    }
+}
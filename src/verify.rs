@@ -0,0 +1,160 @@
+//! Read-back verification of a written image (`--verify-after-write`).
+//!
+//! The image is read again from storage: its metadata is checked like with the
+//! check subcommand, and each data cluster has to be mapped where it was
+//! written and have the hash recorded as it was, so data corrupted on its way
+//! to the disk is caught before the image is used.
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use crate::buffer;
+use crate::check;
+use crate::manifest::ClusterHash;
+use crate::progress;
+use crate::qcow2::reader::{Cluster, Reader};
+use crate::qcow2::CLUSTER_SIZE;
+use crate::signals;
+
+fn corrupted(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Verify the image written to this file, with this virtual size, whose data
+/// clusters were written with these hashes.
+pub fn verify(file: &File, virtual_size: u64, hashes: &[ClusterHash]) -> std::io::Result<()> {
+    progress::set_phase("Checking image");
+    drop_cache(file)?;
+    let report = check::check(BufReader::new(file))?;
+    if let Some(error) = report.errors.first() {
+        return Err(corrupted(format!("the image is corrupted: {}", error)));
+    }
+    if report.leaked_clusters > 0 {
+        return Err(corrupted(format!("the image has {} leaked clusters", report.leaked_clusters)));
+    }
+
+    // The backing file, if any, is not needed to read our own clusters
+    let mut reader = Reader::new(BufReader::new(file))?;
+    if reader.size() != virtual_size {
+        return Err(corrupted(format!(
+            "the image has a virtual size of {} bytes instead of {}",
+            reader.size(),
+            virtual_size,
+        )));
+    }
+    let mut data_clusters = 0;
+    for i in 0..virtual_size.div_ceil(CLUSTER_SIZE) {
+        if matches!(reader.cluster(i)?, Cluster::Data(_)) {
+            data_clusters += 1;
+        }
+    }
+    if data_clusters != hashes.len() {
+        return Err(corrupted(format!(
+            "the image has {} data clusters, {} were written",
+            data_clusters,
+            hashes.len(),
+        )));
+    }
+
+    // Data clusters are in order in the file, read them in large requests
+    progress::start_copy("Verifying data", hashes.len() as u64 * CLUSTER_SIZE);
+    let mut data = BufReader::with_capacity(buffer::size(), file);
+    let mut position = data.seek(SeekFrom::Start(0))?;
+    let mut cluster = vec![0u8; CLUSTER_SIZE as usize];
+    for hash in hashes {
+        signals::check_cancelled()?;
+        if reader.cluster(hash.guest / CLUSTER_SIZE)? != Cluster::Data(hash.host) {
+            return Err(corrupted(format!(
+                "the cluster at offset {} of the disk is not mapped where it was written",
+                hash.guest,
+            )));
+        }
+        if hash.host != position {
+            position = data.seek(SeekFrom::Start(hash.host))?;
+        }
+        data.read_exact(&mut cluster)?;
+        position += CLUSTER_SIZE;
+        if Sha256::digest(&cluster)[..] != hash.sha256 {
+            return Err(corrupted(format!(
+                "the cluster at offset {} of the disk (offset {} of the image) differs from the data written",
+                hash.guest,
+                hash.host,
+            )));
+        }
+        progress::add_copied(CLUSTER_SIZE);
+    }
+    Ok(())
+}
+
+/// Flush the file to disk and drop it from the page cache, so it is read back
+/// from the storage rather than from memory.
+#[cfg(target_os = "linux")]
+fn drop_cache(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    file.sync_data()?;
+    nix::fcntl::posix_fadvise(file.as_raw_fd(), 0, 0, nix::fcntl::PosixFadviseAdvice::POSIX_FADV_DONTNEED)?;
+    Ok(())
+}
+
+/// Flush the file to disk; other systems don't let us drop it from the cache.
+#[cfg(not(target_os = "linux"))]
+fn drop_cache(file: &File) -> std::io::Result<()> {
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::qcow2::StreamingQcow2Writer;
+    use std::io::{Cursor, Write};
+
+    /// Flip a bit of a byte of the file.
+    fn flip(file: &mut File, offset: u64) -> std::io::Result<()> {
+        let mut byte = [0u8];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut byte)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&[byte[0] ^ 0x10])
+    }
+
+    #[test]
+    fn corruption() {
+        let size = 20 * CLUSTER_SIZE;
+        let disk: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+        let ranges = [0..2 * CLUSTER_SIZE, 5 * CLUSTER_SIZE..6 * CLUSTER_SIZE];
+        let writer = StreamingQcow2Writer::new(size, ranges.iter().cloned()).unwrap();
+        let mut manifest = Manifest::in_memory();
+        let mut image = Vec::new();
+        writer.write_header(&mut image).unwrap();
+        writer.copy_data(Cursor::new(&disk), &mut image, Some(&mut manifest)).unwrap();
+        let hashes = manifest.finish().unwrap();
+        assert_eq!(hashes.len(), 3);
+
+        let path = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}.verify.qcow2", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        verify(&file, size, &hashes).unwrap();
+
+        // A byte of the last data cluster
+        let data = hashes[2].host + 1000;
+        flip(&mut file, data).unwrap();
+        let error = verify(&file, size, &hashes).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        flip(&mut file, data).unwrap();
+        verify(&file, size, &hashes).unwrap();
+
+        // The offset in the L2 entry of the first cluster, found from the L1
+        // table
+        let l1_offset = u64::from_be_bytes(image[40..48].try_into().unwrap()) as usize;
+        let l2_offset = u64::from_be_bytes(image[l1_offset..l1_offset + 8].try_into().unwrap()) & !(1 << 63);
+        flip(&mut file, l2_offset + 5).unwrap();
+        let error = verify(&file, size, &hashes).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
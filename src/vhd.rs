@@ -0,0 +1,298 @@
+//! Reader for Microsoft's VHD format, fixed and dynamic disks.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::input::seek_position;
+
+const SECTOR_SIZE: u64 = 512;
+
+const FOOTER_COOKIE: &[u8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8] = b"cxsparse";
+
+const DISK_TYPE_FIXED: u32 = 2;
+const DISK_TYPE_DYNAMIC: u32 = 3;
+const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+const BAT_UNUSED: u32 = 0xFFFF_FFFF;
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Check whether a footer (the last 512 bytes of a file) is a VHD footer.
+pub fn is_vhd_footer(footer: &[u8]) -> bool {
+    footer.len() >= 8 && &footer[0..8] == FOOTER_COOKIE
+}
+
+/// Reads the virtual disk of a VHD file.
+pub struct Reader<R: Read + Seek> {
+    inner: R,
+    size: u64,
+    /// Block size for dynamic disks, `None` for fixed disks
+    block_size: Option<u64>,
+    /// Sector offset of each block
+    bat: Vec<u32>,
+    /// Size of the sector bitmap that precedes each block's data
+    bitmap_size: u64,
+    position: u64,
+    /// Sector bitmap of the last block read
+    cache: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+        let file_size = inner.seek(SeekFrom::End(0))?;
+        let mut footer = [0u8; 512];
+        inner.seek(SeekFrom::End(-512))?;
+        inner.read_exact(&mut footer)?;
+        if !is_vhd_footer(&footer) {
+            // Footers of dynamic disks are also copied at the start
+            inner.seek(SeekFrom::Start(0))?;
+            inner.read_exact(&mut footer)?;
+            if !is_vhd_footer(&footer) {
+                return Err(invalid("not a VHD file"));
+            }
+        }
+        let data_offset = BigEndian::read_u64(&footer[16..24]);
+        let size = BigEndian::read_u64(&footer[48..56]);
+        let disk_type = BigEndian::read_u32(&footer[60..64]);
+
+        match disk_type {
+            DISK_TYPE_FIXED => {
+                // The data is followed by the footer
+                if size > file_size - 512 {
+                    return Err(invalid("VHD file is smaller than its disk"));
+                }
+                return Ok(Reader {
+                    inner,
+                    size,
+                    block_size: None,
+                    bat: Vec::new(),
+                    bitmap_size: 0,
+                    position: 0,
+                    cache: None,
+                });
+            }
+            DISK_TYPE_DYNAMIC => {}
+            DISK_TYPE_DIFFERENCING => {
+                return Err(invalid("differencing VHD files are not supported"));
+            }
+            _ => return Err(invalid("unknown VHD disk type")),
+        }
+
+        // Dynamic disk header
+        let mut header = [0u8; 1024];
+        inner.seek(SeekFrom::Start(data_offset))?;
+        inner.read_exact(&mut header)?;
+        if &header[0..8] != DYNAMIC_COOKIE {
+            return Err(invalid("invalid VHD dynamic disk header"));
+        }
+        let table_offset = BigEndian::read_u64(&header[16..24]);
+        let max_entries = BigEndian::read_u32(&header[28..32]) as u64;
+        let block_size = BigEndian::read_u32(&header[32..36]) as u64;
+        if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE) {
+            return Err(invalid("invalid VHD block size"));
+        }
+        let num_blocks = size.div_ceil(block_size);
+        if max_entries < num_blocks {
+            return Err(invalid("VHD block allocation table is too small"));
+        }
+
+        // Check the table is within the file before allocating it, so a
+        // corrupt size doesn't make us allocate more than the file holds
+        if table_offset.checked_add(max_entries * 4).is_none_or(|end| end > file_size) {
+            return Err(invalid("VHD block allocation table is past the end of the file"));
+        }
+        let mut table = vec![0u8; num_blocks as usize * 4];
+        inner.seek(SeekFrom::Start(table_offset))?;
+        inner.read_exact(&mut table)?;
+        let bat = table.chunks_exact(4).map(BigEndian::read_u32).collect();
+
+        let sectors_per_block = block_size / SECTOR_SIZE;
+        let bitmap_size = sectors_per_block.div_ceil(8).next_multiple_of(SECTOR_SIZE);
+
+        Ok(Reader {
+            inner,
+            size,
+            block_size: Some(block_size),
+            bat,
+            bitmap_size,
+            position: 0,
+            cache: None,
+        })
+    }
+
+    /// Size of the virtual disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Ranges of the virtual disk backed by allocated blocks.
+    pub fn data_extents(&self) -> Vec<Range<u64>> {
+        let Some(block_size) = self.block_size else {
+            return std::iter::once(0..self.size).collect();
+        };
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        for (i, &entry) in self.bat.iter().enumerate() {
+            if entry == BAT_UNUSED {
+                continue;
+            }
+            let start = i as u64 * block_size;
+            let end = (start + block_size).min(self.size);
+            match extents.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => extents.push(start..end),
+            }
+        }
+        extents
+    }
+
+    fn sector_bitmap(&mut self, index: u64, block_offset: u64) -> std::io::Result<&[u8]> {
+        if self.cache.as_ref().is_none_or(|(i, _)| *i != index) {
+            let mut bitmap = vec![0u8; self.bitmap_size as usize];
+            self.inner.seek(SeekFrom::Start(block_offset))?;
+            self.inner.read_exact(&mut bitmap)?;
+            self.cache = Some((index, bitmap));
+        }
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let Some(block_size) = self.block_size else {
+            let length = (buf.len() as u64).min(self.size - self.position) as usize;
+            self.inner.seek(SeekFrom::Start(self.position))?;
+            let n = self.inner.read(&mut buf[..length])?;
+            self.position += n as u64;
+            return Ok(n);
+        };
+
+        let index = self.position / block_size;
+        let offset_in_block = self.position % block_size;
+        let mut length = (buf.len() as u64)
+            .min(block_size - offset_in_block)
+            .min(self.size - self.position);
+
+        let entry = self.bat[index as usize];
+        if entry == BAT_UNUSED {
+            buf[..length as usize].fill(0);
+        } else {
+            // Presence is tracked per sector, read a run of sectors that are
+            // all present or all absent
+            let block_offset = entry as u64 * SECTOR_SIZE;
+            let bitmap_size = self.bitmap_size;
+            let bitmap = self.sector_bitmap(index, block_offset)?;
+            let is_present = |sector: u64| bitmap[sector as usize / 8] & (0x80 >> (sector % 8)) != 0;
+            let first = offset_in_block / SECTOR_SIZE;
+            let present = is_present(first);
+            let mut end = (first + 1) * SECTOR_SIZE;
+            while end < offset_in_block + length && is_present(end / SECTOR_SIZE) == present {
+                end += SECTOR_SIZE;
+            }
+            length = length.min(end - offset_in_block);
+            let buf = &mut buf[..length as usize];
+            if present {
+                self.inner.seek(SeekFrom::Start(block_offset + bitmap_size + offset_in_block))?;
+                self.inner.read_exact(buf)?;
+            } else {
+                buf.fill(0);
+            }
+        }
+        self.position += length;
+        Ok(length as usize)
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    fn footer(disk_type: u32, data_offset: u64, size: u64) -> Vec<u8> {
+        let mut footer = vec![0u8; 512];
+        footer[0..8].copy_from_slice(FOOTER_COOKIE);
+        (&mut footer[16..24]).write_u64::<BigEndian>(data_offset).unwrap();
+        (&mut footer[48..56]).write_u64::<BigEndian>(size).unwrap();
+        (&mut footer[60..64]).write_u32::<BigEndian>(disk_type).unwrap();
+        footer
+    }
+
+    /// A dynamic disk of 3 blocks of 4 KiB with a BAT of `max_entries`:
+    /// only block 0 allocated, its first 4 sectors present.
+    fn dynamic(disk_type: u32, max_entries: u32) -> Vec<u8> {
+        let mut image = footer(disk_type, 512, 3 * 4096);
+        let mut header = vec![0u8; 1024];
+        header[0..8].copy_from_slice(DYNAMIC_COOKIE);
+        (&mut header[16..24]).write_u64::<BigEndian>(1536).unwrap();
+        (&mut header[28..32]).write_u32::<BigEndian>(max_entries).unwrap();
+        (&mut header[32..36]).write_u32::<BigEndian>(4096).unwrap();
+        image.extend_from_slice(&header);
+        // BAT, block 0 at sector 4
+        for entry in [4, BAT_UNUSED, BAT_UNUSED, BAT_UNUSED] {
+            image.write_u32::<BigEndian>(entry).unwrap();
+        }
+        image.resize(2048, 0xFF);
+        // Sector bitmap, then the data
+        let mut bitmap = vec![0u8; 512];
+        bitmap[0] = 0xF0;
+        image.extend_from_slice(&bitmap);
+        image.extend_from_slice(&[0x33; 4096]);
+        image.extend_from_slice(&footer(disk_type, 512, 3 * 4096));
+        image
+    }
+
+    #[test]
+    fn fixed() {
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let image = [&data[..], &footer(DISK_TYPE_FIXED, u64::MAX, 8192)].concat();
+        let mut reader = Reader::new(Cursor::new(image)).unwrap();
+        assert_eq!(reader.size(), 8192);
+        assert_eq!(reader.data_extents(), vec![0..8192]);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert!(read == data);
+
+        // A disk larger than the file
+        let image = [&data[..], &footer(DISK_TYPE_FIXED, u64::MAX, 1 << 40)].concat();
+        assert_eq!(Reader::new(Cursor::new(image)).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dynamic_disk() {
+        let mut reader = Reader::new(Cursor::new(dynamic(DISK_TYPE_DYNAMIC, 4))).unwrap();
+        assert_eq!(reader.size(), 3 * 4096);
+        assert_eq!(reader.data_extents(), vec![0..4096]);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        let mut expected = vec![0u8; 3 * 4096];
+        expected[..2048].fill(0x33);
+        assert!(read == expected);
+    }
+
+    #[test]
+    fn differencing() {
+        let err = Reader::new(Cursor::new(dynamic(DISK_TYPE_DIFFERENCING, 4))).err().unwrap();
+        assert_eq!(err.to_string(), "differencing VHD files are not supported");
+    }
+
+    #[test]
+    fn bat_past_end_of_file() {
+        // A BAT of 16 GiB, in a file of 7 KiB
+        let err = Reader::new(Cursor::new(dynamic(DISK_TYPE_DYNAMIC, u32::MAX))).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
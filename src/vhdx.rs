@@ -0,0 +1,318 @@
+//! Reader for Microsoft's VHDX format, fixed and dynamic disks.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::input::seek_position;
+
+const MIB: u64 = 1 << 20;
+
+const HEADER_OFFSETS: [u64; 2] = [64 << 10, 128 << 10];
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 << 10, 256 << 10];
+
+/// GUIDs, in their on-disk (mixed-endian) byte order
+const BAT_REGION: [u8; 16] = guid(0x2DC27766, 0xF623, 0x4200, [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08]);
+const METADATA_REGION: [u8; 16] = guid(0x8B7CA206, 0x4790, 0x4B9A, [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E]);
+const FILE_PARAMETERS: [u8; 16] = guid(0xCAA16737, 0xFA36, 0x4D43, [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B]);
+const VIRTUAL_DISK_SIZE: [u8; 16] = guid(0x2FA54224, 0xCD1B, 0x4876, [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8]);
+const LOGICAL_SECTOR_SIZE: [u8; 16] = guid(0x8141BF1D, 0xA96F, 0x4709, [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F]);
+
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+        d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7],
+    ]
+}
+
+const FLAG_HAS_PARENT: u32 = 2;
+
+/// Payload block states that hold data; others read as zeros
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Check whether the start of a file is a VHDX file identifier.
+pub fn is_vhdx(header: &[u8]) -> bool {
+    header.len() >= 8 && &header[0..8] == b"vhdxfile"
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buf)
+}
+
+/// Reads the virtual disk of a VHDX file.
+pub struct Reader<R: Read + Seek> {
+    inner: R,
+    size: u64,
+    block_size: u64,
+    /// File offset of each payload block, or `None` if it reads as zeros
+    blocks: Vec<Option<u64>>,
+    position: u64,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+        let mut buffer = [0u8; 8];
+        read_at(&mut inner, 0, &mut buffer)?;
+        if !is_vhdx(&buffer) {
+            return Err(invalid("not a VHDX file"));
+        }
+
+        // Use the current header, the one with the highest sequence number
+        let mut current = None;
+        for offset in HEADER_OFFSETS {
+            let mut header = [0u8; 4096];
+            read_at(&mut inner, offset, &mut header)?;
+            if &header[0..4] != b"head" {
+                continue;
+            }
+            let sequence = LittleEndian::read_u64(&header[8..16]);
+            if current.as_ref().is_none_or(|(s, _)| sequence > *s) {
+                current = Some((sequence, header));
+            }
+        }
+        let Some((_, header)) = current else {
+            return Err(invalid("no valid VHDX header"));
+        };
+        // A non-zero log GUID means the log has to be replayed
+        if header[48..64].iter().any(|&b| b != 0) {
+            return Err(invalid("VHDX file has a pending log, open it with Hyper-V first"));
+        }
+
+        // Locate the BAT and metadata regions
+        let mut table = [0u8; 64 << 10];
+        let mut found = false;
+        for offset in REGION_TABLE_OFFSETS {
+            read_at(&mut inner, offset, &mut table)?;
+            if &table[0..4] == b"regi" {
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            return Err(invalid("no valid VHDX region table"));
+        }
+        let entry_count = LittleEndian::read_u32(&table[8..12]) as usize;
+        if entry_count > 2047 {
+            return Err(invalid("invalid VHDX region table"));
+        }
+        let mut bat_region = None;
+        let mut metadata_region = None;
+        for entry in table[16..16 + entry_count * 32].chunks_exact(32) {
+            let offset = LittleEndian::read_u64(&entry[16..24]);
+            let length = LittleEndian::read_u32(&entry[24..28]) as u64;
+            if entry[0..16] == BAT_REGION {
+                bat_region = Some((offset, length));
+            } else if entry[0..16] == METADATA_REGION {
+                metadata_region = Some((offset, length));
+            }
+        }
+        let (Some(bat_region), Some(metadata_region)) = (bat_region, metadata_region) else {
+            return Err(invalid("VHDX file is missing required regions"));
+        };
+        // The regions are read whole, they have to be within the file
+        let file_size = inner.seek(SeekFrom::End(0))?;
+        for (offset, length) in [bat_region, metadata_region] {
+            if offset.checked_add(length).is_none_or(|end| end > file_size) {
+                return Err(invalid("VHDX region is past the end of the file"));
+            }
+        }
+
+        // Read the metadata items we need
+        let mut metadata = vec![0u8; metadata_region.1 as usize];
+        read_at(&mut inner, metadata_region.0, &mut metadata)?;
+        if metadata.len() < 32 || &metadata[0..8] != b"metadata" {
+            return Err(invalid("invalid VHDX metadata table"));
+        }
+        let item_count = LittleEndian::read_u16(&metadata[10..12]) as usize;
+        if 32 + item_count * 32 > metadata.len() {
+            return Err(invalid("invalid VHDX metadata table"));
+        }
+        let item = |id: &[u8; 16], length: usize| -> std::io::Result<&[u8]> {
+            for entry in metadata[32..32 + item_count * 32].chunks_exact(32) {
+                if entry[0..16] == *id {
+                    let offset = LittleEndian::read_u32(&entry[16..20]) as usize;
+                    if offset + length > metadata.len() {
+                        break;
+                    }
+                    return Ok(&metadata[offset..offset + length]);
+                }
+            }
+            Err(invalid("VHDX file is missing required metadata"))
+        };
+        let file_parameters = item(&FILE_PARAMETERS, 8)?;
+        let block_size = LittleEndian::read_u32(&file_parameters[0..4]) as u64;
+        let flags = LittleEndian::read_u32(&file_parameters[4..8]);
+        let size = LittleEndian::read_u64(item(&VIRTUAL_DISK_SIZE, 8)?);
+        let sector_size = LittleEndian::read_u32(item(&LOGICAL_SECTOR_SIZE, 4)?) as u64;
+        if flags & FLAG_HAS_PARENT != 0 {
+            return Err(invalid("differencing VHDX files are not supported"));
+        }
+        if !block_size.is_power_of_two() || !(MIB..=256 * MIB).contains(&block_size)
+            || (sector_size != 512 && sector_size != 4096)
+        {
+            return Err(invalid("invalid VHDX block size"));
+        }
+
+        // The BAT interleaves an entry for a sector bitmap block after every
+        // chunk of payload blocks
+        let chunk_ratio = (1 << 23) * sector_size / block_size;
+        let num_blocks = size.div_ceil(block_size);
+        let num_entries = num_blocks + num_blocks.saturating_sub(1) / chunk_ratio;
+        if num_entries * 8 > bat_region.1 {
+            return Err(invalid("VHDX block allocation table is too small"));
+        }
+        let mut bat = vec![0u8; num_entries as usize * 8];
+        read_at(&mut inner, bat_region.0, &mut bat)?;
+        let mut blocks = Vec::with_capacity(num_blocks as usize);
+        for i in 0..num_blocks {
+            let index = (i + i / chunk_ratio) as usize;
+            let entry = LittleEndian::read_u64(&bat[index * 8..index * 8 + 8]);
+            match entry & 7 {
+                PAYLOAD_BLOCK_FULLY_PRESENT => blocks.push(Some((entry >> 20) * MIB)),
+                PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
+                    return Err(invalid("differencing VHDX files are not supported"));
+                }
+                _ => blocks.push(None),
+            }
+        }
+
+        Ok(Reader {
+            inner,
+            size,
+            block_size,
+            blocks,
+            position: 0,
+        })
+    }
+
+    /// Size of the virtual disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Ranges of the virtual disk backed by allocated blocks.
+    pub fn data_extents(&self) -> Vec<Range<u64>> {
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            if block.is_none() {
+                continue;
+            }
+            let start = i as u64 * self.block_size;
+            let end = (start + self.block_size).min(self.size);
+            match extents.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => extents.push(start..end),
+            }
+        }
+        extents
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let index = self.position / self.block_size;
+        let offset_in_block = self.position % self.block_size;
+        let length = (buf.len() as u64)
+            .min(self.block_size - offset_in_block)
+            .min(self.size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        match self.blocks[index as usize] {
+            Some(offset) => read_at(&mut self.inner, offset + offset_in_block, buf)?,
+            None => buf.fill(0),
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::Cursor;
+
+    /// A disk of 2 blocks of 1 MiB, its metadata at 1 MiB, its BAT at 2 MiB
+    /// and block 0 at 3 MiB, filled with 0x44.
+    fn image(flags: u32, bat_length: u32) -> Vec<u8> {
+        let mut image = vec![0u8; 4 * MIB as usize];
+        image[0..8].copy_from_slice(b"vhdxfile");
+        for (sequence, offset) in HEADER_OFFSETS.into_iter().enumerate() {
+            let header = &mut image[offset as usize..];
+            header[0..4].copy_from_slice(b"head");
+            (&mut header[8..16]).write_u64::<LittleEndian>(sequence as u64).unwrap();
+        }
+        let table = &mut image[REGION_TABLE_OFFSETS[0] as usize..];
+        table[0..4].copy_from_slice(b"regi");
+        (&mut table[8..12]).write_u32::<LittleEndian>(2).unwrap();
+        for (i, (guid, offset, length)) in [(BAT_REGION, 2 * MIB, bat_length), (METADATA_REGION, MIB, 64 << 10)].into_iter().enumerate() {
+            let entry = &mut table[16 + i * 32..];
+            entry[0..16].copy_from_slice(&guid);
+            (&mut entry[16..24]).write_u64::<LittleEndian>(offset).unwrap();
+            (&mut entry[24..28]).write_u32::<LittleEndian>(length).unwrap();
+        }
+
+        let metadata = &mut image[MIB as usize..];
+        metadata[0..8].copy_from_slice(b"metadata");
+        (&mut metadata[10..12]).write_u16::<LittleEndian>(3).unwrap();
+        let items: [(_, &[u8]); 3] = [
+            (FILE_PARAMETERS, &[&(MIB as u32).to_le_bytes()[..], &flags.to_le_bytes()].concat()),
+            (VIRTUAL_DISK_SIZE, &(2 * MIB).to_le_bytes()),
+            (LOGICAL_SECTOR_SIZE, &512u32.to_le_bytes()),
+        ];
+        for (i, (guid, value)) in items.into_iter().enumerate() {
+            let offset = 4096 + i * 8;
+            let entry = &mut metadata[32 + i * 32..];
+            entry[0..16].copy_from_slice(&guid);
+            (&mut entry[16..20]).write_u32::<LittleEndian>(offset as u32).unwrap();
+            metadata[offset..offset + value.len()].copy_from_slice(value);
+        }
+
+        (&mut image[2 * MIB as usize..]).write_u64::<LittleEndian>(3 << 20 | PAYLOAD_BLOCK_FULLY_PRESENT).unwrap();
+        image[3 * MIB as usize..].fill(0x44);
+        image
+    }
+
+    #[test]
+    fn dynamic_disk() {
+        let mut reader = Reader::new(Cursor::new(image(0, MIB as u32))).unwrap();
+        assert_eq!(reader.size(), 2 * MIB);
+        assert_eq!(reader.data_extents(), vec![0..MIB]);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert!(read[..MIB as usize].iter().all(|&b| b == 0x44));
+        assert!(read[MIB as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn differencing() {
+        let err = Reader::new(Cursor::new(image(FLAG_HAS_PARENT, MIB as u32))).err().unwrap();
+        assert_eq!(err.to_string(), "differencing VHDX files are not supported");
+    }
+
+    #[test]
+    fn region_past_end_of_file() {
+        // A BAT region of 4 GiB, in a file of 4 MiB
+        let err = Reader::new(Cursor::new(image(0, u32::MAX))).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
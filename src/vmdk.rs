@@ -0,0 +1,360 @@
+//! Reader for VMware's sparse VMDK format, in its monolithicSparse and
+//! streamOptimized (compressed grains) variants.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::input::seek_position;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// "KDMV"
+const MAGIC: u32 = 0x564d_444b;
+
+const FLAG_COMPRESSED: u32 = 1 << 16;
+
+const COMPRESSION_DEFLATE: u16 = 1;
+
+/// Grain directory offset meaning the directory is found through the footer
+const GD_AT_END: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+
+/// Grain table entry for grains that read as zeros
+const GRAIN_ZERO: u32 = 1;
+
+/// Check whether the start of a file is a sparse VMDK header.
+pub fn is_vmdk(header: &[u8]) -> bool {
+    header.len() >= 4 && LittleEndian::read_u32(&header[0..4]) == MAGIC
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+struct Header {
+    flags: u32,
+    capacity: u64,
+    grain_size: u64,
+    gtes_per_gt: u64,
+    gd_offset: u64,
+    compress_algorithm: u16,
+}
+
+fn parse_header(header: &[u8; 512]) -> std::io::Result<Header> {
+    if !is_vmdk(header) {
+        return Err(invalid("not a sparse VMDK file"));
+    }
+    Ok(Header {
+        flags: LittleEndian::read_u32(&header[8..12]),
+        capacity: LittleEndian::read_u64(&header[12..20]),
+        grain_size: LittleEndian::read_u64(&header[20..28]),
+        gtes_per_gt: LittleEndian::read_u32(&header[44..48]) as u64,
+        gd_offset: LittleEndian::read_u64(&header[56..64]),
+        compress_algorithm: LittleEndian::read_u16(&header[77..79]),
+    })
+}
+
+/// Read a table of little-endian 32-bit entries, such as the grain
+/// directory or a grain table, at a sector offset.
+///
+/// The table has to be within the file, so a corrupt count doesn't make us
+/// allocate more memory than the file could hold.
+fn read_table<R: Read + Seek>(reader: &mut R, sector: u64, entries: u64) -> std::io::Result<Vec<u32>> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let offset = sector.checked_mul(SECTOR_SIZE);
+    let end = entries.checked_mul(4).zip(offset).and_then(|(len, offset)| len.checked_add(offset));
+    let (Some(offset), Some(end)) = (offset, end) else {
+        return Err(invalid("VMDK table offset is too large"));
+    };
+    if end > file_size {
+        return Err(invalid("VMDK table is past the end of the file"));
+    }
+    let mut table = vec![0u8; entries as usize * 4];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut table)?;
+    Ok(table.chunks_exact(4).map(LittleEndian::read_u32).collect())
+}
+
+/// Reads the virtual disk of a sparse VMDK.
+pub struct Reader<R: Read + Seek> {
+    inner: R,
+    size: u64,
+    grain_size: u64,
+    gtes_per_gt: u64,
+    compressed: bool,
+    /// Sector offset of each grain table, 0 if unallocated
+    directory: Vec<u32>,
+    /// Last grain table read, by directory index, holding the sector offset
+    /// of each grain, or 0 (unallocated) or 1 (zero); tables are read as
+    /// they are needed rather than mapping the whole disk upfront
+    table_cache: Option<(u64, Vec<u32>)>,
+    position: u64,
+    /// Last decompressed grain
+    cache: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+        let mut buffer = [0u8; 512];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut buffer)?;
+        let mut header = parse_header(&buffer)?;
+
+        // The grain directory of streamOptimized files is found through the
+        // footer, a copy of the header near the end of the file
+        if header.gd_offset == GD_AT_END {
+            inner.seek(SeekFrom::End(-1024))?;
+            inner.read_exact(&mut buffer)?;
+            header = parse_header(&buffer)?;
+            if header.gd_offset == GD_AT_END {
+                return Err(invalid("VMDK footer has no grain directory"));
+            }
+        }
+
+        let compressed = header.flags & FLAG_COMPRESSED != 0;
+        if compressed && header.compress_algorithm != COMPRESSION_DEFLATE {
+            return Err(invalid("unsupported VMDK compression algorithm"));
+        }
+        if header.grain_size == 0 || !header.grain_size.is_power_of_two()
+            || header.grain_size > 2048 || header.gtes_per_gt == 0
+        {
+            return Err(invalid("invalid VMDK grain size"));
+        }
+
+        let grain_size = header.grain_size * SECTOR_SIZE;
+        let size = header.capacity.checked_mul(SECTOR_SIZE)
+            .ok_or_else(|| invalid("invalid VMDK capacity"))?;
+        let num_grains = size.div_ceil(grain_size);
+        let num_tables = num_grains.div_ceil(header.gtes_per_gt);
+
+        let directory = read_table(&mut inner, header.gd_offset, num_tables)?;
+
+        Ok(Reader {
+            inner,
+            size,
+            grain_size,
+            gtes_per_gt: header.gtes_per_gt,
+            compressed,
+            directory,
+            table_cache: None,
+            position: 0,
+            cache: None,
+        })
+    }
+
+    /// Size of the virtual disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Sector offset of a grain, or 0 (unallocated) or 1 (zero).
+    fn grain(&mut self, index: u64) -> std::io::Result<u32> {
+        let table_index = index / self.gtes_per_gt;
+        let table_offset = self.directory.get(table_index as usize).copied().unwrap_or(0);
+        if table_offset == 0 {
+            return Ok(0);
+        }
+        if self.table_cache.as_ref().is_none_or(|(i, _)| *i != table_index) {
+            let table = read_table(&mut self.inner, table_offset as u64, self.gtes_per_gt)?;
+            self.table_cache = Some((table_index, table));
+        }
+        let table = &self.table_cache.as_ref().unwrap().1;
+        Ok(table[(index % self.gtes_per_gt) as usize])
+    }
+
+    /// Ranges of the virtual disk backed by allocated grains.
+    pub fn data_extents(&mut self) -> std::io::Result<Vec<Range<u64>>> {
+        let num_grains = self.size.div_ceil(self.grain_size);
+        let mut extents: Vec<Range<u64>> = Vec::new();
+        for table_index in 0..self.directory.len() as u64 {
+            // Skip unallocated tables whole, they can map a lot of grains
+            if self.directory[table_index as usize] == 0 {
+                continue;
+            }
+            let first = table_index * self.gtes_per_gt;
+            for i in first..(first + self.gtes_per_gt).min(num_grains) {
+                if self.grain(i)? <= GRAIN_ZERO {
+                    continue;
+                }
+                let start = i * self.grain_size;
+                let end = (start + self.grain_size).min(self.size);
+                match extents.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => extents.push(start..end),
+                }
+            }
+        }
+        Ok(extents)
+    }
+
+    fn read_compressed_grain(&mut self, index: u64, sector: u64) -> std::io::Result<&[u8]> {
+        if self.cache.as_ref().is_none_or(|(i, _)| *i != index) {
+            // Grain marker: LBA (u64), compressed size (u32), then data
+            let mut marker = [0u8; 12];
+            self.inner.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            self.inner.read_exact(&mut marker)?;
+            let compressed_size = LittleEndian::read_u32(&marker[8..12]) as u64;
+            if compressed_size > self.grain_size * 2 {
+                return Err(invalid("invalid VMDK compressed grain"));
+            }
+            let mut data = Vec::with_capacity(self.grain_size as usize);
+            let compressed = (&mut self.inner).take(compressed_size);
+            flate2::read::ZlibDecoder::new(compressed)
+                .take(self.grain_size)
+                .read_to_end(&mut data)?;
+            data.resize(self.grain_size as usize, 0);
+            self.cache = Some((index, data));
+        }
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let index = self.position / self.grain_size;
+        let offset_in_grain = self.position % self.grain_size;
+        let length = (buf.len() as u64)
+            .min(self.grain_size - offset_in_grain)
+            .min(self.size - self.position) as usize;
+        let buf = &mut buf[..length];
+
+        let grain = self.grain(index)? as u64;
+        if grain <= GRAIN_ZERO as u64 {
+            buf.fill(0);
+        } else if self.compressed {
+            let data = self.read_compressed_grain(index, grain)?;
+            buf.copy_from_slice(&data[offset_in_grain as usize..offset_in_grain as usize + length]);
+        } else {
+            self.inner.seek(SeekFrom::Start(grain * SECTOR_SIZE + offset_in_grain))?;
+            self.inner.read_exact(buf)?;
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::{Cursor, Write};
+
+    const GRAIN: usize = 4096;
+
+    /// A header with grains of 4 KiB and grain tables of 4 entries.
+    fn header(flags: u32, capacity: u64, gtes_per_gt: u32, gd_offset: u64) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        (&mut header[0..4]).write_u32::<LittleEndian>(MAGIC).unwrap();
+        (&mut header[4..8]).write_u32::<LittleEndian>(1).unwrap();
+        (&mut header[8..12]).write_u32::<LittleEndian>(flags).unwrap();
+        (&mut header[12..20]).write_u64::<LittleEndian>(capacity).unwrap();
+        (&mut header[20..28]).write_u64::<LittleEndian>(8).unwrap();
+        (&mut header[44..48]).write_u32::<LittleEndian>(gtes_per_gt).unwrap();
+        (&mut header[56..64]).write_u64::<LittleEndian>(gd_offset).unwrap();
+        if flags & FLAG_COMPRESSED != 0 {
+            (&mut header[77..79]).write_u16::<LittleEndian>(COMPRESSION_DEFLATE).unwrap();
+        }
+        header
+    }
+
+    fn write_u32s(image: &mut [u8], sector: usize, entries: &[u32]) {
+        let mut table = &mut image[sector * 512..];
+        for &entry in entries {
+            table.write_u32::<LittleEndian>(entry).unwrap();
+        }
+    }
+
+    /// A monolithicSparse image of 8 grains, 7.5 of them in the virtual
+    /// disk: grain 0 and 3 allocated, grain 2 zero, the second grain table
+    /// unallocated.
+    fn sparse(capacity: u64, gtes_per_gt: u32) -> Vec<u8> {
+        let mut image = header(0, capacity, gtes_per_gt, 1);
+        image.resize(24 * 512, 0);
+        write_u32s(&mut image, 1, &[2, 0]);
+        write_u32s(&mut image, 2, &[8, 0, GRAIN_ZERO, 16]);
+        image[8 * 512..16 * 512].fill(0x11);
+        image[16 * 512..24 * 512].fill(0x22);
+        image
+    }
+
+    #[test]
+    fn sparse_image() {
+        let mut reader = Reader::new(Cursor::new(sparse(60, 4))).unwrap();
+        assert_eq!(reader.size(), 60 * 512);
+        assert_eq!(reader.data_extents().unwrap(), vec![0..GRAIN as u64, 3 * GRAIN as u64..4 * GRAIN as u64]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        let mut expected = vec![0u8; 60 * 512];
+        expected[..GRAIN].fill(0x11);
+        expected[3 * GRAIN..4 * GRAIN].fill(0x22);
+        assert!(data == expected);
+
+        // Reads within a grain
+        let mut data = [0u8; 100];
+        reader.seek(SeekFrom::Start(4 * GRAIN as u64 - 50)).unwrap();
+        assert_eq!(reader.read(&mut data).unwrap(), 50);
+        assert!(data[..50] == [0x22; 50]);
+    }
+
+    #[test]
+    fn stream_optimized_image() {
+        let grain: Vec<u8> = (0..GRAIN).map(|i| (i % 251) as u8).collect();
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&grain).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        // Header, the grain of LBA 8 at sector 2, then the grain directory,
+        // the grain table, the footer and the end-of-stream marker
+        let mut image = header(FLAG_COMPRESSED, 32, 4, GD_AT_END);
+        image.resize(2 * 512, 0);
+        image.write_u64::<LittleEndian>(8).unwrap();
+        image.write_u32::<LittleEndian>(compressed.len() as u32).unwrap();
+        image.extend_from_slice(&compressed);
+        image.resize(image.len().next_multiple_of(512), 0);
+        let directory = image.len() / 512;
+        image.resize(image.len() + 2 * 512, 0);
+        write_u32s(&mut image, directory, &[directory as u32 + 1]);
+        write_u32s(&mut image, directory + 1, &[0, 2, 0, 0]);
+        image.extend_from_slice(&header(FLAG_COMPRESSED, 32, 4, directory as u64));
+        image.extend_from_slice(&[0; 512]);
+
+        let mut reader = Reader::new(Cursor::new(image)).unwrap();
+        assert_eq!(reader.data_extents().unwrap(), vec![GRAIN as u64..2 * GRAIN as u64]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert!(data[..GRAIN] == [0; GRAIN]);
+        assert!(data[GRAIN..2 * GRAIN] == grain[..]);
+        assert!(data[2 * GRAIN..] == [0; 2 * GRAIN]);
+    }
+
+    #[test]
+    fn corrupt_sizes() {
+        let error = |image: Vec<u8>| Reader::new(Cursor::new(image)).err().unwrap().kind();
+
+        // Capacity overflowing when converted to bytes
+        assert_eq!(error(sparse(u64::MAX, 4)), std::io::ErrorKind::InvalidData);
+
+        // A grain directory of 16 GiB, in a file of 12 KiB
+        assert_eq!(error(sparse(1 << 43, 1)), std::io::ErrorKind::InvalidData);
+
+        // Grain directory offset overflowing when converted to bytes
+        let mut image = sparse(60, 4);
+        image[56..64].copy_from_slice(&(GD_AT_END - 1).to_le_bytes());
+        assert_eq!(error(image), std::io::ErrorKind::InvalidData);
+
+        // Grain tables of 16 GiB each
+        let mut reader = Reader::new(Cursor::new(sparse(60, u32::MAX))).unwrap();
+        assert_eq!(reader.data_extents().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(reader.read(&mut [0; 512]).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Windows shadow copies: converting from a temporary VSS snapshot of a
+//! volume gives an application-consistent image of a volume that is in use,
+//! as the VSS writers of running applications flush their data first.
+//!
+//! The shadow copy is created and deleted through WMI, with PowerShell.
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+#[cfg(windows)]
+use std::process::{Command, Stdio};
+
+#[cfg(windows)]
+use crate::log::message;
+
+/// A temporary shadow copy of a volume, deleted on drop.
+pub struct ShadowCopy {
+    id: String,
+    /// Device of the shadow copy, as `\\?\GLOBALROOT\Device\...`
+    pub device: PathBuf,
+}
+
+impl ShadowCopy {
+    /// Create a shadow copy of the volume given as a drive letter (`C:`), a
+    /// device path (`\\.\C:`) or a volume GUID path.
+    #[cfg(windows)]
+    pub fn create(volume: &OsStr) -> std::io::Result<ShadowCopy> {
+        let volume = volume.to_str().ok_or_else(|| std::io::Error::other("invalid volume name"))?;
+        // WMI wants the root of the volume, with a trailing backslash
+        let mut root = volume.strip_prefix(r"\\.\").unwrap_or(volume).to_owned();
+        if !root.ends_with('\\') {
+            root.push('\\');
+        }
+
+        let script = format!(
+            "$r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+             -Arguments @{{Volume='{}'; Context='ClientAccessible'}}; \
+             if ($r.ReturnValue -ne 0) {{ [Console]::Error.WriteLine('error ' + $r.ReturnValue); exit 1 }}; \
+             $s = Get-CimInstance Win32_ShadowCopy -Filter \"ID='$($r.ShadowID)'\"; \
+             Write-Output $r.ShadowID; Write-Output $s.DeviceObject",
+            root.replace('\'', "''"),
+        );
+        let output = run(&script)?;
+        let output = String::from_utf8_lossy(&output);
+        let mut lines = output.lines().map(str::trim);
+        let (Some(id), Some(device)) = (lines.next(), lines.next()) else {
+            return Err(std::io::Error::other("unexpected output from PowerShell"));
+        };
+        Ok(ShadowCopy {
+            id: id.to_owned(),
+            device: PathBuf::from(device),
+        })
+    }
+
+    #[cfg(not(windows))]
+    pub fn create(_volume: &OsStr) -> std::io::Result<ShadowCopy> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "shadow copies are only available on Windows",
+        ))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!(
+            "Get-CimInstance Win32_ShadowCopy -Filter \"ID='{}'\" | Remove-CimInstance",
+            self.id.replace('\'', "''"),
+        );
+        if run(&script).is_err() {
+            message!("Warning: failed to delete shadow copy {}", self.id);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn run(script: &str) -> std::io::Result<Vec<u8>> {
+    let output = Command::new("powershell")
+        .arg("-NoProfile")
+        .arg("-NonInteractive")
+        .arg("-Command")
+        .arg(script)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("PowerShell command failed ({})", output.status)));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,111 @@
+//! Helpers shared by the integration tests, which check the images against
+//! QEMU's own tools.
+
+// Each test uses only some of them
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use streaming_qcow2_writer::fixture::{Fill, Fixture};
+use streaming_qcow2_writer::qcow2::StreamingQcow2Writer;
+
+/// Directory removed with its contents when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("streaming-qcow2-writer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Whether a program can be run, to skip the tests that need it otherwise.
+pub fn have_program(name: &str) -> bool {
+    let found = Command::new(name)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+    if !found {
+        eprintln!("{} not found, skipping", name);
+    }
+    found
+}
+
+/// Run a command, panicking with its error output if it fails.
+pub fn run(command: &mut Command) -> Output {
+    let output = command.stderr(Stdio::piped()).output()
+        .unwrap_or_else(|e| panic!("can't run {:?}: {}", command, e));
+    if !output.status.success() {
+        panic!(
+            "{:?} failed ({}): {}",
+            command, output.status, String::from_utf8_lossy(&output.stderr).trim(),
+        );
+    }
+    output
+}
+
+/// Run a command and parse its output as JSON.
+pub fn run_json(command: &mut Command) -> serde_json::Value {
+    serde_json::from_slice(&run(command).stdout).unwrap()
+}
+
+/// Write a raw image of this size with random data in these extents,
+/// leaving holes elsewhere.
+pub fn write_input(path: &Path, size: u64, extents: &[Range<u64>]) {
+    let fixture = Fixture {
+        size,
+        extents: extents.to_vec(),
+        fill: Fill::Random,
+        seed: 42,
+    };
+    fixture.write(path).unwrap();
+}
+
+/// Options of a conversion made with the library.
+#[derive(Default)]
+pub struct Conversion {
+    /// Ranges to copy, the whole input if not set
+    pub layout: Option<Vec<Range<u64>>>,
+    pub virtual_size: Option<u64>,
+    pub zero_ranges: Vec<Range<u64>>,
+}
+
+impl Conversion {
+    /// Set up a writer for an input of this size.
+    pub fn writer(&self, size: u64) -> StreamingQcow2Writer {
+        let layout = self.layout.clone().unwrap_or_else(|| std::iter::once(0..size).collect());
+        let mut writer = StreamingQcow2Writer::new(size, layout.into_iter()).unwrap();
+        if let Some(virtual_size) = self.virtual_size {
+            writer.set_virtual_size(virtual_size);
+        }
+        writer.set_zero_ranges(&self.zero_ranges).unwrap();
+        writer
+    }
+
+    /// Convert a raw image to qcow2.
+    pub fn write(&self, input: &Path, output: &Path) {
+        let writer = self.writer(std::fs::metadata(input).unwrap().len());
+        let mut file = BufWriter::new(File::create(output).unwrap());
+        writer.write_header(&mut file).unwrap();
+        writer.copy_data(File::open(input).unwrap(), &mut file, None).unwrap();
+        file.into_inner().unwrap().sync_all().unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! Golden-reference tests: for a matrix of sizes and layouts, images written
+//! by the crate are compared with the ones `qemu-img convert` makes from the
+//! same input, on guest-visible content, allocation and key header fields.
+//!
+//! They are skipped if `qemu-img` isn't installed.
+
+mod common;
+
+use std::ops::Range;
+use std::path::Path;
+use std::process::Command;
+
+use common::{run, run_json, Conversion, TempDir};
+use streaming_qcow2_writer::fixture::random_extents;
+use streaming_qcow2_writer::layout;
+
+const CLUSTER_SIZE: u64 = 65536;
+const M: u64 = 1 << 20;
+const G: u64 = 1 << 30;
+const T: u64 = 1 << 40;
+
+struct Case {
+    name: &'static str,
+    size: u64,
+    /// Extents of the input holding data
+    extents: Vec<Range<u64>>,
+    conversion: Conversion,
+}
+
+impl Case {
+    /// Convert with the extents as the layout.
+    fn exact(name: &'static str, size: u64, extents: Vec<Range<u64>>) -> Case {
+        let conversion = Conversion { layout: Some(extents.clone()), ..Default::default() };
+        Case { name, size, extents, conversion }
+    }
+
+    /// Whether the image should allocate the same clusters as `qemu-img
+    /// convert`, which only allocates those with data.
+    fn exact_layout(&self) -> bool {
+        self.conversion.layout.as_ref() == Some(&self.extents)
+    }
+}
+
+fn cases() -> Vec<Case> {
+    vec![
+        Case::exact("one-sector", 512, std::iter::once(0..512).collect()),
+        Case::exact("unaligned-size", 3 * CLUSTER_SIZE + 1000, random_extents(3 * CLUSTER_SIZE + 1000, 5, 1)),
+        Case::exact("empty", 64 * M, Vec::new()),
+        // Several L2 tables
+        Case::exact("sparse", G + 3 * CLUSTER_SIZE, random_extents(G + 3 * CLUSTER_SIZE, 50, 2)),
+        Case {
+            name: "whole-input",
+            size: 64 * M,
+            extents: random_extents(64 * M, 10, 3),
+            conversion: Conversion::default(),
+        },
+        Case {
+            name: "larger-virtual-size",
+            size: 8 * M,
+            extents: random_extents(8 * M, 10, 4),
+            conversion: Conversion { virtual_size: Some(6 * G), ..Default::default() },
+        },
+        // Several clusters of L1 table
+        Case {
+            name: "multi-cluster-l1",
+            size: M,
+            extents: std::iter::once(0..M).collect(),
+            conversion: Conversion { virtual_size: Some(5 * T), ..Default::default() },
+        },
+        {
+            let extents = random_extents(64 * M, 10, 5);
+            let gaps = layout::subtract(std::iter::once(0..64 * M).collect(), extents.clone());
+            Case {
+                name: "zero-clusters",
+                size: 64 * M,
+                conversion: Conversion { layout: Some(extents.clone()), zero_ranges: gaps, ..Default::default() },
+                extents,
+            }
+        },
+    ]
+}
+
+/// Byte ranges `qemu-img map` reports as holding data.
+fn allocated(image: &Path) -> Vec<Range<u64>> {
+    let map = run_json(Command::new("qemu-img").args(["map", "--output=json"]).arg(image));
+    let ranges = map.as_array().unwrap().iter()
+        .filter(|e| e["data"] == true)
+        .map(|e| {
+            let start = e["start"].as_u64().unwrap();
+            start..start + e["length"].as_u64().unwrap()
+        })
+        .collect();
+    layout::normalize(ranges)
+}
+
+fn info(image: &Path) -> serde_json::Value {
+    run_json(Command::new("qemu-img").args(["info", "--output=json"]).arg(image))
+}
+
+#[test]
+fn matches_qemu_img() {
+    if !common::have_program("qemu-img") {
+        return;
+    }
+    for case in cases() {
+        let dir = TempDir::new(case.name);
+        let (input, image, reference) = (dir.path("input.raw"), dir.path("image.qcow2"), dir.path("reference.qcow2"));
+        common::write_input(&input, case.size, &case.extents);
+        case.conversion.write(&input, &image);
+
+        // The reference is converted from the input grown to the virtual
+        // size, as this tool does
+        let reference_input = match case.conversion.virtual_size {
+            Some(size) => {
+                let path = dir.path("reference.raw");
+                common::write_input(&path, size, &case.extents);
+                path
+            }
+            None => input.clone(),
+        };
+        run(Command::new("qemu-img").args(["convert", "-f", "raw", "-O", "qcow2"]).arg(&reference_input).arg(&reference));
+
+        run(Command::new("qemu-img").args(["check", "-f", "qcow2"]).arg(&image));
+        run(Command::new("qemu-img").args(["compare", "-f", "raw", "-F", "qcow2"]).arg(&input).arg(&image));
+        run(Command::new("qemu-img").args(["compare", "-f", "qcow2", "-F", "qcow2"]).arg(&reference).arg(&image));
+
+        let (ours, theirs) = (info(&image), info(&reference));
+        for field in ["virtual-size", "cluster-size", "format"] {
+            assert_eq!(ours[field], theirs[field], "{}: {}", case.name, field);
+        }
+        let ours = &ours["format-specific"]["data"];
+        let theirs = &theirs["format-specific"]["data"];
+        assert_eq!(ours["refcount-bits"], theirs["refcount-bits"], "{}: refcount-bits", case.name);
+        let compat = if case.conversion.zero_ranges.is_empty() { "0.10" } else { "1.1" };
+        assert_eq!(ours["compat"], compat, "{}: compat", case.name);
+
+        if case.exact_layout() {
+            assert_eq!(allocated(&image), allocated(&reference), "{}: allocated clusters", case.name);
+        }
+    }
+}
+
+/// The description of the image from the writer matches `qemu-img info`.
+#[test]
+fn info_matches_qemu_img() {
+    if !common::have_program("qemu-img") {
+        return;
+    }
+    let dir = TempDir::new("info");
+    let (input, image) = (dir.path("input.raw"), dir.path("image.qcow2"));
+    let extents = random_extents(64 * M, 10, 6);
+    common::write_input(&input, 64 * M, &extents);
+    let conversion = Conversion { layout: Some(extents), virtual_size: Some(G), ..Default::default() };
+    conversion.write(&input, &image);
+
+    let ours = conversion.writer(64 * M).info(&image.to_string_lossy());
+    let theirs = info(&image);
+    for field in ["virtual-size", "cluster-size", "format", "filename"] {
+        assert_eq!(ours[field], theirs[field], "{}", field);
+    }
+    for field in ["compat", "refcount-bits"] {
+        assert_eq!(ours["format-specific"]["data"][field], theirs["format-specific"]["data"][field], "{}", field);
+    }
+}
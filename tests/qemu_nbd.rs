@@ -0,0 +1,122 @@
+//! End-to-end test through QEMU: images are exported with `qemu-nbd` on a
+//! kernel NBD device, and the guest data read back from the device is
+//! compared with the input byte for byte.
+//!
+//! This needs root and the `nbd` module, so it only runs if a free device is
+//! given, for example with
+//! `STREAMING_QCOW2_TEST_NBD_DEVICE=/dev/nbd0 cargo test --test qemu_nbd`.
+
+mod common;
+
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use common::{run, Conversion, TempDir};
+use streaming_qcow2_writer::fixture::random_extents;
+use streaming_qcow2_writer::layout;
+
+const CLUSTER_SIZE: u64 = 65536;
+const M: u64 = 1 << 20;
+
+/// Image connected to the NBD device, disconnected when dropped.
+struct Connection<'a> {
+    device: &'a Path,
+}
+
+impl<'a> Connection<'a> {
+    fn connect(device: &'a Path, image: &Path) -> Connection<'a> {
+        run(Command::new("qemu-nbd")
+            .args(["--read-only", "--format=qcow2", "--cache=none"])
+            .arg(format!("--connect={}", device.display()))
+            .arg(image));
+        let connection = Connection { device };
+        // The device gets its size once the kernel has set up the connection
+        let name = device.file_name().unwrap().to_string_lossy();
+        let size_path = format!("/sys/block/{}/size", name);
+        let started = Instant::now();
+        while std::fs::read_to_string(&size_path).map_or(true, |s| s.trim() == "0") {
+            assert!(started.elapsed() < Duration::from_secs(10), "{} didn't connect", device.display());
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        connection
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        let _ = Command::new("qemu-nbd").arg("--disconnect").arg(self.device).status();
+    }
+}
+
+/// Check that the device reads as the input, followed by zeros up to
+/// `size`.
+fn compare(device: &Path, input: &Path, size: u64) {
+    let mut device = File::open(device).unwrap();
+    let mut input = File::open(input).unwrap();
+    let mut expected = vec![0u8; M as usize];
+    let mut actual = vec![0u8; M as usize];
+    let mut offset = 0;
+    while offset < size {
+        let length = (size - offset).min(M) as usize;
+        streaming_qcow2_writer::qcow2::read_full(&mut input, &mut expected[..length]).unwrap();
+        device.read_exact(&mut actual[..length]).unwrap();
+        if let Some(i) = (0..length).find(|&i| expected[i] != actual[i]) {
+            panic!("data differs at offset {}", offset + i as u64);
+        }
+        offset += length as u64;
+    }
+    assert_eq!(device.read(&mut actual).unwrap(), 0, "device is larger than {} bytes", size);
+}
+
+#[test]
+fn reads_back_through_qemu_nbd() {
+    let Some(device) = std::env::var_os("STREAMING_QCOW2_TEST_NBD_DEVICE").map(PathBuf::from) else {
+        eprintln!("STREAMING_QCOW2_TEST_NBD_DEVICE not set, skipping");
+        return;
+    };
+    if !common::have_program("qemu-nbd") {
+        return;
+    }
+
+    let sparse = random_extents(256 * M, 50, 7);
+    let cases: Vec<(&str, u64, Vec<Range<u64>>, Conversion)> = vec![
+        ("whole-input", 32 * M, random_extents(32 * M, 10, 8), Conversion::default()),
+        ("sparse", 256 * M, sparse.clone(), Conversion { layout: Some(sparse.clone()), ..Default::default() }),
+        (
+            "unaligned-size",
+            3 * CLUSTER_SIZE + 1024,
+            std::iter::once(0..3 * CLUSTER_SIZE + 1024).collect(),
+            Conversion::default(),
+        ),
+        (
+            "larger-virtual-size",
+            8 * M,
+            random_extents(8 * M, 10, 9),
+            Conversion { virtual_size: Some(64 * M), ..Default::default() },
+        ),
+        (
+            "zero-clusters",
+            256 * M,
+            sparse.clone(),
+            Conversion {
+                layout: Some(sparse.clone()),
+                zero_ranges: layout::subtract(std::iter::once(0..256 * M).collect(), sparse),
+                ..Default::default()
+            },
+        ),
+    ];
+    for (name, size, extents, conversion) in cases {
+        let dir = TempDir::new(name);
+        let (input, image) = (dir.path("input.raw"), dir.path("image.qcow2"));
+        common::write_input(&input, size, &extents);
+        conversion.write(&input, &image);
+
+        let _connection = Connection::connect(&device, &image);
+        eprintln!("Comparing {}", name);
+        compare(&device, &input, conversion.virtual_size.unwrap_or(size));
+    }
+}